@@ -0,0 +1,316 @@
+//! aan - always available network
+//!
+//! aan keeps a byte stream alive across transports. Each write becomes a
+//! numbered message that stays in a replay buffer until the peer acks it;
+//! when the transport dies, a new one picks up where the old one stopped
+//! and the 9P session above never notices.
+//!
+//! ```text
+//! nb[4] msg[4] acked[4] data[nb]
+//! ```
+//!
+//! Integers are little-endian, as in 9P. `msg` counts from 0 in each
+//! direction; `acked` is how many messages the sender has received, so
+//! the peer drops everything numbered below it. A message with no data
+//! is only an ack (or a keepalive).
+//!
+//! Resuming is the same from either end: on the new transport each side
+//! sends an ack of what it has, then everything still unacked. Whatever
+//! the peer had already seen it discards by number.
+//!
+//! The protocol is symmetric; the client is just the end that redials.
+//!
+//! Ported from 9front's aan.c.
+
+pub const AANHDRLEN: usize = 3 * 4; // nb, msg, acked
+pub const AANBUFSIZE: usize = 8 * 1024; // Most data in one message
+pub const AANMAXTO: u64 = 24 * 3600; // Seconds to wait for a reconnect
+
+/// Error type for aan
+#[derive(Debug, Clone)]
+pub struct AanError(pub String);
+
+impl std::fmt::Display for AanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AanError {}
+
+/// One message header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AanHdr {
+    pub nb: u32,
+    pub msg: u32,
+    pub acked: u32,
+}
+
+impl AanHdr {
+    pub fn to_bytes(&self) -> [u8; AANHDRLEN] {
+        let mut b = [0u8; AANHDRLEN];
+        b[0..4].copy_from_slice(&self.nb.to_le_bytes());
+        b[4..8].copy_from_slice(&self.msg.to_le_bytes());
+        b[8..12].copy_from_slice(&self.acked.to_le_bytes());
+        b
+    }
+
+    pub fn from_bytes(b: &[u8; AANHDRLEN]) -> Self {
+        let get = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        AanHdr {
+            nb: get(0),
+            msg: get(4),
+            acked: get(8),
+        }
+    }
+}
+
+/// One end of an aan connection
+#[derive(Default)]
+pub struct Aan {
+    /// Number of the next message we send
+    outmsg: u32,
+    /// Messages received, which is what we ack
+    inmsg: u32,
+    /// Sent and not yet acked, oldest first
+    unacked: Vec<(u32, Vec<u8>)>,
+    /// A partial message from the current transport
+    inbuf: Vec<u8>,
+    /// Acks to send
+    outq: Vec<u8>,
+    /// When the peer was last heard from, if ever
+    last: Option<u64>,
+}
+
+impl Aan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages waiting for the peer's ack
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Bytes in those messages
+    pub fn unacked_bytes(&self) -> usize {
+        self.unacked.iter().map(|(_, d)| d.len()).sum()
+    }
+
+    fn frame(&self, msg: u32, data: &[u8]) -> Vec<u8> {
+        let hdr = AanHdr {
+            nb: data.len() as u32,
+            msg,
+            acked: self.inmsg,
+        };
+        let mut out = hdr.to_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// Messages carrying `data`, kept until acked.
+    pub fn send(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(AANBUFSIZE) {
+            out.extend(self.frame(self.outmsg, chunk));
+            self.unacked.push((self.outmsg, chunk.to_vec()));
+            self.outmsg = self.outmsg.wrapping_add(1);
+        }
+        out
+    }
+
+    /// An ack of everything received, doubling as a keepalive.
+    pub fn ack(&self) -> Vec<u8> {
+        self.frame(self.outmsg, &[])
+    }
+
+    /// Acks owed to the peer for what `input` delivered.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outq)
+    }
+
+    /// Bytes from the current transport at `now` (seconds); returns the
+    /// data of the new messages they complete.
+    pub fn input(&mut self, data: &[u8], now: u64) -> Result<Vec<u8>, AanError> {
+        self.inbuf.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut fresh = false;
+        while self.inbuf.len() >= AANHDRLEN {
+            let hdr = AanHdr::from_bytes(self.inbuf[..AANHDRLEN].try_into().unwrap());
+            let nb = hdr.nb as usize;
+            if nb > AANBUFSIZE {
+                return Err(AanError(format!("aan: message too long: {}", nb)));
+            }
+            if self.inbuf.len() < AANHDRLEN + nb {
+                break;
+            }
+            let body: Vec<u8> = self.inbuf.drain(..AANHDRLEN + nb).skip(AANHDRLEN).collect();
+            self.last = Some(now);
+
+            // What the peer has, we need not keep
+            if hdr.acked.wrapping_sub(self.outmsg) < (1 << 31) && hdr.acked != self.outmsg {
+                return Err(AanError(format!(
+                    "aan: peer acked {} of {} messages",
+                    hdr.acked, self.outmsg
+                )));
+            }
+            self.unacked
+                .retain(|(m, _)| hdr.acked.wrapping_sub(*m).wrapping_sub(1) >= (1 << 31));
+
+            if nb == 0 {
+                continue;
+            }
+            match hdr.msg.wrapping_sub(self.inmsg) {
+                0 => {
+                    self.inmsg = self.inmsg.wrapping_add(1);
+                    out.extend(body);
+                    fresh = true;
+                }
+                // A replay of something we have
+                d if d >= (1 << 31) => {}
+                _ => {
+                    return Err(AanError(format!(
+                        "aan: message {} after {}",
+                        hdr.msg, self.inmsg
+                    )))
+                }
+            }
+        }
+        if fresh {
+            self.outq = self.ack();
+        }
+        Ok(out)
+    }
+
+    /// Start over on a new transport: what to send first, an ack of what
+    /// we have and then every message not yet acked.
+    pub fn resume(&mut self) -> Vec<u8> {
+        self.inbuf.clear();
+        self.outq.clear();
+        let mut out = self.ack();
+        for (m, data) in &self.unacked {
+            out.extend(self.frame(*m, data));
+        }
+        out
+    }
+
+    /// Whether the peer has been gone longer than `AANMAXTO` at `now`.
+    pub fn expired(&self, now: u64) -> bool {
+        self.last.is_some_and(|t| now.saturating_sub(t) > AANMAXTO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testaan::TestAanServer;
+
+    #[test]
+    fn test_header() {
+        let hdr = AanHdr {
+            nb: 5,
+            msg: 1,
+            acked: 2,
+        };
+        let b = hdr.to_bytes();
+        assert_eq!(b, [5, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(AanHdr::from_bytes(&b), hdr);
+    }
+
+    #[test]
+    fn test_send_and_ack() {
+        let (mut a, mut b) = (Aan::new(), Aan::new());
+        let m = a.send(b"Tversion");
+        assert_eq!(m.len(), AANHDRLEN + 8);
+        assert_eq!(a.unacked(), 1);
+        // Any chunking of the stream
+        let mut got = Vec::new();
+        for c in m.chunks(5) {
+            got.extend(b.input(c, 0).unwrap());
+        }
+        assert_eq!(got, b"Tversion");
+        a.input(&b.take(), 0).unwrap();
+        assert_eq!(a.unacked(), 0);
+        // Acks are not acked
+        assert!(a.take().is_empty());
+
+        let big = vec![7u8; AANBUFSIZE + 1];
+        b.input(&a.send(&big), 0).unwrap();
+        assert_eq!(a.unacked(), 2);
+    }
+
+    #[test]
+    fn test_resume_replays_unacked() {
+        let (mut a, mut b) = (Aan::new(), Aan::new());
+        b.input(&a.send(b"Twalk"), 0).unwrap();
+        // b's ack and a's next message are lost with the transport
+        b.take();
+        let lost = a.send(b"Topen");
+        b.input(&lost[..7], 0).unwrap();
+
+        let from_b = b.resume();
+        let from_a = a.resume();
+        assert_eq!(a.input(&from_b, 1).unwrap(), b"");
+        assert_eq!(a.unacked(), 1);
+        // Twalk is dropped as a replay, Topen delivered once
+        assert_eq!(b.input(&from_a, 1).unwrap(), b"Topen");
+        a.input(&b.take(), 1).unwrap();
+        assert_eq!(a.unacked(), 0);
+    }
+
+    #[test]
+    fn test_bad_input() {
+        let mut a = Aan::new();
+        let hdr = AanHdr {
+            nb: 1,
+            msg: 3,
+            acked: 0,
+        };
+        let mut m = hdr.to_bytes().to_vec();
+        m.push(0);
+        assert!(Aan::new().input(&m, 0).is_err());
+        let hdr = AanHdr {
+            nb: 0,
+            msg: 0,
+            acked: 1,
+        };
+        assert!(a.input(&hdr.to_bytes(), 0).is_err());
+        let hdr = AanHdr {
+            nb: AANBUFSIZE as u32 + 1,
+            msg: 0,
+            acked: 0,
+        };
+        assert!(Aan::new().input(&hdr.to_bytes(), 0).is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let (mut a, b) = (Aan::new(), Aan::new());
+        assert!(!a.expired(u64::MAX));
+        a.input(&b.ack(), 100).unwrap();
+        assert!(!a.expired(100 + AANMAXTO));
+        assert!(a.expired(101 + AANMAXTO));
+    }
+
+    #[test]
+    fn test_reconnect_to_server() {
+        let mut srv = TestAanServer::new();
+        let mut c = Aan::new();
+        srv.connect();
+        srv.put(&c.send(b"Tversion"));
+        assert_eq!(c.input(&srv.take(), 0).unwrap(), b"Tversion");
+        srv.put(&c.take());
+
+        // Sent into a dying transport; the echo is lost too
+        srv.put(&c.send(b"Tattach"));
+        srv.hangup();
+        srv.connect();
+        srv.put(&c.resume());
+        let mut got = c.input(&srv.take(), 1).unwrap();
+        srv.put(&c.take());
+        got.extend(c.input(&srv.take(), 1).unwrap());
+        assert_eq!(got, b"Tattach");
+        assert_eq!(srv.received(), b"TversionTattach");
+        assert_eq!(c.unacked(), 0);
+    }
+}
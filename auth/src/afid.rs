@@ -0,0 +1,612 @@
+//! afid - authenticate a 9P attach through an auth fid
+//!
+//! A file server that wants authentication answers Tauth with an auth
+//! fid. The client runs factotum's conversation by reading and writing
+//! that fid, then names it in Tattach; the server checks the afid has
+//! authenticated the user and attaches.
+//!
+//! `AuthFid` does the whole thing without I/O: it says what T-message to
+//! send, what to ask the auth server, or what key it needs, and is given
+//! the answers. It runs an `AuthProxy` over the fid: writes carry what
+//! the proxy has for the peer, and reads ask for exactly what its
+//! `toosmall` says is missing, never past the iounit.
+//! A server that refuses Tauth needs no authentication, and the attach
+//! goes ahead with NOFID, as amount does.
+//!
+//! `attach` drives one to the end over a `Conn9p`, for callers that can
+//! block.
+//!
+//! Ported from 9front's libauth amount.c and auth_proxy.c.
+
+use std::io;
+
+use crate::keyring::Key;
+use crate::rpc::{AuthInfo, AuthProxy, Factotum, KeyRequest, ProxyState, RpcReply};
+
+pub const NOFID: u32 = 0xFFFFFFFF;
+pub const IOHDRSZ: u32 = 24; // Room for a Twrite/Rread header
+pub const QIDLEN: usize = 13;
+
+pub const TAUTH: u8 = 102;
+pub const RAUTH: u8 = 103;
+pub const TATTACH: u8 = 104;
+pub const RATTACH: u8 = 105;
+pub const RERROR: u8 = 107;
+pub const TREAD: u8 = 116;
+pub const RREAD: u8 = 117;
+pub const TWRITE: u8 = 118;
+pub const RWRITE: u8 = 119;
+pub const TCLUNK: u8 = 120;
+pub const RCLUNK: u8 = 121;
+
+pub const AFIDPARAMS: &str = "proto=p9any role=client";
+
+/// Error type for auth fid conversations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AfidError(pub String);
+
+impl std::fmt::Display for AfidError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AfidError {}
+
+impl From<io::Error> for AfidError {
+    fn from(e: io::Error) -> Self {
+        AfidError(e.to_string())
+    }
+}
+
+// ============================================================================
+// 9P messages
+// ============================================================================
+
+/// A file's server-unique identification
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Qid {
+    pub qtype: u8,
+    pub vers: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn to_bytes(&self) -> [u8; QIDLEN] {
+        let mut buf = [0u8; QIDLEN];
+        buf[0] = self.qtype;
+        buf[1..5].copy_from_slice(&self.vers.to_le_bytes());
+        buf[5..].copy_from_slice(&self.path.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Qid> {
+        let buf: &[u8; QIDLEN] = buf.get(..QIDLEN)?.try_into().ok()?;
+        Some(Qid {
+            qtype: buf[0],
+            vers: u32::from_le_bytes(buf[1..5].try_into().unwrap()),
+            path: u64::from_le_bytes(buf[5..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Build a message: `size[4] type[1] tag[2]` and `body`.
+pub fn pack(mtype: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+    let mut m = Vec::with_capacity(7 + body.len());
+    m.extend_from_slice(&(7 + body.len() as u32).to_le_bytes());
+    m.push(mtype);
+    m.extend_from_slice(&tag.to_le_bytes());
+    m.extend_from_slice(body);
+    m
+}
+
+/// Split a whole message into type, tag and body.
+pub fn unpack(m: &[u8]) -> Option<(u8, u16, &[u8])> {
+    let size = u32::from_le_bytes(m.get(..4)?.try_into().unwrap()) as usize;
+    if size < 7 || size != m.len() {
+        return None;
+    }
+    Some((m[4], u16::from_le_bytes([m[5], m[6]]), &m[7..]))
+}
+
+/// Append a 9P string: `len[2]` and the bytes.
+pub fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Take a 9P string off the front of `buf`.
+pub fn get_string(buf: &mut &[u8]) -> Option<String> {
+    let n = u16::from_le_bytes(buf.get(..2)?.try_into().unwrap()) as usize;
+    let s = buf.get(2..2 + n)?;
+    let s = String::from_utf8_lossy(s).into_owned();
+    *buf = &buf[2 + n..];
+    Some(s)
+}
+
+fn tauth(afid: u32, uname: &str, aname: &str) -> Vec<u8> {
+    let mut b = afid.to_le_bytes().to_vec();
+    put_string(&mut b, uname);
+    put_string(&mut b, aname);
+    pack(TAUTH, 0, &b)
+}
+
+fn tattach(fid: u32, afid: u32, uname: &str, aname: &str) -> Vec<u8> {
+    let mut b = fid.to_le_bytes().to_vec();
+    b.extend_from_slice(&afid.to_le_bytes());
+    put_string(&mut b, uname);
+    put_string(&mut b, aname);
+    pack(TATTACH, 0, &b)
+}
+
+fn tread(fid: u32, offset: u64, count: u32) -> Vec<u8> {
+    let mut b = fid.to_le_bytes().to_vec();
+    b.extend_from_slice(&offset.to_le_bytes());
+    b.extend_from_slice(&count.to_le_bytes());
+    pack(TREAD, 0, &b)
+}
+
+fn twrite(fid: u32, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut b = fid.to_le_bytes().to_vec();
+    b.extend_from_slice(&offset.to_le_bytes());
+    b.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    b.extend_from_slice(data);
+    pack(TWRITE, 0, &b)
+}
+
+fn tclunk(fid: u32) -> Vec<u8> {
+    pack(TCLUNK, 0, &fid.to_le_bytes())
+}
+
+/// The body of R-message `m` answering a T-message of type `t`, or the
+/// server's error.
+fn rbody(m: &[u8], t: u8) -> Result<&[u8], AfidError> {
+    match unpack(m) {
+        Some((RERROR, _, mut body)) => Err(AfidError(
+            get_string(&mut body).unwrap_or_else(|| "bad Rerror".to_string()),
+        )),
+        Some((r, 0, body)) if r == t + 1 => Ok(body),
+        _ => Err(AfidError(format!(
+            "afid: bad reply to 9P message type {}",
+            t
+        ))),
+    }
+}
+
+// ============================================================================
+// Auth fid driver
+// ============================================================================
+
+/// Who attaches to what, with which fids
+#[derive(Debug, Clone)]
+pub struct Attach {
+    pub uname: String,
+    pub aname: String,
+    pub fid: u32,
+    pub afid: u32,
+    /// As agreed by Tversion
+    pub msize: u32,
+    /// What to start factotum with
+    pub params: String,
+}
+
+impl Attach {
+    /// Fid 0 for the root, 1 for authentication, and the msize the
+    /// client offers.
+    pub fn new(uname: &str, aname: &str) -> Self {
+        Attach {
+            uname: uname.to_string(),
+            aname: aname.to_string(),
+            fid: 0,
+            afid: 1,
+            msize: 8192,
+            params: AFIDPARAMS.to_string(),
+        }
+    }
+}
+
+/// What a finished attach got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attached {
+    pub qid: Qid,
+    /// The auth fid, still open, or NOFID if the server wanted none
+    pub afid: u32,
+    pub ai: Option<AuthInfo>,
+}
+
+/// What the driver needs next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Send the T-message and give its R-message to `reply`
+    Rpc(Vec<u8>),
+    /// Send `data` to the auth server for `dom` and give what comes back
+    /// to `authsrv_reply`; empty `data` asks for more of the answer
+    Authsrv {
+        dom: String,
+        data: Vec<u8>,
+    },
+    /// Give a key to `supply_key`, or `cancel`
+    Key(KeyRequest),
+    Done(Attached),
+    Failed(AfidError),
+}
+
+/// Where the driver stands
+#[derive(Debug, Clone)]
+enum State {
+    Tauth,
+    Proxy,
+    /// Twrite of this much of `out`
+    Write(usize),
+    /// Tread of this many bytes
+    Read(usize),
+    /// Send this to the auth server
+    Authsrv(Vec<u8>),
+    Key,
+    Attach,
+    Clunk(AfidError),
+    Done(Attached),
+    Failed(AfidError),
+}
+
+/// One attach, authenticated through an auth fid, without the I/O
+pub struct AuthFid {
+    p: AuthProxy,
+    a: Attach,
+    state: State,
+    /// The auth fid the server gave, if any
+    afid: Option<u32>,
+    /// Offset of the afid, moved by reads and writes alike
+    offset: u64,
+    /// What the proxy has for the peer, not yet written
+    out: Vec<u8>,
+    authsrv_dom: String,
+    ai: Option<AuthInfo>,
+}
+
+impl AuthFid {
+    pub fn new(f: Factotum, a: Attach) -> Self {
+        let p = AuthProxy::new(f, &a.params);
+        let state = match p.state() {
+            ProxyState::Failed(e) => State::Failed(AfidError(e.clone())),
+            _ => State::Tauth,
+        };
+        AuthFid {
+            p,
+            a,
+            state,
+            afid: None,
+            offset: 0,
+            out: Vec::new(),
+            authsrv_dom: String::new(),
+            ai: None,
+        }
+    }
+
+    pub fn factotum(&self) -> &Factotum {
+        self.p.factotum()
+    }
+
+    fn iounit(&self) -> usize {
+        self.a.msize.saturating_sub(IOHDRSZ).max(1) as usize
+    }
+
+    /// What to do next. Until it is answered the same step comes back.
+    pub fn step(&mut self) -> Step {
+        let afid = self.afid.unwrap_or(self.a.afid);
+        match &self.state {
+            State::Tauth => Step::Rpc(tauth(afid, &self.a.uname, &self.a.aname)),
+            State::Proxy => self.proxy(),
+            State::Write(n) => Step::Rpc(twrite(afid, self.offset, &self.out[..*n])),
+            State::Read(n) => Step::Rpc(tread(afid, self.offset, *n as u32)),
+            State::Authsrv(data) => Step::Authsrv {
+                dom: self.authsrv_dom.clone(),
+                data: data.clone(),
+            },
+            State::Key => match self.p.factotum().key_request() {
+                Some(req) => Step::Key(req.clone()),
+                None => self.fail("afid: key request lost".to_string()),
+            },
+            State::Attach => Step::Rpc(tattach(
+                self.a.fid,
+                self.afid.unwrap_or(NOFID),
+                &self.a.uname,
+                &self.a.aname,
+            )),
+            State::Clunk(_) => Step::Rpc(tclunk(afid)),
+            State::Done(a) => Step::Done(a.clone()),
+            State::Failed(e) => Step::Failed(e.clone()),
+        }
+    }
+
+    /// The R-message answering the last `Step::Rpc`.
+    pub fn reply(&mut self, m: &[u8]) {
+        let state = std::mem::replace(&mut self.state, State::Proxy);
+        let r = match state {
+            State::Tauth => {
+                match rbody(m, TAUTH) {
+                    Ok(_) => self.afid = Some(self.a.afid),
+                    // No authentication required
+                    Err(_) => self.state = State::Attach,
+                }
+                return;
+            }
+            State::Write(n) => rbody(m, TWRITE).and_then(|b| match b.get(..4) {
+                Some(c) if c == (n as u32).to_le_bytes() => {
+                    self.out.drain(..n);
+                    self.offset += n as u64;
+                    Ok(())
+                }
+                _ => Err(AfidError("afid: short write".to_string())),
+            }),
+            State::Read(n) => rbody(m, TREAD).and_then(|b| {
+                let count = b
+                    .get(..4)
+                    .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
+                match count {
+                    Some(0) => Err(AfidError("afid: unexpected eof".to_string())),
+                    Some(c) if c as usize <= n && b.len() == 4 + c as usize => {
+                        self.offset += c as u64;
+                        self.p.put(&b[4..]);
+                        Ok(())
+                    }
+                    _ => Err(AfidError("afid: bad Rread".to_string())),
+                }
+            }),
+            State::Attach => {
+                match rbody(m, TATTACH).and_then(|b| {
+                    Qid::from_bytes(b).ok_or_else(|| AfidError("afid: bad Rattach".to_string()))
+                }) {
+                    Ok(qid) => {
+                        self.state = State::Done(Attached {
+                            qid,
+                            afid: self.afid.unwrap_or(NOFID),
+                            ai: self.ai.clone(),
+                        })
+                    }
+                    Err(e) => {
+                        self.fail(e.0);
+                    }
+                }
+                return;
+            }
+            State::Clunk(e) => {
+                self.afid = None;
+                self.state = State::Failed(e);
+                return;
+            }
+            s => {
+                self.state = s;
+                return;
+            }
+        };
+        if let Err(e) = r {
+            self.fail(e.0);
+        }
+    }
+
+    /// What the auth server sent for the last `Step::Authsrv`.
+    pub fn authsrv_reply(&mut self, data: &[u8]) {
+        if let State::Authsrv(_) = self.state {
+            if data.is_empty() {
+                self.fail("afid: auth server hung up".to_string());
+                return;
+            }
+            self.p.put_authsrv(data);
+            self.state = State::Proxy;
+        }
+    }
+
+    /// Add the key a `Step::Key` asked for and carry on.
+    pub fn supply_key(&mut self, key: Key) {
+        if let State::Key = self.state {
+            self.p.supply_key(key);
+            self.state = State::Proxy;
+        }
+    }
+
+    /// Give up, clunking the auth fid.
+    pub fn cancel(&mut self, why: &str) {
+        if !matches!(
+            self.state,
+            State::Clunk(_) | State::Done(_) | State::Failed(_)
+        ) {
+            self.fail(why.to_string());
+        }
+    }
+
+    /// Fail with `e`, after clunking the auth fid if the server gave one.
+    fn fail(&mut self, e: String) -> Step {
+        self.state = match self.afid {
+            Some(_) => State::Clunk(AfidError(e)),
+            None => State::Failed(AfidError(e)),
+        };
+        self.step()
+    }
+
+    /// Turn what the proxy waits on into 9P or auth server I/O.
+    fn proxy(&mut self) -> Step {
+        self.out.extend(self.p.take());
+        if !self.out.is_empty() {
+            self.state = State::Write(self.out.len().min(self.iounit()));
+            return self.step();
+        }
+        self.state = match self.p.state() {
+            ProxyState::Peer => State::Read(self.p.need().min(self.iounit())),
+            ProxyState::Authsrv => match self.p.take_authsrv() {
+                Some(RpcReply::Authsrv { dom, data }) => {
+                    self.authsrv_dom = dom;
+                    State::Authsrv(data)
+                }
+                // The rest of an answer already asked for
+                _ => State::Authsrv(Vec::new()),
+            },
+            ProxyState::Key => State::Key,
+            ProxyState::Done => {
+                self.ai = self.p.authinfo().cloned();
+                State::Attach
+            }
+            ProxyState::Failed(e) => return self.fail(e.clone()),
+        };
+        self.step()
+    }
+}
+
+/// A 9P connection and a way to the auth server, for `attach`
+pub trait Conn9p {
+    /// Send one T-message and return the R-message answering it.
+    fn rpc(&mut self, tmsg: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Send `data` to the auth server for `dom` and return what it has
+    /// answered so far; empty `data` asks for more of the same answer.
+    fn authsrv(&mut self, dom: &str, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// The key for a needkey, or None to give up.
+    fn key(&mut self, _req: &KeyRequest) -> Option<Key> {
+        None
+    }
+}
+
+/// Authenticate through an auth fid and attach, as amount does, with
+/// `f`'s keys. The connection must have been through Tversion already.
+pub fn attach(conn: &mut dyn Conn9p, f: Factotum, a: Attach) -> Result<Attached, AfidError> {
+    let mut d = AuthFid::new(f, a);
+    loop {
+        match d.step() {
+            Step::Rpc(t) => d.reply(&conn.rpc(&t)?),
+            Step::Authsrv { dom, data } => d.authsrv_reply(&conn.authsrv(&dom, &data)?),
+            Step::Key(req) => match conn.key(&req) {
+                Some(key) => d.supply_key(key),
+                None => d.cancel(&format!("needkey {}", req.template())),
+            },
+            Step::Done(a) => return Ok(a),
+            Step::Failed(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::test9p::{TestFs, ROOTQID};
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    struct Conn {
+        fs: TestFs,
+        srv: TestAuthsrv,
+        answer: Vec<u8>,
+        password: Option<&'static str>,
+    }
+
+    impl Conn9p for Conn {
+        fn rpc(&mut self, tmsg: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(self.fs.rpc(tmsg))
+        }
+
+        // The answer comes back a little at a time
+        fn authsrv(&mut self, dom: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+            assert_eq!(dom, "nawin");
+            if !data.is_empty() {
+                self.answer = self.srv.serve(data);
+            }
+            let n = self.answer.len().min(50);
+            Ok(self.answer.drain(..n).collect())
+        }
+
+        fn key(&mut self, req: &KeyRequest) -> Option<Key> {
+            let pw = self.password?;
+            req.answer(&[("user", "glenda"), ("!password", pw)]).ok()
+        }
+    }
+
+    fn conn(msize: u32, offer: Option<&str>) -> Conn {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let cpu = offer.map(|o| TestServer::new("bootes", "cpusecret", "nawin", o));
+        Conn {
+            fs: TestFs::new(msize, cpu),
+            srv,
+            answer: Vec::new(),
+            password: Some("kittens"),
+        }
+    }
+
+    fn attach_with(c: &mut Conn, keys: &str, msize: u32) -> Result<Attached, AfidError> {
+        let mut a = Attach::new("glenda", "");
+        a.msize = msize;
+        attach(c, Factotum::new(Keyring::parse(keys).unwrap()), a)
+    }
+
+    #[test]
+    fn test_attach() {
+        let keys = "key proto=dp9ik dom=nawin user=glenda !password=kittens";
+        let mut c = conn(8192, Some("dp9ik@nawin p9sk1@nawin"));
+        let a = attach_with(&mut c, keys, 8192).unwrap();
+        assert_eq!(a.qid, ROOTQID);
+        assert_eq!(a.afid, 1);
+        let ai = a.ai.unwrap();
+        assert_eq!(ai.cuid, "glenda");
+        assert_eq!(Some(ai.secret), c.fs.secret());
+        assert!(c.fs.afid_open());
+        assert_eq!(unpack(&c.fs.received[0]).unwrap().0, TAUTH);
+        assert_eq!(unpack(c.fs.received.last().unwrap()).unwrap().0, TATTACH);
+    }
+
+    #[test]
+    fn test_small_msize() {
+        // iounit 40: tickets and replies cross several reads and writes
+        for offer in ["dp9ik@nawin", "p9sk1@nawin"] {
+            let mut c = conn(64, Some(offer));
+            let a = attach_with(&mut c, "", 64).unwrap();
+            assert_eq!(a.ai.unwrap().cuid, "glenda");
+            let reads = c.fs.reads();
+            assert!(reads.len() > 4, "{:?}", reads);
+            assert!(reads.iter().all(|&n| n <= 64 - IOHDRSZ), "{:?}", reads);
+        }
+    }
+
+    #[test]
+    fn test_no_auth() {
+        let mut c = conn(8192, None);
+        let a = attach_with(&mut c, "", 8192).unwrap();
+        assert_eq!(a.afid, NOFID);
+        assert_eq!(a.ai, None);
+        assert_eq!(c.fs.received.len(), 2);
+    }
+
+    #[test]
+    fn test_needkey() {
+        let mut c = conn(8192, Some("dp9ik@nawin"));
+        c.password = None;
+        let e = attach_with(&mut c, "", 8192).unwrap_err();
+        assert!(e.0.starts_with("needkey proto=dp9ik"), "{}", e);
+        assert!(!c.fs.afid_open());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let mut c = conn(8192, Some("dp9ik@nawin"));
+        c.password = Some("puppies");
+        assert!(attach_with(&mut c, "", 8192).is_err());
+        assert!(!c.fs.afid_open());
+        assert_eq!(unpack(c.fs.received.last().unwrap()).unwrap().0, TCLUNK);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut d = AuthFid::new(Factotum::new(Keyring::default()), Attach::new("glenda", ""));
+        let t = d.step();
+        assert_eq!(d.step(), t);
+        // A server that refuses Tauth gets an attach without an afid
+        let mut fs = TestFs::new(8192, None);
+        let Step::Rpc(t) = t else { panic!("{:?}", t) };
+        d.reply(&fs.rpc(&t));
+        let Step::Rpc(t) = d.step() else { panic!() };
+        assert_eq!(&t[4..5], &[TATTACH]);
+        assert_eq!(&t[11..15], &NOFID.to_le_bytes());
+        d.reply(&fs.rpc(&t));
+        assert!(matches!(d.step(), Step::Done(_)));
+    }
+}
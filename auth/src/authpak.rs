@@ -17,6 +17,7 @@ pub const PAKXLEN: usize = 56;        // Private scalar size
 pub const PAKKEYLEN: usize = 32;      // Derived ticket encryption key size
 pub const PAKPLEN: usize = 4 * PAKSLEN;  // Extended point (X,Y,Z,T) = 224 bytes
 pub const PAKHASHLEN: usize = 2 * PAKPLEN; // PM and PN points = 448 bytes
+pub const AESKEYLEN: usize = 16;      // passtoaeskey output
 
 // Ed448-Goldilocks curve parameters
 // p = 2^448 - 2^224 - 1 (Goldilocks prime)
@@ -107,13 +108,24 @@ impl std::error::Error for PakError {}
 /// 4. PN = Elligator2(h[56:112])
 pub fn authpak_hash(password: &str, username: &str) -> [u8; PAKHASHLEN] {
     // Step 1: passtoaeskey - PBKDF2 with HMAC-SHA1
-    let mut aes_key = [0u8; 16];
+    let aes_key = pass_to_aes_key(password);
+    authpak_hash_aes(&aes_key, username)
+}
+
+/// 9front's passtoaeskey: PBKDF2-HMAC-SHA1(password, "Plan 9 key derivation", 9001, 16)
+pub fn pass_to_aes_key(password: &str) -> [u8; AESKEYLEN] {
+    let mut aes_key = [0u8; AESKEYLEN];
     let salt = b"Plan 9 key derivation";
     pbkdf2_hmac::<Sha1>(password.as_bytes(), salt, 9001, &mut aes_key);
+    aes_key
+}
 
+/// authpak_hash starting from an already derived AES key, so callers holding
+/// an `Authkey` don't repeat the PBKDF2.
+pub fn authpak_hash_aes(aes_key: &[u8; AESKEYLEN], username: &str) -> [u8; PAKHASHLEN] {
     // Step 2: HKDF-SHA256
     let username_salt = Sha256::digest(username.as_bytes());
-    let hk = Hkdf::<Sha256>::new(Some(&username_salt), &aes_key);
+    let hk = Hkdf::<Sha256>::new(Some(&username_salt), aes_key);
     let mut h = [0u8; 2 * PAKSLEN]; // 112 bytes
     hk.expand(b"Plan 9 AuthPAK hash", &mut h)
         .expect("HKDF expand failed");
@@ -198,7 +210,7 @@ pub fn authpak_finish(
         y_concat[..PAKYLEN].copy_from_slice(peer_y);
         y_concat[PAKYLEN..].copy_from_slice(&priv_state.y);
     }
-    let salt = Sha256::digest(&y_concat);
+    let salt = Sha256::digest(y_concat);
 
     // HKDF to derive key
     let hk = Hkdf::<Sha256>::new(Some(&salt), &z_bytes);
@@ -228,9 +240,9 @@ fn elligator2_hash_to_point(hash: &[u8]) -> ExtendedPoint {
 
     // D_val = (d*r + a - d) * (d*r - a*r - d)
     let dr = (&*D * &r) % &*P;
-    let a_minus_d = mod_sub(&a(), &*D, &P);
+    let a_minus_d = mod_sub(&a(), &D, &P);
     let term1 = (&dr + &a_minus_d) % &*P;
-    let term2 = mod_sub(&mod_sub(&dr, &((&a() * &r) % &*P), &P), &*D, &P);
+    let term2 = mod_sub(&mod_sub(&dr, &((&a() * &r) % &*P), &P), &D, &P);
     let d_val = (&term1 * &term2) % &*P;
 
     // N = (r+1) * (a - 2*d)
@@ -258,7 +270,7 @@ fn elligator2_hash_to_point(hash: &[u8]) -> ExtendedPoint {
     let r_minus_1 = mod_sub(&r, &BigUint::one(), &P);
     let a2d_e = (&a_2d * &e) % &*P;
     let a2d_e2 = (&a2d_e * &a2d_e) % &*P;
-    let neg_c = mod_sub(&*P, &c, &P);
+    let neg_c = mod_sub(&P, &c, &P);
     let t = mod_sub(&((&neg_c * &n_val * &r_minus_1 * &a2d_e2) % &*P), &BigUint::one(), &P);
 
     // Extended coordinates
@@ -285,7 +297,7 @@ fn elligator2_hash_to_point(hash: &[u8]) -> ExtendedPoint {
 
 fn decaf_encode(p: &ExtendedPoint) -> [u8; PAKYLEN] {
     // r = misqrt((a-d)*(Z+Y)*(Z-Y), p)
-    let a_minus_d = mod_sub(&a(), &*D, &P);
+    let a_minus_d = mod_sub(&a(), &D, &P);
     let z_plus_y = (&p.z + &p.y) % &*P;
     let z_minus_y = mod_sub(&p.z, &p.y, &P);
     let val = (&a_minus_d * &z_plus_y * &z_minus_y) % &*P;
@@ -301,9 +313,9 @@ fn decaf_encode(p: &ExtendedPoint) -> [u8; PAKYLEN] {
     let u = (&a_minus_d * &r) % &*P;
 
     // if -2*u*Z > (p-1)/2, r = -r
-    let neg_2uz = mod_sub(&*P, &(BigUint::from(2u32) * &u * &p.z % &*P), &P);
+    let neg_2uz = mod_sub(&P, &(BigUint::from(2u32) * &u * &p.z % &*P), &P);
     if neg_2uz > *P_HALF {
-        r = mod_sub(&*P, &r, &P);
+        r = mod_sub(&P, &r, &P);
     }
 
     // s = u*(r*(a*Z*X - d*Y*T) + Y) / a
@@ -315,7 +327,7 @@ fn decaf_encode(p: &ExtendedPoint) -> [u8; PAKYLEN] {
 
     // if s > (p-1)/2, s = -s
     if s > *P_HALF {
-        s = mod_sub(&*P, &s, &P);
+        s = mod_sub(&P, &s, &P);
     }
 
     // Convert to bytes (big-endian)
@@ -361,7 +373,7 @@ fn decaf_decode(data: &[u8]) -> Option<ExtendedPoint> {
     // if u*v > (p-1)/2, v = -v
     let uv = (&u * &v) % &*P;
     if uv > *P_HALF {
-        v = mod_sub(&*P, &v, &P);
+        v = mod_sub(&P, &v, &P);
     }
 
     // w = v * s * (2-Z)
@@ -417,10 +429,10 @@ fn add_points(p1: &ExtendedPoint, p2: &ExtendedPoint) -> ExtendedPoint {
 fn subtract_points(p1: &ExtendedPoint, p2: &ExtendedPoint) -> ExtendedPoint {
     // Negate p2: (-X, Y, Z, -T)
     let neg_p2 = ExtendedPoint {
-        x: mod_sub(&*P, &p2.x, &P),
+        x: mod_sub(&P, &p2.x, &P),
         y: p2.y.clone(),
         z: p2.z.clone(),
-        t: mod_sub(&*P, &p2.t, &P),
+        t: mod_sub(&P, &p2.t, &P),
     };
     add_points(p1, &neg_p2)
 }
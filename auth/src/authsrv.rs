@@ -0,0 +1,379 @@
+//! Auth server (authsrv) wire format
+//!
+//! Message types, the ticket request, the `Authkey` bundle and the reply
+//! framing shared by everything that talks to an authsrv on port 567.
+//! Ported from 9front's authsrv.h and libauthsrv (convTR2M, _asrdresp,
+//! _asgetresp).
+//!
+//! Decoders take whatever bytes have arrived so far and either decode a
+//! message or report how many bytes they need in total, the same contract
+//! as libauthsrv's convM2T and factotum's `toosmall`.
+
+use crate::authpak::{self, AESKEYLEN, PAKHASHLEN, PAKKEYLEN};
+use crate::keyring::{Key, KeyringError};
+use crate::p9sk1::{
+    self, read_fixed_string, write_fixed_string, Authenticator, Ticket, ANAMELEN, AUTHENTLEN,
+    AUTH_ERR, AUTH_OK, CHALLEN, DESSION, DOMLEN, TICKETLEN,
+};
+
+// Auth message types from authsrv.h.
+// AuthTreq, AuthOK, AuthErr and the ticket/authenticator types are in p9sk1.
+pub const AUTH_CHAL: u8 = 2; // Challenge box request (p9cr/netkey)
+pub const AUTH_PASS: u8 = 3; // Change password
+pub const AUTH_APOP: u8 = 7; // APOP authentication for pop3
+pub const AUTH_OKVAR: u8 = 9; // Variable length reply follows
+pub const AUTH_CHAP: u8 = 10; // CHAP authentication for ppp
+pub const AUTH_MSCHAP: u8 = 11; // MS-CHAP authentication for ppp
+pub const AUTH_CRAM: u8 = 12; // CRAM verification for IMAP (RFC 2195)
+pub const AUTH_HTTP: u8 = 13; // HTTP domain login
+pub const AUTH_VNC: u8 = 14; // VNC server login
+pub const AUTH_PAK: u8 = 19; // Authenticated key agreement (dp9ik)
+pub const AUTH_MSCHAPV2: u8 = 21; // MS-CHAPv2 authentication for ppp
+
+pub const DESKEYLEN: usize = 7;
+pub const TICKREQLEN: usize = 3 * ANAMELEN + CHALLEN + DOMLEN + 1; // 141
+pub const AERRLEN: usize = 64; // AuthErr message text
+pub const NETCHLEN: usize = 16; // Max network challenge length
+pub const SECRETLEN: usize = 32; // Max length of a secret
+pub const OKVARLEN: usize = 5; // Decimal length after AuthOKvar
+
+/// Error type for auth server conversations
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Result of decoding a message that may only be partially received.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded<T> {
+    /// Not enough input; this many bytes are needed in total.
+    Toosmall(usize),
+    /// The decoded value and how many bytes of input it used.
+    Done(T, usize),
+}
+
+/// A user's long-term keys, as kept by keyfs and derived by passtokey.
+#[derive(Clone)]
+pub struct Authkey {
+    pub des: [u8; DESKEYLEN],
+    pub aes: [u8; AESKEYLEN],
+    pub pakkey: [u8; PAKKEYLEN],
+    pub pakhash: [u8; PAKHASHLEN],
+}
+
+impl Default for Authkey {
+    fn default() -> Self {
+        Authkey {
+            des: [0; DESKEYLEN],
+            aes: [0; AESKEYLEN],
+            pakkey: [0; PAKKEYLEN],
+            pakhash: [0; PAKHASHLEN],
+        }
+    }
+}
+
+impl Authkey {
+    /// 9front's passtokey: DES and AES keys from a password.
+    /// The PAK hash is left empty until `authpak_hash` is called.
+    pub fn from_password(password: &str) -> Self {
+        Authkey {
+            des: p9sk1::pass_to_key(password),
+            aes: authpak::pass_to_aes_key(password),
+            ..Default::default()
+        }
+    }
+
+    /// Keys from a factotum key's `!password`.
+    pub fn from_key(key: &Key) -> Result<Self, KeyringError> {
+        key.password()
+            .map(Self::from_password)
+            .ok_or_else(|| KeyringError(format!("{} key has no !password", key.proto())))
+    }
+
+    /// Fill in the PAK hash points for `user` from the AES key.
+    pub fn authpak_hash(&mut self, user: &str) {
+        self.pakhash = authpak::authpak_hash_aes(&self.aes, user);
+    }
+}
+
+/// Ticket request, the first message of most authsrv conversations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ticketreq {
+    pub req_type: u8,
+    pub authid: String,
+    pub authdom: String,
+    pub chal: [u8; CHALLEN],
+    pub hostid: String,
+    pub uid: String,
+}
+
+impl Ticketreq {
+    /// Marshal to the 141-byte wire format (convTR2M).
+    pub fn to_bytes(&self) -> [u8; TICKREQLEN] {
+        let mut buf = [0u8; TICKREQLEN];
+        let mut off = 0;
+        buf[off] = self.req_type;
+        off += 1;
+        write_fixed_string(&mut buf[off..off + ANAMELEN], &self.authid);
+        off += ANAMELEN;
+        write_fixed_string(&mut buf[off..off + DOMLEN], &self.authdom);
+        off += DOMLEN;
+        buf[off..off + CHALLEN].copy_from_slice(&self.chal);
+        off += CHALLEN;
+        write_fixed_string(&mut buf[off..off + ANAMELEN], &self.hostid);
+        off += ANAMELEN;
+        write_fixed_string(&mut buf[off..off + ANAMELEN], &self.uid);
+        buf
+    }
+
+    /// Unmarshal from the wire format (convM2TR).
+    pub fn from_bytes(buf: &[u8]) -> Decoded<Ticketreq> {
+        if buf.len() < TICKREQLEN {
+            return Decoded::Toosmall(TICKREQLEN);
+        }
+        let mut off = 1;
+        let authid = read_fixed_string(&buf[off..off + ANAMELEN]);
+        off += ANAMELEN;
+        let authdom = read_fixed_string(&buf[off..off + DOMLEN]);
+        off += DOMLEN;
+        let mut chal = [0u8; CHALLEN];
+        chal.copy_from_slice(&buf[off..off + CHALLEN]);
+        off += CHALLEN;
+        let hostid = read_fixed_string(&buf[off..off + ANAMELEN]);
+        off += ANAMELEN;
+        let uid = read_fixed_string(&buf[off..off + ANAMELEN]);
+
+        let tr = Ticketreq {
+            req_type: buf[0],
+            authid,
+            authdom,
+            chal,
+            hostid,
+            uid,
+        };
+        Decoded::Done(tr, TICKREQLEN)
+    }
+}
+
+/// Decode an authsrv reply the way `_asrdresp` reads one.
+///
+/// AuthOK is followed by exactly `len` bytes, AuthOKvar by a five digit
+/// decimal length of at most `len` and then the data, and AuthErr by a
+/// 64-byte error string, which becomes the `Err`.
+pub fn parse_reply(buf: &[u8], len: usize) -> Result<Decoded<&[u8]>, AuthError> {
+    if buf.is_empty() {
+        return Ok(Decoded::Toosmall(1));
+    }
+    match buf[0] {
+        AUTH_OK => {
+            if buf.len() < 1 + len {
+                return Ok(Decoded::Toosmall(1 + len));
+            }
+            Ok(Decoded::Done(&buf[1..1 + len], 1 + len))
+        }
+        AUTH_ERR => {
+            if buf.len() < 1 + AERRLEN {
+                return Ok(Decoded::Toosmall(1 + AERRLEN));
+            }
+            let msg = read_fixed_string(&buf[1..AERRLEN]);
+            Err(AuthError(format!("remote: {}", msg)))
+        }
+        AUTH_OKVAR => {
+            if buf.len() < 1 + OKVARLEN {
+                return Ok(Decoded::Toosmall(1 + OKVARLEN));
+            }
+            let n = std::str::from_utf8(&buf[1..1 + OKVARLEN])
+                .ok()
+                .and_then(|s| {
+                    s.trim_matches(|c: char| c == ' ' || c == '\0')
+                        .parse::<usize>()
+                        .ok()
+                })
+                .unwrap_or(0);
+            if n == 0 || n > len {
+                return Err(AuthError("AS protocol botch".to_string()));
+            }
+            let start = 1 + OKVARLEN;
+            if buf.len() < start + n {
+                return Ok(Decoded::Toosmall(start + n));
+            }
+            Ok(Decoded::Done(&buf[start..start + n], start + n))
+        }
+        _ => Err(AuthError("unknown response".to_string())),
+    }
+}
+
+/// Decode AuthOK followed by a DES ticket and authenticator (`_asgetresp`).
+///
+/// The ticket is opened with `key`, the authenticator with the session key
+/// found inside the ticket.
+pub fn parse_ticket_reply(
+    buf: &[u8],
+    key: &Authkey,
+) -> Result<Decoded<(Ticket, Authenticator)>, AuthError> {
+    let data = match parse_reply(buf, TICKETLEN + AUTHENTLEN)? {
+        Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(n)),
+        Decoded::Done(data, _) => data,
+    };
+
+    let mut t = [0u8; TICKETLEN];
+    t.copy_from_slice(&data[..TICKETLEN]);
+    let ticket = p9sk1::decrypt_ticket(&t, &key.des);
+
+    let mut a = [0u8; AUTHENTLEN];
+    a.copy_from_slice(&data[TICKETLEN..]);
+    let auth = p9sk1::decrypt_authenticator(&a, &ticket.key);
+
+    Ok(Decoded::Done((ticket, auth), 1 + TICKETLEN + AUTHENTLEN))
+}
+
+/// Build an AuthOK reply carrying `data`.
+pub fn ok_reply(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + data.len());
+    out.push(AUTH_OK);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Build an AuthOKvar reply carrying `data`.
+pub fn okvar_reply(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + OKVARLEN + data.len());
+    out.push(AUTH_OKVAR);
+    out.extend_from_slice(format!("{:<5}", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Build an AuthErr reply.
+pub fn error_reply(msg: &str) -> Vec<u8> {
+    let mut out = vec![0u8; 1 + AERRLEN];
+    out[0] = AUTH_ERR;
+    write_fixed_string(&mut out[1..], msg);
+    out
+}
+
+/// Random challenge for a ticket request.
+pub fn gen_chal() -> [u8; CHALLEN] {
+    let mut chal = [0u8; CHALLEN];
+    getrandom::getrandom(&mut chal).expect("Failed to generate random bytes");
+    chal
+}
+
+/// Random DES session key for a ticket.
+pub fn gen_deskey() -> [u8; DESSION] {
+    let mut key = [0u8; DESSION];
+    getrandom::getrandom(&mut key).expect("Failed to generate random bytes");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p9sk1::{AUTH_AC, AUTH_TREQ, AUTH_TS};
+
+    #[test]
+    fn test_ticketreq_roundtrip() {
+        let tr = Ticketreq {
+            req_type: AUTH_TREQ,
+            authid: "bootes".to_string(),
+            authdom: "nawin".to_string(),
+            chal: [1, 2, 3, 4, 5, 6, 7, 8],
+            hostid: "glenda".to_string(),
+            uid: "glenda".to_string(),
+        };
+        let buf = tr.to_bytes();
+
+        // Same layout as the legacy p9sk1 builder
+        let legacy = p9sk1::make_ticket_request("bootes", "nawin", &tr.chal, "glenda", "glenda");
+        assert_eq!(buf, legacy);
+
+        assert_eq!(
+            Ticketreq::from_bytes(&buf[..10]),
+            Decoded::Toosmall(TICKREQLEN)
+        );
+        assert_eq!(Ticketreq::from_bytes(&buf), Decoded::Done(tr, TICKREQLEN));
+    }
+
+    #[test]
+    fn test_parse_reply_ok() {
+        let msg = ok_reply(b"12345678");
+        assert_eq!(parse_reply(&msg[..3], 8).unwrap(), Decoded::Toosmall(9));
+        assert_eq!(
+            parse_reply(&msg, 8).unwrap(),
+            Decoded::Done(&b"12345678"[..], 9)
+        );
+    }
+
+    #[test]
+    fn test_parse_reply_okvar() {
+        let msg = okvar_reply(b"<1896.697170952@dbc.mtview.ca.us>");
+        assert_eq!(&msg[..6], b"\x0933   ");
+        assert_eq!(parse_reply(&msg[..4], 128).unwrap(), Decoded::Toosmall(6));
+        assert_eq!(parse_reply(&msg[..6], 128).unwrap(), Decoded::Toosmall(39));
+        assert_eq!(
+            parse_reply(&msg, 128).unwrap(),
+            Decoded::Done(&b"<1896.697170952@dbc.mtview.ca.us>"[..], 39)
+        );
+
+        // Longer than the caller allows
+        assert!(parse_reply(&msg, 16).is_err());
+    }
+
+    #[test]
+    fn test_parse_reply_error() {
+        let msg = error_reply("bad authentication id");
+        assert_eq!(parse_reply(&msg[..10], 8).unwrap(), Decoded::Toosmall(65));
+        let err = parse_reply(&msg, 8).unwrap_err();
+        assert_eq!(err.to_string(), "remote: bad authentication id");
+    }
+
+    #[test]
+    fn test_parse_ticket_reply() {
+        let host = Authkey::from_password("hostpass");
+        let ticket = Ticket {
+            ticket_type: AUTH_TS,
+            challenge: [9; CHALLEN],
+            cuid: "glenda".to_string(),
+            suid: "glenda".to_string(),
+            key: [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+        };
+        let mut data = p9sk1::encrypt_ticket(&ticket, &host.des).to_vec();
+        data.extend_from_slice(&p9sk1::make_authenticator(
+            AUTH_AC,
+            &[9; CHALLEN],
+            0,
+            &ticket.key,
+        ));
+        let msg = ok_reply(&data);
+
+        assert_eq!(
+            parse_ticket_reply(&msg[..1], &host).unwrap(),
+            Decoded::Toosmall(86)
+        );
+        match parse_ticket_reply(&msg, &host).unwrap() {
+            Decoded::Done((t, a), used) => {
+                assert_eq!(used, 86);
+                assert_eq!(t.ticket_type, AUTH_TS);
+                assert_eq!(t.cuid, "glenda");
+                assert_eq!(t.key, ticket.key);
+                assert_eq!(a.auth_type, AUTH_AC);
+                assert_eq!(a.challenge, [9; CHALLEN]);
+            }
+            Decoded::Toosmall(n) => panic!("toosmall {}", n),
+        }
+    }
+
+    #[test]
+    fn test_authkey_from_key() {
+        let key = Key::parse("proto=dp9ik dom=nawin user=glenda !password=secret").unwrap();
+        let k = Authkey::from_key(&key).unwrap();
+        assert_eq!(k.des, p9sk1::pass_to_key("secret"));
+        assert!(Authkey::from_key(&Key::parse("proto=dp9ik user=glenda").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! enoch-auth - check Plan 9 authentication from the shell
+//!
+//! ```text
+//! enoch-auth passtokey [-u user]
+//! enoch-auth ticket [-p p9sk1|dp9ik] [-a authid] -d authdom [-s authsrv] user
+//! enoch-auth probe host[:port]
+//! enoch-auth login [-u user] [-s authsrv] host[:port]
+//! enoch-auth decrypt-ticket [-k hexkey] ticket
+//! ```
+//!
+//! `passtokey` prints the keys derived from a password: DES, AES and, given
+//! a user, the PAK hash. `ticket` asks the auth server for a ticket pair as
+//! `user` and opens both, so a wrong password or an unknown authid shows up
+//! as such. `probe` dials a server speaking p9any (the cpu server's rcpu
+//! port by default) and lists the protocols and domains it offers.
+//! `login` goes on to authenticate to it as `user`, as the browser would,
+//! and prints who each end is.
+//! `decrypt-ticket` opens a hex ticket with a DES or PAK key, or with the
+//! DES key of a password.
+//!
+//! Passwords are read from standard input, one per line, and never from
+//! the arguments: `ticket` wants the user's and then, if the authid is
+//! someone else, the authid's.
+
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::time::Duration;
+
+use enoch_auth::authsrv::{gen_chal, Authkey, Decoded, Ticketreq};
+use enoch_auth::conn::{authenticate, block_on, Blocking, Services};
+use enoch_auth::getticket::{open_ticket, GetTicket, Opened};
+use enoch_auth::keyring::{Key, Keyring};
+use enoch_auth::p9sk1::TicketProto;
+use enoch_auth::rcpu::RCPUPORT;
+use enoch_auth::relay::AUTHSRVPORT;
+use enoch_auth::rpc::{Factotum, KeyRequest, AUTHRPCMAX};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "usage: enoch-auth passtokey [-u user]
+       enoch-auth ticket [-p p9sk1|dp9ik] [-a authid] -d authdom [-s authsrv] user
+       enoch-auth probe host[:port]
+       enoch-auth login [-u user] [-s authsrv] host[:port]
+       enoch-auth decrypt-ticket [-k hexkey] ticket";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (cmd, args) = match args.split_first() {
+        Some((cmd, args)) => (cmd.as_str(), args),
+        None => usage(),
+    };
+    let r = match cmd {
+        "passtokey" => passtokey(args),
+        "ticket" => ticket(args),
+        "probe" => probe(args),
+        "login" => login(args),
+        "decrypt-ticket" => decrypt_ticket(args),
+        _ => usage(),
+    };
+    match r {
+        Ok(out) => print!("{}", out),
+        Err(e) => {
+            eprintln!("enoch-auth: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1);
+}
+
+// ============================================================================
+// Commands
+// ============================================================================
+
+fn passtokey(args: &[String]) -> Result<String, String> {
+    let (flags, rest) = getflags(args, "u")?;
+    if !rest.is_empty() {
+        usage();
+    }
+    let user = flag(&flags, 'u');
+    let mut key = Authkey::from_password(&readpassword("password")?);
+    let mut out = format!(
+        "des\t{}\naes\t{}\n",
+        hex::encode(key.des),
+        hex::encode(key.aes)
+    );
+    if let Some(user) = user {
+        key.authpak_hash(user);
+        out += &format!("pakhash\t{}\n", hex::encode(key.pakhash));
+    }
+    Ok(out)
+}
+
+fn ticket(args: &[String]) -> Result<String, String> {
+    let (flags, rest) = getflags(args, "adps")?;
+    let user = match rest {
+        [user] => user.as_str(),
+        _ => usage(),
+    };
+    let proto = flag(&flags, 'p').unwrap_or("dp9ik");
+    let proto = TicketProto::from_name(proto).ok_or(format!("unknown protocol {}", proto))?;
+    let authid = flag(&flags, 'a').unwrap_or(user);
+    let authdom = flag(&flags, 'd').unwrap_or_else(|| usage());
+    let authsrv = dialaddr(flag(&flags, 's').unwrap_or("localhost"), AUTHSRVPORT)?;
+
+    let userkey = Authkey::from_password(&readpassword(&format!("{} password", user))?);
+    let hostkey = match authid == user {
+        true => userkey.clone(),
+        false => Authkey::from_password(&readpassword(&format!("{} password", authid))?),
+    };
+    let tr = Ticketreq {
+        authid: authid.to_string(),
+        authdom: authdom.to_string(),
+        chal: gen_chal(),
+        hostid: user.to_string(),
+        uid: user.to_string(),
+        ..Default::default()
+    };
+    let g = GetTicket::new(proto, tr, hostkey, userkey);
+
+    let mut conn = dial(&authsrv)?;
+    conn.write_all(&g.request())
+        .map_err(|e| format!("{}: {}", authsrv, e))?;
+    let mut buf = Vec::new();
+    let (tc, ts) = loop {
+        match g.reply(&buf).map_err(|e| e.0)? {
+            Decoded::Done(t, _) => break t,
+            Decoded::Toosmall(n) => readn(&mut conn, &mut buf, n, &authsrv)?,
+        }
+    };
+    Ok(format!(
+        "Tc\t{}\nTs\t{}\n",
+        showticket(&tc),
+        showticket(&ts)
+    ))
+}
+
+fn probe(args: &[String]) -> Result<String, String> {
+    let (_, rest) = getflags(args, "")?;
+    let addr = match rest {
+        [addr] => dialaddr(addr, RCPUPORT)?,
+        _ => usage(),
+    };
+    let mut conn = dial(&addr)?;
+    let mut buf = Vec::new();
+    while !buf.contains(&0) {
+        if buf.len() >= AUTHRPCMAX {
+            return Err(format!("{}: offer too long", addr));
+        }
+        let n = buf.len() + 1;
+        readn(&mut conn, &mut buf, n, &addr)?;
+    }
+    let msg = String::from_utf8_lossy(&buf[..buf.iter().position(|&b| b == 0).unwrap()]);
+    let (v2, offers) = parse_offer(&msg);
+    let mut out = String::new();
+    if !v2 {
+        out += "# v.1 offer\n";
+    }
+    for (proto, dom) in offers {
+        out += &format!("{}\t{}\n", proto, dom);
+    }
+    Ok(out)
+}
+
+fn login(args: &[String]) -> Result<String, String> {
+    let (flags, rest) = getflags(args, "su")?;
+    let addr = match rest {
+        [addr] => dialaddr(addr, RCPUPORT)?,
+        _ => usage(),
+    };
+    let mut login = Login {
+        authsrv: dialaddr(flag(&flags, 's').unwrap_or("localhost"), AUTHSRVPORT)?,
+        user: match flag(&flags, 'u') {
+            Some(u) => u.to_string(),
+            None => std::env::var("USER").map_err(|_| "no user: use -u".to_string())?,
+        },
+    };
+    let mut conn = Blocking(dial(&addr)?);
+    let f = Factotum::new(Keyring::default());
+    let a = block_on(authenticate(
+        &mut conn,
+        &mut login,
+        f,
+        "proto=p9any role=client",
+    ))
+    .map_err(|e| format!("{}: {}", addr, e))?;
+    Ok(format!("cuid\t{}\nsuid\t{}\n", a.ai.cuid, a.ai.suid))
+}
+
+fn decrypt_ticket(args: &[String]) -> Result<String, String> {
+    let (flags, rest) = getflags(args, "k")?;
+    let msg = match rest {
+        [t] => hex::decode(t.trim()).map_err(|_| "ticket is not hex")?,
+        _ => usage(),
+    };
+    let key = match flag(&flags, 'k') {
+        Some(k) => hex::decode(k.trim()).map_err(|_| "key is not hex")?,
+        None => Authkey::from_password(&readpassword("password")?)
+            .des
+            .to_vec(),
+    };
+    let t = open_ticket(&msg, &key).ok_or_else(|| {
+        format!(
+            "cannot open a {}-byte ticket with a {}-byte key",
+            msg.len(),
+            key.len()
+        )
+    })?;
+    Ok(format!("{}\n", showticket(&t)))
+}
+
+// ============================================================================
+// Helpers
+// ============================================================================
+
+/// Flags and their values, in order
+type Flags<'a> = Vec<(char, &'a str)>;
+
+/// Plan 9 style flags before the operands, each taking a value: `-p
+/// dp9ik` or `-pdp9ik`, and `--` to end them. Only those in `known`.
+fn getflags<'a>(args: &'a [String], known: &str) -> Result<(Flags<'a>, &'a [String]), String> {
+    let mut flags = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let a = args[i].as_str();
+        i += 1;
+        if a == "--" {
+            break;
+        }
+        let Some(c) = a.strip_prefix('-').and_then(|f| f.chars().next()) else {
+            i -= 1;
+            break;
+        };
+        if !known.contains(c) {
+            return Err(format!("unknown flag -{}\n{}", c, USAGE));
+        }
+        let val = match &a[1 + c.len_utf8()..] {
+            "" => {
+                i += 1;
+                args.get(i - 1)
+                    .ok_or_else(|| format!("-{} needs a value", c))?
+            }
+            v => v,
+        };
+        flags.push((c, val));
+    }
+    Ok((flags, &args[i..]))
+}
+
+/// The last value given for flag `c`.
+fn flag<'a>(flags: &Flags<'a>, c: char) -> Option<&'a str> {
+    flags.iter().rev().find(|(f, _)| *f == c).map(|(_, v)| *v)
+}
+
+/// Where `login` gets tickets and keys
+struct Login {
+    authsrv: String,
+    user: String,
+}
+
+impl Services for Login {
+    type Authsrv = Blocking<TcpStream>;
+
+    async fn dial_authsrv(&mut self, _dom: &str) -> std::io::Result<Blocking<TcpStream>> {
+        let conn = dial(&self.authsrv).map_err(std::io::Error::other)?;
+        Ok(Blocking(conn))
+    }
+
+    async fn key(&mut self, req: &KeyRequest) -> Option<Key> {
+        let dom = req.dom.as_deref().unwrap_or("");
+        let pw = readpassword(&format!("{}@{} password", self.user, dom)).ok()?;
+        req.answer(&[("user", &self.user), ("!password", &pw)]).ok()
+    }
+}
+
+/// `tcp!host!port`, `host!port`, `host:port` or `host` as a socket
+/// address, defaulting the port.  Ports may be numbers or the Plan 9
+/// service names `rcpu` and `ticket`.
+fn dialaddr(s: &str, port: u16) -> Result<String, String> {
+    let s = s.strip_prefix("tcp!").unwrap_or(s);
+    let (host, port) = match s.split_once('!') {
+        Some((host, p)) => (host, service(p)?),
+        None => match s.rsplit_once(':') {
+            Some((host, p)) if !host.contains(':') || host.ends_with(']') => {
+                return Ok(format!("{}:{}", host, service(p)?));
+            }
+            Some(_) => return Ok(format!("[{}]:{}", s, port)),
+            None => (s, port),
+        },
+    };
+    Ok(format!("{}:{}", host, port))
+}
+
+/// A port number or service name.
+fn service(s: &str) -> Result<u16, String> {
+    match s {
+        "rcpu" => Ok(RCPUPORT),
+        "ticket" => Ok(AUTHSRVPORT),
+        _ => s.parse().map_err(|_| format!("unknown service {}", s)),
+    }
+}
+
+/// A p9any offer: whether it is v.2, then each proto and domain.
+fn parse_offer(msg: &str) -> (bool, Vec<(&str, &str)>) {
+    let mut words = msg.split_whitespace().peekable();
+    let v2 = words.next_if_eq(&"v.2").is_some();
+    let offers = words
+        .map(|w| w.split_once('@').unwrap_or((w, "")))
+        .collect();
+    (v2, offers)
+}
+
+fn dial(addr: &str) -> Result<TcpStream, String> {
+    let conn = TcpStream::connect(addr).map_err(|e| format!("{}: {}", addr, e))?;
+    conn.set_read_timeout(Some(TIMEOUT)).ok();
+    conn.set_write_timeout(Some(TIMEOUT)).ok();
+    Ok(conn)
+}
+
+/// Read until `buf` holds at least `n` bytes.
+fn readn(conn: &mut TcpStream, buf: &mut Vec<u8>, n: usize, addr: &str) -> Result<(), String> {
+    let mut tmp = [0u8; 4096];
+    while buf.len() < n {
+        match conn.read(&mut tmp) {
+            Ok(0) => return Err(format!("{}: hungup", addr)),
+            Ok(m) => buf.extend_from_slice(&tmp[..m]),
+            Err(e) => return Err(format!("{}: {}", addr, e)),
+        }
+    }
+    Ok(())
+}
+
+/// One line of standard input, prompting on standard error.
+fn readpassword(prompt: &str) -> Result<String, String> {
+    eprint!("{}: ", prompt);
+    std::io::stderr().flush().ok();
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Err("no password on standard input".to_string()),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn showticket(t: &Opened) -> String {
+    format!(
+        "type={} chal={} cuid={} suid={} key={}",
+        t.ticket_type(),
+        hex::encode(t.challenge()),
+        t.cuid(),
+        t.suid(),
+        hex::encode(t.key())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_getflags() {
+        let a = args("-p p9sk1 -dnawin -- -glenda");
+        let (flags, rest) = getflags(&a, "dp").unwrap();
+        assert_eq!(flag(&flags, 'p'), Some("p9sk1"));
+        assert_eq!(flag(&flags, 'd'), Some("nawin"));
+        assert_eq!(flag(&flags, 'a'), None);
+        assert_eq!(rest, ["-glenda"]);
+
+        let a = args("glenda -p dp9ik");
+        assert_eq!(getflags(&a, "p").unwrap().1.len(), 3);
+        assert!(getflags(&args("-x glenda"), "p").is_err());
+        assert!(getflags(&args("-p"), "p").is_err());
+    }
+
+    #[test]
+    fn test_dialaddr() {
+        let ok = |s, port| dialaddr(s, port).unwrap();
+        assert_eq!(ok("tcp!auth!567", 1), "auth:567");
+        assert_eq!(ok("auth!rcpu", 1), "auth:17019");
+        assert_eq!(ok("tcp!auth!ticket", 1), "auth:567");
+        assert_eq!(ok("auth:17019", 1), "auth:17019");
+        assert_eq!(ok("auth:rcpu", 1), "auth:17019");
+        assert_eq!(ok("auth", 567), "auth:567");
+        assert_eq!(ok("::1", 567), "[::1]:567");
+        assert_eq!(ok("[::1]:17019", 567), "[::1]:17019");
+        assert!(dialaddr("auth!exportfs", 1).is_err());
+        assert!(dialaddr("auth:", 1).is_err());
+    }
+
+    #[test]
+    fn test_parse_offer() {
+        let (v2, offers) = parse_offer("v.2 dp9ik@nawin p9sk1@nawin tls");
+        assert!(v2);
+        assert_eq!(
+            offers,
+            [("dp9ik", "nawin"), ("p9sk1", "nawin"), ("tls", "")]
+        );
+        let (v2, offers) = parse_offer("p9sk1@plan9");
+        assert!(!v2);
+        assert_eq!(offers, [("p9sk1", "plan9")]);
+    }
+}
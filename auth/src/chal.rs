@@ -0,0 +1,565 @@
+//! Challenge/response protocols checked by the auth server
+//!
+//! apop, cram, chap, mschap, mschapv2 and p9cr are what mail and PPP
+//! gateways use, and vnc is what vncs uses: the gateway asks the authsrv
+//! for a challenge, relays it to the remote client, and hands the
+//! client's answer back to the authsrv, which replies with a ticket for
+//! the gateway's host key if it matched.
+//! `ChalServer` is that gateway side; the response functions are what a
+//! client computes from its password.
+//!
+//! Ported from 9front's factotum apop.c/chap.c/p9cr.c and authsrv.c. The
+//! VNC response itself is in `vnc`.
+
+use hmac::{Hmac, Mac};
+use md4::Md4;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::authsrv::{
+    self, parse_reply, parse_ticket_reply, AuthError, Authkey, Decoded, Ticketreq, AUTH_APOP,
+    AUTH_CHAL, AUTH_CHAP, AUTH_CRAM, AUTH_MSCHAP, AUTH_MSCHAPV2, AUTH_VNC, NETCHLEN,
+};
+use crate::des9::Des9Key;
+use crate::p9sk1::{write_fixed_string, Authenticator, Ticket, ANAMELEN, AUTH_TS};
+use crate::vnc::VNCCHALLEN;
+
+pub const MD5LEN: usize = 16;
+pub const APOPCHALLEN: usize = 128; // Largest apop/cram challenge we accept
+pub const CHAPCHALLEN: usize = 8;
+pub const MSCHALLEN: usize = 8;
+pub const MSCHALLENV2: usize = 16;
+pub const MSRESPLEN: usize = 24; // LM or NT response
+
+/// OChapreply: `id[1] uid[28] resp[16]`
+pub const CHAPREPLYLEN: usize = 1 + ANAMELEN + MD5LEN;
+/// OMSchapreply: `uid[28] LMresp[24] NTresp[24]`
+pub const MSCHAPREPLYLEN: usize = ANAMELEN + 2 * MSRESPLEN;
+
+/// Which challenge/response protocol an exchange speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChalProto {
+    Apop,
+    Cram,
+    Chap,
+    MSchap,
+    MSchapv2,
+    P9cr,
+    Vnc,
+}
+
+impl ChalProto {
+    /// Look up a protocol by its factotum name.
+    pub fn from_name(name: &str) -> Option<ChalProto> {
+        match name {
+            "apop" => Some(ChalProto::Apop),
+            "cram" => Some(ChalProto::Cram),
+            "chap" => Some(ChalProto::Chap),
+            "mschap" => Some(ChalProto::MSchap),
+            "mschapv2" => Some(ChalProto::MSchapv2),
+            "p9cr" => Some(ChalProto::P9cr),
+            "vnc" => Some(ChalProto::Vnc),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChalProto::Apop => "apop",
+            ChalProto::Cram => "cram",
+            ChalProto::Chap => "chap",
+            ChalProto::MSchap => "mschap",
+            ChalProto::MSchapv2 => "mschapv2",
+            ChalProto::P9cr => "p9cr",
+            ChalProto::Vnc => "vnc",
+        }
+    }
+
+    /// Ticket request type sent to the authsrv.
+    pub fn auth_type(self) -> u8 {
+        match self {
+            ChalProto::Apop => AUTH_APOP,
+            ChalProto::Cram => AUTH_CRAM,
+            ChalProto::Chap => AUTH_CHAP,
+            ChalProto::MSchap => AUTH_MSCHAP,
+            ChalProto::MSchapv2 => AUTH_MSCHAPV2,
+            ChalProto::P9cr => AUTH_CHAL,
+            ChalProto::Vnc => AUTH_VNC,
+        }
+    }
+}
+
+/// Server (gateway) side of a challenge/response exchange with the authsrv.
+///
+/// 1. Send `request()` to the authsrv.
+/// 2. Feed its answer to `read_challenge` and pass the challenge on.
+/// 3. Send `reply(user, response)` with what the remote client answered.
+/// 4. Feed the authsrv's answer to `read_ticket`.
+///
+/// p9cr and vnc name the user in the first request, so set `tr.uid`
+/// before step 1. For mschap and mschapv2 the authsrv follows the ticket
+/// with MPPE key material, up to the end of the connection.
+pub struct ChalServer {
+    pub proto: ChalProto,
+    pub tr: Ticketreq,
+    chal: Vec<u8>,
+}
+
+impl ChalServer {
+    pub fn new(proto: ChalProto, authdom: &str, hostid: &str) -> Self {
+        let tr = Ticketreq {
+            req_type: proto.auth_type(),
+            authid: hostid.to_string(),
+            authdom: authdom.to_string(),
+            chal: authsrv::gen_chal(),
+            hostid: hostid.to_string(),
+            uid: String::new(),
+        };
+        ChalServer {
+            proto,
+            tr,
+            chal: Vec::new(),
+        }
+    }
+
+    /// The ticket request that opens the conversation.
+    pub fn request(&self) -> Vec<u8> {
+        self.tr.to_bytes().to_vec()
+    }
+
+    /// Decode the authsrv's challenge; afterwards it is in `challenge()`.
+    pub fn read_challenge(&mut self, buf: &[u8]) -> Result<Decoded<()>, AuthError> {
+        let (chal, used) = match self.proto {
+            ChalProto::Apop | ChalProto::Cram => match parse_reply(buf, APOPCHALLEN)? {
+                Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(n)),
+                Decoded::Done(chal, used) => (chal.to_vec(), used),
+            },
+            ChalProto::P9cr => match parse_reply(buf, NETCHLEN)? {
+                Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(n)),
+                Decoded::Done(chal, used) => {
+                    let end = chal.iter().position(|&b| b == 0).unwrap_or(chal.len());
+                    (chal[..end].to_vec(), used)
+                }
+            },
+            ChalProto::Vnc => match parse_reply(buf, VNCCHALLEN)? {
+                Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(n)),
+                Decoded::Done(chal, used) => {
+                    if chal.len() != VNCCHALLEN {
+                        return Err(AuthError("AS protocol botch".to_string()));
+                    }
+                    (chal.to_vec(), used)
+                }
+            },
+            // chap and mschap send the raw challenge, without a reply byte
+            ChalProto::Chap | ChalProto::MSchap | ChalProto::MSchapv2 => {
+                let n = match self.proto {
+                    ChalProto::Chap => CHAPCHALLEN,
+                    ChalProto::MSchap => MSCHALLEN,
+                    _ => MSCHALLENV2,
+                };
+                if buf.len() < n {
+                    return Ok(Decoded::Toosmall(n));
+                }
+                (buf[..n].to_vec(), n)
+            }
+        };
+        self.chal = chal;
+        Ok(Decoded::Done((), used))
+    }
+
+    /// The challenge to relay to the remote client.
+    pub fn challenge(&self) -> &[u8] {
+        &self.chal
+    }
+
+    /// Build the message carrying the remote client's response.
+    ///
+    /// `resp` is what the client sent: the hex digest for apop and cram,
+    /// the CHAP id followed by the 16-byte digest for chap, LM||NT for
+    /// mschap (the peer challenge padded to 24 bytes, then NT, for
+    /// mschapv2), the response string for p9cr, and the 16 encrypted
+    /// bytes for vnc.
+    pub fn reply(&self, user: &str, resp: &[u8]) -> Result<Vec<u8>, AuthError> {
+        match self.proto {
+            ChalProto::Apop | ChalProto::Cram => {
+                if resp.len() != 2 * MD5LEN {
+                    return Err(AuthError("bad response length".to_string()));
+                }
+                let mut tr = self.tr.clone();
+                tr.uid = user.to_string();
+                let mut out = tr.to_bytes().to_vec();
+                out.extend_from_slice(resp);
+                Ok(out)
+            }
+            ChalProto::Chap => {
+                if resp.len() != 1 + MD5LEN {
+                    return Err(AuthError("bad response length".to_string()));
+                }
+                let mut out = vec![0u8; CHAPREPLYLEN];
+                out[0] = resp[0];
+                write_fixed_string(&mut out[1..1 + ANAMELEN], user);
+                out[1 + ANAMELEN..].copy_from_slice(&resp[1..]);
+                Ok(out)
+            }
+            ChalProto::MSchap | ChalProto::MSchapv2 => {
+                if resp.len() != 2 * MSRESPLEN {
+                    return Err(AuthError("bad response length".to_string()));
+                }
+                let mut out = vec![0u8; MSCHAPREPLYLEN];
+                write_fixed_string(&mut out[..ANAMELEN], user);
+                out[ANAMELEN..].copy_from_slice(resp);
+                Ok(out)
+            }
+            ChalProto::P9cr => {
+                if resp.len() > NETCHLEN {
+                    return Err(AuthError("bad response length".to_string()));
+                }
+                let mut out = vec![0u8; NETCHLEN];
+                out[..resp.len()].copy_from_slice(resp);
+                Ok(out)
+            }
+            ChalProto::Vnc => {
+                if resp.len() != VNCCHALLEN {
+                    return Err(AuthError("bad response length".to_string()));
+                }
+                Ok(resp.to_vec())
+            }
+        }
+    }
+
+    /// Decode the authsrv's verdict: a ticket for `host_key` and an
+    /// authenticator, checked against our ticket request.
+    pub fn read_ticket(
+        &self,
+        buf: &[u8],
+        host_key: &Authkey,
+    ) -> Result<Decoded<(Ticket, Authenticator)>, AuthError> {
+        match parse_ticket_reply(buf, host_key)? {
+            Decoded::Toosmall(n) => Ok(Decoded::Toosmall(n)),
+            Decoded::Done((t, a), used) => {
+                if t.ticket_type != AUTH_TS || t.challenge != self.tr.chal {
+                    return Err(AuthError("auth server protocol botch".to_string()));
+                }
+                if a.challenge != self.tr.chal {
+                    return Err(AuthError("auth server protocol botch".to_string()));
+                }
+                Ok(Decoded::Done((t, a), used))
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Response calculations (client role)
+// ============================================================================
+
+/// APOP digest: hex(MD5(challenge || secret)), RFC 1939.
+pub fn apop_response(chal: &[u8], secret: &str) -> String {
+    let mut h = Md5::new();
+    h.update(chal);
+    h.update(secret.as_bytes());
+    hex::encode(h.finalize())
+}
+
+/// CRAM-MD5 digest: hex(HMAC-MD5(secret, challenge)), RFC 2195.
+pub fn cram_response(chal: &[u8], secret: &str) -> String {
+    let mut mac =
+        <Hmac<Md5> as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(chal);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// CHAP digest: MD5(id || secret || challenge), RFC 1994.
+pub fn chap_response(id: u8, chal: &[u8], secret: &str) -> [u8; MD5LEN] {
+    let mut h = Md5::new();
+    h.update([id]);
+    h.update(secret.as_bytes());
+    h.update(chal);
+    h.finalize().into()
+}
+
+/// NT password hash: MD4 of the UTF-16LE password.
+pub fn nt_password_hash(secret: &str) -> [u8; MD5LEN] {
+    let mut h = Md4::new();
+    for u in secret.encode_utf16() {
+        h.update(u.to_le_bytes());
+    }
+    h.finalize().into()
+}
+
+/// LAN Manager password hash: the upper-cased password, truncated or padded
+/// to 14 bytes, used as two DES keys to encrypt "KGS!@#$%".
+pub fn lm_password_hash(secret: &str) -> [u8; MD5LEN] {
+    let mut pw = [0u8; 14];
+    let upper = secret.to_uppercase();
+    let n = upper.len().min(14);
+    pw[..n].copy_from_slice(&upper.as_bytes()[..n]);
+
+    let mut hash = [0u8; MD5LEN];
+    for (i, half) in pw.chunks(7).enumerate() {
+        let mut key = [0u8; 7];
+        key.copy_from_slice(half);
+        let mut block = *b"KGS!@#$%";
+        Des9Key::new(&key).encrypt_block(&mut block);
+        hash[8 * i..8 * i + 8].copy_from_slice(&block);
+    }
+    hash
+}
+
+/// MS-CHAP challenge response: the 16-byte hash, zero padded to 21 bytes,
+/// split into three DES keys that each encrypt the 8-byte challenge.
+pub fn mschap_challenge_response(chal: &[u8; MSCHALLEN], hash: &[u8; MD5LEN]) -> [u8; MSRESPLEN] {
+    let mut keys = [0u8; 21];
+    keys[..MD5LEN].copy_from_slice(hash);
+
+    let mut resp = [0u8; MSRESPLEN];
+    for i in 0..3 {
+        let mut key = [0u8; 7];
+        key.copy_from_slice(&keys[7 * i..7 * i + 7]);
+        let mut block = *chal;
+        Des9Key::new(&key).encrypt_block(&mut block);
+        resp[8 * i..8 * i + 8].copy_from_slice(&block);
+    }
+    resp
+}
+
+/// MS-CHAP (RFC 2433) LM and NT responses, in that order.
+pub fn mschap_response(chal: &[u8; MSCHALLEN], secret: &str) -> ([u8; MSRESPLEN], [u8; MSRESPLEN]) {
+    let lm = mschap_challenge_response(chal, &lm_password_hash(secret));
+    let nt = mschap_challenge_response(chal, &nt_password_hash(secret));
+    (lm, nt)
+}
+
+/// MS-CHAPv2 challenge hash: SHA1(peer || authenticator || user)[0..8].
+fn mschapv2_challenge_hash(
+    auth_chal: &[u8; MSCHALLENV2],
+    peer_chal: &[u8; MSCHALLENV2],
+    user: &str,
+) -> [u8; MSCHALLEN] {
+    let mut h = Sha1::new();
+    h.update(peer_chal);
+    h.update(auth_chal);
+    h.update(user.as_bytes());
+    let digest = h.finalize();
+    let mut chal = [0u8; MSCHALLEN];
+    chal.copy_from_slice(&digest[..MSCHALLEN]);
+    chal
+}
+
+/// MS-CHAPv2 (RFC 2759) NT response.
+pub fn mschapv2_response(
+    auth_chal: &[u8; MSCHALLENV2],
+    peer_chal: &[u8; MSCHALLENV2],
+    user: &str,
+    secret: &str,
+) -> [u8; MSRESPLEN] {
+    let chal = mschapv2_challenge_hash(auth_chal, peer_chal, user);
+    mschap_challenge_response(&chal, &nt_password_hash(secret))
+}
+
+/// MS-CHAPv2 authenticator response ("S=" and 40 hex digits) that proves
+/// the server also knew the password.
+pub fn mschapv2_authenticator_response(
+    auth_chal: &[u8; MSCHALLENV2],
+    peer_chal: &[u8; MSCHALLENV2],
+    nt_resp: &[u8; MSRESPLEN],
+    user: &str,
+    secret: &str,
+) -> String {
+    const MAGIC1: &[u8] = b"Magic server to client signing constant";
+    const MAGIC2: &[u8] = b"Pad to make it do more than one iteration";
+
+    let hash_hash: [u8; MD5LEN] = Md4::digest(nt_password_hash(secret)).into();
+
+    let mut h = Sha1::new();
+    h.update(hash_hash);
+    h.update(nt_resp);
+    h.update(MAGIC1);
+    let digest = h.finalize();
+
+    let chal = mschapv2_challenge_hash(auth_chal, peer_chal, user);
+    let mut h = Sha1::new();
+    h.update(digest);
+    h.update(chal);
+    h.update(MAGIC2);
+
+    format!("S={}", hex::encode_upper(h.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsrv::TestAuthsrv;
+
+    #[test]
+    fn test_apop_rfc1939() {
+        let resp = apop_response(b"<1896.697170952@dbc.mtview.ca.us>", "tanstaaf");
+        assert_eq!(resp, "c4c9334bac560ecc979e58001b3e22fb");
+    }
+
+    #[test]
+    fn test_cram_rfc2195() {
+        let resp = cram_response(
+            b"<1896.697170952@postoffice.reston.mci.net>",
+            "tanstaaftanstaaf",
+        );
+        assert_eq!(resp, "b913a602c7eda7a495b4e6e7334d3890");
+    }
+
+    #[test]
+    fn test_mschapv2_rfc2759() {
+        // Test vectors from RFC 2759 section 9.2
+        let auth_chal: [u8; 16] = hex::decode("5b5d7c7d7b3f2f3e3c2c602132262628")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let peer_chal: [u8; 16] = hex::decode("21402324255e262a28295f2b3a337c7e")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert_eq!(
+            hex::encode(nt_password_hash("clientPass")),
+            "44ebba8d5312b8d611474411f56989ae"
+        );
+
+        let nt = mschapv2_response(&auth_chal, &peer_chal, "User", "clientPass");
+        assert_eq!(
+            hex::encode(nt),
+            "82309ecd8d708b5ea08faa3981cd83544233114a3d85d6df"
+        );
+
+        let s = mschapv2_authenticator_response(&auth_chal, &peer_chal, &nt, "User", "clientPass");
+        assert_eq!(s, "S=407A5589115FD0D6209F510FE9C04566932CDA56");
+    }
+
+    #[test]
+    fn test_lm_hash_known() {
+        // Well-known LM hash of the empty password
+        assert_eq!(
+            hex::encode(lm_password_hash("")),
+            "aad3b435b51404eeaad3b435b51404ee"
+        );
+    }
+
+    /// Run one gateway exchange against the stand-in authsrv.
+    fn exchange(
+        srv: &mut TestAuthsrv,
+        proto: ChalProto,
+        uid: &str,
+        respond: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Result<(Ticket, Authenticator), AuthError> {
+        let mut gw = ChalServer::new(proto, "nawin", "mailhost");
+        if proto == ChalProto::P9cr || proto == ChalProto::Vnc {
+            gw.tr.uid = uid.to_string();
+        }
+        let chal_msg = srv.chal_start(&gw.request());
+        assert_eq!(
+            gw.read_challenge(&chal_msg).unwrap(),
+            Decoded::Done((), chal_msg.len())
+        );
+
+        let resp = respond(gw.challenge());
+        let verdict = srv.chal_finish(&gw.reply(uid, &resp)?);
+        match gw.read_ticket(&verdict, &srv.key("mailhost"))? {
+            Decoded::Done(result, _) => Ok(result),
+            Decoded::Toosmall(n) => panic!("toosmall {}", n),
+        }
+    }
+
+    fn test_server() -> TestAuthsrv {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "tanstaaf");
+        srv.add_user("mailhost", "hostsecret");
+        srv
+    }
+
+    #[test]
+    fn test_apop_and_cram_exchange() {
+        let mut srv = test_server();
+        let (t, _) = exchange(&mut srv, ChalProto::Apop, "glenda", |c| {
+            apop_response(c, "tanstaaf").into_bytes()
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+
+        let (t, _) = exchange(&mut srv, ChalProto::Cram, "glenda", |c| {
+            cram_response(c, "tanstaaf").into_bytes()
+        })
+        .unwrap();
+        assert_eq!(t.suid, "glenda");
+
+        let err = exchange(&mut srv, ChalProto::Apop, "glenda", |c| {
+            apop_response(c, "wrong").into_bytes()
+        })
+        .unwrap_err();
+        assert!(err.to_string().starts_with("remote:"));
+    }
+
+    #[test]
+    fn test_chap_exchange() {
+        let mut srv = test_server();
+        let (t, _) = exchange(&mut srv, ChalProto::Chap, "glenda", |c| {
+            let mut r = vec![7u8];
+            r.extend_from_slice(&chap_response(7, c, "tanstaaf"));
+            r
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+    }
+
+    #[test]
+    fn test_mschap_exchange() {
+        let mut srv = test_server();
+        let (t, _) = exchange(&mut srv, ChalProto::MSchap, "glenda", |c| {
+            let (lm, nt) = mschap_response(c.try_into().unwrap(), "tanstaaf");
+            [lm, nt].concat()
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+
+        let peer = [0x42u8; MSCHALLENV2];
+        let (t, _) = exchange(&mut srv, ChalProto::MSchapv2, "glenda", |c| {
+            let nt = mschapv2_response(c.try_into().unwrap(), &peer, "glenda", "tanstaaf");
+            let mut r = peer.to_vec();
+            r.extend_from_slice(&[0u8; 8]);
+            r.extend_from_slice(&nt);
+            r
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+    }
+
+    #[test]
+    fn test_p9cr_exchange() {
+        let mut srv = test_server();
+        let key = srv.key("glenda").des;
+        let (t, _) = exchange(&mut srv, ChalProto::P9cr, "glenda", |c| {
+            crate::netkey::netcrypt(&key, std::str::from_utf8(c).unwrap()).into_bytes()
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+    }
+
+    #[test]
+    fn test_vnc_exchange() {
+        let mut srv = test_server();
+        let (t, _) = exchange(&mut srv, ChalProto::Vnc, "glenda", |c| {
+            crate::vnc::vnc_response(c.try_into().unwrap(), b"tanstaaf").to_vec()
+        })
+        .unwrap();
+        assert_eq!(t.cuid, "glenda");
+
+        let err = exchange(&mut srv, ChalProto::Vnc, "glenda", |c| {
+            crate::vnc::vnc_response(c.try_into().unwrap(), b"tanstaa").to_vec()
+        })
+        .unwrap_err();
+        assert!(err.to_string().starts_with("remote:"));
+    }
+
+    #[test]
+    fn test_reply_length_checked() {
+        let gw = ChalServer::new(ChalProto::Chap, "nawin", "mailhost");
+        assert!(gw.reply("glenda", &[0u8; 3]).is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! conn - authenticate over an asynchronous transport
+//!
+//! `AuthProxy` and `AuthFid` do no I/O, so a caller must feed them between
+//! network round trips itself. Here they are driven to the end instead,
+//! over a `Transport` whose reads and writes are futures: a WebSocket
+//! behind JS promises in the browser, or a plain socket wrapped in
+//! `Blocking` for native tools, run with `block_on`.
+//!
+//! `authenticate` runs factotum's conversation on the stream itself, as
+//! rcpu and exportfs do; `attach` runs it through an auth fid and attaches
+//! over 9P. Either dials the auth server through `Services` when a
+//! protocol wants tickets, and asks it for keys factotum lacks.
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+
+use crate::afid::{AfidError, Attach, Attached, AuthFid, Step};
+use crate::keyring::Key;
+use crate::rpc::{AuthInfo, AuthProxy, Factotum, KeyRequest, ProxyState, RpcReply, AUTHRPCMAX};
+
+/// Error type for authenticating over a transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnError(pub String);
+
+impl std::fmt::Display for ConnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConnError {}
+
+impl From<io::Error> for ConnError {
+    fn from(e: io::Error) -> Self {
+        ConnError(e.to_string())
+    }
+}
+
+impl From<AfidError> for ConnError {
+    fn from(e: AfidError) -> Self {
+        ConnError(e.0)
+    }
+}
+
+/// A byte stream to a peer
+// WASM is single-threaded, so the futures need not be Send.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Up to `n` bytes, at least one; none at end of file.
+    async fn read(&mut self, n: usize) -> io::Result<Vec<u8>>;
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+/// What a conversation needs besides its peer
+#[allow(async_fn_in_trait)]
+pub trait Services {
+    type Authsrv: Transport;
+
+    /// A connection to the auth server for `dom`.
+    async fn dial_authsrv(&mut self, dom: &str) -> io::Result<Self::Authsrv>;
+
+    /// The key for a needkey, or None to give up.
+    async fn key(&mut self, _req: &KeyRequest) -> Option<Key> {
+        None
+    }
+}
+
+/// Any dialer will do when factotum has every key it needs.
+impl<F, T> Services for F
+where
+    F: FnMut(&str) -> io::Result<T>,
+    T: Transport,
+{
+    type Authsrv = T;
+
+    async fn dial_authsrv(&mut self, dom: &str) -> io::Result<T> {
+        self(dom)
+    }
+}
+
+/// The end of a finished conversation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authenticated {
+    pub ai: AuthInfo,
+    /// Bytes the peer sent after the conversation, for what runs next
+    pub leftover: Vec<u8>,
+}
+
+/// Run `params` (such as "proto=p9any role=client") on `f` with the peer
+/// at the other end of `conn`, as auth_proxy does.
+pub async fn authenticate<T: Transport, S: Services>(
+    conn: &mut T,
+    services: &mut S,
+    f: Factotum,
+    params: &str,
+) -> Result<Authenticated, ConnError> {
+    let mut p = AuthProxy::new(f, params);
+    let mut authsrv = None;
+    loop {
+        let out = p.take();
+        if !out.is_empty() {
+            conn.write(&out).await?;
+        }
+        match p.state().clone() {
+            ProxyState::Peer => {
+                let data = conn.read(AUTHRPCMAX).await?;
+                if data.is_empty() {
+                    return Err(ConnError("auth_proxy: peer hung up".to_string()));
+                }
+                p.put(&data);
+            }
+            ProxyState::Authsrv => {
+                if let Some(RpcReply::Authsrv { dom, data }) = p.take_authsrv() {
+                    let mut c = services.dial_authsrv(&dom).await?;
+                    c.write(&data).await?;
+                    authsrv = Some(c);
+                }
+                let c = authsrv
+                    .as_mut()
+                    .ok_or_else(|| ConnError("auth_proxy: no auth server".to_string()))?;
+                let data = c.read(AUTHRPCMAX).await?;
+                if data.is_empty() {
+                    return Err(ConnError("auth_proxy: auth server hung up".to_string()));
+                }
+                p.put_authsrv(&data);
+            }
+            ProxyState::Key => {
+                let req = match p.factotum().key_request() {
+                    Some(req) => req.clone(),
+                    None => return Err(ConnError("auth_proxy: key request lost".to_string())),
+                };
+                match services.key(&req).await {
+                    Some(key) => p.supply_key(key),
+                    None => return Err(ConnError(format!("needkey {}", req.template()))),
+                }
+            }
+            ProxyState::Done => {
+                return Ok(Authenticated {
+                    ai: p.authinfo().cloned().unwrap_or_default(),
+                    leftover: p.leftover(),
+                })
+            }
+            ProxyState::Failed(e) => return Err(ConnError(e)),
+        }
+    }
+}
+
+/// Authenticate through an auth fid and attach, over a 9P connection
+/// that has been through Tversion; see `afid`.
+pub async fn attach<T: Transport, S: Services>(
+    conn: &mut T,
+    services: &mut S,
+    f: Factotum,
+    a: Attach,
+) -> Result<Attached, ConnError> {
+    let msize = a.msize as usize;
+    let mut d = AuthFid::new(f, a);
+    let mut authsrv = None;
+    loop {
+        match d.step() {
+            Step::Rpc(t) => {
+                conn.write(&t).await?;
+                d.reply(&read9p(conn, msize).await?);
+            }
+            Step::Authsrv { dom, data } => {
+                if !data.is_empty() {
+                    let mut c = services.dial_authsrv(&dom).await?;
+                    c.write(&data).await?;
+                    authsrv = Some(c);
+                }
+                match authsrv.as_mut() {
+                    Some(c) => d.authsrv_reply(&c.read(AUTHRPCMAX).await?),
+                    None => d.cancel("afid: no auth server"),
+                }
+            }
+            Step::Key(req) => match services.key(&req).await {
+                Some(key) => d.supply_key(key),
+                None => d.cancel(&format!("needkey {}", req.template())),
+            },
+            Step::Done(a) => return Ok(a),
+            Step::Failed(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Exactly `n` bytes.
+async fn readn<T: Transport>(conn: &mut T, n: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(n);
+    while buf.len() < n {
+        let data = conn.read(n - buf.len()).await?;
+        if data.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend(data);
+    }
+    Ok(buf)
+}
+
+/// One whole 9P message of at most `msize` bytes.
+async fn read9p<T: Transport>(conn: &mut T, msize: usize) -> io::Result<Vec<u8>> {
+    let mut m = readn(conn, 4).await?;
+    let size = u32::from_le_bytes(m[..4].try_into().unwrap()) as usize;
+    if !(7..=msize).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad 9P message size {}", size),
+        ));
+    }
+    m.extend(readn(conn, size - 4).await?);
+    Ok(m)
+}
+
+// ============================================================================
+// Blocking adapter
+// ============================================================================
+
+/// A std stream as a `Transport`; its futures are ready when polled.
+pub struct Blocking<S>(pub S);
+
+impl<S: Read + Write> Transport for Blocking<S> {
+    async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; n];
+        let m = self.0.read(&mut buf)?;
+        buf.truncate(m);
+        Ok(buf)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)?;
+        self.0.flush()
+    }
+}
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `fut` to completion on this thread.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(v) => return v,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::test9p::TestFs;
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    fn authsrv() -> TestAuthsrv {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        srv
+    }
+
+    /// A cpu server that answers a few bytes at a time
+    struct Cpu(TestServer);
+
+    impl Transport for Cpu {
+        async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+            Ok(self.0.take(n.min(30)))
+        }
+
+        async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.put(data);
+            Ok(())
+        }
+    }
+
+    /// 9P over a byte stream
+    struct Fs(TestFs, Vec<u8>);
+
+    impl Transport for Fs {
+        async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+            let n = n.min(self.1.len());
+            Ok(self.1.drain(..n).collect())
+        }
+
+        async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            let r = self.0.rpc(data);
+            self.1.extend(r);
+            Ok(())
+        }
+    }
+
+    struct Authsrv(Arc<TestAuthsrv>, Vec<u8>);
+
+    impl Transport for Authsrv {
+        async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+            let n = n.min(self.1.len()).min(50);
+            Ok(self.1.drain(..n).collect())
+        }
+
+        async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.1 = self.0.serve(data);
+            Ok(())
+        }
+    }
+
+    struct Env {
+        srv: Arc<TestAuthsrv>,
+        password: Option<&'static str>,
+    }
+
+    impl Services for Env {
+        type Authsrv = Authsrv;
+
+        async fn dial_authsrv(&mut self, dom: &str) -> io::Result<Authsrv> {
+            assert_eq!(dom, "nawin");
+            Ok(Authsrv(self.srv.clone(), Vec::new()))
+        }
+
+        async fn key(&mut self, req: &KeyRequest) -> Option<Key> {
+            let pw = self.password?;
+            req.answer(&[("user", "glenda"), ("!password", pw)]).ok()
+        }
+    }
+
+    fn cpu(offer: &str) -> Cpu {
+        Cpu(TestServer::new("bootes", "cpusecret", "nawin", offer))
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let srv = Arc::new(authsrv());
+        let mut dial = |_: &str| io::Result::Ok(Authsrv(srv.clone(), Vec::new()));
+        for offer in ["dp9ik@nawin", "p9sk1@nawin"] {
+            let mut c = cpu(offer);
+            let ring = Keyring::parse(
+                "key proto=dp9ik dom=nawin user=glenda !password=kittens
+key proto=p9sk1 dom=nawin user=glenda !password=kittens",
+            )
+            .unwrap();
+            let fut = authenticate(
+                &mut c,
+                &mut dial,
+                Factotum::new(ring),
+                "proto=p9any role=client",
+            );
+            let a = block_on(fut).unwrap();
+            assert_eq!(a.ai.cuid, "glenda");
+            assert_eq!(Some(a.ai.secret), c.0.secret());
+            assert!(a.leftover.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_needkey() {
+        let mut env = Env {
+            srv: Arc::new(authsrv()),
+            password: Some("kittens"),
+        };
+        let mut c = cpu("dp9ik@nawin");
+        let f = Factotum::new(Keyring::default());
+        let a = block_on(authenticate(&mut c, &mut env, f, "proto=p9any role=client")).unwrap();
+        assert!(c.0.secret().is_some());
+        assert_eq!(a.ai.suid, "glenda");
+
+        env.password = None;
+        let mut c = cpu("dp9ik@nawin");
+        let f = Factotum::new(Keyring::default());
+        let e = block_on(authenticate(&mut c, &mut env, f, "proto=p9any role=client"));
+        assert!(e.unwrap_err().0.starts_with("needkey proto=dp9ik"));
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let mut env = Env {
+            srv: Arc::new(authsrv()),
+            password: Some("puppies"),
+        };
+        let mut c = cpu("dp9ik@nawin");
+        let f = Factotum::new(Keyring::default());
+        assert!(block_on(authenticate(&mut c, &mut env, f, "proto=p9any role=client")).is_err());
+        assert!(c.0.secret().is_none());
+    }
+
+    #[test]
+    fn test_attach() {
+        let mut env = Env {
+            srv: Arc::new(authsrv()),
+            password: Some("kittens"),
+        };
+        let cpu = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let mut c = Fs(TestFs::new(8192, Some(cpu)), Vec::new());
+        let f = Factotum::new(Keyring::default());
+        let a = block_on(attach(&mut c, &mut env, f, Attach::new("glenda", ""))).unwrap();
+        assert_eq!(a.afid, 1);
+        assert_eq!(a.ai.unwrap().secret, c.0.secret().unwrap());
+    }
+
+    #[test]
+    fn test_blocking() {
+        let mut b = Blocking(io::Cursor::new(Vec::new()));
+        block_on(b.write(b"Tversion")).unwrap();
+        b.0.set_position(0);
+        assert_eq!(block_on(b.read(5)).unwrap(), b"Tvers");
+        assert_eq!(block_on(b.read(8192)).unwrap(), b"ion");
+        assert!(block_on(b.read(8192)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_on_pending() {
+        // A future that is woken from another thread
+        struct Later(Option<thread::JoinHandle<()>>);
+
+        impl Future for Later {
+            type Output = u32;
+
+            fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+                match self.0.take() {
+                    Some(h) if h.is_finished() => Poll::Ready(9),
+                    Some(h) => {
+                        let w = cx.waker().clone();
+                        self.0 = Some(h);
+                        thread::spawn(move || {
+                            thread::sleep(std::time::Duration::from_millis(10));
+                            w.wake();
+                        });
+                        Poll::Pending
+                    }
+                    None => Poll::Ready(0),
+                }
+            }
+        }
+
+        let h = thread::spawn(|| thread::sleep(std::time::Duration::from_millis(20)));
+        assert_eq!(block_on(Later(Some(h))), 9);
+    }
+}
@@ -179,6 +179,7 @@ pub fn expand_key(key7: &[u8; 7]) -> [u8; 8] {
 }
 
 /// 9front's DES key schedule generation (des_key_setup from des.c)
+#[allow(clippy::identity_op)] // `>> 0` as in des.c
 pub fn des_key_setup(key: &[u8; 8]) -> [u32; 32] {
     let mut ek = [0u32; 32];
 
@@ -220,6 +221,7 @@ pub fn des_key_setup(key: &[u8; 8]) -> [u32; 32] {
     ek
 }
 
+#[allow(clippy::identity_op, clippy::erasing_op, clippy::needless_range_loop)] // As in des.c
 fn key_comp_perm(mut left: u32, mut right: u32, ek: &mut [u32; 32]) {
     let mut ek_idx = 0;
     for i in 0..16 {
@@ -257,6 +259,7 @@ fn key_comp_perm(mut left: u32, mut right: u32, ek: &mut [u32; 32]) {
 
 /// 9front's DES block cipher (block_cipher from des.c)
 /// Encrypts/decrypts 8 bytes in place at the given offset.
+#[allow(clippy::identity_op, clippy::erasing_op, clippy::manual_rotate)] // As in des.c
 pub fn block_cipher(key: &[u32; 32], text: &mut [u8], offset: usize, decrypting: bool) {
     // Initial permutation with 9front's byte interleaving
     let v0 = (text[offset] as u32)
@@ -325,59 +328,132 @@ pub fn block_cipher(key: &[u32; 32], text: &mut [u8], offset: usize, decrypting:
     text[offset + 7] = (v1_final >> 24) as u8;
 }
 
-/// Plan 9's non-standard DES encryption with 7-byte stride.
-/// Encrypts data in place using 9front's exact block_cipher implementation.
-pub fn plan9_encrypt(key: &[u8; 7], data: &mut [u8]) {
-    if data.len() < 8 {
-        return;
-    }
+/// An expanded DES key schedule, so callers that run many blocks under one
+/// key (MS-CHAP, VNC, ticket streams) only pay for `des_key_setup` once.
+#[derive(Clone)]
+pub struct Des9Key {
+    ek: [u32; 32],
+}
 
-    let des_key = expand_key(key);
-    let ekey = des_key_setup(&des_key);
+impl Des9Key {
+    /// Schedule a 7-byte Plan 9 key (parity added by `expand_key`).
+    pub fn new(key7: &[u8; 7]) -> Self {
+        Self::from_key8(&expand_key(key7))
+    }
 
-    let n = (data.len() - 1) / 7;
-    let r = (data.len() - 1) % 7;
+    /// Schedule an 8-byte key as-is; parity bits are ignored by DES.
+    pub fn from_key8(key8: &[u8; 8]) -> Self {
+        Des9Key {
+            ek: des_key_setup(key8),
+        }
+    }
 
-    let mut pos = 0;
-    for _ in 0..n {
-        block_cipher(&ekey, data, pos, false);
-        pos += 7;
+    /// Encrypt one 8-byte block (plain ECB).
+    pub fn encrypt_block(&self, block: &mut [u8; 8]) {
+        block_cipher(&self.ek, block, 0, false);
     }
 
-    if r > 0 {
-        let final_pos = pos - 7 + r;
-        block_cipher(&ekey, data, final_pos, false);
+    /// Decrypt one 8-byte block (plain ECB).
+    pub fn decrypt_block(&self, block: &mut [u8; 8]) {
+        block_cipher(&self.ek, block, 0, true);
     }
-}
 
-/// Plan 9's non-standard DES decryption with 7-byte stride.
-/// Decrypts data in place.
-pub fn plan9_decrypt(key: &[u8; 7], data: &mut [u8]) {
-    if data.len() < 8 {
-        return;
+    /// Plan 9's 7-byte stride encryption (libauthsrv encrypt()).
+    pub fn encrypt(&self, data: &mut [u8]) {
+        if data.len() < 8 {
+            return;
+        }
+
+        let n = (data.len() - 1) / 7;
+        let r = (data.len() - 1) % 7;
+
+        let mut pos = 0;
+        for _ in 0..n {
+            block_cipher(&self.ek, data, pos, false);
+            pos += 7;
+        }
+
+        if r > 0 {
+            let final_pos = pos - 7 + r;
+            block_cipher(&self.ek, data, final_pos, false);
+        }
     }
 
-    let des_key = expand_key(key);
-    let ekey = des_key_setup(&des_key);
+    /// Plan 9's 7-byte stride decryption (libauthsrv decrypt()).
+    pub fn decrypt(&self, data: &mut [u8]) {
+        if data.len() < 8 {
+            return;
+        }
+
+        let n = (data.len() - 1) / 7;
+        let r = (data.len() - 1) % 7;
 
-    let n = (data.len() - 1) / 7;
-    let r = (data.len() - 1) % 7;
+        // Decrypt in reverse order
+        if r > 0 {
+            let final_pos = n * 7 - 7 + r;
+            block_cipher(&self.ek, data, final_pos, true);
+        }
 
-    // Decrypt in reverse order
-    if r > 0 {
-        let final_pos = n * 7 - 7 + r;
-        block_cipher(&ekey, data, final_pos, true);
+        let mut pos = (n - 1) * 7;
+        for _ in 0..n {
+            block_cipher(&self.ek, data, pos, true);
+            if pos >= 7 {
+                pos -= 7;
+            } else {
+                break;
+            }
+        }
     }
 
-    let mut pos = (n - 1) * 7;
-    for _ in 0..n {
-        block_cipher(&ekey, data, pos, true);
-        if pos >= 7 {
-            pos -= 7;
-        } else {
-            break;
+    /// libsec's desCBCencrypt: CBC over whole blocks, then a trailing
+    /// partial block XORed with the encryption of the last ciphertext block.
+    pub fn cbc_encrypt(&self, ivec: &mut [u8; 8], data: &mut [u8]) {
+        let mut blocks = data.chunks_exact_mut(8);
+        for p in &mut blocks {
+            for (b, iv) in p.iter_mut().zip(ivec.iter()) {
+                *b ^= iv;
+            }
+            block_cipher(&self.ek, p, 0, false);
+            ivec.copy_from_slice(p);
         }
+        self.cbc_tail(ivec, blocks.into_remainder());
     }
+
+    /// libsec's desCBCdecrypt, the inverse of `cbc_encrypt`.
+    pub fn cbc_decrypt(&self, ivec: &mut [u8; 8], data: &mut [u8]) {
+        let mut blocks = data.chunks_exact_mut(8);
+        for p in &mut blocks {
+            let c: [u8; 8] = (*p).try_into().unwrap();
+            block_cipher(&self.ek, p, 0, true);
+            for (b, iv) in p.iter_mut().zip(ivec.iter()) {
+                *b ^= iv;
+            }
+            *ivec = c;
+        }
+        self.cbc_tail(ivec, blocks.into_remainder());
+    }
+
+    fn cbc_tail(&self, ivec: &mut [u8; 8], tail: &mut [u8]) {
+        if tail.is_empty() {
+            return;
+        }
+        block_cipher(&self.ek, ivec, 0, false);
+        for (b, k) in tail.iter_mut().zip(ivec.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Plan 9's non-standard DES encryption with 7-byte stride.
+/// Encrypts data in place using 9front's exact block_cipher implementation.
+pub fn plan9_encrypt(key: &[u8; 7], data: &mut [u8]) {
+    Des9Key::new(key).encrypt(data);
+}
+
+/// Plan 9's non-standard DES decryption with 7-byte stride.
+/// Decrypts data in place.
+pub fn plan9_decrypt(key: &[u8; 7], data: &mut [u8]) {
+    Des9Key::new(key).decrypt(data);
 }
 
 #[cfg(test)]
@@ -482,4 +558,38 @@ mod tests {
             "DES 72-byte decryption mismatch"
         );
     }
+
+    #[test]
+    fn test_des9key_block_matches_stride() {
+        // A single 8-byte buffer takes exactly one block in the stride
+        // cipher, so both paths must agree.
+        let key: [u8; 7] = [0x67, 0x76, 0xd9, 0x4d, 0x0e, 0x03, 0x40];
+        let dk = Des9Key::new(&key);
+
+        let mut block = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        dk.encrypt_block(&mut block);
+        assert_eq!(hex::encode(block), "35597a5f09782178");
+
+        dk.decrypt_block(&mut block);
+        assert_eq!(block, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_cbc_roundtrip() {
+        let key = Des9Key::new(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde]);
+        let plain: Vec<u8> = (0..29).collect();
+        let mut data = plain.clone();
+        let mut iv = [0u8; 8];
+        key.cbc_encrypt(&mut iv, &mut data);
+        assert_ne!(data, plain);
+
+        // First block is plain ECB under a zero IV
+        let mut block: [u8; 8] = plain[..8].try_into().unwrap();
+        key.encrypt_block(&mut block);
+        assert_eq!(&data[..8], &block);
+
+        let mut iv = [0u8; 8];
+        key.cbc_decrypt(&mut iv, &mut data);
+        assert_eq!(data, plain);
+    }
 }
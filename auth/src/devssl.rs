@@ -0,0 +1,381 @@
+//! devssl - the record layer of `cpu -e 'rc4_256 sha1'`
+//!
+//! Before TLS, cpu encrypted its connection with the kernel's ssl device.
+//! After p9sk1 each side sends four random bytes, the client first, and
+//! both hash
+//!
+//! ```text
+//! key[16] = crand[4] secret[8] srand[4]
+//! ```
+//!
+//! with SHA1, writing digest bytes 0-9 and 10-19 as hex (mksecret) for
+//! the secret from the client and the secret from the server. devssl's ctl
+//! file then base64-decodes those strings, so the keys proper are 15 bytes.
+//!
+//! Each record is a 2-byte count with the high bit set, then the MAC and
+//! the data, encrypted together with one RC4 stream per direction. The MAC
+//! is the hash of the direction's secret, the data and a 4-byte big-endian
+//! record number.
+//!
+//! Like `tlspsk`, `Devssl` does no I/O of its own.
+//!
+//! Ported from Plan 9's cpu.c (p9auth, srvp9auth) and devssl.c.
+
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::Engine;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+use crate::rpc::AuthInfo;
+use crate::secstore::Rc4;
+
+pub const DEFAULTALGS: &str = "rc4_256 sha1"; // What cpu -e asks for by default
+pub const RANDLEN: usize = 4; // Random bytes each side sends
+pub const MAXRECLEN: usize = 0x7fff; // Largest count in a 2-byte header
+
+/// Error type for the ssl record layer
+#[derive(Debug, Clone)]
+pub struct DevsslError(pub String);
+
+impl std::fmt::Display for DevsslError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DevsslError {}
+
+/// Encryption algorithms devssl offers that cpu servers use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslEnc {
+    Clear,
+    Rc4_40,
+    Rc4_128,
+    Rc4_256,
+}
+
+/// Digest algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslHash {
+    Clear,
+    Md5,
+    Sha1,
+}
+
+impl SslHash {
+    fn len(self) -> usize {
+        match self {
+            SslHash::Clear => 0,
+            SslHash::Md5 => 16,
+            SslHash::Sha1 => 20,
+        }
+    }
+
+    fn mac(self, secret: &[u8], data: &[u8], mid: u32) -> Vec<u8> {
+        fn run<D: Digest>(secret: &[u8], data: &[u8], mid: u32) -> Vec<u8> {
+            let mut h = D::new();
+            h.update(secret);
+            h.update(data);
+            h.update(mid.to_be_bytes());
+            h.finalize().to_vec()
+        }
+        match self {
+            SslHash::Clear => Vec::new(),
+            SslHash::Md5 => run::<Md5>(secret, data, mid),
+            SslHash::Sha1 => run::<Sha1>(secret, data, mid),
+        }
+    }
+}
+
+/// Parse an `alg` string such as "rc4_256 sha1"; either half may be
+/// missing or "clear".
+pub fn parse_algs(algs: &str) -> Result<(SslEnc, SslHash), DevsslError> {
+    let (mut enc, mut hash) = (SslEnc::Clear, SslHash::Clear);
+    for a in algs.split_whitespace() {
+        match a {
+            "clear" => {}
+            "rc4_40" => enc = SslEnc::Rc4_40,
+            "rc4_128" => enc = SslEnc::Rc4_128,
+            "rc4" | "rc4_256" => enc = SslEnc::Rc4_256,
+            "md5" => hash = SslHash::Md5,
+            "sha1" | "sha" => hash = SslHash::Sha1,
+            _ => return Err(DevsslError(format!("unsupported algorithm {}", a))),
+        }
+    }
+    Ok((enc, hash))
+}
+
+/// Four random bytes for the exchange.
+pub fn cpu_random() -> [u8; RANDLEN] {
+    let mut r = [0u8; RANDLEN];
+    getrandom::getrandom(&mut r).expect("Failed to get random bytes");
+    r
+}
+
+/// cpu's two secrets from the p9sk1 AuthInfo secret and the random bytes
+/// each side sent: (from client, from server), as mksecret writes them.
+pub fn cpu_secrets(
+    secret: &[u8],
+    crand: &[u8; RANDLEN],
+    srand: &[u8; RANDLEN],
+) -> (String, String) {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(crand);
+    let n = secret.len().min(8);
+    key[4..4 + n].copy_from_slice(&secret[..n]);
+    key[12..].copy_from_slice(srand);
+    let digest = Sha1::digest(key);
+    (hex::encode(&digest[..10]), hex::encode(&digest[10..]))
+}
+
+/// A secret as devssl's ctl takes it: base64, however it was meant.
+fn ctl_secret(s: &str) -> Result<Vec<u8>, DevsslError> {
+    BASE64
+        .decode(s.trim_end_matches('='))
+        .ok()
+        .filter(|k| !k.is_empty())
+        .ok_or_else(|| DevsslError(format!("bad secret {:?}", s)))
+}
+
+/// One direction: its secret, cipher state and record number
+struct OneWay {
+    secret: Vec<u8>,
+    rc4: Option<Rc4>,
+    mid: u32,
+}
+
+impl OneWay {
+    fn new(enc: SslEnc, mut secret: Vec<u8>) -> Self {
+        let klen = match enc {
+            SslEnc::Clear => 0,
+            SslEnc::Rc4_40 => 5,
+            SslEnc::Rc4_128 => 16,
+            SslEnc::Rc4_256 => secret.len(),
+        };
+        // devssl.c shortens the secret itself, so the MAC uses the short key
+        if klen > 0 {
+            secret.truncate(klen);
+        }
+        let rc4 = (klen > 0).then(|| Rc4::new(&secret));
+        OneWay {
+            secret,
+            rc4,
+            mid: 0,
+        }
+    }
+}
+
+/// An ssl connection as pushssl sets it up
+pub struct Devssl {
+    hash: SslHash,
+    out: OneWay,
+    inp: OneWay,
+    /// Bytes from the peer not yet making a whole record
+    inbuf: Vec<u8>,
+}
+
+impl Devssl {
+    /// pushssl: `secin` and `secout` in the form cpu passes them.
+    pub fn new(algs: &str, secin: &str, secout: &str) -> Result<Self, DevsslError> {
+        let (enc, hash) = parse_algs(algs)?;
+        Ok(Devssl {
+            hash,
+            inp: OneWay::new(enc, ctl_secret(secin)?),
+            out: OneWay::new(enc, ctl_secret(secout)?),
+            inbuf: Vec::new(),
+        })
+    }
+
+    /// The client end after p9sk1 and the random exchange (p9auth).
+    pub fn client(
+        ai: &AuthInfo,
+        crand: &[u8; RANDLEN],
+        srand: &[u8; RANDLEN],
+        algs: &str,
+    ) -> Result<Self, DevsslError> {
+        let (fromclient, fromserver) = cpu_secrets(&ai.secret, crand, srand);
+        Self::new(algs, &fromserver, &fromclient)
+    }
+
+    /// The server end (srvp9auth).
+    pub fn server(
+        ai: &AuthInfo,
+        crand: &[u8; RANDLEN],
+        srand: &[u8; RANDLEN],
+        algs: &str,
+    ) -> Result<Self, DevsslError> {
+        let (fromclient, fromserver) = cpu_secrets(&ai.secret, crand, srand);
+        Self::new(algs, &fromclient, &fromserver)
+    }
+
+    /// Most data one record carries
+    pub fn max_data(&self) -> usize {
+        MAXRECLEN - self.hash.len()
+    }
+
+    /// Records carrying `data`.
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 2 + self.hash.len());
+        for chunk in data.chunks(self.max_data()) {
+            let mut rec = self.hash.mac(&self.out.secret, chunk, self.out.mid);
+            rec.extend_from_slice(chunk);
+            if let Some(rc4) = &mut self.out.rc4 {
+                rc4.apply(&mut rec);
+            }
+            out.push(0x80 | (rec.len() >> 8) as u8);
+            out.push(rec.len() as u8);
+            out.extend_from_slice(&rec);
+            self.out.mid = self.out.mid.wrapping_add(1);
+        }
+        out
+    }
+
+    /// Bytes from the peer, in any pieces; returns the data of the records
+    /// they complete.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, DevsslError> {
+        self.inbuf.extend_from_slice(data);
+        let mut out = Vec::new();
+        while self.inbuf.len() >= 2 {
+            let (hlen, len, pad) = if self.inbuf[0] & 0x80 != 0 {
+                (
+                    2,
+                    ((self.inbuf[0] & 0x7f) as usize) << 8 | self.inbuf[1] as usize,
+                    0,
+                )
+            } else {
+                // Only block ciphers pad, but the header allows it
+                if self.inbuf.len() < 3 {
+                    break;
+                }
+                let len = ((self.inbuf[0] & 0x3f) as usize) << 8 | self.inbuf[1] as usize;
+                (3, len, self.inbuf[2] as usize)
+            };
+            if self.inbuf.len() < hlen + len {
+                break;
+            }
+            let mut rec: Vec<u8> = self.inbuf.drain(..hlen + len).skip(hlen).collect();
+            if let Some(rc4) = &mut self.inp.rc4 {
+                rc4.apply(&mut rec);
+            }
+            let dlen = self.hash.len();
+            if rec.len() < dlen + pad {
+                return Err(DevsslError("ssl: record too short".to_string()));
+            }
+            let body = &rec[dlen..rec.len() - pad];
+            if self.hash != SslHash::Clear
+                && self.hash.mac(&self.inp.secret, body, self.inp.mid) != rec[..dlen]
+            {
+                return Err(DevsslError("ssl: bad digest".to_string()));
+            }
+            self.inp.mid = self.inp.mid.wrapping_add(1);
+            out.extend_from_slice(body);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(algs: &str) -> (Devssl, Devssl) {
+        let ai = AuthInfo {
+            secret: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            ..Default::default()
+        };
+        let (crand, srand) = ([9, 9, 9, 9], [7, 7, 7, 7]);
+        (
+            Devssl::client(&ai, &crand, &srand, algs).unwrap(),
+            Devssl::server(&ai, &crand, &srand, algs).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cpu_secrets() {
+        let (c, s) = cpu_secrets(&[1, 2, 3, 4, 5, 6, 7, 8], &[9; 4], &[7; 4]);
+        let key = [9, 9, 9, 9, 1, 2, 3, 4, 5, 6, 7, 8, 7, 7, 7, 7];
+        let d = Sha1::digest(key);
+        assert_eq!(c, hex::encode(&d[..10]));
+        assert_eq!(s, hex::encode(&d[10..]));
+        // The ctl reads 20 hex digits as base64
+        assert_eq!(ctl_secret(&c).unwrap().len(), 15);
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let (mut c, _) = pair("clear sha1");
+        let (fromclient, _) = cpu_secrets(&[1, 2, 3, 4, 5, 6, 7, 8], &[9; 4], &[7; 4]);
+        let rec = c.encrypt(b"Tversion");
+        assert_eq!(&rec[..2], &[0x80, 28]);
+        let mut h = Sha1::new();
+        h.update(ctl_secret(&fromclient).unwrap());
+        h.update(b"Tversion");
+        h.update(0u32.to_be_bytes());
+        assert_eq!(&rec[2..22], h.finalize().as_slice());
+        assert_eq!(&rec[22..], b"Tversion");
+        // Record numbers go up
+        assert_ne!(&c.encrypt(b"Tversion")[2..22], &rec[2..22]);
+    }
+
+    #[test]
+    fn test_rc4_40_vector() {
+        // Secret bytes 0-14; rc4_40 keys both RC4 and the MAC with 0-4
+        let secret = "AAECAwQFBgcICQoLDA0O";
+        let mut c = Devssl::new("rc4_40 sha1", secret, secret).unwrap();
+        let rec = c.encrypt(b"Tversion");
+        assert_eq!(
+            hex::encode(&rec),
+            "801c9afe6261e625dd9410951790b08d277fb4e332124303fd3dcaf0d63e"
+        );
+        assert_eq!(c.decrypt(&rec).unwrap(), b"Tversion");
+    }
+
+    #[test]
+    fn test_roundtrip_algs() {
+        for algs in [
+            "rc4_256 sha1",
+            "rc4_128 md5",
+            "rc4_40 sha1",
+            "clear md5",
+            "rc4_256",
+        ] {
+            let (mut c, mut s) = pair(algs);
+            for msg in [&b"Tattach"[..], b"Twalk", &[0u8; 40000]] {
+                let rec = c.encrypt(msg);
+                if algs.starts_with("rc4") {
+                    assert!(!rec.windows(msg.len()).any(|w| w == msg));
+                }
+                assert_eq!(s.decrypt(&rec).unwrap(), msg, "{}", algs);
+                let rec = s.encrypt(msg);
+                assert_eq!(c.decrypt(&rec).unwrap(), msg, "{}", algs);
+            }
+        }
+        assert!(parse_algs("des_56_cbc sha1").is_err());
+    }
+
+    #[test]
+    fn test_fragmented_input() {
+        let (mut c, mut s) = pair(DEFAULTALGS);
+        let rec = [c.encrypt(b"Tread"), c.encrypt(b"Tclunk")].concat();
+        let mut got = Vec::new();
+        for b in rec.chunks(3) {
+            got.extend(s.decrypt(b).unwrap());
+        }
+        assert_eq!(got, b"TreadTclunk");
+    }
+
+    #[test]
+    fn test_tampering() {
+        let (mut c, mut s) = pair(DEFAULTALGS);
+        let mut rec = c.encrypt(b"Tremove");
+        let n = rec.len();
+        rec[n - 1] ^= 1;
+        assert!(s.decrypt(&rec).is_err());
+
+        // A replayed record has the wrong number
+        let (mut c, mut s) = pair("clear sha1");
+        let rec = c.encrypt(b"Tremove");
+        s.decrypt(&rec).unwrap();
+        assert!(s.decrypt(&rec).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! form1 tickets and authenticators (dp9ik)
+//!
+//! After AuthPAK the auth server seals tickets with ChaCha20-Poly1305 under
+//! the PAK key instead of DES. A form1 message starts with an 8-byte
+//! signature naming its type ("form1 Tc") and a 4-byte counter; those 12
+//! bytes are the nonce. The body that follows is the DES-era layout minus
+//! the type byte, with 32-byte keys and nonces in place of the 7-byte DES
+//! key and 4-byte id, then the 16-byte tag.
+//!
+//! Ported from 9front's libauthsrv form1.c, convM2T.c and convA2M.c.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+
+use crate::authsrv::AUTH_PASS;
+use crate::p9sk1::{
+    read_fixed_string, write_fixed_string, ANAMELEN, AUTHENTLEN, AUTH_AC, AUTH_AS, AUTH_TC,
+    AUTH_TS, CHALLEN, TICKETLEN,
+};
+
+pub const NONCELEN: usize = 32;
+pub const FORM1HDRLEN: usize = 12; // Signature and counter
+pub const FORM1TAGLEN: usize = 16;
+pub const FORM1TICKETLEN: usize = FORM1HDRLEN + CHALLEN + 2 * ANAMELEN + NONCELEN + FORM1TAGLEN;
+pub const FORM1AUTHENTLEN: usize = FORM1HDRLEN + CHALLEN + NONCELEN + FORM1TAGLEN;
+
+pub const AUTH_TP: u8 = 68; // Ticket encrypted with client's key for password change
+pub const AUTH_HR: u8 = 69; // http reply
+
+const FORM1SIG: [(u8, &[u8; 8]); 7] = [
+    (AUTH_PASS, b"form1 PR"),
+    (AUTH_TS, b"form1 Ts"),
+    (AUTH_TC, b"form1 Tc"),
+    (AUTH_AS, b"form1 As"),
+    (AUTH_AC, b"form1 Ac"),
+    (AUTH_TP, b"form1 Tp"),
+    (AUTH_HR, b"form1 Hr"),
+];
+
+static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// The message type of a form1 message, or None for DES form (form1check).
+pub fn form1_check(msg: &[u8]) -> Option<u8> {
+    let sig = msg.get(..8)?;
+    FORM1SIG
+        .iter()
+        .find(|(_, s)| &s[..] == sig)
+        .map(|&(num, _)| num)
+}
+
+/// Seal a body as a form1 message of type `num` (form1B2M).
+pub fn form1_seal(num: u8, body: &[u8], key: &[u8; NONCELEN]) -> Vec<u8> {
+    let sig = FORM1SIG
+        .iter()
+        .find(|&&(n, _)| n == num)
+        .map(|&(_, s)| s)
+        .expect("no form1 signature for message type");
+    let mut msg = Vec::with_capacity(FORM1HDRLEN + body.len() + FORM1TAGLEN);
+    msg.extend_from_slice(sig);
+    msg.extend_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    msg.extend_from_slice(body);
+
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let (hdr, data) = msg.split_at_mut(FORM1HDRLEN);
+    let tag = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(hdr), b"", data)
+        .expect("form1 message too long");
+    msg.extend_from_slice(&tag);
+    msg
+}
+
+/// Open a form1 message; returns its type and body (form1M2B).
+pub fn form1_open(msg: &[u8], key: &[u8; NONCELEN]) -> Option<(u8, Vec<u8>)> {
+    let num = form1_check(msg)?;
+    if msg.len() <= FORM1HDRLEN + FORM1TAGLEN {
+        return None;
+    }
+    let (hdr, rest) = msg.split_at(FORM1HDRLEN);
+    let (data, tag) = rest.split_at(rest.len() - FORM1TAGLEN);
+    let mut body = data.to_vec();
+    let cipher = ChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt_in_place_detached(Nonce::from_slice(hdr), b"", &mut body, Tag::from_slice(tag))
+        .ok()?;
+    Some((num, body))
+}
+
+/// Length of the ticket at the start of `buf`, DES or form1 (convM2T).
+/// None until the 8 bytes that tell them apart have arrived.
+pub fn ticket_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 8 {
+        return None;
+    }
+    Some(match form1_check(buf) {
+        Some(_) => FORM1TICKETLEN,
+        None => TICKETLEN,
+    })
+}
+
+/// Length of the authenticator at the start of `buf` (convM2A).
+pub fn authenticator_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 8 {
+        return None;
+    }
+    Some(match form1_check(buf) {
+        Some(_) => FORM1AUTHENTLEN,
+        None => AUTHENTLEN,
+    })
+}
+
+/// A form1 ticket: like `p9sk1::Ticket`, with a 32-byte key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form1Ticket {
+    pub ticket_type: u8,
+    pub challenge: [u8; CHALLEN],
+    pub cuid: String,
+    pub suid: String,
+    pub key: [u8; NONCELEN],
+}
+
+impl Form1Ticket {
+    pub fn seal(&self, key: &[u8; NONCELEN]) -> Vec<u8> {
+        let mut body = [0u8; CHALLEN + 2 * ANAMELEN + NONCELEN];
+        body[..CHALLEN].copy_from_slice(&self.challenge);
+        write_fixed_string(&mut body[CHALLEN..CHALLEN + ANAMELEN], &self.cuid);
+        write_fixed_string(
+            &mut body[CHALLEN + ANAMELEN..CHALLEN + 2 * ANAMELEN],
+            &self.suid,
+        );
+        body[CHALLEN + 2 * ANAMELEN..].copy_from_slice(&self.key);
+        form1_seal(self.ticket_type, &body, key)
+    }
+
+    pub fn open(msg: &[u8], key: &[u8; NONCELEN]) -> Option<Self> {
+        let (num, body) = form1_open(msg.get(..FORM1TICKETLEN)?, key)?;
+        Some(Form1Ticket {
+            ticket_type: num,
+            challenge: body[..CHALLEN].try_into().unwrap(),
+            cuid: read_fixed_string(&body[CHALLEN..CHALLEN + ANAMELEN]),
+            suid: read_fixed_string(&body[CHALLEN + ANAMELEN..CHALLEN + 2 * ANAMELEN]),
+            key: body[CHALLEN + 2 * ANAMELEN..].try_into().unwrap(),
+        })
+    }
+}
+
+/// A form1 authenticator: a 32-byte nonce where DES form has an id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form1Authenticator {
+    pub auth_type: u8,
+    pub challenge: [u8; CHALLEN],
+    pub rand: [u8; NONCELEN],
+}
+
+impl Form1Authenticator {
+    pub fn seal(&self, key: &[u8; NONCELEN]) -> Vec<u8> {
+        let mut body = [0u8; CHALLEN + NONCELEN];
+        body[..CHALLEN].copy_from_slice(&self.challenge);
+        body[CHALLEN..].copy_from_slice(&self.rand);
+        form1_seal(self.auth_type, &body, key)
+    }
+
+    pub fn open(msg: &[u8], key: &[u8; NONCELEN]) -> Option<Self> {
+        let (num, body) = form1_open(msg.get(..FORM1AUTHENTLEN)?, key)?;
+        Some(Form1Authenticator {
+            auth_type: num,
+            challenge: body[..CHALLEN].try_into().unwrap(),
+            rand: body[CHALLEN..].try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form1_lengths() {
+        assert_eq!(FORM1TICKETLEN, 124);
+        assert_eq!(FORM1AUTHENTLEN, 68);
+        assert_eq!(ticket_len(b"form1 T"), None);
+        assert_eq!(ticket_len(b"form1 Tc...."), Some(FORM1TICKETLEN));
+        assert_eq!(ticket_len(&[0u8; 8]), Some(TICKETLEN));
+        assert_eq!(authenticator_len(b"form1 As"), Some(FORM1AUTHENTLEN));
+    }
+
+    #[test]
+    fn test_ticket_seal_open() {
+        let key = [0x33u8; NONCELEN];
+        let t = Form1Ticket {
+            ticket_type: AUTH_TC,
+            challenge: [1, 2, 3, 4, 5, 6, 7, 8],
+            cuid: "glenda".to_string(),
+            suid: "glenda".to_string(),
+            key: [0x44; NONCELEN],
+        };
+        let msg = t.seal(&key);
+        assert_eq!(msg.len(), FORM1TICKETLEN);
+        assert_eq!(&msg[..8], b"form1 Tc");
+        assert_eq!(form1_check(&msg), Some(AUTH_TC));
+        assert_eq!(Form1Ticket::open(&msg, &key), Some(t));
+
+        assert_eq!(Form1Ticket::open(&msg, &[0x34; NONCELEN]), None);
+        let mut bad = msg.clone();
+        bad[20] ^= 1;
+        assert_eq!(Form1Ticket::open(&bad, &key), None);
+    }
+
+    #[test]
+    fn test_authenticator_seal_open() {
+        let key = [7u8; NONCELEN];
+        let a = Form1Authenticator {
+            auth_type: AUTH_AS,
+            challenge: [9; CHALLEN],
+            rand: [0x5a; NONCELEN],
+        };
+        let msg = a.seal(&key);
+        assert_eq!(msg.len(), FORM1AUTHENTLEN);
+        assert_eq!(Form1Authenticator::open(&msg, &key), Some(a));
+    }
+
+    #[test]
+    fn test_form1_is_chacha20poly1305() {
+        // The 12-byte header is the nonce and there is no associated data
+        use chacha20poly1305::aead::Aead;
+        let key = [1u8; NONCELEN];
+        let msg = form1_seal(AUTH_AC, b"body", &key);
+        let cipher = ChaCha20Poly1305::new(&key.into());
+        let plain = cipher
+            .decrypt(Nonce::from_slice(&msg[..12]), &msg[12..])
+            .unwrap();
+        assert_eq!(plain, b"body");
+    }
+}
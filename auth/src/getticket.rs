@@ -0,0 +1,309 @@
+//! getticket - ask the auth server for a ticket pair directly
+//!
+//! Factotum asks for tickets on behalf of a server that sent it a ticket
+//! request. To check a password against the auth server, or to see what
+//! it hands out, a client can make the request itself. It then plays both
+//! ends, so it needs the authid's key as well as the user's.
+//!
+//! p9sk1 sends AuthTreq and gets back two DES tickets. dp9ik first runs
+//! AuthPAK for both authid and uid and gets form1 tickets under the keys
+//! it agrees. The requests are pipelined, as factotum sends them.
+//!
+//! Ported from 9front's libauthsrv _asgetticket.c and _asgetpakkey.c.
+
+use crate::authpak::{authpak_finish, authpak_new, PakPriv, PAKHASHLEN, PAKKEYLEN, PAKYLEN};
+use crate::authsrv::{parse_reply, AuthError, Authkey, Decoded, Ticketreq, AUTH_PAK};
+use crate::form1::{self, Form1Ticket, FORM1TICKETLEN};
+use crate::p9sk1::{decrypt_ticket, Ticket, TicketProto, AUTH_TC, AUTH_TREQ, DESSION, TICKETLEN};
+
+/// A ticket opened with its key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Opened {
+    Des(Ticket),
+    Form1(Form1Ticket),
+}
+
+impl Opened {
+    pub fn ticket_type(&self) -> u8 {
+        match self {
+            Opened::Des(t) => t.ticket_type,
+            Opened::Form1(t) => t.ticket_type,
+        }
+    }
+
+    pub fn challenge(&self) -> &[u8] {
+        match self {
+            Opened::Des(t) => &t.challenge,
+            Opened::Form1(t) => &t.challenge,
+        }
+    }
+
+    pub fn cuid(&self) -> &str {
+        match self {
+            Opened::Des(t) => &t.cuid,
+            Opened::Form1(t) => &t.cuid,
+        }
+    }
+
+    pub fn suid(&self) -> &str {
+        match self {
+            Opened::Des(t) => &t.suid,
+            Opened::Form1(t) => &t.suid,
+        }
+    }
+
+    /// The session key: 7 bytes for DES, 32 for form1.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Opened::Des(t) => &t.key,
+            Opened::Form1(t) => &t.key,
+        }
+    }
+}
+
+/// Open `msg` with `key`: a DES key for a 72-byte ticket, a PAK key for
+/// a form1 one. DES always "opens"; check the type and challenge.
+pub fn open_ticket(msg: &[u8], key: &[u8]) -> Option<Opened> {
+    match (msg.len(), key.len()) {
+        (TICKETLEN, DESSION) => Some(Opened::Des(decrypt_ticket(
+            msg.try_into().unwrap(),
+            key.try_into().unwrap(),
+        ))),
+        (FORM1TICKETLEN, PAKKEYLEN) => {
+            Form1Ticket::open(msg, key.try_into().unwrap()).map(Opened::Form1)
+        }
+        _ => None,
+    }
+}
+
+/// One client-made ticket request and the keys to open its answer
+pub struct GetTicket {
+    proto: TicketProto,
+    tr: Ticketreq,
+    hostkey: Authkey,
+    userkey: Authkey,
+    /// authid's and uid's halves of AuthPAK
+    pak: Option<(PakPriv, PakPriv)>,
+}
+
+impl GetTicket {
+    /// Tickets for `tr.uid` to present to `tr.authid`, whose keys are
+    /// `userkey` and `hostkey`. For dp9ik the PAK hashes are filled in
+    /// here if empty.
+    pub fn new(
+        proto: TicketProto,
+        tr: Ticketreq,
+        mut hostkey: Authkey,
+        mut userkey: Authkey,
+    ) -> Self {
+        let mut pak = None;
+        if proto == TicketProto::Dp9ik {
+            if hostkey.pakhash == [0u8; PAKHASHLEN] {
+                hostkey.authpak_hash(&tr.authid);
+            }
+            if userkey.pakhash == [0u8; PAKHASHLEN] {
+                userkey.authpak_hash(&tr.uid);
+            }
+            pak = Some((
+                authpak_new(&hostkey.pakhash, true),
+                authpak_new(&userkey.pakhash, true),
+            ));
+        }
+        GetTicket {
+            proto,
+            tr,
+            hostkey,
+            userkey,
+            pak,
+        }
+    }
+
+    pub fn ticketreq(&self) -> &Ticketreq {
+        &self.tr
+    }
+
+    /// Everything to send: [AuthPAK treq YAs YAc] AuthTreq treq.
+    pub fn request(&self) -> Vec<u8> {
+        let mut tr = self.tr.clone();
+        let mut out = Vec::new();
+        if let Some((s, c)) = &self.pak {
+            tr.req_type = AUTH_PAK;
+            out.extend_from_slice(&tr.to_bytes());
+            out.extend_from_slice(&s.y);
+            out.extend_from_slice(&c.y);
+        }
+        tr.req_type = AUTH_TREQ;
+        out.extend_from_slice(&tr.to_bytes());
+        out
+    }
+
+    /// The auth server's answer, opened: Tc and Ts. Tc must carry our
+    /// challenge, or the user's key is wrong.
+    pub fn reply(&self, buf: &[u8]) -> Result<Decoded<(Opened, Opened)>, AuthError> {
+        let mut off = 0;
+        let mut keys: Option<([u8; PAKKEYLEN], [u8; PAKKEYLEN])> = None;
+        if let Some((s, c)) = &self.pak {
+            let y = match parse_reply(buf, 2 * PAKYLEN)? {
+                Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(n)),
+                Decoded::Done(y, used) => {
+                    off = used;
+                    y
+                }
+            };
+            let ks = authpak_finish(s, &self.hostkey.pakhash, y[..PAKYLEN].try_into().unwrap())
+                .map_err(|e| AuthError(e.0))?;
+            let kc = authpak_finish(c, &self.userkey.pakhash, y[PAKYLEN..].try_into().unwrap())
+                .map_err(|e| AuthError(e.0))?;
+            keys = Some((ks, kc));
+        }
+        match parse_reply(&buf[off..], 0)? {
+            Decoded::Toosmall(n) => return Ok(Decoded::Toosmall(off + n)),
+            Decoded::Done(_, used) => off += used,
+        }
+
+        let rest = &buf[off..];
+        let tclen = match form1::ticket_len(rest) {
+            Some(n) => n,
+            None => return Ok(Decoded::Toosmall(off + 8)),
+        };
+        let tslen = match form1::ticket_len(rest.get(tclen..).unwrap_or(&[])) {
+            Some(n) => n,
+            None => return Ok(Decoded::Toosmall(off + tclen + 8)),
+        };
+        if rest.len() < tclen + tslen {
+            return Ok(Decoded::Toosmall(off + tclen + tslen));
+        }
+        let (tc, ts) = (&rest[..tclen], &rest[tclen..tclen + tslen]);
+
+        let (ckey, skey): (&[u8], &[u8]) = match &keys {
+            Some((ks, kc)) => (kc, ks),
+            None => (&self.userkey.des, &self.hostkey.des),
+        };
+        let wrong = || {
+            AuthError(format!(
+                "{}: invalid ticket: wrong password?",
+                self.proto.name()
+            ))
+        };
+        let tc = open_ticket(tc, ckey).ok_or_else(wrong)?;
+        if tc.ticket_type() != AUTH_TC || tc.challenge() != self.tr.chal {
+            return Err(wrong());
+        }
+        let ts = open_ticket(ts, skey).ok_or_else(|| {
+            AuthError(format!(
+                "{}: cannot open Ts: wrong authid key?",
+                self.proto.name()
+            ))
+        })?;
+        Ok(Decoded::Done((tc, ts), off + tclen + tslen))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authsrv::gen_chal;
+    use crate::form1::NONCELEN;
+    use crate::p9sk1::AUTH_TS;
+    use crate::testsrv::TestAuthsrv;
+
+    fn setup(proto: TicketProto, password: &str) -> (GetTicket, TestAuthsrv) {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let tr = Ticketreq {
+            authid: "bootes".to_string(),
+            authdom: "nawin".to_string(),
+            chal: gen_chal(),
+            hostid: "glenda".to_string(),
+            uid: "glenda".to_string(),
+            ..Default::default()
+        };
+        let g = GetTicket::new(
+            proto,
+            tr,
+            Authkey::from_password("cpusecret"),
+            Authkey::from_password(password),
+        );
+        (g, srv)
+    }
+
+    #[test]
+    fn test_p9sk1_tickets() {
+        let (g, srv) = setup(TicketProto::P9sk1, "kittens");
+        let req = g.request();
+        assert_eq!(req[0], AUTH_TREQ);
+        let reply = srv.serve(&req);
+        let (tc, ts) = match g.reply(&reply).unwrap() {
+            Decoded::Done(t, n) => {
+                assert_eq!(n, reply.len());
+                t
+            }
+            Decoded::Toosmall(n) => panic!("toosmall {}", n),
+        };
+        assert!(matches!(tc, Opened::Des(_)));
+        assert_eq!(tc.cuid(), "glenda");
+        assert_eq!(ts.ticket_type(), AUTH_TS);
+        assert_eq!(ts.key(), tc.key());
+    }
+
+    #[test]
+    fn test_dp9ik_tickets() {
+        let (g, srv) = setup(TicketProto::Dp9ik, "kittens");
+        let req = g.request();
+        assert_eq!(req[0], AUTH_PAK);
+        let reply = srv.serve(&req);
+        // Every prefix asks for more
+        for n in [0, 1, 2 * PAKYLEN, 2 * PAKYLEN + 2, reply.len() - 1] {
+            match g.reply(&reply[..n]).unwrap() {
+                Decoded::Toosmall(m) => assert!(m > n),
+                Decoded::Done(..) => panic!("done with {} bytes", n),
+            }
+        }
+        let (tc, ts) = match g.reply(&reply).unwrap() {
+            Decoded::Done(t, _) => t,
+            Decoded::Toosmall(n) => panic!("toosmall {}", n),
+        };
+        assert!(matches!(tc, Opened::Form1(_)));
+        assert_eq!(tc.challenge(), g.ticketreq().chal);
+        assert_eq!(ts.suid(), "glenda");
+        assert_eq!(ts.key().len(), NONCELEN);
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        for proto in [TicketProto::P9sk1, TicketProto::Dp9ik] {
+            let (g, srv) = setup(proto, "puppies");
+            let reply = srv.serve(&g.request());
+            let e = g.reply(&reply).expect_err("wrong password accepted");
+            assert!(e.0.contains("wrong password"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_open_ticket() {
+        let t = Ticket {
+            ticket_type: AUTH_TC,
+            challenge: [1; 8],
+            cuid: "glenda".to_string(),
+            suid: "bootes".to_string(),
+            key: [2; DESSION],
+        };
+        let key = crate::p9sk1::pass_to_key("kittens");
+        let msg = crate::p9sk1::encrypt_ticket(&t, &key);
+        assert_eq!(open_ticket(&msg, &key), Some(Opened::Des(t)));
+        assert_eq!(open_ticket(&msg, &[0; NONCELEN]), None);
+        assert_eq!(open_ticket(&msg[1..], &key), None);
+
+        let f = Form1Ticket {
+            ticket_type: AUTH_TC,
+            challenge: [1; 8],
+            cuid: "glenda".to_string(),
+            suid: "glenda".to_string(),
+            key: [3; NONCELEN],
+        };
+        let msg = f.seal(&[4; NONCELEN]);
+        assert_eq!(open_ticket(&msg, &[4; NONCELEN]), Some(Opened::Form1(f)));
+        assert_eq!(open_ticket(&msg, &[5; NONCELEN]), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! keycache - derived keys kept for reconnects
+//!
+//! Turning a password into an `Authkey` costs a 9001-round PBKDF2, and
+//! dp9ik adds the PAK hash on top. When the connection drops and the
+//! client authenticates again, the cache hands back the keys it derived
+//! last time, by user and authentication domain, until its time to live
+//! runs out. A TTL of 0 keeps nothing.
+//!
+//! With a password in the keyring a cached entry is used only if it was
+//! derived from that same password, checked cheaply against a SHA-256 of
+//! it kept with the entry (the DES key alone sees only the first 27
+//! bytes). With none, a live entry stands in for the key, so the
+//! reconnect needs no prompt.
+//!
+//! There is one cache for the session (the page, under WASM), so a new
+//! `Factotum` built for the reconnect finds what the old one derived.
+//! Entries stay on the Rust side; JS can only set the TTL and purge.
+
+use std::cell::RefCell;
+
+use sha2::{Digest, Sha256};
+
+use crate::authsrv::Authkey;
+
+struct Entry {
+    user: String,
+    dom: String,
+    key: Authkey,
+    /// SHA-256 of the password `key` came from, if `derive` made it
+    pass: Option<[u8; 32]>,
+    expires: u64,
+}
+
+/// Derived keys by user@authdom, each with an expiry
+#[derive(Default)]
+pub struct KeyCache {
+    ttl: u64,
+    entries: Vec<Entry>,
+}
+
+impl KeyCache {
+    /// A cache whose entries live `ttl` seconds.
+    pub fn new(ttl: u64) -> Self {
+        KeyCache {
+            ttl,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    /// Change the TTL for new entries; 0 also drops the current ones.
+    pub fn set_ttl(&mut self, ttl: u64) {
+        self.ttl = ttl;
+        if ttl == 0 {
+            self.purge();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget everything.
+    pub fn purge(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Forget what has expired by `now`.
+    pub fn expire(&mut self, now: u64) {
+        self.entries.retain(|e| now < e.expires);
+    }
+
+    /// Keep `key` for `user` in `dom` until `now` plus the TTL. The same
+    /// keys again, say with the PAK hash filled in, still know their
+    /// password.
+    pub fn insert(&mut self, user: &str, dom: &str, key: &Authkey, now: u64) {
+        let pass = self
+            .entries
+            .iter()
+            .find(|e| e.user == user && e.dom == dom && e.key.aes == key.aes)
+            .and_then(|e| e.pass);
+        self.put(user, dom, key, pass, now);
+    }
+
+    fn put(&mut self, user: &str, dom: &str, key: &Authkey, pass: Option<[u8; 32]>, now: u64) {
+        self.entries.retain(|e| e.user != user || e.dom != dom);
+        if self.ttl == 0 {
+            return;
+        }
+        self.entries.push(Entry {
+            user: user.to_string(),
+            dom: dom.to_string(),
+            key: key.clone(),
+            pass,
+            expires: now.saturating_add(self.ttl),
+        });
+    }
+
+    /// A live entry for `dom`, for `user` if given, else the first; returns
+    /// the user with the key.
+    pub fn get(&mut self, user: Option<&str>, dom: &str, now: u64) -> Option<(String, Authkey)> {
+        self.expire(now);
+        self.entries
+            .iter()
+            .find(|e| e.dom == dom && user.is_none_or(|u| u == e.user))
+            .map(|e| (e.user.clone(), e.key.clone()))
+    }
+
+    /// The keys for `password`: the cached ones if they were derived from
+    /// it, otherwise derived afresh and cached.
+    pub fn derive(&mut self, user: &str, dom: &str, password: &str, now: u64) -> Authkey {
+        let pass: [u8; 32] = Sha256::digest(password.as_bytes()).into();
+        self.expire(now);
+        let cached = self
+            .entries
+            .iter()
+            .find(|e| e.user == user && e.dom == dom && e.pass == Some(pass));
+        match cached {
+            Some(e) => e.key.clone(),
+            None => {
+                let key = Authkey::from_password(password);
+                self.put(user, dom, &key, Some(pass), now);
+                key
+            }
+        }
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<KeyCache> = RefCell::default();
+}
+
+/// Run `f` on the session's cache, shared by every `Factotum`; off (TTL 0)
+/// until `set_ttl`.
+pub fn with<R>(f: impl FnOnce(&mut KeyCache) -> R) -> R {
+    CACHE.with(|c| f(&mut c.borrow_mut()))
+}
+
+/// Seconds since the epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Seconds since the epoch, from the browser's clock.
+#[cfg(target_arch = "wasm32")]
+pub fn unix_time() -> u64 {
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = Date, js_name = now)]
+        fn date_now() -> f64;
+    }
+    (date_now() / 1000.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p9sk1::pass_to_key;
+
+    #[test]
+    fn test_insert_get_expire() {
+        let mut c = KeyCache::new(60);
+        let key = Authkey::from_password("kittens");
+        c.insert("glenda", "nawin", &key, 1000);
+        let (user, got) = c.get(None, "nawin", 1059).unwrap();
+        assert_eq!(user, "glenda");
+        assert_eq!(got.aes, key.aes);
+        assert!(c.get(Some("bootes"), "nawin", 1000).is_none());
+        assert!(c.get(None, "other", 1000).is_none());
+        assert!(c.get(None, "nawin", 1060).is_none());
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn test_derive_checks_password() {
+        let mut c = KeyCache::new(60);
+        let mut key = c.derive("glenda", "nawin", "kittens", 0);
+        // A PAK hash filled in later is kept with the entry
+        key.authpak_hash("glenda");
+        c.insert("glenda", "nawin", &key, 0);
+        assert_eq!(
+            c.derive("glenda", "nawin", "kittens", 1).pakhash,
+            key.pakhash
+        );
+        let other = c.derive("glenda", "nawin", "puppies", 1);
+        assert_eq!(other.des, pass_to_key("puppies"));
+        assert_eq!(c.len(), 1);
+
+        // DES keys only see 27 bytes; the AES key sees them all
+        let long = "a very long password that goes on";
+        let key = c.derive("glenda", "nawin", long, 2);
+        let other = c.derive("glenda", "nawin", &format!("{}!", long), 2);
+        assert_eq!(other.des, key.des);
+        assert_ne!(other.aes, key.aes);
+    }
+
+    #[test]
+    fn test_ttl_zero_and_purge() {
+        let mut c = KeyCache::new(0);
+        c.derive("glenda", "nawin", "kittens", 0);
+        assert!(c.is_empty());
+        c.set_ttl(60);
+        c.derive("glenda", "nawin", "kittens", 0);
+        c.derive("bootes", "nawin", "cpusecret", 0);
+        assert_eq!(c.len(), 2);
+        c.purge();
+        assert!(c.is_empty());
+        c.derive("glenda", "nawin", "kittens", 0);
+        c.set_ttl(0);
+        assert!(c.is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! /adm/keys - the auth server's user database
+//!
+//! keyfs keeps one fixed-size record per user in a single file, encrypted
+//! under the machine key (the authid's key, from nvram):
+//!
+//! ```text
+//! name[28] deskey[7] status[1] warnings[1] expire[4] secret[32] [aeskey[16]]
+//! ```
+//!
+//! `expire` is little-endian seconds since the epoch, 0 for never; `secret`
+//! is the NUL-padded secure-net secret used by the challenge protocols.
+//! The file starts with `KEYDBOFF` bytes ahead of the first record. In the
+//! old form they are random, the records carry no AES key, and the whole
+//! file is DES-CBC under the machine's DES key. 9front's form puts
+//! "AES KEYS" there, adds each user's AES key, and uses AES-CBC under the
+//! machine's AES key. Both use a zero IV and libsec's partial-block tail.
+//!
+//! Ported from 9front's keyfs.c readusers and writeusers.
+
+use aes::cipher::KeyInit;
+use aes::Aes128;
+
+use crate::authpak::AESKEYLEN;
+use crate::authsrv::{Authkey, DESKEYLEN, SECRETLEN};
+use crate::des9::Des9Key;
+use crate::p9sk1::{read_fixed_string, write_fixed_string, ANAMELEN};
+use crate::secstore::{aes_cbc_decrypt, aes_cbc_encrypt, AESBSIZE};
+
+pub const KEYDBOFF: usize = 8; // Bytes ahead of the first record
+pub const OKEYDBLEN: usize = ANAMELEN + DESKEYLEN + 4 + 2; // Record before secrets were added
+pub const KEYDBLEN: usize = OKEYDBLEN + SECRETLEN; // DES-form record
+pub const AESKEYDBLEN: usize = KEYDBLEN + AESKEYLEN; // AES-form record
+
+const AESMAGIC: &[u8; KEYDBOFF] = b"AES KEYS";
+
+/// Error type for key file operations
+#[derive(Debug)]
+pub struct KeyfsError(pub String);
+
+impl std::fmt::Display for KeyfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for KeyfsError {}
+
+/// A user's status file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserStatus {
+    Ok = 0,
+    Disabled = 1,
+}
+
+/// One user's record
+#[derive(Clone)]
+pub struct KeyfsUser {
+    pub name: String,
+    pub key: Authkey,
+    pub status: UserStatus,
+    /// Expiry warnings already sent
+    pub warnings: u8,
+    /// Seconds since the epoch; 0 never expires
+    pub expire: u32,
+    pub secret: String,
+}
+
+impl KeyfsUser {
+    /// A new, enabled, never-expiring user with keys from `password` and
+    /// no secure-net secret (what `auth/changeuser -p` sets up).
+    pub fn new(name: &str, password: &str) -> Self {
+        KeyfsUser {
+            name: name.to_string(),
+            key: Authkey::from_password(password),
+            status: UserStatus::Ok,
+            warnings: 0,
+            expire: 0,
+            secret: String::new(),
+        }
+    }
+
+    /// Whether the auth server would accept this user at time `now`.
+    pub fn is_valid(&self, now: u32) -> bool {
+        self.status == UserStatus::Ok && (self.expire == 0 || now < self.expire)
+    }
+
+    fn to_record(&self, aes: bool, rec: &mut [u8]) {
+        let mut off = 0;
+        write_fixed_string(&mut rec[off..off + ANAMELEN], &self.name);
+        off += ANAMELEN;
+        rec[off..off + DESKEYLEN].copy_from_slice(&self.key.des);
+        off += DESKEYLEN;
+        rec[off] = self.status as u8;
+        rec[off + 1] = self.warnings;
+        off += 2;
+        rec[off..off + 4].copy_from_slice(&self.expire.to_le_bytes());
+        off += 4;
+        write_fixed_string(&mut rec[off..off + SECRETLEN], &self.secret);
+        off += SECRETLEN;
+        if aes {
+            rec[off..off + AESKEYLEN].copy_from_slice(&self.key.aes);
+        }
+    }
+
+    fn from_record(rec: &[u8], aes: bool) -> Result<Self, KeyfsError> {
+        let mut off = 0;
+        let name = read_fixed_string(&rec[off..off + ANAMELEN]);
+        off += ANAMELEN;
+        let mut key = Authkey::default();
+        key.des.copy_from_slice(&rec[off..off + DESKEYLEN]);
+        off += DESKEYLEN;
+        let status = match rec[off] {
+            0 => UserStatus::Ok,
+            1 => UserStatus::Disabled,
+            s => return Err(KeyfsError(format!("bad status {} for {}", s, name))),
+        };
+        let warnings = rec[off + 1];
+        off += 2;
+        let expire = u32::from_le_bytes(rec[off..off + 4].try_into().unwrap());
+        off += 4;
+        let secret = read_fixed_string(&rec[off..off + SECRETLEN]);
+        off += SECRETLEN;
+        if aes {
+            key.aes.copy_from_slice(&rec[off..off + AESKEYLEN]);
+        }
+        if name.is_empty() {
+            return Err(KeyfsError("record without a name".to_string()));
+        }
+        Ok(KeyfsUser {
+            name,
+            key,
+            status,
+            warnings,
+            expire,
+            secret,
+        })
+    }
+}
+
+/// Build a key file (writeusers); `aes` selects 9front's form.
+pub fn write_keys(users: &[KeyfsUser], machine: &Authkey, aes: bool) -> Vec<u8> {
+    let reclen = if aes { AESKEYDBLEN } else { KEYDBLEN };
+    let mut buf = vec![0u8; KEYDBOFF + users.len() * reclen];
+    if aes {
+        buf[..KEYDBOFF].copy_from_slice(AESMAGIC);
+    } else {
+        getrandom::getrandom(&mut buf[..KEYDBOFF]).expect("Failed to get random bytes");
+    }
+    for (u, rec) in users.iter().zip(buf[KEYDBOFF..].chunks_exact_mut(reclen)) {
+        u.to_record(aes, rec);
+    }
+
+    if aes {
+        let cipher = Aes128::new(&machine.aes.into());
+        aes_cbc_encrypt(&cipher, &mut [0u8; AESBSIZE], &mut buf);
+    } else {
+        Des9Key::new(&machine.des).cbc_encrypt(&mut [0u8; 8], &mut buf);
+    }
+    buf
+}
+
+/// Read a key file in either form (readusers). The AES form is recognised
+/// by its magic once decrypted, so a wrong machine key reads as DES
+/// garbage and fails on the record layout.
+pub fn read_keys(file: &[u8], machine: &Authkey) -> Result<Vec<KeyfsUser>, KeyfsError> {
+    let mut buf = file.to_vec();
+    let cipher = Aes128::new(&machine.aes.into());
+    aes_cbc_decrypt(&cipher, &mut [0u8; AESBSIZE], &mut buf);
+    let aes = buf.starts_with(AESMAGIC);
+    if !aes {
+        buf.copy_from_slice(file);
+        Des9Key::new(&machine.des).cbc_decrypt(&mut [0u8; 8], &mut buf);
+    }
+
+    let reclen = if aes { AESKEYDBLEN } else { KEYDBLEN };
+    if buf.len() < KEYDBOFF || (buf.len() - KEYDBOFF) % reclen != 0 {
+        return Err(KeyfsError(format!(
+            "key file of {} bytes is not {} records",
+            file.len(),
+            if aes { "AES" } else { "DES" }
+        )));
+    }
+    buf[KEYDBOFF..]
+        .chunks_exact(reclen)
+        .map(|rec| KeyfsUser::from_record(rec, aes))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> Vec<KeyfsUser> {
+        let mut glenda = KeyfsUser::new("glenda", "kittens");
+        glenda.secret = "netsecret".to_string();
+        glenda.expire = 2_000_000_000;
+        let mut bootes = KeyfsUser::new("bootes", "cpusecret");
+        bootes.status = UserStatus::Disabled;
+        bootes.warnings = 2;
+        vec![glenda, bootes]
+    }
+
+    fn check(read: &[KeyfsUser], aes: bool) {
+        let want = users();
+        assert_eq!(read.len(), want.len());
+        for (r, w) in read.iter().zip(want.iter()) {
+            assert_eq!(r.name, w.name);
+            assert_eq!(r.key.des, w.key.des);
+            if aes {
+                assert_eq!(r.key.aes, w.key.aes);
+            } else {
+                assert_eq!(r.key.aes, [0; AESKEYLEN]);
+            }
+            assert_eq!(r.status, w.status);
+            assert_eq!(r.warnings, w.warnings);
+            assert_eq!(r.expire, w.expire);
+            assert_eq!(r.secret, w.secret);
+        }
+    }
+
+    #[test]
+    fn test_record_lengths() {
+        assert_eq!(OKEYDBLEN, 41);
+        assert_eq!(KEYDBLEN, 73);
+        assert_eq!(AESKEYDBLEN, 89);
+        let machine = Authkey::from_password("machinekey");
+        assert_eq!(write_keys(&users(), &machine, false).len(), 8 + 2 * 73);
+        assert_eq!(write_keys(&users(), &machine, true).len(), 8 + 2 * 89);
+    }
+
+    #[test]
+    fn test_des_roundtrip() {
+        let machine = Authkey::from_password("machinekey");
+        let file = write_keys(&users(), &machine, false);
+        check(&read_keys(&file, &machine).unwrap(), false);
+    }
+
+    #[test]
+    fn test_aes_roundtrip() {
+        let machine = Authkey::from_password("machinekey");
+        let file = write_keys(&users(), &machine, true);
+        assert_ne!(&file[..KEYDBOFF], AESMAGIC);
+        check(&read_keys(&file, &machine).unwrap(), true);
+    }
+
+    #[test]
+    fn test_aes_layout() {
+        // Decrypting by hand finds the magic and glenda's record
+        let machine = Authkey::from_password("machinekey");
+        let mut buf = write_keys(&users(), &machine, true);
+        let cipher = Aes128::new(&machine.aes.into());
+        aes_cbc_decrypt(&cipher, &mut [0u8; AESBSIZE], &mut buf);
+        assert_eq!(&buf[..KEYDBOFF], b"AES KEYS");
+        assert_eq!(&buf[KEYDBOFF..KEYDBOFF + 7], b"glenda\0");
+        let off = KEYDBOFF + ANAMELEN + DESKEYLEN + 2;
+        assert_eq!(&buf[off..off + 4], &2_000_000_000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_wrong_machine_key() {
+        let machine = Authkey::from_password("machinekey");
+        let file = write_keys(&users(), &machine, true);
+        let other = Authkey::from_password("otherkey");
+        assert!(read_keys(&file, &other).is_err());
+        assert!(read_keys(&[1, 2, 3], &machine).is_err());
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let u = users();
+        assert!(u[0].is_valid(1_000_000_000));
+        assert!(!u[0].is_valid(2_000_000_000));
+        assert!(!u[1].is_valid(0));
+    }
+
+    #[test]
+    fn test_authsrv_from_keys() {
+        use crate::keyring::Keyring;
+        use crate::rpc::Factotum;
+        use crate::testsrv::{proxy, TestAuthsrv, TestServer};
+
+        let machine = Authkey::from_password("cpusecret");
+        let mut list = users();
+        list[1].status = UserStatus::Ok;
+        let file = write_keys(&list, &machine, true);
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.load_keys(&file, &machine).unwrap();
+
+        let ring =
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens").unwrap();
+        let mut f = Factotum::new(ring);
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ai = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        );
+        assert_eq!(ai.unwrap().cuid, "glenda");
+    }
+}
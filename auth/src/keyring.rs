@@ -0,0 +1,542 @@
+//! Factotum keys and an in-memory keyring
+//!
+//! A key is a list of attributes, as written to factotum's ctl file or kept
+//! in the secstore `factotum` file:
+//!
+//! ```text
+//! key proto=dp9ik dom=nawin user=glenda !password='my secret'
+//! ```
+//!
+//! Values are quoted rc-style. Attributes whose names start with '!' are
+//! private: they are never shown when a key is listed. A bare name, like
+//! `confirm` or `disabled`, is an attribute with an empty value.
+//!
+//! Lookups take an attribute pattern, as factotum's keymatch does:
+//! `name=val` must be present, `name?` needs any value, and `name:=val`
+//! only has to match if the key has the attribute at all.
+//!
+//! Ported from 9front's libauth attr.c, libc tokenize.c and factotum util.c.
+
+use std::fmt;
+
+/// Error parsing keys or finding one
+#[derive(Debug, Clone)]
+pub struct KeyringError(pub String);
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for KeyringError {}
+
+// ============================================================================
+// Attributes (attr.c)
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrType {
+    /// name=val
+    Nameval,
+    /// name?
+    Query,
+    /// name:=val
+    Default,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attr {
+    pub attr_type: AttrType,
+    pub name: String,
+    pub val: String,
+}
+
+impl Attr {
+    pub fn new(name: &str, val: &str) -> Self {
+        Attr {
+            attr_type: AttrType::Nameval,
+            name: name.to_string(),
+            val: val.to_string(),
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.name.starts_with('!')
+    }
+}
+
+impl fmt::Display for Attr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.attr_type {
+            AttrType::Query => write!(f, "{}?", quote(&self.name)),
+            // A flag reads better bare than as confirm=''
+            AttrType::Nameval if self.val.is_empty() => write!(f, "{}", quote(&self.name)),
+            AttrType::Nameval => write!(f, "{}={}", quote(&self.name), quote(&self.val)),
+            AttrType::Default => write!(f, "{}:={}", quote(&self.name), quote(&self.val)),
+        }
+    }
+}
+
+/// Split a line into words, honouring rc-style quotes (tokenize).
+///
+/// Inside single quotes, `''` stands for one quote; quoted and unquoted
+/// runs can abut, so `a'b c'd` is the single word `ab cd`.
+pub fn tokenize(s: &str) -> Vec<String> {
+    let mut toks = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| " \t\r\n".contains(*c)) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return toks;
+        }
+
+        let mut tok = String::new();
+        let mut quoting = false;
+        while let Some(c) = chars.next() {
+            if quoting {
+                if c == '\'' {
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                        tok.push('\'');
+                    } else {
+                        quoting = false;
+                    }
+                } else {
+                    tok.push(c);
+                }
+            } else if c == '\'' {
+                quoting = true;
+            } else if " \t\r\n".contains(c) {
+                break;
+            } else {
+                tok.push(c);
+            }
+        }
+        toks.push(tok);
+    }
+}
+
+/// Quote a word if `tokenize` would otherwise split or mangle it (%q).
+pub fn quote(s: &str) -> String {
+    if !s.is_empty() && !s.chars().any(|c| c <= ' ' || c == '\'') {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Parse attributes from a line (_parseattr).
+pub fn parse_attrs(s: &str) -> Vec<Attr> {
+    tokenize(s)
+        .into_iter()
+        .map(|t| {
+            if let Some((name, val)) = t.split_once('=') {
+                match name.strip_suffix(':') {
+                    Some(name) => Attr {
+                        attr_type: AttrType::Default,
+                        name: name.to_string(),
+                        val: val.to_string(),
+                    },
+                    None => Attr::new(name, val),
+                }
+            } else if let Some(name) = t.strip_suffix('?') {
+                Attr {
+                    attr_type: AttrType::Query,
+                    name: name.to_string(),
+                    val: String::new(),
+                }
+            } else {
+                // Really a syntax error, but this is how flags are written
+                Attr::new(&t, "")
+            }
+        })
+        .collect()
+}
+
+/// Format attributes back into a line (_attrfmt).
+pub fn format_attrs(attrs: &[Attr]) -> String {
+    attrs
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn find_attr<'a>(attrs: &'a [Attr], name: &str) -> Option<&'a Attr> {
+    attrs
+        .iter()
+        .find(|a| a.attr_type == AttrType::Nameval && a.name == name)
+}
+
+// ============================================================================
+// Keys
+// ============================================================================
+
+/// A factotum key: public attributes, then the private ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub attrs: Vec<Attr>,
+    pub privattrs: Vec<Attr>,
+}
+
+impl Key {
+    /// Parse a key line, with or without the leading `key` verb.
+    pub fn parse(line: &str) -> Result<Key, KeyringError> {
+        let mut attrs = parse_attrs(line);
+        if attrs
+            .first()
+            .is_some_and(|a| a.name == "key" && a.val.is_empty())
+        {
+            attrs.remove(0);
+        }
+        if let Some(a) = attrs.iter().find(|a| a.attr_type != AttrType::Nameval) {
+            return Err(KeyringError(format!("bad attribute in key: {}", a)));
+        }
+        if find_attr(&attrs, "proto").is_none() {
+            return Err(KeyringError("key without proto".to_string()));
+        }
+        let (privattrs, attrs) = attrs.into_iter().partition(|a| a.is_private());
+        Ok(Key { attrs, privattrs })
+    }
+
+    /// Look up an attribute value, public or private.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        find_attr(&self.attrs, name)
+            .or_else(|| find_attr(&self.privattrs, name))
+            .map(|a| a.val.as_str())
+    }
+
+    /// Set an attribute, replacing any existing value.
+    pub fn set(&mut self, name: &str, val: &str) {
+        let list = if name.starts_with('!') {
+            &mut self.privattrs
+        } else {
+            &mut self.attrs
+        };
+        match list.iter_mut().find(|a| a.name == name) {
+            Some(a) => a.val = val.to_string(),
+            None => list.push(Attr::new(name, val)),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.attrs.retain(|a| a.name != name);
+        self.privattrs.retain(|a| a.name != name);
+    }
+
+    pub fn proto(&self) -> &str {
+        self.get("proto").unwrap_or("")
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.get("user")
+    }
+
+    pub fn password(&self) -> Option<&str> {
+        self.get("!password")
+    }
+
+    /// `confirm`: ask the owner before each use.
+    pub fn needs_confirm(&self) -> bool {
+        self.get("confirm").is_some()
+    }
+
+    /// `disabled`: kept, but not offered by `Keyring::find`.
+    pub fn is_disabled(&self) -> bool {
+        self.get("disabled").is_some()
+    }
+
+    /// Does the key match an attribute pattern (matchattr)?
+    pub fn matches(&self, pattern: &[Attr]) -> bool {
+        pattern.iter().all(|p| {
+            let has_name = || {
+                self.attrs
+                    .iter()
+                    .chain(self.privattrs.iter())
+                    .any(|a| a.name == p.name)
+            };
+            let has_val = || self.get(&p.name) == Some(p.val.as_str());
+            // role is about the caller, not the key
+            if p.name == "role" {
+                return true;
+            }
+            match p.attr_type {
+                AttrType::Query => has_name(),
+                AttrType::Nameval => has_val(),
+                AttrType::Default => !has_name() || has_val(),
+            }
+        })
+    }
+
+    /// The full key line, secrets included, as stored in secstore.
+    pub fn to_line(&self) -> String {
+        let mut all = self.attrs.clone();
+        all.extend(self.privattrs.iter().cloned());
+        format!("key {}", format_attrs(&all))
+    }
+}
+
+/// Lists the key as factotum's ctl file does: secrets become `!name?`.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {}", format_attrs(&self.attrs))?;
+        for a in &self.privattrs {
+            write!(f, " {}?", quote(&a.name))?;
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Keyring
+// ============================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: Vec<Key>,
+    /// Public attributes of the `confirm` key the owner let through
+    approved: Option<Vec<Attr>>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load keys from factotum file text: one key per line, ignoring blank
+    /// lines and `#` comments.
+    pub fn parse(text: &str) -> Result<Keyring, KeyringError> {
+        let mut ring = Keyring::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key =
+                Key::parse(line).map_err(|e| KeyringError(format!("line {}: {}", i + 1, e)))?;
+            ring.add(key);
+        }
+        Ok(ring)
+    }
+
+    /// Serialize the whole ring, secrets included.
+    pub fn to_text(&self) -> String {
+        self.keys.iter().map(|k| k.to_line() + "\n").collect()
+    }
+
+    /// Add a key; one with the same public attributes is replaced.
+    pub fn add(&mut self, key: Key) {
+        match self.keys.iter_mut().find(|k| k.attrs == key.attrs) {
+            Some(k) => *k = key,
+            None => self.keys.push(key),
+        }
+    }
+
+    /// Remove every key matching `pattern` (delkey); returns how many.
+    pub fn delete(&mut self, pattern: &str) -> usize {
+        let pat = parse_attrs(pattern);
+        let before = self.keys.len();
+        self.keys.retain(|k| !k.matches(&pat));
+        before - self.keys.len()
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Every key matching `pattern`, disabled or not.
+    pub fn find_all(&self, pattern: &str) -> Vec<&Key> {
+        let pat = parse_attrs(pattern);
+        self.keys.iter().filter(|k| k.matches(&pat)).collect()
+    }
+
+    /// The first usable key matching `pattern` (findkey): not disabled,
+    /// and not a `confirm` key the owner has yet to approve.
+    pub fn find(&self, pattern: &str) -> Result<&Key, KeyringError> {
+        let pat = parse_attrs(pattern);
+        self.keys
+            .iter()
+            .find(|k| {
+                !k.is_disabled() && k.matches(&pat) && (!k.needs_confirm() || self.is_approved(k))
+            })
+            .ok_or_else(|| KeyringError(format!("needkey {}", pattern)))
+    }
+
+    /// Let the `confirm` key with these public attributes be found until
+    /// `clear_approval`.
+    pub fn approve(&mut self, attrs: &[Attr]) {
+        self.approved = Some(attrs.to_vec());
+    }
+
+    pub fn clear_approval(&mut self) {
+        self.approved = None;
+    }
+
+    pub fn is_approved(&self, key: &Key) -> bool {
+        self.approved.as_ref() == Some(&key.attrs)
+    }
+
+    /// A protocol client's key: `proto` must match, and `dom` and `user`
+    /// narrow the search when given.
+    pub fn lookup(
+        &self,
+        proto: &str,
+        dom: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<&Key, KeyringError> {
+        let mut pat = vec![Attr::new("proto", proto)];
+        if let Some(dom) = dom {
+            pat.push(Attr::new("dom", dom));
+        }
+        match user {
+            Some(user) => pat.push(Attr::new("user", user)),
+            None => pat.push(Attr {
+                attr_type: AttrType::Query,
+                name: "user".to_string(),
+                val: String::new(),
+            }),
+        }
+        pat.push(Attr {
+            attr_type: AttrType::Query,
+            name: "!password".to_string(),
+            val: String::new(),
+        });
+        self.find(&format_attrs(&pat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_quotes() {
+        assert_eq!(tokenize("  a b\tc \n"), vec!["a", "b", "c"]);
+        assert_eq!(tokenize("x='it''s here' y"), vec!["x=it's here", "y"]);
+        assert_eq!(tokenize("a'b c'd ''"), vec!["ab cd", ""]);
+
+        for s in ["plain", "two words", "it's", "", "tab\there"] {
+            assert_eq!(tokenize(&quote(s)), vec![s.to_string()]);
+        }
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn test_parse_attr_types() {
+        let attrs = parse_attrs("proto=p9sk1 user? role:=client confirm");
+        assert_eq!(attrs[0], Attr::new("proto", "p9sk1"));
+        assert_eq!(attrs[1].attr_type, AttrType::Query);
+        assert_eq!(attrs[2].attr_type, AttrType::Default);
+        assert_eq!(attrs[2].val, "client");
+        assert_eq!(attrs[3], Attr::new("confirm", ""));
+        assert_eq!(
+            format_attrs(&attrs),
+            "proto=p9sk1 user? role:=client confirm"
+        );
+    }
+
+    #[test]
+    fn test_key_parse_and_hide() {
+        let key = Key::parse("key proto=dp9ik dom=nawin user=glenda !password='my secret' confirm")
+            .unwrap();
+        assert_eq!(key.proto(), "dp9ik");
+        assert_eq!(key.user(), Some("glenda"));
+        assert_eq!(key.password(), Some("my secret"));
+        assert!(key.needs_confirm());
+        assert!(!key.is_disabled());
+
+        assert_eq!(
+            key.to_string(),
+            "key proto=dp9ik dom=nawin user=glenda confirm !password?"
+        );
+        assert_eq!(
+            key.to_line(),
+            "key proto=dp9ik dom=nawin user=glenda confirm !password='my secret'"
+        );
+        assert_eq!(Key::parse(&key.to_line()).unwrap(), key);
+
+        assert!(Key::parse("user=glenda").is_err());
+        assert!(Key::parse("proto=pass user?").is_err());
+    }
+
+    #[test]
+    fn test_keymatch() {
+        let key = Key::parse("proto=p9sk1 dom=nawin user=glenda !password=x").unwrap();
+        let m = |p: &str| key.matches(&parse_attrs(p));
+        assert!(m("proto=p9sk1"));
+        assert!(m("proto=p9sk1 dom=nawin user?"));
+        assert!(m("!password?"));
+        assert!(m("proto=p9sk1 role=client"));
+        assert!(!m("proto=dp9ik"));
+        assert!(!m("service?"));
+
+        // name:=val only constrains keys that have the attribute
+        assert!(m("service:=cpu"));
+        assert!(m("dom:=nawin"));
+        assert!(!m("dom:=other"));
+    }
+
+    #[test]
+    fn test_keyring_find() {
+        let ring = Keyring::parse(
+            "# my keys\n\
+             key proto=p9sk1 dom=old user=glenda !password=a disabled\n\
+             key proto=p9sk1 dom=nawin user=glenda !password=b\n\
+             \n\
+             key proto=dp9ik dom=nawin user=glenda !password=c\n\
+             key proto=pass service=ssh server=tenshi user=glenda !password=d\n",
+        )
+        .unwrap();
+        assert_eq!(ring.keys().len(), 4);
+
+        assert_eq!(ring.find("proto=p9sk1").unwrap().password(), Some("b"));
+        assert_eq!(ring.find_all("proto=p9sk1").len(), 2);
+        assert_eq!(
+            ring.lookup("dp9ik", Some("nawin"), None)
+                .unwrap()
+                .password(),
+            Some("c")
+        );
+        assert_eq!(
+            ring.lookup("pass", None, Some("glenda"))
+                .unwrap()
+                .get("server"),
+            Some("tenshi")
+        );
+        assert!(ring.lookup("p9sk1", Some("old"), None).is_err());
+        assert!(ring.lookup("dp9ik", Some("nawin"), Some("bootes")).is_err());
+    }
+
+    #[test]
+    fn test_confirm_needs_approval() {
+        let mut ring =
+            Keyring::parse("key proto=totp label=vpn confirm !secret=JBSWY3DPEHPK3PXP\n").unwrap();
+        assert!(ring.find("proto=totp").is_err());
+        let attrs = ring.keys()[0].attrs.clone();
+        ring.approve(&attrs);
+        assert!(ring.find("proto=totp").is_ok());
+        ring.clear_approval();
+        assert!(ring.find("proto=totp").is_err());
+        assert_eq!(ring.find_all("proto=totp").len(), 1);
+    }
+
+    #[test]
+    fn test_keyring_add_delete_roundtrip() {
+        let mut ring = Keyring::new();
+        ring.add(Key::parse("proto=dp9ik dom=nawin user=glenda !password=old").unwrap());
+        ring.add(Key::parse("proto=dp9ik dom=nawin user=glenda !password=new").unwrap());
+        ring.add(Key::parse("proto=vnc server=tenshi !password='a b'").unwrap());
+        assert_eq!(ring.keys().len(), 2);
+        assert_eq!(ring.find("proto=dp9ik").unwrap().password(), Some("new"));
+
+        let again = Keyring::parse(&ring.to_text()).unwrap();
+        assert_eq!(again.keys(), ring.keys());
+
+        assert_eq!(ring.delete("proto=vnc"), 1);
+        assert_eq!(ring.delete("proto=vnc"), 0);
+        assert_eq!(ring.keys().len(), 1);
+    }
+}
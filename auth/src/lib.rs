@@ -3,10 +3,73 @@
 //! This crate implements the client-side authentication protocols used by Plan 9:
 //! - p9sk1: Classic Plan 9 auth using non-standard DES
 //! - dp9ik: Modern 9front auth using SPAKE2-EE on Ed448 + ChaCha20-Poly1305
+//!
+//! and around them:
+//! - aan: Sessions that outlive a dropped WebSocket
+//! - afid: 9P attach authentication through an auth fid
+//! - authsrv: Auth server wire format
+//! - chal: apop, cram, chap, mschap and p9cr challenge/response
+//! - conn: Authentication driven over an async transport
+//! - devssl: The RC4 record layer of `cpu -e`
+//! - form1: dp9ik tickets and authenticators
+//! - getticket: Tickets fetched from the auth server directly
+//! - keycache: Derived keys kept for reconnects
+//! - keyfs: The auth server's /adm/keys
+//! - keyring: Factotum keys and an in-memory keyring
+//! - netkey: The SecureNet calculator
+//! - nvram: The host owner's credentials
+//! - p9any: Negotiation of the ticket protocol
+//! - rcpu: Dialing a 9front cpu server's rcpu port
+//! - relay: Auth server conversations beside 9P on the WebSocket
+//! - rpc: Factotum's rpc interface over p9any, p9sk1 and dp9ik
+//! - rsa: Factotum RSA keys and PKCS#1 v1.5 signing
+//! - sealed: The keyring encrypted under a PIN
+//! - secstore: Fetching the factotum key file from secstore
+//! - session: Several authenticated connections in one page
+//! - tlspsk: TLS keyed by the session secret
+//! - totp: Time-based one-time passwords
+//! - transcript: Redacted transcripts to replay a failed login
+//! - vnc: VNC challenge/response
+//! - wasm: Exports for the browser
 
+pub mod aan;
+pub mod afid;
 pub mod authpak;
+pub mod authsrv;
+pub mod chal;
+pub mod conn;
 pub mod des9;
+pub mod devssl;
+pub mod form1;
+pub mod getticket;
+pub mod keycache;
+pub mod keyfs;
+pub mod keyring;
+pub mod netkey;
+pub mod nvram;
+pub mod p9any;
 pub mod p9sk1;
+pub mod rcpu;
+pub mod relay;
+pub mod rpc;
+pub mod rsa;
+pub mod sealed;
+pub mod secstore;
+pub mod session;
+pub mod tlspsk;
+pub mod totp;
+pub mod transcript;
+pub mod vnc;
+pub mod wasm;
+
+#[cfg(test)]
+mod test9p;
+#[cfg(test)]
+mod testaan;
+#[cfg(test)]
+mod testsecstore;
+#[cfg(test)]
+mod testsrv;
 
 // Re-export main types for p9sk1
 pub use p9sk1::{pass_to_key, P9sk1Client};
@@ -15,3 +78,9 @@ pub use p9sk1::{pass_to_key, P9sk1Client};
 pub use authpak::{
     authpak_finish, authpak_hash, authpak_new, PakError, PakPriv, PAKHASHLEN, PAKKEYLEN, PAKYLEN,
 };
+
+// Re-export auth server types
+pub use authsrv::{AuthError, Authkey, Decoded, Ticketreq};
+pub use des9::Des9Key;
+pub use keyring::{Key, Keyring, KeyringError};
+pub use rpc::{AuthInfo, Factotum, KeyRequest, RpcReply};
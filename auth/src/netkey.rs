@@ -0,0 +1,112 @@
+//! netkey - SecureNet challenge/response
+//!
+//! The authsrv's AuthChal challenge (p9cr) is a decimal number. The answer
+//! is that number DES-encrypted under the user's `pass_to_key` key, with the
+//! first four bytes printed as 8 hex digits. SecureNet boxes that can only
+//! show digits display the decimal form instead, where a-c read as 2 and
+//! d-f as 3, like letters on a telephone keypad. The authsrv accepts both.
+//!
+//! Ported from 9front's libauthsrv netcrypt() and authsrv.c (netresp,
+//! netdecimal, netcheck).
+
+use crate::authsrv::{Authkey, DESKEYLEN};
+use crate::des9;
+
+/// Challenges are below this bound, so they fit in 7 digits.
+pub const MAXNETCHAL: u32 = 100_000_000;
+
+/// Hex response to a challenge string (netcrypt).
+///
+/// At most 7 characters of the challenge are used, and it ends at the
+/// first newline, so a line typed at a prompt can be passed straight in.
+pub fn netcrypt(key: &[u8; DESKEYLEN], chal: &str) -> String {
+    let chal = chal.split('\n').next().unwrap_or("");
+    let mut buf = [0u8; 8];
+    let n = chal.len().min(7);
+    buf[..n].copy_from_slice(&chal.as_bytes()[..n]);
+
+    des9::plan9_encrypt(key, &mut buf);
+    format!("{:02x}{:02x}{:02x}{:02x}", buf[0], buf[1], buf[2], buf[3])
+}
+
+/// Hex response to a numeric challenge (netresp).
+pub fn netresp(key: &[u8; DESKEYLEN], chal: u32) -> String {
+    netcrypt(key, &chal.to_string())
+}
+
+/// Map a hex response to SecureNet's decimal display (netdecimal).
+pub fn netdecimal(resp: &str) -> String {
+    resp.chars()
+        .map(|c| match c {
+            'a' | 'b' | 'c' => '2',
+            'd' | 'e' | 'f' => '3',
+            c => c,
+        })
+        .collect()
+}
+
+/// Check a response in either hex or decimal form (netcheck).
+pub fn netcheck(key: &[u8; DESKEYLEN], chal: &str, response: &str) -> bool {
+    let response = response.split('\n').next().unwrap_or("");
+    let answer = netcrypt(key, chal);
+    answer == response || netdecimal(&answer) == response
+}
+
+/// Answer a netkey prompt with the DES half of an `Authkey`.
+pub fn netkey_response(chal: &str, key: &Authkey) -> String {
+    netcrypt(&key.des, chal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p9sk1::pass_to_key;
+
+    #[test]
+    fn test_netcrypt_format() {
+        let key = pass_to_key("glenda");
+        let resp = netcrypt(&key, "12345678");
+        assert_eq!(resp.len(), 8);
+        assert!(resp
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+
+        // Only the first 7 characters count, and a trailing newline is ignored
+        assert_eq!(resp, netcrypt(&key, "1234567"));
+        assert_eq!(resp, netcrypt(&key, "1234567\n"));
+        assert_eq!(netresp(&key, 1234567), resp);
+    }
+
+    #[test]
+    fn test_netcrypt_matches_des() {
+        // The challenge is NUL padded to one 8-byte block
+        let key = pass_to_key("glenda");
+        let mut block = *b"42\0\0\0\0\0\0";
+        des9::plan9_encrypt(&key, &mut block);
+        assert_eq!(netcrypt(&key, "42"), hex::encode(&block[..4]));
+    }
+
+    #[test]
+    fn test_netdecimal() {
+        assert_eq!(netdecimal("0123456789abcdef"), "0123456789222333");
+    }
+
+    #[test]
+    fn test_netcheck_both_forms() {
+        let key = pass_to_key("secret");
+        let hex = netcrypt(&key, "9087123");
+        assert!(netcheck(&key, "9087123", &hex));
+        assert!(netcheck(&key, "9087123", &netdecimal(&hex)));
+        assert!(netcheck(&key, "9087123", &format!("{}\n", hex)));
+        assert!(!netcheck(&key, "9087124", &hex));
+    }
+
+    #[test]
+    fn test_netkey_response_uses_des_key() {
+        let key = Authkey::from_password("secret");
+        assert_eq!(
+            netkey_response("555", &key),
+            netcrypt(&pass_to_key("secret"), "555")
+        );
+    }
+}
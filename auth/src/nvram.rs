@@ -0,0 +1,228 @@
+//! nvram - the host owner's credentials (Nvrsafe)
+//!
+//! A cpu or auth server boots without asking for a password by reading its
+//! machine key, authid and authdom from nvram. Each field is followed by a
+//! one-byte checksum:
+//!
+//! ```text
+//! machkey[7] sum authkey[7] sum config[64] sum authid[28] sum
+//! authdom[48] sum aesmachkey[16] sum
+//! ```
+//!
+//! `config` is the file server's configuration device, or the secstore
+//! password elsewhere. Older nvram has no AES key, which shows as a bad
+//! checksum on that field only. Where the structure sits depends on the
+//! device (readnvram's nvtab); here it starts at offset 0.
+//!
+//! Ported from 9front's libauthsrv readnvram.c and nvcsum.c.
+
+use crate::authpak::{pass_to_aes_key, AESKEYLEN};
+use crate::authsrv::{Authkey, DESKEYLEN};
+use crate::p9sk1::{pass_to_key, read_fixed_string, write_fixed_string, ANAMELEN, DOMLEN};
+
+pub const CONFIGLEN: usize = 64;
+pub const NVRSAFELEN: usize =
+    DESKEYLEN + 1 + DESKEYLEN + 1 + CONFIGLEN + 1 + ANAMELEN + 1 + DOMLEN + 1 + AESKEYLEN + 1; // 176
+
+/// Error type for nvram parsing
+#[derive(Debug)]
+pub struct NvramError(pub String);
+
+impl std::fmt::Display for NvramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NvramError {}
+
+/// Checksum of an nvram field: 9 plus the byte sum.
+pub fn nvcsum(mem: &[u8]) -> u8 {
+    mem.iter().fold(9u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// The decoded Nvrsafe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nvrsafe {
+    /// The authid's DES key
+    pub machkey: [u8; DESKEYLEN],
+    /// Kept in step with `machkey` by readnvram
+    pub authkey: [u8; DESKEYLEN],
+    pub config: String,
+    pub authid: String,
+    pub authdom: String,
+    /// The authid's AES key; None in nvram written before dp9ik
+    pub aesmachkey: Option<[u8; AESKEYLEN]>,
+}
+
+impl Nvrsafe {
+    /// What readnvram writes after prompting for the host owner's password.
+    pub fn new(authid: &str, authdom: &str, password: &str) -> Self {
+        let machkey = pass_to_key(password);
+        Nvrsafe {
+            machkey,
+            authkey: machkey,
+            config: String::new(),
+            authid: authid.to_string(),
+            authdom: authdom.to_string(),
+            aesmachkey: Some(pass_to_aes_key(password)),
+        }
+    }
+
+    /// The machine key as an `Authkey`, ready for keyfs or tickets.
+    pub fn machine_key(&self) -> Authkey {
+        Authkey {
+            des: self.machkey,
+            aes: self.aesmachkey.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; NVRSAFELEN] {
+        let mut buf = [0u8; NVRSAFELEN];
+        let mut off = 0;
+        put(&mut buf, &mut off, &self.machkey);
+        put(&mut buf, &mut off, &self.authkey);
+        put_string(&mut buf, &mut off, &self.config, CONFIGLEN);
+        put_string(&mut buf, &mut off, &self.authid, ANAMELEN);
+        put_string(&mut buf, &mut off, &self.authdom, DOMLEN);
+        // Without an AES key, leave the old layout's zeros, checksum included
+        if let Some(k) = &self.aesmachkey {
+            put(&mut buf, &mut off, k);
+        }
+        buf
+    }
+
+    /// Parse and check an Nvrsafe. A bad machine key, authid or authdom
+    /// is an error, as it makes readnvram prompt; a bad config reads as
+    /// empty and a bad AES key as absent.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, NvramError> {
+        if buf.len() < NVRSAFELEN {
+            return Err(NvramError(format!(
+                "nvram too short: {} < {}",
+                buf.len(),
+                NVRSAFELEN
+            )));
+        }
+        let mut off = 0;
+        let mut field = |len: usize| {
+            let f = &buf[off..off + len];
+            let ok = nvcsum(f) == buf[off + len];
+            off += len + 1;
+            (f, ok)
+        };
+        let machkey = required(field(DESKEYLEN), "key")?.try_into().unwrap();
+        let (authkey, _) = field(DESKEYLEN);
+        let (config, config_ok) = field(CONFIGLEN);
+        let authid = read_fixed_string(required(field(ANAMELEN), "authid")?);
+        let authdom = read_fixed_string(required(field(DOMLEN), "authdom")?);
+        let (aes, aes_ok) = field(AESKEYLEN);
+
+        Ok(Nvrsafe {
+            machkey,
+            authkey: authkey.try_into().unwrap(),
+            config: if config_ok {
+                read_fixed_string(config)
+            } else {
+                String::new()
+            },
+            authid,
+            authdom,
+            aesmachkey: if aes_ok && aes.iter().any(|&b| b != 0) {
+                Some(aes.try_into().unwrap())
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// A field whose bad checksum makes the whole Nvrsafe unusable.
+fn required<'a>((f, ok): (&'a [u8], bool), name: &str) -> Result<&'a [u8], NvramError> {
+    if ok {
+        Ok(f)
+    } else {
+        Err(NvramError(format!("bad nvram {} checksum", name)))
+    }
+}
+
+/// Store a field and its checksum.
+fn put(buf: &mut [u8], off: &mut usize, data: &[u8]) {
+    buf[*off..*off + data.len()].copy_from_slice(data);
+    buf[*off + data.len()] = nvcsum(data);
+    *off += data.len() + 1;
+}
+
+fn put_string(buf: &mut [u8], off: &mut usize, s: &str, len: usize) {
+    let mut field = vec![0u8; len];
+    write_fixed_string(&mut field, s);
+    put(buf, off, &field);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nvcsum() {
+        assert_eq!(NVRSAFELEN, 176);
+        assert_eq!(nvcsum(b""), 9);
+        assert_eq!(nvcsum(&[0u8; 16]), 9);
+        assert_eq!(nvcsum(&[0xff, 0xff]), 7);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut nv = Nvrsafe::new("bootes", "nawin", "cpusecret");
+        nv.config = "secstorepass".to_string();
+        let buf = nv.to_bytes();
+        assert_eq!(&buf[..DESKEYLEN], &pass_to_key("cpusecret"));
+        assert_eq!(buf[DESKEYLEN], nvcsum(&buf[..DESKEYLEN]));
+        let off = 2 * (DESKEYLEN + 1) + CONFIGLEN + 1;
+        assert_eq!(&buf[off..off + 7], b"bootes\0");
+        assert_eq!(Nvrsafe::from_bytes(&buf).unwrap(), nv);
+    }
+
+    #[test]
+    fn test_machine_key() {
+        let nv = Nvrsafe::new("bootes", "nawin", "cpusecret");
+        let key = nv.machine_key();
+        let want = Authkey::from_password("cpusecret");
+        assert_eq!(key.des, want.des);
+        assert_eq!(key.aes, want.aes);
+    }
+
+    #[test]
+    fn test_old_nvram_without_aes() {
+        let mut nv = Nvrsafe::new("bootes", "nawin", "cpusecret");
+        nv.aesmachkey = None;
+        let buf = nv.to_bytes();
+        assert_eq!(&buf[NVRSAFELEN - AESKEYLEN - 1..], &[0u8; AESKEYLEN + 1]);
+        assert_eq!(Nvrsafe::from_bytes(&buf).unwrap().aesmachkey, None);
+    }
+
+    #[test]
+    fn test_bad_checksums() {
+        let nv = Nvrsafe::new("bootes", "nawin", "cpusecret");
+        let mut buf = nv.to_bytes();
+        buf[DESKEYLEN + 1 + DESKEYLEN + 1] ^= 1; // config
+        assert_eq!(Nvrsafe::from_bytes(&buf).unwrap().config, "");
+        buf[0] ^= 1; // machkey
+        assert!(Nvrsafe::from_bytes(&buf).is_err());
+        assert!(Nvrsafe::from_bytes(&buf[..100]).is_err());
+    }
+
+    #[test]
+    fn test_boot_authsrv() {
+        use crate::keyfs::{write_keys, KeyfsUser};
+        use crate::testsrv::TestAuthsrv;
+
+        let nv =
+            Nvrsafe::from_bytes(&Nvrsafe::new("bootes", "nawin", "cpusecret").to_bytes()).unwrap();
+        let users = [KeyfsUser::new("glenda", "kittens")];
+        let keys = write_keys(&users, &Authkey::from_password("cpusecret"), true);
+        let srv = TestAuthsrv::boot(&nv, &keys).unwrap();
+        assert_eq!(srv.authdom, "nawin");
+        assert_eq!(srv.key("glenda").aes, users[0].key.aes);
+    }
+}
@@ -0,0 +1,275 @@
+//! p9any - negotiate which ticket protocol to run
+//!
+//! The server opens with its offer, `v.2 dp9ik@dom p9sk1@dom`, each entry a
+//! protocol and the authentication domain it would use. The client answers
+//! `proto dom` and, for v.2 offers, waits for `OK` before the chosen
+//! protocol's own conversation starts. All three messages are
+//! NUL-terminated strings.
+//!
+//! Servers may offer several domains. We take one we hold a key for,
+//! preferring dp9ik to p9sk1, and p9sk1 can be refused outright: its DES
+//! tickets fall to brute force.
+//!
+//! Ported from 9front's factotum p9any.c.
+
+use crate::keyring::{Attr, AttrType, Keyring};
+use crate::p9sk1::{TicketClient, TicketProto};
+use crate::rpc::{self, key_query, AuthInfo, Proto, RpcReply, AUTHRPCMAX};
+
+enum State {
+    NeedOffer,
+    HaveChoice { v2: bool },
+    NeedOk,
+    Sub(Box<TicketClient>),
+}
+
+/// Client side of p9any, handing over to p9sk1 or dp9ik once agreed.
+pub struct P9anyClient {
+    start: Vec<Attr>,
+    state: State,
+    choice: Option<(TicketProto, String)>,
+    refuse_p9sk1: bool,
+}
+
+impl P9anyClient {
+    /// `start` holds the rpc start attributes; a `dom=` among them limits
+    /// which offers are considered. With `refuse_p9sk1` only dp9ik is
+    /// accepted.
+    pub fn new(start: Vec<Attr>, refuse_p9sk1: bool) -> Self {
+        P9anyClient {
+            start,
+            state: State::NeedOffer,
+            choice: None,
+            refuse_p9sk1,
+        }
+    }
+
+    fn start_dom(&self) -> Option<&str> {
+        self.start
+            .iter()
+            .find(|a| a.attr_type == AttrType::Nameval && a.name == "dom")
+            .map(|a| a.val.as_str())
+    }
+
+    /// Pick from the offer. Entries whose domain we hold a key for come
+    /// first, then dp9ik before p9sk1, then the server's order. With no
+    /// key at all the pick still stands and the sub-protocol asks for one.
+    fn choose(&self, ring: &Keyring, offers: &[&str]) -> Result<(TicketProto, String), String> {
+        let mut usable: Vec<(bool, TicketProto, &str)> = offers
+            .iter()
+            .filter_map(|o| {
+                let (name, dom) = o.split_once('@')?;
+                let proto = TicketProto::from_name(name)?;
+                match self.start_dom() {
+                    Some(want) if want != dom => None,
+                    _ => Some((proto, dom)),
+                }
+            })
+            .map(|(proto, dom)| {
+                let query = key_query(proto.name(), Some(dom), &self.start);
+                (rpc::has_key(ring, &query), proto, dom)
+            })
+            .collect();
+        let offered = !usable.is_empty();
+        if self.refuse_p9sk1 {
+            usable.retain(|&(_, proto, _)| proto != TicketProto::P9sk1);
+        }
+        usable.sort_by_key(|&(keyed, proto, _)| (!keyed, proto != TicketProto::Dp9ik));
+        match usable.first() {
+            Some(&(_, proto, dom)) => Ok((proto, dom.to_string())),
+            None if offered => Err("p9sk1 refused by policy".to_string()),
+            None => Err("no supported protocol offered".to_string()),
+        }
+    }
+
+    fn write_offer(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply {
+        let msg = match nul_string(data) {
+            Ok(s) => s,
+            Err(reply) => return reply,
+        };
+        let mut words: Vec<&str> = msg.split_whitespace().collect();
+        let v2 = words.first() == Some(&"v.2");
+        if v2 {
+            words.remove(0);
+        }
+        match self.choose(ring, &words) {
+            Ok(choice) => {
+                self.choice = Some(choice);
+                self.state = State::HaveChoice { v2 };
+                RpcReply::Ok(Vec::new())
+            }
+            Err(e) => RpcReply::Error(format!("{} in {:?}", e, msg)),
+        }
+    }
+
+    fn start_sub(&mut self) {
+        let (proto, dom) = self.choice.clone().expect("no protocol chosen");
+        self.state = State::Sub(Box::new(TicketClient::new(
+            proto,
+            Some(&dom),
+            self.start.clone(),
+        )));
+    }
+}
+
+/// The NUL-terminated string at the start of `data`, or toosmall for one
+/// more byte.
+fn nul_string(data: &[u8]) -> Result<String, RpcReply> {
+    match data.iter().position(|&b| b == 0) {
+        Some(i) => Ok(String::from_utf8_lossy(&data[..i]).to_string()),
+        None if data.len() >= AUTHRPCMAX => {
+            Err(RpcReply::Error("p9any message too long".to_string()))
+        }
+        None => Err(RpcReply::Toosmall(data.len() + 1)),
+    }
+}
+
+impl Proto for P9anyClient {
+    fn read(&mut self, ring: &Keyring) -> RpcReply {
+        match &mut self.state {
+            State::HaveChoice { v2 } => {
+                let v2 = *v2;
+                let (proto, dom) = self.choice.as_ref().unwrap();
+                let mut reply = format!("{} {}", proto.name(), dom).into_bytes();
+                reply.push(0);
+                if v2 {
+                    self.state = State::NeedOk;
+                } else {
+                    self.start_sub();
+                }
+                RpcReply::Ok(reply)
+            }
+            State::Sub(sub) => sub.read(ring),
+            _ => rpc::phase_error("write expected"),
+        }
+    }
+
+    fn write(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply {
+        match &mut self.state {
+            State::NeedOffer => self.write_offer(ring, data),
+            State::NeedOk => {
+                if data.len() < 3 {
+                    return RpcReply::Toosmall(3);
+                }
+                if &data[..3] != b"OK\0" {
+                    return RpcReply::Error("server did not accept p9any choice".to_string());
+                }
+                self.start_sub();
+                RpcReply::Ok(Vec::new())
+            }
+            State::Sub(sub) => sub.write(ring, data),
+            State::HaveChoice { .. } => rpc::phase_error("read expected"),
+        }
+    }
+
+    fn authinfo(&self) -> Option<&AuthInfo> {
+        match &self.state {
+            State::Sub(sub) => sub.authinfo(),
+            _ => None,
+        }
+    }
+
+    fn attrs(&self) -> Vec<Attr> {
+        match &self.state {
+            State::Sub(sub) => sub.attrs(),
+            _ => vec![Attr::new("proto", "p9any"), Attr::new("role", "client")],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring() -> Keyring {
+        Keyring::parse("key proto=dp9ik dom=plan9 user=glenda !password=kittens\n").unwrap()
+    }
+
+    #[test]
+    fn test_offer_needs_nul() {
+        let mut c = P9anyClient::new(vec![], false);
+        assert_eq!(c.write(&ring(), b""), RpcReply::Toosmall(1));
+        assert_eq!(c.write(&ring(), b"v.2 dp9ik@plan9"), RpcReply::Toosmall(16));
+        assert_eq!(c.write(&ring(), &[b'x'; AUTHRPCMAX]).verb(), "error");
+    }
+
+    #[test]
+    fn test_choose_keyed_domain() {
+        let mut c = P9anyClient::new(vec![], false);
+        let offer = b"v.2 p9sk1@other dp9ik@other dp9ik@plan9\0";
+        assert_eq!(c.write(&ring(), offer), RpcReply::Ok(vec![]));
+        assert_eq!(c.read(&ring()), RpcReply::Ok(b"dp9ik plan9\0".to_vec()));
+        assert_eq!(c.read(&ring()).verb(), "phase");
+        assert_eq!(c.write(&ring(), b"OK"), RpcReply::Toosmall(3));
+        assert_eq!(c.write(&ring(), b"OK\0"), RpcReply::Ok(vec![]));
+        assert_eq!(
+            crate::keyring::format_attrs(&c.attrs()),
+            "proto=dp9ik dom=plan9 role=client"
+        );
+    }
+
+    #[test]
+    fn test_no_key_falls_back_to_first() {
+        let mut c = P9anyClient::new(vec![], false);
+        assert_eq!(
+            c.write(&ring(), b"p9sk1@other rsa@x\0"),
+            RpcReply::Ok(vec![])
+        );
+        // v.1 offers go straight into the sub-protocol
+        assert_eq!(c.read(&ring()), RpcReply::Ok(b"p9sk1 other\0".to_vec()));
+        match c.read(&ring()) {
+            RpcReply::Ok(chal) => assert_eq!(chal.len(), 8),
+            r => panic!("unexpected {}", r),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_offer() {
+        let mut c = P9anyClient::new(vec![], false);
+        assert_eq!(c.write(&ring(), b"v.2 rsa@plan9\0").verb(), "error");
+        let start = crate::keyring::parse_attrs("proto=p9any role=client dom=elsewhere");
+        let mut c = P9anyClient::new(start, false);
+        assert_eq!(c.write(&ring(), b"v.2 dp9ik@plan9\0").verb(), "error");
+    }
+
+    #[test]
+    fn test_prefer_dp9ik_and_keyed_domain() {
+        let ring = Keyring::parse(
+            "key proto=p9sk1 dom=nawin user=glenda !password=kittens\n\
+             key proto=dp9ik dom=lab user=glenda !password=kittens\n\
+             key proto=p9sk1 dom=lab user=glenda !password=kittens\n",
+        )
+        .unwrap();
+        let offer = ["dp9ik@nawin", "p9sk1@nawin", "p9sk1@lab", "dp9ik@lab"];
+        let c = P9anyClient::new(vec![], false);
+        assert_eq!(
+            c.choose(&ring, &offer),
+            Ok((TicketProto::Dp9ik, "lab".to_string()))
+        );
+        assert_eq!(
+            c.choose(&ring, &offer[..3]),
+            Ok((TicketProto::P9sk1, "nawin".to_string()))
+        );
+        // No keys: dp9ik still wins, in the server's order
+        assert_eq!(
+            c.choose(&Keyring::new(), &["p9sk1@lab", "dp9ik@nawin", "dp9ik@lab"]),
+            Ok((TicketProto::Dp9ik, "nawin".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_refuse_p9sk1() {
+        let ring =
+            Keyring::parse("key proto=p9sk1 dom=nawin user=glenda !password=kittens").unwrap();
+        let c = P9anyClient::new(vec![], true);
+        assert_eq!(
+            c.choose(&ring, &["p9sk1@nawin", "dp9ik@nawin"]),
+            Ok((TicketProto::Dp9ik, "nawin".to_string()))
+        );
+        let mut c = P9anyClient::new(vec![], true);
+        match c.write(&ring, b"v.2 p9sk1@nawin\0") {
+            RpcReply::Error(e) => assert!(e.starts_with("p9sk1 refused by policy")),
+            r => panic!("unexpected {}", r),
+        }
+    }
+}
@@ -10,8 +10,22 @@
 //! - `pass_to_key`: Derive 7-byte DES key from password (passtokey.c)
 //! - `decrypt_ticket`: Decrypt a p9sk1 ticket
 //! - `make_authenticator`: Create an authenticator message
+//!
+//! `TicketClient` runs the whole client conversation, for p9sk1 and for
+//! dp9ik, which differs only in getting PAK-keyed form1 tickets.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
 
+use crate::authpak::{authpak_finish, authpak_new, PakPriv, PAKHASHLEN, PAKKEYLEN, PAKYLEN};
+use crate::authsrv::{parse_reply, Authkey, Decoded, Ticketreq, AUTH_PAK, TICKREQLEN};
 use crate::des9;
+use crate::form1::{
+    self, Form1Authenticator, Form1Ticket, FORM1AUTHENTLEN, FORM1TICKETLEN, NONCELEN,
+};
+use crate::keycache::{self, unix_time};
+use crate::keyring::{Attr, AttrType, Keyring, KeyringError};
+use crate::rpc::{self, find_key, key_query, AuthInfo, KeyRequest, Proto, RpcReply};
 
 // Protocol constants from authsrv.h
 pub const ANAMELEN: usize = 28;
@@ -31,7 +45,7 @@ pub const AUTH_AS: u8 = 66; // Server authenticator
 pub const AUTH_AC: u8 = 67; // Client authenticator
 
 /// Decrypted p9sk1 ticket contents
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ticket {
     pub ticket_type: u8,
     pub challenge: [u8; CHALLEN],
@@ -41,7 +55,7 @@ pub struct Ticket {
 }
 
 /// p9sk1 authenticator
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Authenticator {
     pub auth_type: u8,
     pub challenge: [u8; CHALLEN],
@@ -55,9 +69,7 @@ pub fn pass_to_key(password: &str) -> [u8; DESSION] {
     let mut key = [0u8; DESSION];
 
     // Pad password with spaces to 8 bytes minimum
-    for i in 0..8 {
-        buf[i] = b' ';
-    }
+    buf[..8].fill(b' ');
 
     // Copy password bytes
     let pw_bytes = password.as_bytes();
@@ -126,6 +138,23 @@ pub fn decrypt_ticket(encrypted: &[u8; TICKETLEN], key: &[u8; DESSION]) -> Ticke
     }
 }
 
+/// Encrypt a ticket under the given key (the auth server's side of
+/// `decrypt_ticket`).
+pub fn encrypt_ticket(ticket: &Ticket, key: &[u8; DESSION]) -> [u8; TICKETLEN] {
+    let mut data = [0u8; TICKETLEN];
+    data[0] = ticket.ticket_type;
+    data[1..1 + CHALLEN].copy_from_slice(&ticket.challenge);
+    write_fixed_string(&mut data[1 + CHALLEN..1 + CHALLEN + ANAMELEN], &ticket.cuid);
+    write_fixed_string(
+        &mut data[1 + CHALLEN + ANAMELEN..1 + CHALLEN + 2 * ANAMELEN],
+        &ticket.suid,
+    );
+    data[1 + CHALLEN + 2 * ANAMELEN..].copy_from_slice(&ticket.key);
+
+    des9::plan9_encrypt(key, &mut data);
+    data
+}
+
 /// Create an encrypted authenticator.
 /// The challenge should be the server's challenge with byte 0 incremented.
 pub fn make_authenticator(
@@ -191,13 +220,13 @@ pub fn make_ticket_request(
 }
 
 /// Helper to read a null-terminated string from a fixed-size buffer
-fn read_fixed_string(data: &[u8]) -> String {
+pub(crate) fn read_fixed_string(data: &[u8]) -> String {
     let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
     String::from_utf8_lossy(&data[..end]).to_string()
 }
 
 /// Helper to write a string to a fixed-size buffer (null-padded)
-fn write_fixed_string(dest: &mut [u8], s: &str) {
+pub(crate) fn write_fixed_string(dest: &mut [u8], s: &str) {
     dest.fill(0);
     let bytes = s.as_bytes();
     let len = bytes.len().min(dest.len() - 1);
@@ -221,6 +250,20 @@ impl P9sk1Client {
         }
     }
 
+    /// Take credentials from the first usable `proto=p9sk1` key, narrowed
+    /// by `dom` and `user` when given.
+    pub fn from_keyring(
+        ring: &Keyring,
+        dom: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<Self, KeyringError> {
+        let key = ring.lookup("p9sk1", dom, user)?;
+        match (key.user(), key.password()) {
+            (Some(user), Some(password)) => Ok(Self::new(user, password)),
+            _ => Err(KeyringError("p9sk1 key needs user and !password".to_string())),
+        }
+    }
+
     /// Get the DES key derived from password
     pub fn get_key(&self) -> &[u8; DESSION] {
         &self.key
@@ -247,6 +290,374 @@ impl P9sk1Client {
     }
 }
 
+// ============================================================================
+// Client state machine (factotum p9sk1.c)
+// ============================================================================
+
+/// Which ticket protocol a `TicketClient` speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TicketProto {
+    /// DES tickets straight from the authsrv
+    P9sk1,
+    /// AuthPAK first, then form1 tickets under the PAK key
+    Dp9ik,
+}
+
+impl TicketProto {
+    pub fn from_name(name: &str) -> Option<TicketProto> {
+        match name {
+            "p9sk1" => Some(TicketProto::P9sk1),
+            "dp9ik" => Some(TicketProto::Dp9ik),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TicketProto::P9sk1 => "p9sk1",
+            TicketProto::Dp9ik => "dp9ik",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    HaveChal,
+    NeedTreq,
+    HaveAsreq,
+    NeedTickets,
+    HaveTicket,
+    NeedAuth,
+    Established,
+}
+
+/// The session key found in the client's ticket
+#[derive(Clone)]
+enum SessionKey {
+    Des([u8; DESSION]),
+    Form1([u8; NONCELEN]),
+}
+
+/// Client side of p9sk1 and dp9ik, driven through `rpc::Proto`.
+///
+/// 1. read: our challenge for the server
+/// 2. write: the server's ticket request (and its PAK value for dp9ik);
+///    the key is chosen here, by the request's authdom
+/// 3. read: `authsrv`, the requests for the auth server
+/// 4. write: the auth server's answer, ending with our two tickets
+/// 5. read: the server's ticket and our authenticator
+/// 6. write: the server's authenticator; then authinfo is ready
+pub struct TicketClient {
+    proto: TicketProto,
+    dom: Option<String>,
+    start: Vec<Attr>,
+    state: ClientState,
+    cchal: [u8; CHALLEN],
+    tr: Ticketreq,
+    key: Authkey,
+    pak: Option<PakPriv>,
+    pakkey: Option<[u8; PAKKEYLEN]>,
+    ys: [u8; PAKYLEN],
+    asreq: Vec<u8>,
+    out: Vec<u8>,
+    skey: Option<SessionKey>,
+    cnonce: [u8; NONCELEN],
+    ai: Option<AuthInfo>,
+}
+
+impl TicketClient {
+    /// `dom` is the domain p9any negotiated, if any; `start` holds the
+    /// attributes from the rpc start, which narrow the key search.
+    pub fn new(proto: TicketProto, dom: Option<&str>, start: Vec<Attr>) -> Self {
+        let mut cchal = [0u8; CHALLEN];
+        getrandom::getrandom(&mut cchal).expect("Failed to generate random bytes");
+        TicketClient {
+            proto,
+            dom: dom.map(str::to_string),
+            start,
+            state: ClientState::HaveChal,
+            cchal,
+            tr: Ticketreq::default(),
+            key: Authkey::default(),
+            pak: None,
+            pakkey: None,
+            ys: [0u8; PAKYLEN],
+            asreq: Vec::new(),
+            out: Vec::new(),
+            skey: None,
+            cnonce: [0u8; NONCELEN],
+            ai: None,
+        }
+    }
+
+    fn is_dp9ik(&self) -> bool {
+        self.proto == TicketProto::Dp9ik
+    }
+
+    /// The server's ticket request: pick a key and queue the auth server
+    /// requests.
+    fn write_treq(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply {
+        let m = TICKREQLEN + if self.is_dp9ik() { PAKYLEN } else { 0 };
+        if data.len() < m {
+            return RpcReply::Toosmall(m);
+        }
+        let mut tr = match Ticketreq::from_bytes(data) {
+            Decoded::Done(tr, _) => tr,
+            Decoded::Toosmall(n) => return RpcReply::Toosmall(n),
+        };
+
+        let query = key_query(self.proto.name(), Some(&tr.authdom), &self.start);
+        let now = unix_time();
+        let user = match find_key(ring, &query) {
+            Ok(key) => match (key.user(), key.password()) {
+                (Some(u), Some(p)) => {
+                    self.key = keycache::with(|c| c.derive(u, &tr.authdom, p, now));
+                    u.to_string()
+                }
+                _ => return RpcReply::Error("key has no user or !password".to_string()),
+            },
+            // A confirm key is asked about, not worked around
+            Err(RpcReply::Needkey(t)) if KeyRequest::from_template(&t).is_confirm() => {
+                return RpcReply::Needkey(t)
+            }
+            // No key, but one derived for this domain is still live
+            Err(reply) => {
+                let user = query
+                    .iter()
+                    .find(|a| a.attr_type == AttrType::Nameval && a.name == "user")
+                    .map(|a| a.val.as_str());
+                match keycache::with(|c| c.get(user, &tr.authdom, now)) {
+                    Some((user, key)) => {
+                        self.key = key;
+                        user
+                    }
+                    None => return reply,
+                }
+            }
+        };
+        tr.uid = user.clone();
+
+        let mut req = Vec::new();
+        if self.is_dp9ik() {
+            if self.key.pakhash == [0u8; PAKHASHLEN] {
+                self.key.authpak_hash(&user);
+                keycache::with(|c| c.insert(&user, &tr.authdom, &self.key, now));
+            }
+            self.ys.copy_from_slice(&data[TICKREQLEN..m]);
+            let pak = authpak_new(&self.key.pakhash, true);
+            tr.req_type = AUTH_PAK;
+            req.extend_from_slice(&tr.to_bytes());
+            req.extend_from_slice(&self.ys);
+            req.extend_from_slice(&pak.y);
+            self.pak = Some(pak);
+            self.pakkey = None;
+        }
+        tr.req_type = AUTH_TREQ;
+        req.extend_from_slice(&tr.to_bytes());
+
+        self.tr = tr;
+        self.asreq = req;
+        self.state = ClientState::HaveAsreq;
+        RpcReply::Ok(Vec::new())
+    }
+
+    /// The auth server's answer: [AuthOK YBs YBc] AuthOK Tc Ts.
+    fn write_tickets(&mut self, data: &[u8]) -> RpcReply {
+        let mut off = 0;
+        let mut ybs = [0u8; PAKYLEN];
+        if self.is_dp9ik() {
+            match parse_reply(data, 2 * PAKYLEN) {
+                Err(e) => return RpcReply::Error(e.0),
+                Ok(Decoded::Toosmall(n)) => return RpcReply::Toosmall(n),
+                Ok(Decoded::Done(y, used)) => {
+                    ybs.copy_from_slice(&y[..PAKYLEN]);
+                    if self.pakkey.is_none() {
+                        let ybc: &[u8; PAKYLEN] = y[PAKYLEN..].try_into().unwrap();
+                        let pak = self.pak.as_ref().expect("AuthPAK without PAK state");
+                        match authpak_finish(pak, &self.key.pakhash, ybc) {
+                            Ok(k) => self.pakkey = Some(k),
+                            Err(e) => return RpcReply::Error(e.0),
+                        }
+                    }
+                    off = used;
+                }
+            }
+        }
+        match parse_reply(&data[off..], 0) {
+            Err(e) => return RpcReply::Error(e.0),
+            Ok(Decoded::Toosmall(n)) => return RpcReply::Toosmall(off + n),
+            Ok(Decoded::Done(_, used)) => off += used,
+        }
+
+        let rest = &data[off..];
+        let tclen = match form1::ticket_len(rest) {
+            Some(n) => n,
+            None => return RpcReply::Toosmall(off + 8),
+        };
+        let tslen = match form1::ticket_len(rest.get(tclen..).unwrap_or(&[])) {
+            Some(n) => n,
+            None => return RpcReply::Toosmall(off + tclen + 8),
+        };
+        if rest.len() < tclen + tslen {
+            return RpcReply::Toosmall(off + tclen + tslen);
+        }
+        let (tc, ts) = (&rest[..tclen], &rest[tclen..tclen + tslen]);
+
+        // Open our ticket and check it answers our request
+        let (num, chal, cuid, suid, skey) = match (self.pakkey, tclen) {
+            (Some(pakkey), FORM1TICKETLEN) => match Form1Ticket::open(tc, &pakkey) {
+                Some(t) => (
+                    t.ticket_type,
+                    t.challenge,
+                    t.cuid,
+                    t.suid,
+                    SessionKey::Form1(t.key),
+                ),
+                None => return RpcReply::Error("invalid ticket: wrong password?".to_string()),
+            },
+            (None, TICKETLEN) => {
+                let t = decrypt_ticket(tc.try_into().unwrap(), &self.key.des);
+                (
+                    t.ticket_type,
+                    t.challenge,
+                    t.cuid,
+                    t.suid,
+                    SessionKey::Des(t.key),
+                )
+            }
+            _ => return RpcReply::Error("ticket form does not match protocol".to_string()),
+        };
+        if num != AUTH_TC || chal != self.tr.chal {
+            return RpcReply::Error("invalid ticket: wrong password?".to_string());
+        }
+
+        // Forward the server's ticket with our authenticator
+        let mut out = Vec::new();
+        if self.is_dp9ik() {
+            out.extend_from_slice(&ybs);
+        }
+        out.extend_from_slice(ts);
+        match &skey {
+            SessionKey::Form1(k) => {
+                getrandom::getrandom(&mut self.cnonce).expect("Failed to generate random bytes");
+                let a = Form1Authenticator {
+                    auth_type: AUTH_AC,
+                    challenge: self.tr.chal,
+                    rand: self.cnonce,
+                };
+                out.extend_from_slice(&a.seal(k));
+            }
+            SessionKey::Des(k) => {
+                out.extend_from_slice(&make_authenticator(AUTH_AC, &self.tr.chal, 0, k));
+            }
+        }
+
+        self.ai = Some(AuthInfo {
+            cuid,
+            suid,
+            ..Default::default()
+        });
+        self.skey = Some(skey);
+        self.out = out;
+        self.state = ClientState::HaveTicket;
+        RpcReply::Ok(Vec::new())
+    }
+
+    /// The server's authenticator, which proves it could open its ticket.
+    fn write_auth(&mut self, data: &[u8]) -> RpcReply {
+        let secret = match self.skey.as_ref().expect("authenticator before ticket") {
+            SessionKey::Form1(k) => {
+                if data.len() < FORM1AUTHENTLEN {
+                    return RpcReply::Toosmall(FORM1AUTHENTLEN);
+                }
+                let a = match Form1Authenticator::open(data, k) {
+                    Some(a) if a.auth_type == AUTH_AS && a.challenge == self.cchal => a,
+                    _ => return RpcReply::Error("invalid authenticator".to_string()),
+                };
+                let mut salt = [0u8; 2 * NONCELEN];
+                salt[..NONCELEN].copy_from_slice(&self.cnonce);
+                salt[NONCELEN..].copy_from_slice(&a.rand);
+                let mut secret = vec![0u8; 256];
+                Hkdf::<Sha256>::new(Some(&salt), k)
+                    .expand(b"Plan 9 session secret", &mut secret)
+                    .expect("HKDF expand failed");
+                secret
+            }
+            SessionKey::Des(k) => {
+                if data.len() < AUTHENTLEN {
+                    return RpcReply::Toosmall(AUTHENTLEN);
+                }
+                let a = decrypt_authenticator(data[..AUTHENTLEN].try_into().unwrap(), k);
+                if a.auth_type != AUTH_AS || a.challenge != self.cchal {
+                    return RpcReply::Error("invalid authenticator".to_string());
+                }
+                des9::expand_key(k).to_vec()
+            }
+        };
+        if let Some(ai) = self.ai.as_mut() {
+            ai.secret = secret;
+        }
+        self.state = ClientState::Established;
+        RpcReply::Done { haveai: true }
+    }
+}
+
+impl Proto for TicketClient {
+    fn read(&mut self, _ring: &Keyring) -> RpcReply {
+        match self.state {
+            ClientState::HaveChal => {
+                self.state = ClientState::NeedTreq;
+                RpcReply::Ok(self.cchal.to_vec())
+            }
+            ClientState::HaveAsreq => {
+                self.state = ClientState::NeedTickets;
+                RpcReply::Authsrv {
+                    dom: self.tr.authdom.clone(),
+                    data: std::mem::take(&mut self.asreq),
+                }
+            }
+            ClientState::HaveTicket => {
+                self.state = ClientState::NeedAuth;
+                RpcReply::Ok(std::mem::take(&mut self.out))
+            }
+            ClientState::Established => RpcReply::Done { haveai: true },
+            _ => rpc::phase_error("write expected"),
+        }
+    }
+
+    fn write(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply {
+        match self.state {
+            ClientState::NeedTreq => self.write_treq(ring, data),
+            ClientState::NeedTickets => self.write_tickets(data),
+            ClientState::NeedAuth => self.write_auth(data),
+            _ => rpc::phase_error("read expected"),
+        }
+    }
+
+    fn authinfo(&self) -> Option<&AuthInfo> {
+        match self.state {
+            ClientState::Established => self.ai.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn attrs(&self) -> Vec<Attr> {
+        let mut attrs = vec![Attr::new("proto", self.proto.name())];
+        let dom = match self.state {
+            ClientState::HaveChal | ClientState::NeedTreq => self.dom.as_deref(),
+            _ => Some(self.tr.authdom.as_str()),
+        };
+        if let Some(dom) = dom {
+            attrs.push(Attr::new("dom", dom));
+        }
+        attrs.push(Attr::new("role", "client"));
+        if !self.tr.uid.is_empty() {
+            attrs.push(Attr::new("user", &self.tr.uid));
+        }
+        attrs
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -370,4 +781,57 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_client_from_keyring() {
+        let ring = Keyring::parse(
+            "key proto=p9sk1 dom=nawin user=glenda !password=glenda\n\
+             key proto=p9sk1 dom=other user=bootes\n",
+        )
+        .unwrap();
+        let client = P9sk1Client::from_keyring(&ring, Some("nawin"), None).unwrap();
+        assert_eq!(client.user, "glenda");
+        assert_eq!(hex::encode(client.get_key()), "6776d94d0e0340");
+
+        // A key without !password is not a candidate
+        assert!(P9sk1Client::from_keyring(&ring, Some("other"), None).is_err());
+    }
+
+    #[test]
+    fn test_ticket_client_phases() {
+        let ring =
+            Keyring::parse("key proto=p9sk1 dom=nawin user=glenda !password=kittens").unwrap();
+        let mut c = TicketClient::new(TicketProto::P9sk1, None, vec![]);
+        assert_eq!(c.write(&ring, b"").verb(), "phase");
+        let cchal = match c.read(&ring) {
+            RpcReply::Ok(chal) => chal,
+            r => panic!("unexpected {}", r),
+        };
+        assert_eq!(cchal.len(), CHALLEN);
+        assert_eq!(c.read(&ring).verb(), "phase");
+        assert_eq!(c.write(&ring, b""), RpcReply::Toosmall(TICKREQLEN));
+
+        let tr = Ticketreq {
+            req_type: AUTH_TREQ,
+            authid: "bootes".to_string(),
+            authdom: "nawin".to_string(),
+            chal: [5; CHALLEN],
+            hostid: "bootes".to_string(),
+            uid: String::new(),
+        };
+        assert_eq!(c.write(&ring, &tr.to_bytes()), RpcReply::Ok(vec![]));
+        match c.read(&ring) {
+            RpcReply::Authsrv { dom, data } => {
+                assert_eq!(dom, "nawin");
+                let sent = match Ticketreq::from_bytes(&data) {
+                    Decoded::Done(t, _) => t,
+                    _ => panic!("short ticket request"),
+                };
+                assert_eq!(sent.uid, "glenda");
+                assert_eq!(sent.chal, tr.chal);
+            }
+            r => panic!("unexpected {}", r),
+        }
+        assert!(c.authinfo().is_none());
+    }
 }
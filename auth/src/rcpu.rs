@@ -0,0 +1,311 @@
+//! rcpu - the terminal's end of a connection to a 9front cpu server
+//!
+//! The cpu server listens on tcp!*!rcpu (17019) and runs `tlssrv -a`,
+//! so the conversation is
+//!
+//! 1. p9any on the raw stream, the server speaking first;
+//! 2. TLS 1.2 PSK keyed by the AuthInfo secret (`tlspsk`);
+//! 3. the script to run, as a decimal count on a line of its own then
+//!    that many bytes, which the server's rc reads and executes;
+//! 4. 9P the other way round: the script mounts the connection on
+//!    /mnt/term, so the server sends T-messages and the terminal serves
+//!    its namespace, as exportfs does for rcpu and drawterm.
+//!
+//! `Rcpu` runs the first three steps and then carries the 9P, doing no
+//! I/O itself: send what `take` returns, feed the server's bytes to
+//! `input`, relay `take_authsrv` requests to the auth server, and answer
+//! key requests with `supply_key`.
+//!
+//! Ported from 9front's rcpu and drawterm's cpu.c.
+
+use crate::keyring::Key;
+use crate::rpc::{AuthInfo, AuthProxy, Factotum, ProxyState, RpcReply};
+use crate::tlspsk::{TlsPsk, TlsSuite};
+
+pub const RCPUPORT: u16 = 17019; // tcp!*!rcpu
+pub const RCPUPARAMS: &str = "proto=p9any role=client";
+
+/// What drawterm runs: mount the terminal, take its console and start a
+/// login shell, whose profile binds the rest of /mnt/term/dev.
+pub const RCPUSCRIPT: &str = "syscall fversion 0 65536 buf 256 >/dev/null >[2=1]\n\
+mount -nc /fd/0 /mnt/term || exit\n\
+bind -q /mnt/term/dev/cons /dev/cons\n\
+</dev/cons >/dev/cons >[2=1] service=cpu rc -li\n\
+echo -n hangup >/proc/$pid/notepg\n";
+
+/// Error type for the rcpu handshake
+#[derive(Debug, Clone)]
+pub struct RcpuError(pub String);
+
+impl std::fmt::Display for RcpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RcpuError {}
+
+/// Where the handshake stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcpuState {
+    /// p9any; the auth server or a key may be wanted too
+    Auth,
+    /// The TLS handshake
+    Tls,
+    /// The script is sent and the connection carries 9P
+    Running,
+    Closed,
+}
+
+/// The script as the server's rc reads it: `n=`{read}` then `read -c $n`.
+pub fn script_message(script: &str) -> Vec<u8> {
+    let mut out = format!("{:7}\n", script.len()).into_bytes();
+    out.extend_from_slice(script.as_bytes());
+    out
+}
+
+/// The client side of the rcpu handshake
+pub struct Rcpu {
+    auth: AuthProxy,
+    tls: Option<TlsPsk>,
+    script: Option<Vec<u8>>,
+    /// Handshake bytes and the script record, in order
+    out: Vec<u8>,
+    closed: bool,
+}
+
+impl Rcpu {
+    /// Authenticate with the keys in `f` and then run `script` (see
+    /// `RCPUSCRIPT`) on the server.
+    pub fn new(f: Factotum, script: &str) -> Self {
+        Self::with_params(f, RCPUPARAMS, script)
+    }
+
+    /// As `new`, starting factotum with `params`, such as
+    /// "proto=p9any role=client user=glenda".
+    pub fn with_params(f: Factotum, params: &str, script: &str) -> Self {
+        Rcpu {
+            auth: AuthProxy::new(f, params),
+            tls: None,
+            script: Some(script_message(script)),
+            out: Vec::new(),
+            closed: false,
+        }
+    }
+
+    pub fn state(&self) -> RcpuState {
+        match &self.tls {
+            _ if self.closed => RcpuState::Closed,
+            None => RcpuState::Auth,
+            Some(tls) if tls.is_closed() => RcpuState::Closed,
+            Some(_) if self.script.is_some() => RcpuState::Tls,
+            Some(_) => RcpuState::Running,
+        }
+    }
+
+    /// Where authentication stands; `Key` and `Authsrv` need the caller.
+    pub fn auth_state(&self) -> &ProxyState {
+        self.auth.state()
+    }
+
+    pub fn factotum(&self) -> &Factotum {
+        self.auth.factotum()
+    }
+
+    /// What authentication established, once past `Auth`.
+    pub fn authinfo(&self) -> Option<&AuthInfo> {
+        self.auth.authinfo()
+    }
+
+    /// Bytes to send to the cpu server.
+    pub fn take(&mut self) -> Vec<u8> {
+        let mut out = self.auth.take();
+        out.append(&mut self.out);
+        if let Some(tls) = &mut self.tls {
+            out.extend(tls.take());
+        }
+        out
+    }
+
+    /// A ticket request for the auth server: an `RpcReply::Authsrv` naming
+    /// the domain. Its answer goes to `authsrv_reply`.
+    pub fn take_authsrv(&mut self) -> Option<RpcReply> {
+        self.auth.take_authsrv()
+    }
+
+    pub fn authsrv_reply(&mut self, data: &[u8]) -> Result<(), RcpuError> {
+        self.auth.put_authsrv(data);
+        self.advance(&[]).map(|_| ())
+    }
+
+    /// The key `Factotum::key_request` asked for.
+    pub fn supply_key(&mut self, key: Key) -> Result<(), RcpuError> {
+        self.auth.supply_key(key);
+        self.advance(&[]).map(|_| ())
+    }
+
+    /// Bytes from the cpu server; returns the 9P they carry once running.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, RcpuError> {
+        if self.tls.is_none() {
+            self.auth.put(data);
+            return self.advance(&[]);
+        }
+        self.advance(data)
+    }
+
+    /// 9P replies for the server.
+    pub fn send(&mut self, data: &[u8]) -> Result<Vec<u8>, RcpuError> {
+        match (&mut self.tls, self.script.is_none()) {
+            (Some(tls), true) => tls.encrypt(data).map_err(|e| RcpuError(e.0)),
+            _ => Err(RcpuError("rcpu: not running".to_string())),
+        }
+    }
+
+    /// The close_notify to send before hanging up.
+    pub fn close(&mut self) -> Vec<u8> {
+        self.closed = true;
+        self.tls.as_mut().map(|t| t.close()).unwrap_or_default()
+    }
+
+    /// Move on from auth to TLS to the script as each finishes.
+    fn advance(&mut self, data: &[u8]) -> Result<Vec<u8>, RcpuError> {
+        let mut data = data.to_vec();
+        if self.tls.is_none() {
+            match self.auth.state() {
+                ProxyState::Done => {}
+                ProxyState::Failed(e) => return Err(RcpuError(e.clone())),
+                _ => return Ok(Vec::new()),
+            }
+            let ai = self
+                .auth
+                .authinfo()
+                .ok_or_else(|| RcpuError("rcpu: no authinfo".to_string()))?;
+            self.tls = Some(TlsPsk::client(&ai.secret, &TlsSuite::ALL));
+            data = self.auth.leftover();
+        }
+        let tls = self.tls.as_mut().unwrap();
+        let nine = tls.decrypt(&data).map_err(|e| RcpuError(e.0))?;
+        if tls.is_established() {
+            if let Some(script) = self.script.take() {
+                self.out.extend(tls.take());
+                self.out
+                    .extend(tls.encrypt(&script).map_err(|e| RcpuError(e.0))?);
+            }
+        }
+        Ok(nine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    fn setup(keys: &str, offer: &str) -> (Rcpu, TestServer, TestAuthsrv) {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let f = Factotum::new(Keyring::parse(keys).unwrap());
+        let cpu = TestServer::new("bootes", "cpusecret", "nawin", offer);
+        (Rcpu::new(f, "echo hello\n"), cpu, srv)
+    }
+
+    /// Play the cpu server until the script is in; returns its TLS end
+    /// and what it read.
+    fn converse(c: &mut Rcpu, cpu: &mut TestServer, srv: &TestAuthsrv) -> (TlsPsk, Vec<u8>) {
+        let mut tls: Option<TlsPsk> = None;
+        let mut script = Vec::new();
+        for _ in 0..20 {
+            if *c.auth_state() == ProxyState::Key {
+                let req = c.factotum().key_request().unwrap().clone();
+                let key = req
+                    .answer(&[("user", "glenda"), ("!password", "kittens")])
+                    .unwrap();
+                c.supply_key(key).unwrap();
+            }
+            if let Some(RpcReply::Authsrv { data, .. }) = c.take_authsrv() {
+                c.authsrv_reply(&srv.serve(&data)).unwrap();
+            }
+            let out = c.take();
+            let back = match &mut tls {
+                None => {
+                    cpu.put(&out);
+                    if let Some(secret) = cpu.secret() {
+                        tls = Some(TlsPsk::server(&secret, &TlsSuite::ALL));
+                    }
+                    cpu.take(4096)
+                }
+                Some(t) => {
+                    script.extend(t.decrypt(&out).unwrap());
+                    t.take()
+                }
+            };
+            if c.state() == RcpuState::Running && !script.is_empty() {
+                return (tls.unwrap(), script);
+            }
+            assert!(c.input(&back).unwrap().is_empty());
+        }
+        panic!("rcpu handshake did not finish: {:?}", c.state());
+    }
+
+    #[test]
+    fn test_script_message() {
+        assert_eq!(script_message("echo hello\n"), b"     11\necho hello\n");
+        let m = script_message(RCPUSCRIPT);
+        assert_eq!(m[7], b'\n');
+        assert_eq!(m.len(), 8 + RCPUSCRIPT.len());
+    }
+
+    #[test]
+    fn test_handshake_dp9ik() {
+        let (mut c, mut cpu, srv) = setup(
+            "key proto=dp9ik dom=nawin user=glenda !password=kittens",
+            "dp9ik@nawin",
+        );
+        assert_eq!(c.state(), RcpuState::Auth);
+        let (mut s, script) = converse(&mut c, &mut cpu, &srv);
+        assert_eq!(script, b"     11\necho hello\n");
+        assert_eq!(c.authinfo().unwrap().cuid, "glenda");
+
+        // The server mounts us: it asks, we answer
+        let rec = s.encrypt(b"Tversion").unwrap();
+        assert_eq!(c.input(&rec).unwrap(), b"Tversion");
+        let rec = c.send(b"Rversion").unwrap();
+        assert_eq!(s.decrypt(&rec).unwrap(), b"Rversion");
+
+        s.decrypt(&c.close()).unwrap();
+        assert_eq!(c.state(), RcpuState::Closed);
+    }
+
+    #[test]
+    fn test_handshake_needkey_p9sk1() {
+        let (mut c, mut cpu, srv) = setup("", "p9sk1@nawin");
+        let (_, script) = converse(&mut c, &mut cpu, &srv);
+        assert_eq!(script, b"     11\necho hello\n");
+        assert_eq!(c.authinfo().unwrap().secret, cpu.secret().unwrap());
+    }
+
+    #[test]
+    fn test_auth_failure() {
+        let (mut c, mut cpu, srv) = setup(
+            "key proto=dp9ik dom=nawin user=glenda !password=puppies",
+            "dp9ik@nawin",
+        );
+        let data = loop {
+            if let Some(RpcReply::Authsrv { data, .. }) = c.take_authsrv() {
+                break data;
+            }
+            cpu.put(&c.take());
+            c.input(&cpu.take(4096)).unwrap();
+        };
+        assert!(c.authsrv_reply(&srv.serve(&data)).is_err());
+        assert_eq!(c.state(), RcpuState::Auth);
+    }
+
+    #[test]
+    fn test_send_before_running() {
+        let (mut c, _, _) = setup("", "dp9ik@nawin");
+        assert!(c.send(b"Rversion").is_err());
+    }
+}
@@ -0,0 +1,600 @@
+//! relay - auth server conversations beside 9P on one WebSocket
+//!
+//! p9sk1 and dp9ik need the client to reach the auth server itself, which
+//! a browser cannot dial. The relay multiplexes the WebSocket instead:
+//! channel 0 is the connection to the backend as before, and the client
+//! opens further channels by authentication domain, which the relay dials
+//! on port 567 from its own table. Nothing else can be dialed.
+//!
+//! ```text
+//! type[1] chan[1] count[2] data[count]
+//!
+//! RelayOpen   client  data is the domain
+//! RelayOk     relay   the auth server answered the dial
+//! RelayErr    relay   data says why the dial failed or the channel died
+//! RelayData   either  bytes for the channel
+//! RelayClose  either  hang up
+//! ```
+//!
+//! `count` is little-endian, as in 9P. `RelayClient` is the browser's
+//! end and `RelayServer` the relay's; neither does I/O.
+
+use crate::rpc::{AuthProxy, ProxyState, RpcReply};
+
+pub const RELAYHDRLEN: usize = 4; // type, chan, count[2]
+pub const RELAYMAXDATA: usize = 0xffff; // Most data in one frame
+pub const RELAYMAXCHANS: usize = 4; // Auth server channels open at once
+pub const AUTHSRVPORT: u16 = 567;
+
+pub const RELAYOPEN: u8 = 1; // request dial of auth server for domain
+pub const RELAYOK: u8 = 2; // dial succeeded
+pub const RELAYERR: u8 = 3; // dial failed or channel died
+pub const RELAYDATA: u8 = 4; // data on channel
+pub const RELAYCLOSE: u8 = 5; // hang up channel
+
+/// Error type for the relay
+#[derive(Debug, Clone)]
+pub struct RelayError(pub String);
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+/// One relay frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayMsg {
+    Open { chan: u8, dom: String },
+    Ok { chan: u8 },
+    Err { chan: u8, msg: String },
+    Data { chan: u8, data: Vec<u8> },
+    Close { chan: u8 },
+}
+
+impl RelayMsg {
+    /// The frame, or frames for long Data. A domain or error longer than
+    /// one frame is refused rather than cut short.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RelayError> {
+        let (t, chan, data) = match self {
+            RelayMsg::Open { chan, dom } => (RELAYOPEN, *chan, dom.as_bytes()),
+            RelayMsg::Ok { chan } => (RELAYOK, *chan, &[][..]),
+            RelayMsg::Err { chan, msg } => (RELAYERR, *chan, msg.as_bytes()),
+            RelayMsg::Data { chan, data } => return Ok(data_frames(*chan, data)),
+            RelayMsg::Close { chan } => (RELAYCLOSE, *chan, &[][..]),
+        };
+        if data.len() > RELAYMAXDATA {
+            return Err(RelayError(format!(
+                "relay: {} bytes too long for a frame",
+                data.len()
+            )));
+        }
+        Ok(frame(t, chan, data))
+    }
+
+    /// The first frame in `buf` and its length, or None if incomplete.
+    pub fn parse(buf: &[u8]) -> Result<Option<(RelayMsg, usize)>, RelayError> {
+        if buf.len() < RELAYHDRLEN {
+            return Ok(None);
+        }
+        let (t, chan) = (buf[0], buf[1]);
+        let n = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let data = match buf.get(RELAYHDRLEN..RELAYHDRLEN + n) {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+        let text = || String::from_utf8_lossy(data).to_string();
+        let msg = match t {
+            RELAYOPEN => RelayMsg::Open { chan, dom: text() },
+            RELAYOK => RelayMsg::Ok { chan },
+            RELAYERR => RelayMsg::Err { chan, msg: text() },
+            RELAYDATA => RelayMsg::Data {
+                chan,
+                data: data.to_vec(),
+            },
+            RELAYCLOSE => RelayMsg::Close { chan },
+            _ => return Err(RelayError(format!("relay: bad frame type {}", t))),
+        };
+        Ok(Some((msg, RELAYHDRLEN + n)))
+    }
+}
+
+/// One frame; `data` must fit.
+fn frame(t: u8, chan: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![t, chan];
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Data frames for `chan`, split to fit.
+pub fn data_frames(chan: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + RELAYHDRLEN);
+    for chunk in data.chunks(RELAYMAXDATA) {
+        out.extend(frame(RELAYDATA, chan, chunk));
+    }
+    out
+}
+
+/// Parse every whole frame in `buf`, leaving any partial one.
+fn frames(buf: &mut Vec<u8>) -> Result<Vec<RelayMsg>, RelayError> {
+    let mut msgs = Vec::new();
+    let mut off = 0;
+    while let Some((m, n)) = RelayMsg::parse(&buf[off..])? {
+        msgs.push(m);
+        off += n;
+    }
+    buf.drain(..off);
+    Ok(msgs)
+}
+
+// ============================================================================
+// The browser's end
+// ============================================================================
+
+/// An auth server channel as the client sees it
+struct ClientChan {
+    chan: u8,
+    dom: String,
+    inq: Vec<u8>,
+    err: Option<String>,
+}
+
+/// The browser's end of the relay
+#[derive(Default)]
+pub struct RelayClient {
+    inbuf: Vec<u8>,
+    chans: Vec<ClientChan>,
+    /// The channel carrying the current `authsrv` request
+    authsrv: Option<u8>,
+    last: u8,
+}
+
+impl RelayClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 9P (or whatever the backend speaks) for channel 0.
+    pub fn send(&mut self, data: &[u8]) -> Vec<u8> {
+        data_frames(0, data)
+    }
+
+    /// Open a channel to the auth server for `dom`.
+    pub fn open(&mut self, dom: &str) -> Result<(u8, Vec<u8>), RelayError> {
+        if self.chans.len() >= RELAYMAXCHANS {
+            return Err(RelayError("relay: too many channels".to_string()));
+        }
+        let mut chan = self.last;
+        loop {
+            chan = chan.wrapping_add(1).max(1);
+            if !self.chans.iter().any(|c| c.chan == chan) {
+                break;
+            }
+        }
+        let msg = RelayMsg::Open {
+            chan,
+            dom: dom.to_string(),
+        }
+        .to_bytes()?;
+        self.last = chan;
+        self.chans.push(ClientChan {
+            chan,
+            dom: dom.to_string(),
+            inq: Vec::new(),
+            err: None,
+        });
+        Ok((chan, msg))
+    }
+
+    pub fn write(&mut self, chan: u8, data: &[u8]) -> Vec<u8> {
+        data_frames(chan, data)
+    }
+
+    /// Hang up `chan`.
+    pub fn close(&mut self, chan: u8) -> Vec<u8> {
+        self.chans.retain(|c| c.chan != chan);
+        if self.authsrv == Some(chan) {
+            self.authsrv = None;
+        }
+        frame(RELAYCLOSE, chan, &[])
+    }
+
+    /// What has arrived on `chan`; once that is read, why it failed.
+    pub fn read(&mut self, chan: u8) -> Result<Vec<u8>, RelayError> {
+        let c = self
+            .chans
+            .iter_mut()
+            .find(|c| c.chan == chan)
+            .ok_or_else(|| RelayError(format!("relay: channel {} not open", chan)))?;
+        match &c.err {
+            Some(e) if c.inq.is_empty() => Err(RelayError(format!("authsrv {}: {}", c.dom, e))),
+            _ => Ok(std::mem::take(&mut c.inq)),
+        }
+    }
+
+    /// Frames from the relay; returns what they carry for channel 0.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, RelayError> {
+        self.inbuf.extend_from_slice(data);
+        let mut out = Vec::new();
+        for m in frames(&mut self.inbuf)? {
+            match m {
+                RelayMsg::Data { chan: 0, data } => out.extend(data),
+                RelayMsg::Data { chan, data } => {
+                    if let Some(c) = self.chans.iter_mut().find(|c| c.chan == chan) {
+                        c.inq.extend(data);
+                    }
+                }
+                RelayMsg::Ok { .. } => {}
+                RelayMsg::Err { chan, msg } => {
+                    if let Some(c) = self.chans.iter_mut().find(|c| c.chan == chan) {
+                        c.err = Some(msg);
+                    }
+                }
+                RelayMsg::Close { chan } => {
+                    if let Some(c) = self.chans.iter_mut().find(|c| c.chan == chan) {
+                        c.err.get_or_insert_with(|| "hungup".to_string());
+                    }
+                }
+                RelayMsg::Open { .. } => {
+                    return Err(RelayError("relay: open from the relay".to_string()))
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Send a factotum `authsrv` request on a fresh channel, hanging up
+    /// the previous one.
+    pub fn authsrv_request(&mut self, reply: &RpcReply) -> Result<Vec<u8>, RelayError> {
+        let (dom, data) = match reply {
+            RpcReply::Authsrv { dom, data } => (dom, data),
+            r => return Err(RelayError(format!("relay: not an authsrv reply: {}", r))),
+        };
+        let mut out = match self.authsrv {
+            Some(chan) => self.close(chan),
+            None => Vec::new(),
+        };
+        let (chan, open) = self.open(dom)?;
+        out.extend(open);
+        out.extend(self.write(chan, data));
+        self.authsrv = Some(chan);
+        Ok(out)
+    }
+
+    /// The answer so far to the current `authsrv` request.
+    pub fn authsrv_answer(&mut self) -> Result<Vec<u8>, RelayError> {
+        match self.authsrv {
+            Some(chan) => self.read(chan),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Carry `proxy`'s auth server conversations: call after each `input`
+    /// and each step of the proxy, and send what it returns.
+    pub fn pump(&mut self, proxy: &mut AuthProxy) -> Result<Vec<u8>, RelayError> {
+        let mut out = Vec::new();
+        loop {
+            if let Some(reply) = proxy.take_authsrv() {
+                out.extend(self.authsrv_request(&reply)?);
+            }
+            let chan = match self.authsrv {
+                Some(chan) => chan,
+                None => return Ok(out),
+            };
+            let answer = self.authsrv_answer()?;
+            if !answer.is_empty() {
+                proxy.put_authsrv(&answer);
+            }
+            if *proxy.state() != ProxyState::Authsrv {
+                out.extend(self.close(chan));
+            }
+            if answer.is_empty() {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// The relay's end
+// ============================================================================
+
+/// What the relay has to do for the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayEvent {
+    /// Bytes for the backend
+    Backend(Vec<u8>),
+    /// Dial `addr` for `chan`, then report with `dialed`
+    Dial { chan: u8, addr: String },
+    /// Bytes for the auth server on `chan`
+    Authsrv { chan: u8, data: Vec<u8> },
+    /// Hang up `chan`
+    Hangup { chan: u8 },
+}
+
+/// The relay's end: the trampoline side of the WebSocket
+pub struct RelayServer {
+    /// Authentication domains and their auth servers' addresses
+    doms: Vec<(String, String)>,
+    chans: Vec<u8>,
+    inbuf: Vec<u8>,
+    outq: Vec<u8>,
+}
+
+impl RelayServer {
+    /// A relay that will dial only the auth servers in `doms`, given as
+    /// (domain, address) pairs like ndb's `authdom=` and `auth=`.
+    pub fn new(doms: &[(&str, &str)]) -> Self {
+        RelayServer {
+            doms: doms
+                .iter()
+                .map(|(d, a)| (d.to_string(), a.to_string()))
+                .collect(),
+            chans: Vec::new(),
+            inbuf: Vec::new(),
+            outq: Vec::new(),
+        }
+    }
+
+    /// Frames for the client.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outq)
+    }
+
+    /// Frames from the client.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<RelayEvent>, RelayError> {
+        self.inbuf.extend_from_slice(data);
+        let mut ev = Vec::new();
+        for m in frames(&mut self.inbuf)? {
+            match m {
+                RelayMsg::Data { chan: 0, data } => ev.push(RelayEvent::Backend(data)),
+                RelayMsg::Open { chan: 0, .. } => {
+                    return Err(RelayError("relay: open of channel 0".to_string()))
+                }
+                RelayMsg::Open { chan, dom } => {
+                    let addr = self.doms.iter().find(|(d, _)| *d == dom).map(|(_, a)| a);
+                    let why = match addr {
+                        _ if self.chans.contains(&chan) => "channel in use",
+                        _ if self.chans.len() >= RELAYMAXCHANS => "too many channels",
+                        None => "unknown authentication domain",
+                        Some(addr) => {
+                            self.chans.push(chan);
+                            ev.push(RelayEvent::Dial {
+                                chan,
+                                addr: addr.clone(),
+                            });
+                            continue;
+                        }
+                    };
+                    self.outq.extend(
+                        RelayMsg::Err {
+                            chan,
+                            msg: format!("{}: {}", dom, why),
+                        }
+                        .to_bytes()?,
+                    );
+                }
+                RelayMsg::Data { chan, data } if self.chans.contains(&chan) => {
+                    ev.push(RelayEvent::Authsrv { chan, data })
+                }
+                RelayMsg::Close { chan } if self.chans.contains(&chan) => {
+                    self.chans.retain(|&c| c != chan);
+                    ev.push(RelayEvent::Hangup { chan });
+                }
+                RelayMsg::Data { chan, .. } => self.outq.extend(
+                    RelayMsg::Err {
+                        chan,
+                        msg: "channel not open".to_string(),
+                    }
+                    .to_bytes()?,
+                ),
+                RelayMsg::Close { .. } => {}
+                RelayMsg::Ok { .. } | RelayMsg::Err { .. } => {
+                    return Err(RelayError("relay: reply from the client".to_string()))
+                }
+            }
+        }
+        Ok(ev)
+    }
+
+    /// How the dial for `chan` went; a reason too long to send is an
+    /// error, and the channel is dropped all the same.
+    pub fn dialed(&mut self, chan: u8, result: Result<(), String>) -> Result<(), RelayError> {
+        let msg = match result {
+            Ok(()) => RelayMsg::Ok { chan },
+            Err(msg) => {
+                self.chans.retain(|&c| c != chan);
+                RelayMsg::Err { chan, msg }
+            }
+        };
+        self.outq.extend(msg.to_bytes()?);
+        Ok(())
+    }
+
+    /// Bytes from the backend.
+    pub fn backend(&mut self, data: &[u8]) {
+        self.outq.extend(data_frames(0, data));
+    }
+
+    /// Bytes from the auth server on `chan`.
+    pub fn authsrv(&mut self, chan: u8, data: &[u8]) {
+        self.outq.extend(data_frames(chan, data));
+    }
+
+    /// The auth server hung up `chan`.
+    pub fn hungup(&mut self, chan: u8) {
+        if self.chans.contains(&chan) {
+            self.chans.retain(|&c| c != chan);
+            self.outq.extend(frame(RELAYCLOSE, chan, &[]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::rpc::Factotum;
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    #[test]
+    fn test_frames() {
+        let m = RelayMsg::Open {
+            chan: 1,
+            dom: "nawin".to_string(),
+        };
+        let b = m.to_bytes().unwrap();
+        assert_eq!(&b[..4], &[RELAYOPEN, 1, 5, 0]);
+        assert_eq!(RelayMsg::parse(&b).unwrap(), Some((m, 9)));
+        assert_eq!(RelayMsg::parse(&b[..8]).unwrap(), None);
+        assert!(RelayMsg::parse(&[9, 0, 0, 0]).is_err());
+        // Long data is split
+        let big = data_frames(0, &vec![0u8; RELAYMAXDATA + 1]);
+        assert_eq!(big.len(), 2 * RELAYHDRLEN + RELAYMAXDATA + 1);
+    }
+
+    #[test]
+    fn test_oversized_payload() {
+        let long = "x".repeat(RELAYMAXDATA + 1);
+        let m = RelayMsg::Data {
+            chan: 1,
+            data: long.clone().into_bytes(),
+        };
+        let b = m.to_bytes().unwrap();
+        let (first, n) = RelayMsg::parse(&b).unwrap().unwrap();
+        assert_eq!(
+            first,
+            RelayMsg::Data {
+                chan: 1,
+                data: long.as_bytes()[..RELAYMAXDATA].to_vec()
+            }
+        );
+        assert_eq!(
+            RelayMsg::parse(&b[n..]).unwrap().unwrap().1,
+            RELAYHDRLEN + 1
+        );
+
+        // Domains and errors are not cut short
+        let m = RelayMsg::Err {
+            chan: 1,
+            msg: long.clone(),
+        };
+        assert!(m.to_bytes().is_err());
+        let mut c = RelayClient::new();
+        assert!(c.open(&long).is_err());
+        assert!(c.open("nawin").is_ok());
+        let mut r = RelayServer::new(&[("nawin", "tcp!auth!567")]);
+        r.input(&c.open("nawin").unwrap().1).unwrap();
+        assert!(r.dialed(2, Err(long)).is_err());
+        assert!(r.take().is_empty());
+    }
+
+    #[test]
+    fn test_relay_refuses_other_domains() {
+        let mut c = RelayClient::new();
+        let mut r = RelayServer::new(&[("nawin", "tcp!auth!567")]);
+        let (chan, open) = c.open("evil.example").unwrap();
+        assert!(r.input(&open).unwrap().is_empty());
+        c.input(&r.take()).unwrap();
+        assert!(c.read(chan).unwrap_err().0.contains("unknown"));
+
+        let (chan, open) = c.open("nawin").unwrap();
+        assert_eq!(
+            r.input(&open).unwrap(),
+            vec![RelayEvent::Dial {
+                chan,
+                addr: "tcp!auth!567".to_string()
+            }]
+        );
+        // Data on a channel never opened
+        assert!(r.input(&c.write(9, b"x")).unwrap().is_empty());
+        assert!(!r.take().is_empty());
+    }
+
+    #[test]
+    fn test_channel_limit() {
+        let mut c = RelayClient::new();
+        for _ in 0..RELAYMAXCHANS {
+            c.open("nawin").unwrap();
+        }
+        assert!(c.open("nawin").is_err());
+        let mut r = RelayServer::new(&[("nawin", "tcp!auth!567")]);
+        for chan in 1..=RELAYMAXCHANS as u8 + 1 {
+            r.input(
+                &RelayMsg::Open {
+                    chan,
+                    dom: "nawin".to_string(),
+                }
+                .to_bytes()
+                .unwrap(),
+            )
+            .unwrap();
+        }
+        let (m, _) = RelayMsg::parse(&r.take()).unwrap().unwrap();
+        assert_eq!(
+            m,
+            RelayMsg::Err {
+                chan: RELAYMAXCHANS as u8 + 1,
+                msg: "nawin: too many channels".to_string()
+            }
+        );
+    }
+
+    /// p9any to a cpu server on channel 0 with tickets fetched over the
+    /// relay, everything through one byte stream each way.
+    #[test]
+    fn test_auth_through_relay() {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut cpu = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ring =
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens").unwrap();
+        let mut p = AuthProxy::new(Factotum::new(ring), "proto=p9any role=client");
+        let mut c = RelayClient::new();
+        let mut r = RelayServer::new(&[("nawin", "tcp!localhost!567")]);
+        let mut asks: Vec<(u8, Vec<u8>)> = Vec::new();
+
+        for _ in 0..20 {
+            // Browser
+            let mut ws = c.send(&p.take());
+            ws.extend(c.pump(&mut p).unwrap());
+            // Relay, with the backend and auth server in-process
+            for ev in r.input(&ws).unwrap() {
+                match ev {
+                    RelayEvent::Backend(data) => cpu.put(&data),
+                    RelayEvent::Dial { chan, addr } => {
+                        assert_eq!(addr, "tcp!localhost!567");
+                        r.dialed(chan, Ok(())).unwrap();
+                        asks.push((chan, Vec::new()));
+                    }
+                    RelayEvent::Authsrv { chan, data } => {
+                        let a = asks.iter_mut().find(|a| a.0 == chan).unwrap();
+                        a.1.extend(data);
+                        let answer = srv.serve(&a.1);
+                        r.authsrv(chan, &answer);
+                        // As authsrv does after one request
+                        r.hungup(chan);
+                        asks.retain(|a| a.0 != chan);
+                    }
+                    RelayEvent::Hangup { chan } => asks.retain(|a| a.0 != chan),
+                }
+            }
+            r.backend(&cpu.take(4096));
+            // Browser again
+            let back = r.take();
+            p.put(&c.input(&back).unwrap());
+            if *p.state() == ProxyState::Done {
+                break;
+            }
+        }
+        assert_eq!(*p.state(), ProxyState::Done);
+        cpu.put(&p.take());
+        assert_eq!(p.authinfo().unwrap().secret, cpu.secret().unwrap());
+        assert!(asks.is_empty());
+        assert!(c.pump(&mut p).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1018 @@
+//! factotum RPC emulation
+//!
+//! Plan 9 programs drive authentication through factotum's rpc file:
+//! `start proto=p9any role=client`, then `read` and `write` until `done`,
+//! then `authinfo`. `Factotum` speaks the same verbs and replies (ok, done,
+//! error, needkey, toosmall, phase), backed by a `Keyring`, so a port of
+//! libauth's auth_proxy can drive any of our client protocols.
+//!
+//! One reply is ours alone. Real factotum dials the auth server itself; we
+//! can't, so when a protocol needs tickets `read` answers
+//! `authsrv` with the request bytes. The caller sends them to the auth
+//! server for the named domain and `write`s back its answer, following
+//! `toosmall` as usual.
+//!
+//! `needkey` suspends the conversation rather than ending it. The template
+//! is kept as a `KeyRequest` saying which protocol and domain need a key and
+//! which attributes to prompt for; `Factotum::supply_key` adds the answer
+//! and replays the verb that stopped, as auth_proxy's getkey does.
+//!
+//! A key marked `confirm` is asked about the same way before each use: the
+//! template is the key's public attributes, with nothing to fill in, and
+//! supplying it back lets that one replay use it. Not supplying it is
+//! saying no.
+//!
+//! `AuthProxy` runs a conversation over a byte stream the caller feeds
+//! and drains, sizing each `write` by `toosmall`.
+//!
+//! Besides the client protocols, `start proto=rsa role=sign` runs the RSA
+//! signer: write a digest, read back the signature.
+//!
+//! Ported from 9front's factotum rpc.c and libauth auth_proxy.c.
+
+use std::fmt;
+
+use crate::keycache;
+use crate::keyring::{format_attrs, parse_attrs, Attr, AttrType, Key, Keyring, KeyringError};
+use crate::p9any::P9anyClient;
+use crate::p9sk1::{TicketClient, TicketProto};
+use crate::rsa::RsaSigner;
+use crate::transcript::Transcript;
+
+pub const AUTHRPCMAX: usize = 4096; // Largest message through the rpc file
+
+/// What an authentication established (libauth's AuthInfo).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthInfo {
+    /// Caller's id
+    pub cuid: String,
+    /// Server's id
+    pub suid: String,
+    /// Capability, only for the server role
+    pub cap: String,
+    /// Session secret shared with the other end
+    pub secret: Vec<u8>,
+}
+
+impl AuthInfo {
+    /// Marshal as factotum's authinfo reply (convAI2M): each field with a
+    /// 2-byte little-endian length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for field in [
+            self.cuid.as_bytes(),
+            self.suid.as_bytes(),
+            self.cap.as_bytes(),
+        ] {
+            out.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            out.extend_from_slice(field);
+        }
+        out.extend_from_slice(&(self.secret.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.secret);
+        out
+    }
+
+    /// Unmarshal an authinfo reply (convM2AI).
+    pub fn from_bytes(buf: &[u8]) -> Option<AuthInfo> {
+        let mut fields = Vec::with_capacity(4);
+        let mut p = buf;
+        for _ in 0..4 {
+            let n = u16::from_le_bytes(p.get(..2)?.try_into().unwrap()) as usize;
+            fields.push(p.get(2..2 + n)?.to_vec());
+            p = &p[2 + n..];
+        }
+        let secret = fields.pop().unwrap();
+        let mut strs = fields
+            .into_iter()
+            .map(|f| String::from_utf8_lossy(&f).to_string());
+        Some(AuthInfo {
+            cuid: strs.next().unwrap(),
+            suid: strs.next().unwrap(),
+            cap: strs.next().unwrap(),
+            secret,
+        })
+    }
+}
+
+/// A reply from the rpc file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcReply {
+    /// Success, with data to send to the peer for `read`
+    Ok(Vec<u8>),
+    /// Authentication finished; `haveai` if authinfo can be read
+    Done {
+        haveai: bool,
+    },
+    Error(String),
+    /// No key matches; the attribute template says what is wanted
+    Needkey(String),
+    /// `write` needs this many bytes in total
+    Toosmall(usize),
+    /// Wrong verb for the protocol state: `read` when input is expected
+    Phase(String),
+    /// Not in factotum: send `data` to the auth server for `dom`, then
+    /// `write` its answer
+    Authsrv {
+        dom: String,
+        data: Vec<u8>,
+    },
+}
+
+impl RpcReply {
+    pub fn verb(&self) -> &'static str {
+        match self {
+            RpcReply::Ok(_) => "ok",
+            RpcReply::Done { .. } => "done",
+            RpcReply::Error(_) => "error",
+            RpcReply::Needkey(_) => "needkey",
+            RpcReply::Toosmall(_) => "toosmall",
+            RpcReply::Phase(_) => "phase",
+            RpcReply::Authsrv { .. } => "authsrv",
+        }
+    }
+
+    /// The argument after the verb, as factotum would write it.
+    pub fn arg(&self) -> Vec<u8> {
+        match self {
+            RpcReply::Ok(data) => data.clone(),
+            RpcReply::Done { haveai: true } => b"haveai".to_vec(),
+            RpcReply::Done { haveai: false } => Vec::new(),
+            RpcReply::Error(s) | RpcReply::Needkey(s) | RpcReply::Phase(s) => s.as_bytes().to_vec(),
+            RpcReply::Toosmall(n) => n.to_string().into_bytes(),
+            RpcReply::Authsrv { data, .. } => data.clone(),
+        }
+    }
+}
+
+impl fmt::Display for RpcReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcReply::Ok(data) => write!(f, "ok ({} bytes)", data.len()),
+            RpcReply::Authsrv { dom, data } => write!(f, "authsrv {} ({} bytes)", dom, data.len()),
+            r => write!(f, "{} {}", r.verb(), String::from_utf8_lossy(&r.arg())),
+        }
+    }
+}
+
+/// A client protocol as the rpc file sees it.
+///
+/// `write` is always handed everything received so far for the current
+/// message, and answers `Toosmall` until that is enough.
+pub trait Proto {
+    fn read(&mut self, ring: &Keyring) -> RpcReply;
+    fn write(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply;
+    fn authinfo(&self) -> Option<&AuthInfo>;
+    /// Attributes describing the conversation, for the `attr` verb.
+    fn attrs(&self) -> Vec<Attr>;
+}
+
+pub(crate) fn phase_error(msg: &str) -> RpcReply {
+    RpcReply::Phase(format!("protocol phase error: {}", msg))
+}
+
+/// The key template a client protocol asks for: `proto` and `dom` as
+/// given, the caller's other start attributes, and a user and password.
+pub(crate) fn key_query(proto: &str, dom: Option<&str>, start: &[Attr]) -> Vec<Attr> {
+    let mut q = vec![Attr::new("proto", proto)];
+    if let Some(dom) = dom {
+        q.push(Attr::new("dom", dom));
+    }
+    q.push(Attr::new("role", "client"));
+    q.extend(
+        start
+            .iter()
+            .filter(|a| !matches!(a.name.as_str(), "proto" | "role" | "dom"))
+            .cloned(),
+    );
+    for name in ["user", "!password"] {
+        if !q.iter().any(|a| a.name == name) {
+            q.push(Attr {
+                attr_type: AttrType::Query,
+                name: name.to_string(),
+                val: String::new(),
+            });
+        }
+    }
+    q
+}
+
+/// Find a usable key for a query, or the needkey reply asking for one,
+/// or for approval of a `confirm` key.
+pub(crate) fn find_key<'a>(ring: &'a Keyring, query: &[Attr]) -> Result<&'a Key, RpcReply> {
+    let pattern = format_attrs(query);
+    match ring
+        .find_all(&pattern)
+        .into_iter()
+        .find(|k| !k.is_disabled())
+    {
+        Some(k) if k.needs_confirm() && !ring.is_approved(k) => {
+            Err(RpcReply::Needkey(format_attrs(&k.attrs)))
+        }
+        Some(k) => Ok(k),
+        None => Err(RpcReply::Needkey(pattern)),
+    }
+}
+
+/// Whether a query has a key, approved for use or not.
+pub(crate) fn has_key(ring: &Keyring, query: &[Attr]) -> bool {
+    ring.find_all(&format_attrs(query))
+        .iter()
+        .any(|k| !k.is_disabled())
+}
+
+/// A needkey template taken apart for a prompt: which protocol and domain
+/// want a key, and which attributes the user has to supply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRequest {
+    pub proto: String,
+    pub dom: Option<String>,
+    /// The whole template; queries (`user?`, `!password?`) are the blanks.
+    pub attrs: Vec<Attr>,
+}
+
+impl KeyRequest {
+    pub fn from_template(template: &str) -> KeyRequest {
+        let attrs = parse_attrs(template);
+        let get = |name: &str| {
+            attrs
+                .iter()
+                .find(|a| a.attr_type == AttrType::Nameval && a.name == name)
+                .map(|a| a.val.clone())
+        };
+        KeyRequest {
+            proto: get("proto").unwrap_or_default(),
+            dom: get("dom"),
+            attrs,
+        }
+    }
+
+    /// The template as factotum writes it after `needkey`.
+    pub fn template(&self) -> String {
+        format_attrs(&self.attrs)
+    }
+
+    /// Whether this asks to use a `confirm` key rather than for a new
+    /// one; `answer(&[])` approves it.
+    pub fn is_confirm(&self) -> bool {
+        self.attrs
+            .iter()
+            .any(|a| a.attr_type == AttrType::Nameval && a.name == "confirm")
+    }
+
+    /// Names to prompt for; those starting with `!` are secret.
+    pub fn prompts(&self) -> Vec<&str> {
+        self.attrs
+            .iter()
+            .filter(|a| a.attr_type == AttrType::Query)
+            .map(|a| a.name.as_str())
+            .collect()
+    }
+
+    /// Fill in the blanks to make the key (auth_getkey).
+    pub fn answer(&self, values: &[(&str, &str)]) -> Result<Key, KeyringError> {
+        let mut key = Key::parse(&format_attrs(
+            &self
+                .attrs
+                .iter()
+                .filter(|a| a.attr_type == AttrType::Nameval)
+                .cloned()
+                .collect::<Vec<_>>(),
+        ))?;
+        for name in self.prompts() {
+            match values.iter().find(|(n, _)| *n == name) {
+                Some((_, val)) if !val.is_empty() => key.set(name, val),
+                _ => return Err(KeyringError(format!("no value for {}", name))),
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// An emulated factotum: a keyring and at most one conversation.
+pub struct Factotum {
+    ring: Keyring,
+    proto: Option<Box<dyn Proto>>,
+    /// The verb that answered needkey, to replay once the key is in
+    pending: Option<(String, Vec<u8>, KeyRequest)>,
+    refuse_p9sk1: bool,
+    transcript: Option<Transcript>,
+}
+
+impl Factotum {
+    pub fn new(ring: Keyring) -> Self {
+        Factotum {
+            ring,
+            proto: None,
+            pending: None,
+            refuse_p9sk1: false,
+            transcript: None,
+        }
+    }
+
+    /// Note conversations from now on, secrets redacted; any earlier
+    /// transcript is dropped.
+    pub fn start_transcript(&mut self) {
+        self.transcript = Some(Transcript::new());
+    }
+
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Refuse p9sk1, in p9any and when started directly, leaving dp9ik.
+    pub fn set_refuse_p9sk1(&mut self, refuse: bool) {
+        self.refuse_p9sk1 = refuse;
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.ring
+    }
+
+    pub fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.ring
+    }
+
+    /// Add a key, noting it in the transcript.
+    pub fn add_key(&mut self, key: Key) {
+        if let Some(t) = &mut self.transcript {
+            t.key(&key);
+        }
+        self.ring.add(key);
+    }
+
+    /// A write to factotum's ctl file: `key attrs...` or `delkey attrs...`.
+    pub fn ctl(&mut self, line: &str) -> Result<(), KeyringError> {
+        let line = line.trim();
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match verb {
+            "key" => {
+                self.add_key(Key::parse(rest)?);
+                Ok(())
+            }
+            "delkey" => {
+                if parse_attrs(rest).iter().any(|a| a.is_private()) {
+                    return Err(KeyringError(
+                        "cannot specify private attribute in delkey".to_string(),
+                    ));
+                }
+                // What was derived from a deleted key goes with it
+                keycache::with(|c| c.purge());
+                match self.ring.delete(rest) {
+                    0 => Err(KeyringError("found no keys to delete".to_string())),
+                    _ => Ok(()),
+                }
+            }
+            _ => Err(KeyringError(format!("unknown ctl verb {:?}", verb))),
+        }
+    }
+
+    /// One transaction on the rpc file.
+    pub fn rpc(&mut self, verb: &str, arg: &[u8]) -> RpcReply {
+        let reply = self.dispatch(verb, arg);
+        // An approval is good for one use
+        self.ring.clear_approval();
+        if let Some(t) = &mut self.transcript {
+            let ai = self.proto.as_ref().and_then(|p| p.authinfo());
+            t.rpc(verb, arg, &reply, ai);
+        }
+        self.pending = match &reply {
+            RpcReply::Needkey(template) => Some((
+                verb.to_string(),
+                arg.to_vec(),
+                KeyRequest::from_template(template),
+            )),
+            _ => None,
+        };
+        reply
+    }
+
+    /// What the finished conversation established, if it is done.
+    pub fn authinfo(&self) -> Option<&AuthInfo> {
+        self.proto.as_ref().and_then(|p| p.authinfo())
+    }
+
+    /// The key the suspended conversation is waiting for, if any.
+    pub fn key_request(&self) -> Option<&KeyRequest> {
+        self.pending.as_ref().map(|(_, _, req)| req)
+    }
+
+    /// Add the key a needkey asked for and resume: the verb that stopped
+    /// is run again and its reply returned, which may ask for another key.
+    /// For a `confirm` request the key handed back approves that one use.
+    pub fn supply_key(&mut self, key: Key) -> RpcReply {
+        match self.answer_key(key) {
+            Ok((verb, arg)) => self.rpc(&verb, &arg),
+            Err(r) => r,
+        }
+    }
+
+    /// Take the answer to the pending request, returning the verb to
+    /// replay: a new key is added, a `confirm` key approved.
+    fn answer_key(&mut self, key: Key) -> Result<(String, Vec<u8>), RpcReply> {
+        let (verb, arg, req) = match self.pending.take() {
+            Some(p) => p,
+            None => return Err(RpcReply::Error("no key request pending".to_string())),
+        };
+        if req.is_confirm() {
+            if !key.matches(&req.attrs) {
+                self.pending = Some((verb, arg, req));
+                return Err(RpcReply::Error("not the key to confirm".to_string()));
+            }
+            self.ring.approve(&req.attrs);
+        } else {
+            self.add_key(key);
+        }
+        Ok((verb, arg))
+    }
+
+    fn dispatch(&mut self, verb: &str, arg: &[u8]) -> RpcReply {
+        match verb {
+            "start" => self.start(&String::from_utf8_lossy(arg)),
+            "read" => match &mut self.proto {
+                Some(p) => p.read(&self.ring),
+                None => phase_error("no current protocol"),
+            },
+            "write" => match &mut self.proto {
+                Some(p) => p.write(&self.ring, arg),
+                None => phase_error("no current protocol"),
+            },
+            "authinfo" => match self.authinfo() {
+                Some(ai) => RpcReply::Ok(ai.to_bytes()),
+                None => RpcReply::Error("no authinfo available".to_string()),
+            },
+            "attr" => match &self.proto {
+                Some(p) => RpcReply::Ok(format_attrs(&p.attrs()).into_bytes()),
+                None => phase_error("no current protocol"),
+            },
+            _ => RpcReply::Error("unknown verb".to_string()),
+        }
+    }
+
+    fn start(&mut self, args: &str) -> RpcReply {
+        self.proto = None;
+        let attrs = parse_attrs(args);
+        let get = |name: &str| {
+            attrs
+                .iter()
+                .find(|a| a.attr_type == AttrType::Nameval && a.name == name)
+                .map(|a| a.val.as_str())
+        };
+        let proto = match get("proto") {
+            Some(p) => p,
+            None => return RpcReply::Error("start: no proto specified".to_string()),
+        };
+        match (proto, get("role")) {
+            ("rsa", Some("sign")) | (_, Some("client")) => {}
+            (_, Some(role)) => return RpcReply::Error(format!("unknown role {}", role)),
+            (_, None) => return RpcReply::Error("role not specified".to_string()),
+        }
+        let p: Box<dyn Proto> = match proto {
+            "rsa" if get("role") == Some("sign") => Box::new(RsaSigner::new(attrs.clone())),
+            "p9any" => Box::new(P9anyClient::new(attrs.clone(), self.refuse_p9sk1)),
+            "p9sk1" if self.refuse_p9sk1 => {
+                return RpcReply::Error("p9sk1 refused by policy".to_string())
+            }
+            "p9sk1" => Box::new(TicketClient::new(TicketProto::P9sk1, None, attrs.clone())),
+            "dp9ik" => Box::new(TicketClient::new(TicketProto::Dp9ik, None, attrs.clone())),
+            _ => return RpcReply::Error(format!("unknown proto {}", proto)),
+        };
+        self.proto = Some(p);
+        RpcReply::Ok(Vec::new())
+    }
+}
+
+/// Where an `AuthProxy` conversation stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyState {
+    /// Waiting for bytes from the peer
+    Peer,
+    /// Waiting for the auth server's answer to `take_authsrv`
+    Authsrv,
+    /// Waiting for `supply_key`; `Factotum::key_request` says what for
+    Key,
+    Done,
+    Failed(String),
+}
+
+/// libauth's auth_proxy without the I/O: runs a `Factotum` conversation
+/// over a byte stream fed with `put`, handing `write` only as many bytes
+/// as each `toosmall` asks for.
+pub struct AuthProxy {
+    f: Factotum,
+    state: ProxyState,
+    /// Some(authsrv) while `write`ing a message from the peer or authsrv
+    writing: Option<bool>,
+    buf: Vec<u8>,
+    /// Bytes the last `toosmall` is still missing
+    need: usize,
+    peer_in: Vec<u8>,
+    authsrv_in: Vec<u8>,
+    out: Vec<u8>,
+    authsrv_out: Option<RpcReply>,
+    ai: Option<AuthInfo>,
+}
+
+impl AuthProxy {
+    /// Start `params` (such as "proto=p9any role=client") on `f`.
+    pub fn new(mut f: Factotum, params: &str) -> Self {
+        let state = match f.rpc("start", params.as_bytes()) {
+            RpcReply::Ok(_) => ProxyState::Peer,
+            r => ProxyState::Failed(r.to_string()),
+        };
+        let mut p = AuthProxy {
+            f,
+            state,
+            writing: None,
+            buf: Vec::new(),
+            need: 0,
+            peer_in: Vec::new(),
+            authsrv_in: Vec::new(),
+            out: Vec::new(),
+            authsrv_out: None,
+            ai: None,
+        };
+        p.run();
+        p
+    }
+
+    pub fn state(&self) -> &ProxyState {
+        &self.state
+    }
+
+    pub fn factotum(&self) -> &Factotum {
+        &self.f
+    }
+
+    pub fn into_factotum(self) -> Factotum {
+        self.f
+    }
+
+    pub fn authinfo(&self) -> Option<&AuthInfo> {
+        self.ai.as_ref()
+    }
+
+    /// How many more bytes a `Peer` or `Authsrv` state is waiting for,
+    /// for callers that must size their reads.
+    pub fn need(&self) -> usize {
+        match self.state {
+            ProxyState::Peer | ProxyState::Authsrv => self.need,
+            _ => 0,
+        }
+    }
+
+    /// Bytes to send to the peer.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    /// The `authsrv` reply to act on, once.
+    pub fn take_authsrv(&mut self) -> Option<RpcReply> {
+        self.authsrv_out.take()
+    }
+
+    /// Bytes from the peer.
+    pub fn put(&mut self, data: &[u8]) {
+        self.peer_in.extend_from_slice(data);
+        self.run();
+    }
+
+    /// Bytes from the auth server.
+    pub fn put_authsrv(&mut self, data: &[u8]) {
+        self.authsrv_in.extend_from_slice(data);
+        self.run();
+    }
+
+    /// Add the key a `Key` state asked for, or approve the `confirm` key
+    /// it asked about, and carry on.
+    pub fn supply_key(&mut self, key: Key) {
+        if self.state == ProxyState::Key && self.f.answer_key(key).is_ok() {
+            self.run();
+        }
+    }
+
+    /// Add a key for later without resuming; a `Key` state still waits
+    /// for `supply_key`.
+    pub fn add_key(&mut self, key: Key) {
+        if !matches!(self.state, ProxyState::Done | ProxyState::Failed(_)) {
+            self.f.add_key(key);
+        }
+    }
+
+    /// Peer bytes that arrived after the conversation ended, for whatever
+    /// runs on the connection next.
+    pub fn leftover(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.peer_in)
+    }
+
+    fn run(&mut self) {
+        if matches!(self.state, ProxyState::Done | ProxyState::Failed(_)) {
+            return;
+        }
+        loop {
+            let reply = match self.writing {
+                None => self.f.rpc("read", b""),
+                Some(_) => self.f.rpc("write", &self.buf),
+            };
+            match (reply, self.writing) {
+                (RpcReply::Ok(data), None) => self.out.extend(data),
+                (RpcReply::Ok(_), Some(_)) => {
+                    self.writing = None;
+                    self.buf.clear();
+                }
+                (RpcReply::Phase(_), None) => self.writing = Some(false),
+                (r @ RpcReply::Authsrv { .. }, None) => {
+                    self.authsrv_in.clear();
+                    self.authsrv_out = Some(r);
+                    self.writing = Some(true);
+                }
+                (RpcReply::Toosmall(m), Some(authsrv))
+                    if m > self.buf.len() && m <= AUTHRPCMAX =>
+                {
+                    let src = if authsrv {
+                        &mut self.authsrv_in
+                    } else {
+                        &mut self.peer_in
+                    };
+                    self.need = m - self.buf.len();
+                    let n = self.need.min(src.len());
+                    if n == 0 {
+                        self.state = if authsrv {
+                            ProxyState::Authsrv
+                        } else {
+                            ProxyState::Peer
+                        };
+                        return;
+                    }
+                    self.buf.extend(src.drain(..n));
+                }
+                (RpcReply::Done { haveai: true }, _) => {
+                    self.ai = self.f.authinfo().cloned();
+                    self.state = ProxyState::Done;
+                    return;
+                }
+                (RpcReply::Needkey(_), _) => {
+                    self.state = ProxyState::Key;
+                    return;
+                }
+                (RpcReply::Error(e), _) => {
+                    self.state = ProxyState::Failed(e);
+                    return;
+                }
+                (r, _) => {
+                    self.state = ProxyState::Failed(format!("auth_proxy: unexpected {}", r));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsrv::{proxy, TestAuthsrv, TestServer};
+
+    fn setup() -> (Factotum, TestAuthsrv) {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let ring =
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens\n").unwrap();
+        (Factotum::new(ring), srv)
+    }
+
+    #[test]
+    fn test_authinfo_roundtrip() {
+        let ai = AuthInfo {
+            cuid: "glenda".to_string(),
+            suid: "glenda".to_string(),
+            cap: String::new(),
+            secret: vec![1, 2, 3],
+        };
+        let b = ai.to_bytes();
+        assert_eq!(&b[..8], b"\x06\x00glenda");
+        assert_eq!(AuthInfo::from_bytes(&b), Some(ai));
+        assert_eq!(AuthInfo::from_bytes(&b[..b.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_start_errors() {
+        let (mut f, _) = setup();
+        assert_eq!(f.rpc("read", b"").verb(), "phase");
+        assert_eq!(f.rpc("start", b"role=client").verb(), "error");
+        assert_eq!(f.rpc("start", b"proto=p9any").verb(), "error");
+        assert_eq!(f.rpc("start", b"proto=p9any role=server").verb(), "error");
+        assert_eq!(f.rpc("start", b"proto=rsa role=client").verb(), "error");
+        assert_eq!(
+            f.rpc("start", b"proto=p9any role=client"),
+            RpcReply::Ok(vec![])
+        );
+        assert_eq!(f.rpc("authinfo", b"").verb(), "error");
+        assert_eq!(f.rpc("bogus", b"").verb(), "error");
+    }
+
+    #[test]
+    fn test_ctl() {
+        let (mut f, _) = setup();
+        f.ctl("key proto=p9sk1 dom=nawin user=glenda !password=kittens")
+            .unwrap();
+        assert_eq!(f.keyring().keys().len(), 2);
+        assert!(f.ctl("delkey proto=p9sk1 !password=kittens").is_err());
+        f.ctl("delkey proto=p9sk1").unwrap();
+        assert!(f.ctl("delkey proto=p9sk1").is_err());
+        assert!(f.ctl("frob").is_err());
+    }
+
+    #[test]
+    fn test_p9any_dp9ik() {
+        let (mut f, srv) = setup();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ai = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        )
+        .unwrap();
+        assert_eq!(ai.cuid, "glenda");
+        assert_eq!(ai.suid, "glenda");
+        assert_eq!(ai.secret.len(), 256);
+        assert_eq!(Some(ai.secret), server.secret());
+        assert_eq!(
+            String::from_utf8(f.rpc("attr", b"").arg()).unwrap(),
+            "proto=dp9ik dom=nawin role=client user=glenda"
+        );
+    }
+
+    #[test]
+    fn test_p9any_p9sk1() {
+        let (mut f, srv) = setup();
+        f.ctl("key proto=p9sk1 dom=nawin user=glenda !password=kittens")
+            .unwrap();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin");
+        let ai = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        )
+        .unwrap();
+        assert_eq!(ai.cuid, "glenda");
+        assert_eq!(ai.secret.len(), 8);
+        assert_eq!(Some(ai.secret), server.secret());
+    }
+
+    #[test]
+    fn test_needkey_then_retry() {
+        let (mut f, srv) = setup();
+        f.ctl("delkey proto=dp9ik").unwrap();
+        let want = "proto=dp9ik dom=nawin role=client user? !password?";
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let err = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        );
+        assert_eq!(err, Err(RpcReply::Needkey(want.to_string())));
+
+        // auth_proxy's getkey adds the key and retries the same verb
+        let mut asked = Vec::new();
+        let mut getkey = |q: &str| {
+            asked.push(q.to_string());
+            Some("key proto=dp9ik dom=nawin user=glenda !password=kittens".to_string())
+        };
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ai = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut getkey,
+        )
+        .unwrap();
+        assert_eq!(asked, vec![want.to_string()]);
+        assert_eq!(Some(ai.secret), server.secret());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let (mut f, srv) = setup();
+        f.ctl("key proto=dp9ik dom=nawin user=glenda !password=puppies")
+            .unwrap();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let err = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        )
+        .unwrap_err();
+        assert_eq!(err.verb(), "error");
+    }
+
+    #[test]
+    fn test_key_request() {
+        let req = KeyRequest::from_template("proto=dp9ik dom=nawin role=client user? !password?");
+        assert_eq!(req.proto, "dp9ik");
+        assert_eq!(req.dom.as_deref(), Some("nawin"));
+        assert_eq!(req.prompts(), vec!["user", "!password"]);
+        assert_eq!(
+            req.template(),
+            "proto=dp9ik dom=nawin role=client user? !password?"
+        );
+        assert!(req.answer(&[("user", "glenda")]).is_err());
+        let key = req
+            .answer(&[("user", "glenda"), ("!password", "kittens")])
+            .unwrap();
+        assert_eq!(key.user(), Some("glenda"));
+        assert_eq!(key.password(), Some("kittens"));
+        assert_eq!(key.get("dom"), Some("nawin"));
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        let (mut f, srv) = setup();
+        f.ctl("delkey proto=dp9ik").unwrap();
+        assert_eq!(
+            f.supply_key(Key::parse("proto=dp9ik").unwrap()).verb(),
+            "error"
+        );
+
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let err = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        );
+        assert_eq!(err.unwrap_err().verb(), "needkey");
+        let req = f.key_request().unwrap().clone();
+        assert_eq!(
+            (req.proto.as_str(), req.dom.as_deref()),
+            ("dp9ik", Some("nawin"))
+        );
+
+        // The replayed write carries on with the server's ticket request
+        let key = req
+            .answer(&[("user", "glenda"), ("!password", "kittens")])
+            .unwrap();
+        match f.supply_key(key) {
+            RpcReply::Ok(_) => {}
+            r => panic!("unexpected {}", r),
+        }
+        assert!(f.key_request().is_none());
+        assert_eq!(f.rpc("read", b"").verb(), "authsrv");
+    }
+
+    #[test]
+    fn test_confirm_key() {
+        let (mut f, srv) = setup();
+        f.ctl("delkey proto=dp9ik").unwrap();
+        f.ctl("key proto=dp9ik dom=nawin user=glenda confirm !password=kittens")
+            .unwrap();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let err = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        );
+        // Refusing is not supplying: the key was never used
+        let template = "proto=dp9ik dom=nawin user=glenda confirm";
+        assert_eq!(err, Err(RpcReply::Needkey(template.to_string())));
+        assert_eq!(server.secret(), None);
+        let req = f.key_request().unwrap().clone();
+        assert!(req.is_confirm());
+        assert!(req.prompts().is_empty());
+
+        // Anything but the key itself is no answer
+        let other = Key::parse("proto=dp9ik dom=nawin user=bootes confirm").unwrap();
+        assert_eq!(f.supply_key(other).verb(), "error");
+        match f.supply_key(req.answer(&[]).unwrap()) {
+            RpcReply::Ok(_) => {}
+            r => panic!("unexpected {}", r),
+        }
+        assert_eq!(f.rpc("read", b"").verb(), "authsrv");
+        // The key and its secret are still there, approved no longer
+        assert_eq!(f.keyring().keys()[0].password(), Some("kittens"));
+        assert!(f.keyring().find("proto=dp9ik user=glenda confirm").is_err());
+    }
+
+    #[test]
+    fn test_refuse_p9sk1() {
+        let (mut f, srv) = setup();
+        f.ctl("key proto=p9sk1 dom=nawin user=glenda !password=kittens")
+            .unwrap();
+        f.set_refuse_p9sk1(true);
+        assert_eq!(f.rpc("start", b"proto=p9sk1 role=client").verb(), "error");
+
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin dp9ik@nawin");
+        let ai = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        )
+        .unwrap();
+        assert_eq!(ai.secret.len(), 256);
+
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin");
+        let err = proxy(
+            &mut f,
+            "proto=p9any role=client",
+            &mut server,
+            &srv,
+            &mut |_| None,
+        );
+        assert_eq!(err.unwrap_err().verb(), "error");
+    }
+
+    #[test]
+    fn test_rsa_sign() {
+        use crate::rsa::RsaPriv;
+        use num_bigint::BigUint;
+
+        let key = RsaPriv::from_primes(
+            BigUint::from(4294967291u64),
+            BigUint::from(4294967279u64),
+            BigUint::from(65537u32),
+        );
+        // 64 bits is too small for a DigestInfo; the key still loads
+        let mut f = Factotum::new(Keyring::new());
+        f.ctl(&key.unwrap().to_key().to_line()).unwrap();
+        assert_eq!(f.rpc("start", b"proto=rsa role=client").verb(), "error");
+        assert_eq!(f.rpc("start", b"proto=rsa role=sign").verb(), "ok");
+        assert_eq!(f.rpc("write", &[0u8; 20]).verb(), "error");
+
+        assert_eq!(f.rpc("start", b"proto=p9sk1 role=sign").verb(), "error");
+    }
+
+    #[test]
+    fn test_key_cache_reconnect() {
+        let (mut f, srv) = setup();
+        keycache::with(|c| c.set_ttl(600));
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let p = "proto=p9any role=client";
+        proxy(&mut f, p, &mut server, &srv, &mut |_| None).unwrap();
+        assert_eq!(keycache::with(|c| c.len()), 1);
+
+        // A new factotum with no keys reconnects on the cached ones
+        let mut f = Factotum::new(Keyring::new());
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ai = proxy(&mut f, p, &mut server, &srv, &mut |_| None).unwrap();
+        assert_eq!(ai.cuid, "glenda");
+
+        keycache::with(|c| c.purge());
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let err = proxy(&mut f, p, &mut server, &srv, &mut |_| None);
+        assert_eq!(err.unwrap_err().verb(), "needkey");
+    }
+
+    #[test]
+    fn test_auth_proxy_stream() {
+        let (_, srv) = setup();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin");
+        let mut p = AuthProxy::new(Factotum::new(Keyring::new()), "proto=p9any role=client");
+        // The server's bytes a few at a time, and a key only when asked
+        while *p.state() != ProxyState::Done {
+            match p.state().clone() {
+                ProxyState::Peer => {
+                    assert!(p.need() > 0);
+                    server.put(&p.take());
+                    p.put(&server.take(5));
+                }
+                ProxyState::Key => {
+                    assert_eq!(p.need(), 0);
+                    let req = p.factotum().key_request().unwrap().clone();
+                    assert_eq!(req.proto, "p9sk1");
+                    let key = req
+                        .answer(&[("user", "glenda"), ("!password", "kittens")])
+                        .unwrap();
+                    p.supply_key(key);
+                }
+                ProxyState::Authsrv => {
+                    let data = match p.take_authsrv() {
+                        Some(RpcReply::Authsrv { dom, data }) if dom == "nawin" => data,
+                        r => panic!("{:?}", r),
+                    };
+                    p.put_authsrv(&srv.serve(&data));
+                }
+                s => panic!("{:?}", s),
+            }
+        }
+        server.put(&p.take());
+        assert_eq!(p.authinfo().unwrap().secret, server.secret().unwrap());
+        assert!(p.leftover().is_empty());
+
+        let p = AuthProxy::new(Factotum::new(Keyring::new()), "proto=p9any");
+        assert!(matches!(p.state(), ProxyState::Failed(_)));
+    }
+}
@@ -0,0 +1,465 @@
+//! rsa - factotum RSA keys and PKCS#1 v1.5 signing
+//!
+//! ssh and tls keep their RSA keys in factotum, numbers in hex:
+//!
+//! ```text
+//! key proto=rsa size=1024 ek=10001 n=... !dk=... !p=... !q=... !kp=... !kq=... !c2=...
+//! ```
+//!
+//! `kp` and `kq` are `dk` reduced mod p-1 and q-1 and `c2` is p⁻¹ mod q;
+//! they are computed when missing. The private operation runs under a
+//! random blinding factor, by the Chinese remainder theorem, and is checked
+//! with the public exponent before the result is let out.
+//!
+//! `RsaSigner` is the `proto=rsa role=sign` conversation: write a digest,
+//! read the signature.
+//!
+//! Ported from 9front's factotum rsa.c and libsec rsadecrypt.c.
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+
+use crate::keyring::{Attr, AttrType, Key, Keyring, KeyringError};
+use crate::rpc::{self, find_key, AuthInfo, Proto, RpcReply};
+
+/// Error type for RSA operations
+#[derive(Debug)]
+pub struct RsaError(pub String);
+
+impl std::fmt::Display for RsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RsaError {}
+
+/// The digest a signature covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsaHash {
+    Sha1,
+    Sha256,
+}
+
+impl RsaHash {
+    pub fn from_name(name: &str) -> Option<RsaHash> {
+        match name {
+            "sha1" => Some(RsaHash::Sha1),
+            "sha256" => Some(RsaHash::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn digest_len(self) -> usize {
+        match self {
+            RsaHash::Sha1 => 20,
+            RsaHash::Sha256 => 32,
+        }
+    }
+
+    /// DER of the DigestInfo up to the digest itself (RFC 8017 9.2).
+    fn digest_info(self) -> &'static [u8] {
+        match self {
+            RsaHash::Sha1 => &[
+                0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04,
+                0x14,
+            ],
+            RsaHash::Sha256 => &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+        }
+    }
+}
+
+/// EMSA-PKCS1-v1_5: 00 01 ff.. 00 DigestInfo digest, `k` bytes long.
+fn pkcs1_pad(hash: RsaHash, digest: &[u8], k: usize) -> Result<Vec<u8>, RsaError> {
+    if digest.len() != hash.digest_len() {
+        return Err(RsaError(format!("digest is {} bytes", digest.len())));
+    }
+    let info = hash.digest_info();
+    let tlen = info.len() + digest.len();
+    if k < tlen + 11 {
+        return Err(RsaError("key too small for digest".to_string()));
+    }
+    let mut em = vec![0xffu8; k];
+    em[0] = 0;
+    em[1] = 1;
+    em[k - tlen - 1] = 0;
+    em[k - tlen..k - digest.len()].copy_from_slice(info);
+    em[k - digest.len()..].copy_from_slice(digest);
+    Ok(em)
+}
+
+/// An RSA public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaPub {
+    pub n: BigUint,
+    pub ek: BigUint,
+}
+
+impl RsaPub {
+    /// Modulus length in bytes
+    pub fn len(&self) -> usize {
+        self.n.bits().div_ceil(8) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n.is_zero()
+    }
+
+    /// Check a PKCS#1 v1.5 signature.
+    pub fn verify(&self, hash: RsaHash, digest: &[u8], sig: &[u8]) -> bool {
+        let k = self.len();
+        let em = match pkcs1_pad(hash, digest, k) {
+            Ok(em) => em,
+            Err(_) => return false,
+        };
+        let s = BigUint::from_bytes_be(sig);
+        sig.len() == k && s < self.n && s.modpow(&self.ek, &self.n) == BigUint::from_bytes_be(&em)
+    }
+}
+
+/// An RSA private key in factotum's form (RSApriv)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaPriv {
+    pub public: RsaPub,
+    pub dk: BigUint,
+    pub p: BigUint,
+    pub q: BigUint,
+    pub kp: BigUint,
+    pub kq: BigUint,
+    pub c2: BigUint,
+}
+
+fn hex_attr(key: &Key, name: &str) -> Result<BigUint, KeyringError> {
+    let v = key
+        .get(name)
+        .ok_or_else(|| KeyringError(format!("rsa key missing {}", name)))?;
+    BigUint::parse_bytes(v.as_bytes(), 16)
+        .ok_or_else(|| KeyringError(format!("rsa key {} is not hex", name)))
+}
+
+fn opt_hex_attr(key: &Key, name: &str) -> Result<Option<BigUint>, KeyringError> {
+    match key.get(name) {
+        Some(_) => hex_attr(key, name).map(Some),
+        None => Ok(None),
+    }
+}
+
+impl RsaPriv {
+    /// Complete a key from its primes and public exponent.
+    pub fn from_primes(p: BigUint, q: BigUint, ek: BigUint) -> Result<Self, RsaError> {
+        let one = BigUint::one();
+        let phi = (&p - &one) * (&q - &one);
+        let dk = ek
+            .modinv(&phi)
+            .ok_or_else(|| RsaError("public exponent not invertible".to_string()))?;
+        Self::complete(p, q, ek, dk, None, None, None)
+    }
+
+    fn complete(
+        p: BigUint,
+        q: BigUint,
+        ek: BigUint,
+        dk: BigUint,
+        kp: Option<BigUint>,
+        kq: Option<BigUint>,
+        c2: Option<BigUint>,
+    ) -> Result<Self, RsaError> {
+        let one = BigUint::one();
+        if p <= one || q <= one || p == q {
+            return Err(RsaError("bad rsa primes".to_string()));
+        }
+        let c2 = match c2 {
+            Some(c2) => c2,
+            None => p
+                .modinv(&q)
+                .ok_or_else(|| RsaError("rsa primes not coprime".to_string()))?,
+        };
+        Ok(RsaPriv {
+            public: RsaPub { n: &p * &q, ek },
+            kp: kp.unwrap_or_else(|| &dk % (&p - &one)),
+            kq: kq.unwrap_or_else(|| &dk % (&q - &one)),
+            dk,
+            p,
+            q,
+            c2,
+        })
+    }
+
+    /// Read a `proto=rsa` key; `n` must match the primes.
+    pub fn from_key(key: &Key) -> Result<Self, KeyringError> {
+        if key.proto() != "rsa" {
+            return Err(KeyringError(format!("not an rsa key: {}", key)));
+        }
+        let n = hex_attr(key, "n")?;
+        let k = Self::complete(
+            hex_attr(key, "!p")?,
+            hex_attr(key, "!q")?,
+            hex_attr(key, "ek")?,
+            hex_attr(key, "!dk")?,
+            opt_hex_attr(key, "!kp")?,
+            opt_hex_attr(key, "!kq")?,
+            opt_hex_attr(key, "!c2")?,
+        )
+        .map_err(|e| KeyringError(e.0))?;
+        if k.public.n != n {
+            return Err(KeyringError("rsa key n is not p*q".to_string()));
+        }
+        Ok(k)
+    }
+
+    /// The key line factotum would hold for this key.
+    pub fn to_key(&self) -> Key {
+        let hex = |v: &BigUint| v.to_str_radix(16);
+        let line = format!(
+            "proto=rsa size={} ek={} n={} !dk={} !p={} !q={} !kp={} !kq={} !c2={}",
+            self.public.n.bits(),
+            hex(&self.public.ek),
+            hex(&self.public.n),
+            hex(&self.dk),
+            hex(&self.p),
+            hex(&self.q),
+            hex(&self.kp),
+            hex(&self.kq),
+            hex(&self.c2),
+        );
+        Key::parse(&line).expect("rsa key line")
+    }
+
+    /// The private operation by CRT (rsadecrypt).
+    fn crt(&self, c: &BigUint) -> BigUint {
+        let v1 = (c % &self.p).modpow(&self.kp, &self.p);
+        let v2 = (c % &self.q).modpow(&self.kq, &self.q);
+        // out = v1 + p*((v2-v1)*c2 mod q)
+        let diff = (&v2 + &self.q - (&v1 % &self.q)) % &self.q;
+        v1 + &self.p * ((diff * &self.c2) % &self.q)
+    }
+
+    /// The private operation on `c`, blinded and checked.
+    pub fn decrypt(&self, c: &BigUint) -> Result<BigUint, RsaError> {
+        let RsaPub { n, ek } = &self.public;
+        let (r, rinv) = loop {
+            let mut buf = vec![0u8; self.public.len()];
+            getrandom::getrandom(&mut buf).expect("Failed to get random bytes");
+            let r = BigUint::from_bytes_be(&buf) % n;
+            if let Some(rinv) = r.modinv(n) {
+                break (r, rinv);
+            }
+        };
+        let blinded = (c * r.modpow(ek, n)) % n;
+        let m = (self.crt(&blinded) * rinv) % n;
+        if &m.modpow(ek, n) != c {
+            return Err(RsaError("rsa private operation failed check".to_string()));
+        }
+        Ok(m)
+    }
+
+    /// PKCS#1 v1.5 signature of `digest`, as long as the modulus.
+    pub fn sign(&self, hash: RsaHash, digest: &[u8]) -> Result<Vec<u8>, RsaError> {
+        let k = self.public.len();
+        let em = pkcs1_pad(hash, digest, k)?;
+        let s = self.decrypt(&BigUint::from_bytes_be(&em))?.to_bytes_be();
+        let mut sig = vec![0u8; k - s.len()];
+        sig.extend_from_slice(&s);
+        Ok(sig)
+    }
+}
+
+// ============================================================================
+// proto=rsa role=sign
+// ============================================================================
+
+enum SignState {
+    NeedDigest,
+    HaveSig(Vec<u8>),
+    Done,
+}
+
+/// factotum's RSA signer: `write` the digest named by the start's `hash=`
+/// (sha1 unless given), then `read` the signature.
+pub struct RsaSigner {
+    start: Vec<Attr>,
+    hash: Option<RsaHash>,
+    state: SignState,
+    key: Option<Key>,
+}
+
+impl RsaSigner {
+    pub fn new(start: Vec<Attr>) -> Self {
+        let hash = match start
+            .iter()
+            .find(|a| a.attr_type == AttrType::Nameval && a.name == "hash")
+        {
+            Some(a) => RsaHash::from_name(&a.val),
+            None => Some(RsaHash::Sha1),
+        };
+        RsaSigner {
+            start,
+            hash,
+            state: SignState::NeedDigest,
+            key: None,
+        }
+    }
+
+    /// The key template: the start attributes other than role and hash,
+    /// and a private exponent.
+    fn query(&self) -> Vec<Attr> {
+        let mut q: Vec<Attr> = self
+            .start
+            .iter()
+            .filter(|a| !matches!(a.name.as_str(), "role" | "hash"))
+            .cloned()
+            .collect();
+        q.push(Attr {
+            attr_type: AttrType::Query,
+            name: "!dk".to_string(),
+            val: String::new(),
+        });
+        q
+    }
+}
+
+impl Proto for RsaSigner {
+    fn read(&mut self, _ring: &Keyring) -> RpcReply {
+        match std::mem::replace(&mut self.state, SignState::Done) {
+            SignState::HaveSig(sig) => RpcReply::Ok(sig),
+            SignState::Done => RpcReply::Done { haveai: false },
+            SignState::NeedDigest => {
+                self.state = SignState::NeedDigest;
+                rpc::phase_error("write expected")
+            }
+        }
+    }
+
+    fn write(&mut self, ring: &Keyring, data: &[u8]) -> RpcReply {
+        if !matches!(self.state, SignState::NeedDigest) {
+            return rpc::phase_error("read expected");
+        }
+        let hash = match self.hash {
+            Some(h) => h,
+            None => return RpcReply::Error("unknown hash".to_string()),
+        };
+        let key = match find_key(ring, &self.query()) {
+            Ok(k) => k,
+            Err(reply) => return reply,
+        };
+        let sig = RsaPriv::from_key(key)
+            .map_err(|e| e.0)
+            .and_then(|k| k.sign(hash, data).map_err(|e| e.0));
+        match sig {
+            Ok(sig) => {
+                self.key = Some(key.clone());
+                self.state = SignState::HaveSig(sig);
+                RpcReply::Ok(Vec::new())
+            }
+            Err(e) => RpcReply::Error(e),
+        }
+    }
+
+    fn authinfo(&self) -> Option<&AuthInfo> {
+        None
+    }
+
+    /// The public half of the key used, so the caller can tell which.
+    fn attrs(&self) -> Vec<Attr> {
+        let mut attrs = vec![Attr::new("proto", "rsa"), Attr::new("role", "sign")];
+        if let Some(key) = &self.key {
+            attrs.extend(
+                key.attrs
+                    .iter()
+                    .filter(|a| a.name != "proto" && a.name != "role")
+                    .cloned(),
+            );
+        }
+        attrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::parse_attrs;
+    use sha2::{Digest, Sha256};
+
+    // A 512-bit key and signatures of "abc" made by openssl
+    const P: &str = "d0ff7ec41a3b5b04f0e205b7e81be86bbb3d5539a0aec1a91a9fd701d0db3a67";
+    const Q: &str = "c288eb886794b82562a45ff7d66983ed49efefe16f0f31d6fba2465b580f854d";
+    const N: &str = "9ed16615d9b87cd0ddc075d757f68cd288570f655ad1b13353cca22f1eff87d9\
+                     7c4faa7c0556041dc5c09d21a21912c49b5392293e3f2ed167c222968b5113fb";
+    const SIG256: &str = "11838bc00d72c5006fb03ad246065443bae745575f99c4bc6e3d2e284c3dd591\
+                          ccd09dd1122bd5badf5dba96c591a2a8e26939ef69b0f7b98ebee1a94e4cac1a";
+    const SIG1: &str = "3c606719bed0e80c0298b864a6e104caf266419fd230cb0e8900d69590b9d5bc\
+                        937d7b0be70bc2c8cd1c9da5d81bf3aefd66a4b0d950e1d8efb52d1004eafb26";
+
+    fn big(s: &str) -> BigUint {
+        BigUint::parse_bytes(s.as_bytes(), 16).unwrap()
+    }
+
+    fn test_key() -> RsaPriv {
+        RsaPriv::from_primes(big(P), big(Q), BigUint::from(65537u32)).unwrap()
+    }
+
+    #[test]
+    fn test_openssl_vectors() {
+        let k = test_key();
+        assert_eq!(k.public.n, big(N));
+        assert_eq!(k.public.len(), 64);
+
+        let d256 = Sha256::digest(b"abc");
+        let sig = k.sign(RsaHash::Sha256, &d256).unwrap();
+        assert_eq!(hex::encode(&sig), SIG256);
+        assert!(k.public.verify(RsaHash::Sha256, &d256, &sig));
+
+        let d1 = sha1::Sha1::digest(b"abc");
+        assert_eq!(hex::encode(k.sign(RsaHash::Sha1, &d1).unwrap()), SIG1);
+        assert!(!k.public.verify(RsaHash::Sha1, &d256[..20], &sig));
+    }
+
+    #[test]
+    fn test_key_line_roundtrip() {
+        let k = test_key();
+        let key = k.to_key();
+        assert_eq!(key.get("size"), Some("512"));
+        assert_eq!(key.get("ek"), Some("10001"));
+        assert_eq!(RsaPriv::from_key(&key).unwrap(), k);
+
+        // kp, kq and c2 are optional
+        let mut short = key.clone();
+        for name in ["!kp", "!kq", "!c2"] {
+            short.remove(name);
+        }
+        assert_eq!(RsaPriv::from_key(&short).unwrap(), k);
+
+        let mut bad = key.clone();
+        bad.set("n", "1234");
+        assert!(RsaPriv::from_key(&bad).is_err());
+    }
+
+    #[test]
+    fn test_bad_digest() {
+        let k = test_key();
+        assert!(k.sign(RsaHash::Sha256, b"short").is_err());
+    }
+
+    #[test]
+    fn test_signer_rpc() {
+        let line = format!("{} comment=glenda@nawin", test_key().to_key().to_line());
+        let ring = Keyring::parse(&line).unwrap();
+        let start = parse_attrs("proto=rsa role=sign hash=sha256");
+        let mut s = RsaSigner::new(start.clone());
+        assert_eq!(s.read(&ring).verb(), "phase");
+        assert_eq!(s.write(&ring, b"short").verb(), "error");
+
+        let mut s = RsaSigner::new(start.clone());
+        let digest = Sha256::digest(b"abc");
+        assert_eq!(s.write(&ring, &digest), RpcReply::Ok(vec![]));
+        assert_eq!(s.read(&ring), RpcReply::Ok(hex::decode(SIG256).unwrap()));
+        assert_eq!(s.read(&ring), RpcReply::Done { haveai: false });
+        assert!(s.attrs().contains(&Attr::new("comment", "glenda@nawin")));
+
+        let mut s = RsaSigner::new(start.clone());
+        assert_eq!(s.write(&Keyring::new(), &digest).verb(), "needkey");
+    }
+}
@@ -0,0 +1,283 @@
+//! sealed - a keyring encrypted under a PIN, for the browser to keep
+//!
+//! The browser stores the blob (in IndexedDB) and hands it back with the
+//! PIN; the keys are only ever in the clear inside WASM memory.
+//!
+//! ```text
+//! magic[8] version[1] rounds[4] salt[16] nonce[12] metalen[2] meta[metalen]
+//! ciphertext tag[16]
+//! ```
+//!
+//! Integers are big-endian. The key is PBKDF2-HMAC-SHA256 of the PIN over
+//! `rounds` and `salt`; the ciphertext is the factotum key file text under
+//! ChaCha20-Poly1305, with the whole header as associated data. `meta` is
+//! the caller's label (which user, which server) and can be read without
+//! the PIN, but not changed without breaking the tag.
+
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::keyring::Keyring;
+
+pub const SEALMAGIC: &[u8; 8] = b"9KEYRING";
+pub const SEALVERSION: u8 = 1;
+pub const SEALSALTLEN: usize = 16;
+pub const SEALNONCELEN: usize = 12;
+pub const SEALTAGLEN: usize = 16;
+pub const SEALHDRLEN: usize = 8 + 1 + 4 + SEALSALTLEN + SEALNONCELEN + 2; // 43, before meta
+pub const SEALROUNDS: u32 = 310_000; // PBKDF2 rounds for new blobs
+pub const SEALMINROUNDS: u32 = 1000; // Fewest rounds accepted
+pub const SEALMAXROUNDS: u32 = 5_000_000; // Most, so a bad blob can't hang the page
+
+/// Error type for sealing and unsealing
+#[derive(Debug)]
+pub struct SealError(pub String);
+
+impl std::fmt::Display for SealError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// The clear part of a sealed keyring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealHeader {
+    pub version: u8,
+    pub rounds: u32,
+    pub salt: [u8; SEALSALTLEN],
+    pub nonce: [u8; SEALNONCELEN],
+    pub metadata: String,
+}
+
+impl SealHeader {
+    fn new(rounds: u32, metadata: &str) -> Self {
+        let mut salt = [0u8; SEALSALTLEN];
+        let mut nonce = [0u8; SEALNONCELEN];
+        getrandom::getrandom(&mut salt).expect("Failed to get random bytes");
+        getrandom::getrandom(&mut nonce).expect("Failed to get random bytes");
+        SealHeader {
+            version: SEALVERSION,
+            rounds,
+            salt,
+            nonce,
+            metadata: metadata.to_string(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SEALHDRLEN + self.metadata.len());
+        out.extend_from_slice(SEALMAGIC);
+        out.push(self.version);
+        out.extend_from_slice(&self.rounds.to_be_bytes());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&(self.metadata.len() as u16).to_be_bytes());
+        out.extend_from_slice(self.metadata.as_bytes());
+        out
+    }
+
+    /// Parse the header of a blob; returns it and its length.
+    pub fn from_bytes(buf: &[u8]) -> Result<(Self, usize), SealError> {
+        if buf.len() < SEALHDRLEN || !buf.starts_with(SEALMAGIC) {
+            return Err(SealError("not a sealed keyring".to_string()));
+        }
+        let version = buf[8];
+        if version != SEALVERSION {
+            return Err(SealError(format!(
+                "sealed keyring version {} unsupported",
+                version
+            )));
+        }
+        let rounds = u32::from_be_bytes(buf[9..13].try_into().unwrap());
+        check_rounds(rounds)?;
+        let mut off = 13;
+        let salt = buf[off..off + SEALSALTLEN].try_into().unwrap();
+        off += SEALSALTLEN;
+        let nonce = buf[off..off + SEALNONCELEN].try_into().unwrap();
+        off += SEALNONCELEN;
+        let metalen = u16::from_be_bytes([buf[off], buf[off + 1]]) as usize;
+        off += 2;
+        let meta = buf
+            .get(off..off + metalen)
+            .ok_or_else(|| SealError("sealed keyring truncated".to_string()))?;
+        let metadata = String::from_utf8(meta.to_vec())
+            .map_err(|_| SealError("sealed keyring metadata is not UTF-8".to_string()))?;
+        Ok((
+            SealHeader {
+                version,
+                rounds,
+                salt,
+                nonce,
+                metadata,
+            },
+            off + metalen,
+        ))
+    }
+
+    fn cipher(&self, pin: &str) -> ChaCha20Poly1305 {
+        let mut key = [0u8; 32];
+        pbkdf2_hmac::<Sha256>(pin.as_bytes(), &self.salt, self.rounds, &mut key);
+        ChaCha20Poly1305::new(&key.into())
+    }
+}
+
+fn check_rounds(rounds: u32) -> Result<(), SealError> {
+    match rounds {
+        r if r < SEALMINROUNDS => Err(SealError(format!("too few rounds: {}", r))),
+        r if r > SEALMAXROUNDS => Err(SealError(format!("too many rounds: {}", r))),
+        _ => Ok(()),
+    }
+}
+
+/// Seal `ring` under `pin` with `SEALROUNDS` rounds.
+pub fn seal(ring: &Keyring, pin: &str, metadata: &str) -> Result<Vec<u8>, SealError> {
+    seal_rounds(ring, pin, metadata, SEALROUNDS)
+}
+
+/// Seal with a chosen PBKDF2 round count.
+pub fn seal_rounds(
+    ring: &Keyring,
+    pin: &str,
+    metadata: &str,
+    rounds: u32,
+) -> Result<Vec<u8>, SealError> {
+    check_rounds(rounds)?;
+    if metadata.len() > u16::MAX as usize {
+        return Err(SealError("metadata too long".to_string()));
+    }
+    // The key file has a key per line, and quoting keeps newlines
+    if ring.keys().iter().any(|k| k.to_line().contains('\n')) {
+        return Err(SealError(
+            "cannot seal a key with a newline in it".to_string(),
+        ));
+    }
+    let hdr = SealHeader::new(rounds, metadata);
+    let mut out = hdr.to_bytes();
+    let mut body = ring.to_text().into_bytes();
+    let tag = hdr
+        .cipher(pin)
+        .encrypt_in_place_detached(Nonce::from_slice(&hdr.nonce), &out, &mut body)
+        .expect("chacha20poly1305 encrypt");
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+/// Open a sealed keyring. A wrong PIN and a tampered blob look the same.
+pub fn unseal(blob: &[u8], pin: &str) -> Result<Keyring, SealError> {
+    let (hdr, hdrlen) = SealHeader::from_bytes(blob)?;
+    if blob.len() < hdrlen + SEALTAGLEN {
+        return Err(SealError("sealed keyring truncated".to_string()));
+    }
+    let (aad, rest) = blob.split_at(hdrlen);
+    let (body, tag) = rest.split_at(rest.len() - SEALTAGLEN);
+    let mut body = body.to_vec();
+    hdr.cipher(pin)
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&hdr.nonce),
+            aad,
+            &mut body,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| SealError("wrong pin or damaged keyring".to_string()))?;
+    let text =
+        String::from_utf8(body).map_err(|_| SealError("sealed keyring is not text".to_string()))?;
+    Keyring::parse(&text).map_err(|e| SealError(e.0))
+}
+
+/// The metadata of a sealed keyring, without the PIN.
+pub fn metadata(blob: &[u8]) -> Result<String, SealError> {
+    SealHeader::from_bytes(blob).map(|(hdr, _)| hdr.metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::Key;
+
+    const KEYS: &str = "key proto=dp9ik dom=nawin user=glenda !password=kittens\n\
+                        key proto=totp label=vpn !secret=GEZDGNBV\n";
+
+    fn sealed() -> Vec<u8> {
+        let ring = Keyring::parse(KEYS).unwrap();
+        seal_rounds(&ring, "1234", "glenda@nawin", SEALMINROUNDS).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let blob = sealed();
+        let ring = unseal(&blob, "1234").unwrap();
+        assert_eq!(ring.to_text(), KEYS);
+        assert_eq!(metadata(&blob).unwrap(), "glenda@nawin");
+        // Fresh salt and nonce each time
+        assert_ne!(sealed(), blob);
+    }
+
+    #[test]
+    fn test_newline_in_value() {
+        // Fine quoted on a ctl line, but it would split the key file
+        let mut ring = Keyring::new();
+        ring.add(Key::parse("proto=pass server=tenshi !password='two\nlines'").unwrap());
+        assert!(seal_rounds(&ring, "1234", "", SEALMINROUNDS).is_err());
+
+        let mut ring = Keyring::new();
+        ring.add(Key::parse("proto=pass server=tenshi !password='two lines'").unwrap());
+        let blob = seal_rounds(&ring, "1234", "", SEALMINROUNDS).unwrap();
+        assert_eq!(unseal(&blob, "1234").unwrap().keys(), ring.keys());
+    }
+
+    #[test]
+    fn test_layout() {
+        let blob = sealed();
+        let meta = "glenda@nawin".len();
+        assert_eq!(SEALHDRLEN, 43);
+        assert_eq!(&blob[..8], b"9KEYRING");
+        assert_eq!(blob[8], SEALVERSION);
+        assert_eq!(&blob[9..13], &SEALMINROUNDS.to_be_bytes());
+        assert_eq!(&blob[41..43], &(meta as u16).to_be_bytes());
+        assert_eq!(blob.len(), SEALHDRLEN + meta + KEYS.len() + SEALTAGLEN);
+        assert!(!blob.windows(7).any(|w| w == b"kittens"));
+    }
+
+    #[test]
+    fn test_wrong_pin() {
+        assert!(unseal(&sealed(), "4321").is_err());
+    }
+
+    #[test]
+    fn test_tampering() {
+        let blob = sealed();
+        // Metadata is authenticated
+        let mut b = blob.clone();
+        b[SEALHDRLEN] ^= 1;
+        assert!(unseal(&b, "1234").is_err());
+        // So is the body
+        let mut b = blob.clone();
+        b[SEALHDRLEN + 20] ^= 1;
+        assert!(unseal(&b, "1234").is_err());
+        assert!(unseal(&blob[..blob.len() - 1], "1234").is_err());
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let blob = sealed();
+        let mut b = blob.clone();
+        b[8] = 2;
+        assert!(metadata(&b).unwrap_err().0.contains("version 2"));
+        let mut b = blob.clone();
+        b[9..13].copy_from_slice(&10u32.to_be_bytes());
+        assert!(unseal(&b, "1234").is_err());
+        // Refused before any PBKDF2 is run
+        let mut b = blob.clone();
+        b[9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(unseal(&b, "1234").unwrap_err().0.contains("too many"));
+        assert!(seal_rounds(&Keyring::new(), "1234", "", SEALMAXROUNDS + 1).is_err());
+        assert!(metadata(b"9KEYRING").is_err());
+        assert!(metadata(&blob[..SEALHDRLEN + 3]).is_err());
+        assert!(seal_rounds(&Keyring::new(), "1234", "", 10).is_err());
+    }
+}
@@ -0,0 +1,771 @@
+//! secstore client
+//!
+//! secstored keeps a user's files, chiefly the factotum key file, behind a
+//! password. Login is PAK over a 1024-bit mod-p group, keyed by a slow hash
+//! of the password; afterwards both ends switch to an SSL-style record layer
+//! (RC4 with a SHA1 MAC) and the client issues GET and PUT commands.
+//! secstored never sees plaintext: files are AES-CBC encrypted on the
+//! client under a key hashed from the password (`encrypt_file`).
+//!
+//! Everything runs over a `ByteStream`, so the same code works on a TCP
+//! socket or on the browser's trampoline connection.
+//!
+//! Ported from 9front's secstore (pak.c, SConn.c, secstore.c) and libsec's
+//! aesCBCencrypt.
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use sha1::{Digest, Sha1};
+
+pub const VERSION: &str = "secstore";
+pub const MAXMSG: usize = 4096; // Largest record payload
+pub const MAXFILESIZE: i64 = 10 * 1024 * 1024; // Largest file secstored stores
+pub const SHA1DLEN: usize = 20;
+
+/// Error talking to secstored
+#[derive(Debug, Clone)]
+pub struct SecstoreError(pub String);
+
+impl std::fmt::Display for SecstoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SecstoreError {}
+
+/// A reliable, ordered byte stream to secstored.
+///
+/// Anything that is `Read + Write` already is one.
+pub trait ByteStream {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), SecstoreError>;
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), SecstoreError>;
+}
+
+impl<T: std::io::Read + std::io::Write> ByteStream for T {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), SecstoreError> {
+        self.read_exact(buf)
+            .map_err(|e| SecstoreError(format!("read: {}", e)))
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), SecstoreError> {
+        self.write_all(buf)
+            .and_then(|_| self.flush())
+            .map_err(|e| SecstoreError(format!("write: {}", e)))
+    }
+}
+
+// ============================================================================
+// Record layer (SConn.c)
+// ============================================================================
+
+/// RC4, as secstore and devssl use it
+#[derive(Clone)]
+pub(crate) struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    pub(crate) fn apply(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *b ^= self.s[k as usize];
+        }
+    }
+}
+
+/// One direction of an `SConn`
+struct Half {
+    seqno: u32,
+    keys: Option<([u8; SHA1DLEN], Rc4)>,
+}
+
+fn record_mac(secret: &[u8; SHA1DLEN], data: &[u8], seqno: u32) -> [u8; SHA1DLEN] {
+    let mut h = Sha1::new();
+    h.update(secret);
+    h.update(data);
+    h.update(seqno.to_be_bytes());
+    h.finalize().into()
+}
+
+fn hmac_sha1(data: &[u8], key: &[u8]) -> [u8; SHA1DLEN] {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Framed records, in the clear until `secret` is called.
+///
+/// Each record is a 2-byte SSL-style count (high bit set) and the payload;
+/// once keyed, the payload is preceded by a SHA1 MAC and both are RC4
+/// encrypted. Sequence numbers count from the first record, keyed or not.
+pub struct SConn<S: ByteStream> {
+    stream: S,
+    out: Half,
+    inp: Half,
+}
+
+impl<S: ByteStream> SConn<S> {
+    pub fn new(stream: S) -> Self {
+        SConn {
+            stream,
+            out: Half {
+                seqno: 0,
+                keys: None,
+            },
+            inp: Half {
+                seqno: 0,
+                keys: None,
+            },
+        }
+    }
+
+    /// Switch on encryption with the PAK session digest (SC_secret).
+    pub fn secret(&mut self, sigma: &[u8; SHA1DLEN], is_client: bool) {
+        let (out, inp) = if is_client {
+            (hmac_sha1(sigma, b"two"), hmac_sha1(sigma, b"one"))
+        } else {
+            (hmac_sha1(sigma, b"one"), hmac_sha1(sigma, b"two"))
+        };
+        // RC4 keys are restricted to 128 bits
+        self.out.keys = Some((out, Rc4::new(&out[..16])));
+        self.inp.keys = Some((inp, Rc4::new(&inp[..16])));
+    }
+
+    pub fn write_msg(&mut self, buf: &[u8]) -> Result<(), SecstoreError> {
+        if buf.is_empty() || buf.len() > MAXMSG {
+            return Err(SecstoreError(format!("SC_write invalid n {}", buf.len())));
+        }
+        let mut len = buf.len();
+        if self.out.keys.is_some() {
+            len += SHA1DLEN;
+        }
+        let mut rec = vec![0x80 | (len >> 8) as u8, len as u8];
+        match &mut self.out.keys {
+            Some((secret, rc4)) => {
+                let mut digest = record_mac(secret, buf, self.out.seqno);
+                rc4.apply(&mut digest);
+                let mut enc = buf.to_vec();
+                rc4.apply(&mut enc);
+                rec.extend_from_slice(&digest);
+                rec.extend_from_slice(&enc);
+            }
+            None => rec.extend_from_slice(buf),
+        }
+        self.stream.write_bytes(&rec)?;
+        self.out.seqno = self.out.seqno.wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn read_msg(&mut self) -> Result<Vec<u8>, SecstoreError> {
+        let mut count = [0u8; 2];
+        self.stream.read_bytes(&mut count)?;
+        if count[0] & 0x80 == 0 {
+            return Err(SecstoreError("SC_read invalid count".to_string()));
+        }
+        let mut len = ((count[0] & 0x7f) as usize) << 8 | count[1] as usize;
+        let buf = match &mut self.inp.keys {
+            Some((secret, rc4)) => {
+                if len <= SHA1DLEN {
+                    return Err(SecstoreError("SC_read missing sha1".to_string()));
+                }
+                len -= SHA1DLEN;
+                if len > MAXMSG {
+                    return Err(SecstoreError(
+                        "SC_read implausible record length".to_string(),
+                    ));
+                }
+                let mut digest = [0u8; SHA1DLEN];
+                self.stream.read_bytes(&mut digest)?;
+                let mut buf = vec![0u8; len];
+                self.stream.read_bytes(&mut buf)?;
+                rc4.apply(&mut digest);
+                rc4.apply(&mut buf);
+                if record_mac(secret, &buf, self.inp.seqno) != digest {
+                    return Err(SecstoreError("SC_read integrity check failed".to_string()));
+                }
+                buf
+            }
+            None => {
+                if len == 0 || len > MAXMSG {
+                    return Err(SecstoreError(
+                        "SC_read implausible record length".to_string(),
+                    ));
+                }
+                let mut buf = vec![0u8; len];
+                self.stream.read_bytes(&mut buf)?;
+                buf
+            }
+        };
+        self.inp.seqno = self.inp.seqno.wrapping_add(1);
+        Ok(buf)
+    }
+
+    /// Read a string message; one starting with '!' is the peer's error.
+    pub fn read_str(&mut self) -> Result<String, SecstoreError> {
+        let buf = self.read_msg()?;
+        let s = String::from_utf8_lossy(&buf).to_string();
+        match s.strip_prefix('!') {
+            Some(err) => Err(SecstoreError(format!("remote: {}", err))),
+            None => Ok(s),
+        }
+    }
+
+    pub fn write_str(&mut self, s: &str) -> Result<(), SecstoreError> {
+        self.write_msg(s.as_bytes())
+    }
+
+    /// Tell the peer something went wrong (writerr).
+    pub fn write_err(&mut self, msg: &str) -> Result<(), SecstoreError> {
+        self.write_str(&format!("!{}", msg))
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+// ============================================================================
+// PAK (pak.c)
+// ============================================================================
+
+lazy_static::lazy_static! {
+    static ref PAK_P: BigUint = hex_mp("C41CFBE4D4846F67A3DF7DE9921A49D3B42DC33728427AB159CEC8CBBDB12B5F0C244F1A734AEB9840804EA3C25036AD1B61AFF3ABBC247CD4B384224567A863A6F020E7EE9795554BCD08ABAD7321AF27E1E92E3DB1C6E7E94FAAE590AE9C48F96D93D178E809401ABE8A534A1EC44359733475A36A70C7B425125062B1142D");
+    static ref PAK_Q: BigUint = hex_mp("E0F0EF284E10796C5A2A511E94748BA03C795C13");
+    static ref PAK_R: BigUint = hex_mp("DF310F4E54A5FEC5D86D3E14863921E834113E060F90052AD332B3241CEF2497EFA0303D6344F7C819691A0F9C4A773815AF8EAECFB7EC1D98F039F17A32A7E887D97251A927D093F44A55577F4D70444AEBD06B9B45695EC23962B175F266895C67D21C4656848614D888A4");
+    static ref PAK_G: BigUint = hex_mp("2F1C308DC46B9A44B52DF7DACCE1208CCEF72F69C743ADD4D2327173444ED6E65E074694246E07F9FD4AE26E0FDDD9F54F813C40CB9BCD4338EA6F242AB94CD410E676C290368A16B1A3594877437E516C53A6EEE5493A038A017E955E218E7819734E3E2A6E0BAE08B14258F8C03CC1B30E0DDADFCF7CEDF0727684D3D255F1");
+}
+
+fn hex_mp(hex: &str) -> BigUint {
+    BigUint::parse_bytes(hex.as_bytes(), 16).unwrap()
+}
+
+/// An mpint as secstore writes it: base64 of the big-endian bytes.
+pub(crate) fn mp_to64(n: &BigUint) -> String {
+    BASE64.encode(n.to_bytes_be())
+}
+
+pub(crate) fn mp_from64(s: &str) -> Option<BigUint> {
+    BASE64.decode(s).ok().map(|b| BigUint::from_bytes_be(&b))
+}
+
+/// H = (longhash(ver, C, sha1(pass)) mod p)^r mod p and its inverse Hi,
+/// with Hi in base64 as it enters the short hashes (PAK_Hi).
+pub(crate) fn pak_hi(user: &str, password: &str) -> (BigUint, BigUint, String) {
+    let passhash = Sha1::digest(password.as_bytes());
+    let mut cp = Vec::with_capacity(VERSION.len() + user.len() + SHA1DLEN);
+    cp.extend_from_slice(VERSION.as_bytes());
+    cp.extend_from_slice(user.as_bytes());
+    cp.extend_from_slice(&passhash);
+
+    let mut buf = [0u8; 7 * SHA1DLEN];
+    for (i, chunk) in buf.chunks_exact_mut(SHA1DLEN).enumerate() {
+        chunk.copy_from_slice(&hmac_sha1(&cp, &[b'A' + i as u8]));
+    }
+    cp.fill(0);
+
+    let h = (BigUint::from_bytes_be(&buf) % &*PAK_P).modpow(&PAK_R, &PAK_P);
+    let hi = h.modpow(&(&*PAK_P - BigUint::from(2u32)), &PAK_P);
+    let hex_hi = mp_to64(&hi);
+    (h, hi, hex_hi)
+}
+
+/// The transcript hash both sides use for confirmation and the session key.
+pub(crate) fn shorthash(
+    mess: &str,
+    c: &str,
+    s: &str,
+    m: &str,
+    mu: &str,
+    sigma: &str,
+    hi: &str,
+) -> [u8; SHA1DLEN] {
+    let mut h = Sha1::new();
+    for _ in 0..2 {
+        for part in [mess, c, s, m, mu, sigma, hi] {
+            h.update(part.as_bytes());
+        }
+    }
+    h.finalize().into()
+}
+
+/// A random exponent, 1 <= x < q.
+pub(crate) fn pak_random_exp() -> BigUint {
+    // mprand(164): 21 random bytes with the top 4 bits cleared
+    let mut bytes = [0u8; 21];
+    getrandom::getrandom(&mut bytes).expect("Failed to get random bytes");
+    bytes[0] &= 0x0f;
+    let x = BigUint::from_bytes_be(&bytes) % &*PAK_Q;
+    if x.is_zero() {
+        BigUint::one()
+    } else {
+        x
+    }
+}
+
+// The group, for the secstored stand-in
+#[cfg(test)]
+pub(crate) fn pak_g() -> &'static BigUint {
+    &PAK_G
+}
+
+#[cfg(test)]
+pub(crate) fn pak_p() -> &'static BigUint {
+    &PAK_P
+}
+
+/// Split "key=value\n" lines, in order, checking the keys.
+pub(crate) fn parse_fields<'a>(
+    mess: &'a str,
+    keys: &[&str],
+) -> Result<Vec<&'a str>, SecstoreError> {
+    let mut lines = mess.split('\n');
+    let mut vals = Vec::with_capacity(keys.len());
+    for key in keys {
+        let line = lines.next().unwrap_or("");
+        match line.strip_prefix(key).and_then(|l| l.strip_prefix('=')) {
+            Some(v) => vals.push(v),
+            None => return Err(SecstoreError(format!("protocol botch: expected {}=", key))),
+        }
+    }
+    Ok(vals)
+}
+
+/// Run the client side of PAK as `user`; returns the server's name.
+///
+/// On success the connection is keyed with the session digest.
+pub fn pak_client<S: ByteStream>(
+    conn: &mut SConn<S>,
+    user: &str,
+    password: &str,
+) -> Result<String, SecstoreError> {
+    let (h, _, hex_hi) = pak_hi(user, password);
+
+    // random x, send C and m = g^x H
+    let x = pak_random_exp();
+    let m = (PAK_G.modpow(&x, &PAK_P) * &h) % &*PAK_P;
+    let hex_m = mp_to64(&m);
+    conn.write_str(&format!("{}\tPAK\nC={}\nm={}\n", VERSION, user, hex_m))?;
+
+    // receive mu = g^y, S and the server's confirmation
+    let mess = conn.read_str()?;
+    let vals = parse_fields(&mess, &["mu", "k", "S"])?;
+    let (hex_mu, hex_k, server) = (vals[0], vals[1], vals[2]);
+    let mu =
+        mp_from64(hex_mu).ok_or_else(|| SecstoreError("protocol botch: bad mu".to_string()))?;
+    let sigma = mu.modpow(&x, &PAK_P);
+    let hex_sigma = mp_to64(&sigma);
+
+    let k = shorthash("server", user, server, &hex_m, hex_mu, &hex_sigma, &hex_hi);
+    if BASE64.encode(k) != hex_k {
+        return Err(SecstoreError(
+            "secstore: password mismatch or server is an impostor".to_string(),
+        ));
+    }
+
+    let k = shorthash("client", user, server, &hex_m, hex_mu, &hex_sigma, &hex_hi);
+    conn.write_str(&format!("k'={}\n", BASE64.encode(k)))?;
+
+    let session = shorthash("session", user, server, &hex_m, hex_mu, &hex_sigma, &hex_hi);
+    conn.secret(&session, true);
+    Ok(server.to_string())
+}
+
+// ============================================================================
+// Client (secstore.c)
+// ============================================================================
+
+/// A logged-in secstore session.
+pub struct Secstore<S: ByteStream> {
+    conn: SConn<S>,
+    /// The server's name, as it gave it during PAK
+    pub server: String,
+}
+
+impl<S: ByteStream> Secstore<S> {
+    /// Authenticate to secstored as `user`.
+    pub fn login(stream: S, user: &str, password: &str) -> Result<Self, SecstoreError> {
+        let mut conn = SConn::new(stream);
+        let server = pak_client(&mut conn, user, password)?;
+        let status = conn.read_str()?;
+        match status.as_str() {
+            "OK" => Ok(Secstore { conn, server }),
+            // The server wants a one-time PIN as well
+            "STA" => Err(SecstoreError(format!(
+                "{}: PIN (STA) login not supported",
+                server
+            ))),
+            s => Err(SecstoreError(format!("{}: {}", server, s))),
+        }
+    }
+
+    /// Fetch a file. `.` asks for the directory listing.
+    pub fn get(&mut self, name: &str) -> Result<Vec<u8>, SecstoreError> {
+        check_name(name)?;
+        self.conn.write_str(&format!("GET {}", name))?;
+
+        let s = self.conn.read_str()?;
+        let len: i64 = s
+            .trim()
+            .parse()
+            .map_err(|_| SecstoreError(format!("secstore: bad file size {:?}", s)))?;
+        match len {
+            -1 => {
+                return Err(SecstoreError(format!(
+                    "remote file {} does not exist",
+                    name
+                )))
+            }
+            -3 => return Err(SecstoreError(format!("implausible filesize for {}", name))),
+            n if n < 0 => return Err(SecstoreError(format!("GET refused for {}", name))),
+            n if n > MAXFILESIZE => {
+                return Err(SecstoreError(format!("implausible filesize for {}", name)))
+            }
+            _ => {}
+        }
+
+        let len = len as usize;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let rec = self.conn.read_msg()?;
+            data.extend_from_slice(&rec);
+        }
+        if data.len() != len {
+            return Err(SecstoreError(format!(
+                "secstore: {} is longer than promised",
+                name
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Store a file, replacing any previous version.
+    pub fn put(&mut self, name: &str, data: &[u8]) -> Result<(), SecstoreError> {
+        check_name(name)?;
+        if data.len() as i64 > MAXFILESIZE {
+            return Err(SecstoreError(format!("implausible filesize for {}", name)));
+        }
+        self.conn.write_str(&format!("PUT {}", name))?;
+        self.conn.write_str(&data.len().to_string())?;
+        for chunk in data.chunks(MAXMSG) {
+            self.conn.write_msg(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// End the session politely.
+    pub fn bye(mut self) -> Result<S, SecstoreError> {
+        self.conn.write_str("BYE")?;
+        Ok(self.conn.into_inner())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), SecstoreError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(SecstoreError(
+            "simple filenames, not paths like /tmp/foo".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// ============================================================================
+// File encryption (secstore.c getfile/putfile)
+// ============================================================================
+
+pub const AESBSIZE: usize = 16;
+pub const CHK: usize = 16; // Trailing check block
+
+const CHKBLOCK: [u8; CHK] = [b'X'; CHK];
+
+/// The AES key for stored files: SHA1("aescbc file" || password).
+pub fn file_key(password: &str) -> [u8; AESBSIZE] {
+    let mut h = Sha1::new();
+    h.update(b"aescbc file");
+    h.update(password.as_bytes());
+    let digest: [u8; SHA1DLEN] = h.finalize().into();
+    digest[..AESBSIZE].try_into().unwrap()
+}
+
+/// libsec's aesCBCencrypt: CBC over whole blocks, and a trailing partial
+/// block XORed with the encryption of the last ciphertext block.
+pub(crate) fn aes_cbc_encrypt(aes: &Aes128, ivec: &mut [u8; AESBSIZE], data: &mut [u8]) {
+    let mut blocks = data.chunks_exact_mut(AESBSIZE);
+    for p in &mut blocks {
+        for (b, iv) in p.iter_mut().zip(ivec.iter()) {
+            *b ^= iv;
+        }
+        aes.encrypt_block(GenericArray::from_mut_slice(p));
+        ivec.copy_from_slice(p);
+    }
+    aes_cbc_tail(aes, ivec, blocks.into_remainder());
+}
+
+/// libsec's aesCBCdecrypt, the inverse of `aes_cbc_encrypt`.
+pub(crate) fn aes_cbc_decrypt(aes: &Aes128, ivec: &mut [u8; AESBSIZE], data: &mut [u8]) {
+    let mut blocks = data.chunks_exact_mut(AESBSIZE);
+    for p in &mut blocks {
+        let c: [u8; AESBSIZE] = (*p).try_into().unwrap();
+        aes.decrypt_block(GenericArray::from_mut_slice(p));
+        for (b, iv) in p.iter_mut().zip(ivec.iter()) {
+            *b ^= iv;
+        }
+        *ivec = c;
+    }
+    aes_cbc_tail(aes, ivec, blocks.into_remainder());
+}
+
+fn aes_cbc_tail(aes: &Aes128, ivec: &mut [u8; AESBSIZE], tail: &mut [u8]) {
+    if tail.is_empty() {
+        return;
+    }
+    aes.encrypt_block(GenericArray::from_mut_slice(ivec));
+    for (b, k) in tail.iter_mut().zip(ivec.iter()) {
+        *b ^= k;
+    }
+}
+
+/// Encrypt a file for secstore: a random IV, then AES-CBC of the data
+/// followed by the check block.
+pub fn encrypt_file(password: &str, data: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; AESBSIZE];
+    getrandom::getrandom(&mut iv).expect("Failed to get random bytes");
+    encrypt_file_iv(password, &iv, data)
+}
+
+/// `encrypt_file` with a chosen IV, for fixed test vectors.
+pub fn encrypt_file_iv(password: &str, iv: &[u8; AESBSIZE], data: &[u8]) -> Vec<u8> {
+    let aes = Aes128::new(&file_key(password).into());
+    let mut out = Vec::with_capacity(AESBSIZE + data.len() + CHK);
+    out.extend_from_slice(iv);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&CHKBLOCK);
+
+    let mut ivec = *iv;
+    aes_cbc_encrypt(&aes, &mut ivec, &mut out[AESBSIZE..]);
+    out
+}
+
+/// Decrypt a file fetched from secstore, checking the trailing block.
+pub fn decrypt_file(password: &str, file: &[u8]) -> Result<Vec<u8>, SecstoreError> {
+    if file.len() < AESBSIZE + CHK {
+        return Err(SecstoreError("secstore: no IV in file".to_string()));
+    }
+    let aes = Aes128::new(&file_key(password).into());
+    let mut ivec: [u8; AESBSIZE] = file[..AESBSIZE].try_into().unwrap();
+    let mut data = file[AESBSIZE..].to_vec();
+    aes_cbc_decrypt(&aes, &mut ivec, &mut data);
+
+    let n = data.len() - CHK;
+    if data[n..] != CHKBLOCK {
+        return Err(SecstoreError(
+            "secstore: decrypted file failed to authenticate".to_string(),
+        ));
+    }
+    data.truncate(n);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testsecstore::{pipe, Secstored};
+
+    #[test]
+    fn test_rc4_known_answer() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+    }
+
+    #[test]
+    fn test_pak_group() {
+        // g generates the order q subgroup, and r q = p - 1
+        assert!(PAK_G.modpow(&PAK_Q, &PAK_P).is_one());
+        assert_eq!(&*PAK_R * &*PAK_Q, &*PAK_P - BigUint::one());
+
+        let (h, hi, hex_hi) = pak_hi("glenda", "password");
+        assert!(((h * hi.clone()) % &*PAK_P).is_one());
+        assert_eq!(mp_from64(&hex_hi), Some(hi));
+    }
+
+    #[test]
+    fn test_records_keyed() {
+        let (a, b) = pipe();
+        let mut ca = SConn::new(a);
+        let mut cb = SConn::new(b);
+        ca.write_msg(b"in the clear").unwrap();
+        assert_eq!(cb.read_msg().unwrap(), b"in the clear");
+
+        let sigma = [0x17u8; SHA1DLEN];
+        ca.secret(&sigma, true);
+        cb.secret(&sigma, false);
+        for msg in [&b"one"[..], &[0u8; MAXMSG][..], b"three"] {
+            ca.write_msg(msg).unwrap();
+            assert_eq!(cb.read_msg().unwrap(), msg);
+            cb.write_msg(msg).unwrap();
+            assert_eq!(ca.read_msg().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_records_mismatched_keys() {
+        let (a, b) = pipe();
+        let mut ca = SConn::new(a);
+        let mut cb = SConn::new(b);
+        ca.secret(&[1u8; SHA1DLEN], true);
+        cb.secret(&[2u8; SHA1DLEN], false);
+        ca.write_msg(b"hello").unwrap();
+        let err = cb.read_msg().unwrap_err();
+        assert!(err.0.contains("integrity"));
+    }
+
+    #[test]
+    fn test_get_put() {
+        let mut srv = Secstored::new("secstore.nawin");
+        srv.add_user("glenda", "kittens");
+        srv.put_file(
+            "glenda",
+            "factotum",
+            b"key proto=p9sk1 user=glenda !password=x\n",
+        );
+        let (client_end, server_end) = pipe();
+        let server = srv.spawn(server_end);
+
+        let mut ss = Secstore::login(client_end, "glenda", "kittens").unwrap();
+        assert_eq!(ss.server, "secstore.nawin");
+        assert_eq!(
+            ss.get("factotum").unwrap(),
+            b"key proto=p9sk1 user=glenda !password=x\n"
+        );
+
+        let big: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        ss.put("big", &big).unwrap();
+        assert_eq!(ss.get("big").unwrap(), big);
+
+        let err = ss.get("nonesuch").unwrap_err();
+        assert!(err.0.contains("does not exist"));
+        assert!(ss.get("../etc/passwd").is_err());
+        ss.bye().unwrap();
+
+        let (srv, result) = server.join().unwrap();
+        result.unwrap();
+        assert_eq!(srv.file("glenda", "big"), Some(big));
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let mut srv = Secstored::new("secstore.nawin");
+        srv.add_user("glenda", "kittens");
+        let (client_end, server_end) = pipe();
+        let server = srv.spawn(server_end);
+
+        let err = Secstore::login(client_end, "glenda", "puppies")
+            .err()
+            .unwrap();
+        assert!(err.0.contains("password mismatch"));
+        let (_, result) = server.join().unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        let srv = Secstored::new("secstore.nawin");
+        let (client_end, server_end) = pipe();
+        let server = srv.spawn(server_end);
+
+        let err = Secstore::login(client_end, "nobody", "x").err().unwrap();
+        assert!(err.0.starts_with("remote:"));
+        let _ = server.join().unwrap();
+    }
+
+    #[test]
+    fn test_aes_cbc_sp800_38a() {
+        // NIST SP 800-38A F.2.1, CBC-AES128.Encrypt, first two blocks
+        let key = hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        let aes = Aes128::new(GenericArray::from_slice(&key));
+        let mut iv: [u8; 16] = hex::decode("000102030405060708090a0b0c0d0e0f")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut data =
+            hex::decode("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51")
+                .unwrap();
+        aes_cbc_encrypt(&aes, &mut iv, &mut data);
+        assert_eq!(
+            hex::encode(&data),
+            "7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2"
+        );
+    }
+
+    #[test]
+    fn test_aes_cbc_partial_tail() {
+        let aes = Aes128::new(&[7u8; 16].into());
+        let iv = [1u8; 16];
+        for len in [0, 1, 15, 16, 17, 40] {
+            let plain: Vec<u8> = (0..len as u8).collect();
+            let mut data = plain.clone();
+            aes_cbc_encrypt(&aes, &mut iv.clone(), &mut data);
+            aes_cbc_decrypt(&aes, &mut iv.clone(), &mut data);
+            assert_eq!(data, plain, "len {}", len);
+        }
+
+        // The tail is the last ciphertext block, encrypted, XORed in
+        let mut data = vec![0u8; 19];
+        aes_cbc_encrypt(&aes, &mut iv.clone(), &mut data);
+        let mut ks = [0u8; 16];
+        ks.copy_from_slice(&data[..16]);
+        aes.encrypt_block(GenericArray::from_mut_slice(&mut ks));
+        assert_eq!(data[16..], ks[..3]);
+    }
+
+    #[test]
+    fn test_file_fixed_vector() {
+        let iv: [u8; AESBSIZE] = *b"0123456789abcdef";
+        let file = encrypt_file_iv("kittens", &iv, b"key proto=pass\n");
+        assert_eq!(file.len(), AESBSIZE + 15 + CHK);
+        assert_eq!(&file[..AESBSIZE], &iv);
+        // Checked against openssl's AES-128-ECB, chaining by hand
+        assert_eq!(
+            hex::encode(&file[AESBSIZE..]),
+            "48f4f13642e2f8d38949d878b370ecd405dda17e27422e10aa750af39d0a75"
+        );
+        assert_eq!(decrypt_file("kittens", &file).unwrap(), b"key proto=pass\n");
+    }
+
+    #[test]
+    fn test_file_wrong_password() {
+        let file = encrypt_file("kittens", b"key proto=pass user=glenda !password=x\n");
+        let err = decrypt_file("puppies", &file).unwrap_err();
+        assert!(err.0.contains("failed to authenticate"));
+        assert!(decrypt_file("kittens", &file[..20]).is_err());
+        assert_eq!(
+            decrypt_file("kittens", &encrypt_file("kittens", b"")).unwrap(),
+            b""
+        );
+    }
+}
@@ -0,0 +1,512 @@
+//! session - several authenticated connections in one page
+//!
+//! Each connection gets a `Session` under a numeric handle: its
+//! auth_proxy conversation, the AuthInfo that ends it, and then the TLS
+//! or devssl channel keyed by that secret. One page can so hold sessions
+//! to several cpu servers at once.
+//!
+//! Sessions share the registry's keyring, so a key typed in for one
+//! connection reaches the others, open or still to come, and nothing
+//! else. Closing a handle drops everything the session held; handles are
+//! not reused, so a stale one fails rather than reaching another
+//! connection. Closing them all, as on logout, forgets the keys too.
+//!
+//! Like the key cache there is one registry for the page under WASM,
+//! reached with `with`; JS sees only the handles.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::devssl::{self, Devssl, RANDLEN};
+use crate::keyring::{Key, Keyring};
+use crate::rpc::{AuthInfo, AuthProxy, Factotum, KeyRequest, ProxyState};
+use crate::tlspsk::{TlsPsk, TlsSuite};
+
+pub const SESSIONPARAMS: &str = "proto=p9any role=client";
+
+/// A session's name in the registry
+pub type Handle = u32;
+
+/// Error type for sessions
+#[derive(Debug, Clone)]
+pub struct SessionError(pub String);
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// What protects the connection once authenticated
+pub enum Channel {
+    Tls(TlsPsk),
+    /// `cpu -e`: our random bytes are sent, the server's awaited
+    SslRand {
+        crand: [u8; RANDLEN],
+        algs: String,
+        buf: Vec<u8>,
+    },
+    Ssl(Devssl),
+}
+
+/// One connection: authentication, then a channel
+pub struct Session {
+    label: String,
+    auth: AuthProxy,
+    channel: Option<Channel>,
+    out: Vec<u8>,
+}
+
+impl Session {
+    /// What the caller named it, such as the server's address.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn state(&self) -> &ProxyState {
+        self.auth.state()
+    }
+
+    pub fn auth(&self) -> &AuthProxy {
+        &self.auth
+    }
+
+    /// For the authsrv and needkey steps; see `AuthProxy`.
+    pub fn auth_mut(&mut self) -> &mut AuthProxy {
+        &mut self.auth
+    }
+
+    pub fn authinfo(&self) -> Option<&AuthInfo> {
+        self.auth.authinfo()
+    }
+
+    pub fn channel(&self) -> Option<&Channel> {
+        self.channel.as_ref()
+    }
+
+    /// Bytes to send to the server.
+    pub fn take(&mut self) -> Vec<u8> {
+        let mut out = self.auth.take();
+        out.append(&mut self.out);
+        if let Some(Channel::Tls(tls)) = &mut self.channel {
+            out.extend(tls.take());
+        }
+        out
+    }
+
+    /// Bytes from the server; returns what the channel carried.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, SessionError> {
+        match &mut self.channel {
+            None => {
+                self.auth.put(data);
+                match self.auth.state() {
+                    ProxyState::Failed(e) => Err(SessionError(e.clone())),
+                    _ => Ok(Vec::new()),
+                }
+            }
+            Some(Channel::Tls(tls)) => tls.decrypt(data).map_err(|e| SessionError(e.0)),
+            Some(Channel::SslRand { crand, algs, buf }) => {
+                buf.extend_from_slice(data);
+                if buf.len() < RANDLEN {
+                    return Ok(Vec::new());
+                }
+                let srand: [u8; RANDLEN] = buf[..RANDLEN].try_into().unwrap();
+                let rest = buf.split_off(RANDLEN);
+                let ai = self.auth.authinfo().expect("channel before authinfo");
+                let mut ssl =
+                    Devssl::client(ai, crand, &srand, algs).map_err(|e| SessionError(e.0))?;
+                let data = ssl.decrypt(&rest).map_err(|e| SessionError(e.0));
+                self.channel = Some(Channel::Ssl(ssl));
+                data
+            }
+            Some(Channel::Ssl(ssl)) => ssl.decrypt(data).map_err(|e| SessionError(e.0)),
+        }
+    }
+
+    /// Data for the server, through the channel.
+    pub fn send(&mut self, data: &[u8]) -> Result<Vec<u8>, SessionError> {
+        match &mut self.channel {
+            Some(Channel::Tls(tls)) => tls.encrypt(data).map_err(|e| SessionError(e.0)),
+            Some(Channel::Ssl(ssl)) => Ok(ssl.encrypt(data)),
+            _ => Err(SessionError("session: no channel yet".to_string())),
+        }
+    }
+
+    /// Whether the conversation waits for approval of a `confirm` key.
+    pub fn is_confirming(&self) -> bool {
+        self.auth
+            .factotum()
+            .key_request()
+            .is_some_and(KeyRequest::is_confirm)
+    }
+
+    /// Whether `send` can be used.
+    pub fn is_established(&self) -> bool {
+        match &self.channel {
+            Some(Channel::Tls(tls)) => tls.is_established(),
+            Some(Channel::Ssl(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn secret(&self) -> Result<&AuthInfo, SessionError> {
+        match (&self.channel, self.auth.authinfo()) {
+            (Some(_), _) => Err(SessionError("session: channel already set".to_string())),
+            (None, Some(ai)) => Ok(ai),
+            (None, None) => Err(SessionError("session: not authenticated".to_string())),
+        }
+    }
+
+    /// TLS-PSK keyed by the secret, as rcpu and `tlsclient -a` use.
+    pub fn start_tls(&mut self) -> Result<(), SessionError> {
+        let tls = TlsPsk::client(&self.secret()?.secret, &TlsSuite::ALL);
+        self.channel = Some(Channel::Tls(tls));
+        let rest = self.auth.leftover();
+        self.input(&rest).map(|_| ())
+    }
+
+    /// The `cpu -e` record layer with `algs` (see `devssl::DEFAULTALGS`),
+    /// starting with the exchange of random bytes.
+    pub fn start_ssl(&mut self, algs: &str) -> Result<(), SessionError> {
+        self.secret()?;
+        devssl::parse_algs(algs).map_err(|e| SessionError(e.0))?;
+        let crand = devssl::cpu_random();
+        self.out.extend_from_slice(&crand);
+        self.channel = Some(Channel::SslRand {
+            crand,
+            algs: algs.to_string(),
+            buf: Vec::new(),
+        });
+        let rest = self.auth.leftover();
+        self.input(&rest).map(|_| ())
+    }
+
+    /// The goodbye to send, if the channel has one.
+    fn close(&mut self) -> Vec<u8> {
+        match &mut self.channel {
+            Some(Channel::Tls(tls)) => tls.close(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Sessions by handle, with the keyring they share
+#[derive(Default)]
+pub struct Registry {
+    ring: Keyring,
+    next: Handle,
+    sessions: BTreeMap<Handle, Session>,
+}
+
+impl Registry {
+    pub fn new(ring: Keyring) -> Self {
+        Registry {
+            ring,
+            ..Default::default()
+        }
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.ring
+    }
+
+    /// A key for every session, open or still to come; those waiting for
+    /// a key try again with it. Approving a `confirm` key is not adding
+    /// one; see `supply_key`.
+    pub fn add_key(&mut self, key: Key) {
+        for s in self.sessions.values_mut() {
+            match s.state() {
+                ProxyState::Key if !s.is_confirming() => s.auth.supply_key(key.clone()),
+                _ => s.auth.add_key(key.clone()),
+            }
+        }
+        self.ring.add(key);
+    }
+
+    /// Start authenticating a new connection with `params` (see
+    /// `SESSIONPARAMS`); `label` is for the caller.
+    pub fn open(&mut self, label: &str, params: &str) -> Handle {
+        self.next += 1;
+        let f = Factotum::new(self.ring.clone());
+        let s = Session {
+            label: label.to_string(),
+            auth: AuthProxy::new(f, params),
+            channel: None,
+            out: Vec::new(),
+        };
+        self.sessions.insert(self.next, s);
+        self.next
+    }
+
+    pub fn get(&self, h: Handle) -> Result<&Session, SessionError> {
+        self.sessions
+            .get(&h)
+            .ok_or_else(|| SessionError(format!("session: no handle {}", h)))
+    }
+
+    pub fn get_mut(&mut self, h: Handle) -> Result<&mut Session, SessionError> {
+        self.sessions
+            .get_mut(&h)
+            .ok_or_else(|| SessionError(format!("session: no handle {}", h)))
+    }
+
+    /// Answer session `h`'s needkey; the key goes to the others too. An
+    /// answer to a `confirm` request approves that one use in `h` alone.
+    pub fn supply_key(&mut self, h: Handle, key: Key) -> Result<(), SessionError> {
+        let s = self.get_mut(h)?;
+        if s.state() == &ProxyState::Key && s.is_confirming() {
+            s.auth.supply_key(key);
+        } else {
+            self.add_key(key);
+        }
+        Ok(())
+    }
+
+    /// The live handles, oldest first.
+    pub fn handles(&self) -> Vec<Handle> {
+        self.sessions.keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Drop session `h`; returns the goodbye to send before hanging up.
+    pub fn close(&mut self, h: Handle) -> Result<Vec<u8>, SessionError> {
+        let mut s = self
+            .sessions
+            .remove(&h)
+            .ok_or_else(|| SessionError(format!("session: no handle {}", h)))?;
+        Ok(s.close())
+    }
+
+    /// Drop every session and forget the keys, as on logout.
+    pub fn close_all(&mut self) -> Vec<(Handle, Vec<u8>)> {
+        self.ring = Keyring::default();
+        std::mem::take(&mut self.sessions)
+            .into_iter()
+            .map(|(h, mut s)| (h, s.close()))
+            .collect()
+    }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::default();
+}
+
+/// Run `f` on the page's registry, which starts with no keys.
+pub fn with<R>(f: impl FnOnce(&mut Registry) -> R) -> R {
+    REGISTRY.with(|r| f(&mut r.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::RpcReply;
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    fn authsrv() -> TestAuthsrv {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        srv
+    }
+
+    /// One round of traffic between session `h` and its server.
+    fn pump(r: &mut Registry, h: Handle, cpu: &mut TestServer, srv: &TestAuthsrv) {
+        if *r.get(h).unwrap().state() == ProxyState::Key {
+            let s = r.get(h).unwrap();
+            let req = s.auth().factotum().key_request().unwrap().clone();
+            let key = req
+                .answer(&[("user", "glenda"), ("!password", "kittens")])
+                .unwrap();
+            r.supply_key(h, key).unwrap();
+        }
+        let s = r.get_mut(h).unwrap();
+        if let Some(RpcReply::Authsrv { data, .. }) = s.auth_mut().take_authsrv() {
+            s.auth_mut().put_authsrv(&srv.serve(&data));
+        }
+        cpu.put(&s.take());
+        s.input(&cpu.take(4096)).unwrap();
+    }
+
+    #[test]
+    fn test_two_sessions() {
+        let srv = authsrv();
+        let mut r = Registry::new(
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens").unwrap(),
+        );
+        let mut cpu1 = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let mut cpu2 = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let a = r.open("cpu1", SESSIONPARAMS);
+        let b = r.open("cpu2", SESSIONPARAMS);
+        assert_ne!(a, b);
+        // Interleaved, each keeps to its own conversation
+        for _ in 0..6 {
+            pump(&mut r, a, &mut cpu1, &srv);
+            pump(&mut r, b, &mut cpu2, &srv);
+        }
+        assert_eq!(*r.get(a).unwrap().state(), ProxyState::Done);
+        assert_eq!(*r.get(b).unwrap().state(), ProxyState::Done);
+        let (sa, sb) = (cpu1.secret().unwrap(), cpu2.secret().unwrap());
+        assert_ne!(sa, sb);
+        assert_eq!(r.get(a).unwrap().authinfo().unwrap().secret, sa);
+        assert_eq!(r.get(b).unwrap().authinfo().unwrap().secret, sb);
+        assert_eq!(r.get(b).unwrap().label(), "cpu2");
+    }
+
+    #[test]
+    fn test_key_shared() {
+        let srv = authsrv();
+        let mut r = Registry::default();
+        let mut cpu1 = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let a = r.open("cpu1", SESSIONPARAMS);
+        for _ in 0..6 {
+            pump(&mut r, a, &mut cpu1, &srv);
+        }
+        assert!(cpu1.secret().is_some());
+        assert_eq!(r.keyring().keys().len(), 1);
+
+        // The second connection finds the key the first was given
+        let mut cpu2 = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let b = r.open("cpu2", SESSIONPARAMS);
+        cpu2.put(&r.get_mut(b).unwrap().take());
+        r.get_mut(b).unwrap().input(&cpu2.take(4096)).unwrap();
+        cpu2.put(&r.get_mut(b).unwrap().take());
+        r.get_mut(b).unwrap().input(&cpu2.take(4096)).unwrap();
+        cpu2.put(&r.get_mut(b).unwrap().take());
+        r.get_mut(b).unwrap().input(&cpu2.take(4096)).unwrap();
+        assert_eq!(*r.get(b).unwrap().state(), ProxyState::Authsrv);
+    }
+
+    #[test]
+    fn test_key_unblocks_open_sessions() {
+        let srv = authsrv();
+        let mut r = Registry::default();
+        let mut cpus = [
+            TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin"),
+            TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin"),
+        ];
+        let hs = [r.open("cpu1", SESSIONPARAMS), r.open("cpu2", SESSIONPARAMS)];
+        // Both get as far as wanting a key
+        for (&h, cpu) in hs.iter().zip(cpus.iter_mut()) {
+            while *r.get(h).unwrap().state() != ProxyState::Key {
+                let s = r.get_mut(h).unwrap();
+                cpu.put(&s.take());
+                s.input(&cpu.take(4096)).unwrap();
+            }
+        }
+        // One answer does for both
+        let req = r
+            .get(hs[0])
+            .unwrap()
+            .auth()
+            .factotum()
+            .key_request()
+            .unwrap()
+            .clone();
+        let key = req
+            .answer(&[("user", "glenda"), ("!password", "kittens")])
+            .unwrap();
+        r.supply_key(hs[0], key).unwrap();
+        for _ in 0..6 {
+            for (&h, cpu) in hs.iter().zip(cpus.iter_mut()) {
+                assert_ne!(*r.get(h).unwrap().state(), ProxyState::Key);
+                pump(&mut r, h, cpu, &srv);
+            }
+        }
+        for (&h, cpu) in hs.iter().zip(cpus.iter()) {
+            assert_eq!(*r.get(h).unwrap().state(), ProxyState::Done);
+            assert!(cpu.secret().is_some());
+        }
+
+        // Logout forgets the key with the sessions
+        r.close_all();
+        assert!(r.keyring().keys().is_empty());
+    }
+
+    #[test]
+    fn test_tls_channel() {
+        let srv = authsrv();
+        let mut r = Registry::new(
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens").unwrap(),
+        );
+        let mut cpu = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let h = r.open("cpu", SESSIONPARAMS);
+        assert!(r.get_mut(h).unwrap().start_tls().is_err());
+        for _ in 0..6 {
+            pump(&mut r, h, &mut cpu, &srv);
+        }
+        let s = r.get_mut(h).unwrap();
+        s.start_tls().unwrap();
+        assert!(s.start_tls().is_err());
+        let mut tls = TlsPsk::server(&cpu.secret().unwrap(), &TlsSuite::ALL);
+        while !s.is_established() {
+            tls.decrypt(&s.take()).unwrap();
+            s.input(&tls.take()).unwrap();
+        }
+        let rec = s.send(b"Tversion").unwrap();
+        assert_eq!(tls.decrypt(&rec).unwrap(), b"Tversion");
+        assert_eq!(
+            s.input(&tls.encrypt(b"Rversion").unwrap()).unwrap(),
+            b"Rversion"
+        );
+
+        let bye = r.close(h).unwrap();
+        tls.decrypt(&bye).unwrap();
+        assert!(tls.is_closed());
+    }
+
+    #[test]
+    fn test_ssl_channel() {
+        let srv = authsrv();
+        let mut r = Registry::new(
+            Keyring::parse("key proto=p9sk1 dom=nawin user=glenda !password=kittens").unwrap(),
+        );
+        let mut cpu = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin");
+        let h = r.open("cpu", SESSIONPARAMS);
+        for _ in 0..6 {
+            pump(&mut r, h, &mut cpu, &srv);
+        }
+        let s = r.get_mut(h).unwrap();
+        assert!(s.start_ssl("rc4_256 frob").is_err());
+        s.start_ssl(devssl::DEFAULTALGS).unwrap();
+        let crand: [u8; RANDLEN] = s.take().try_into().unwrap();
+        let srand = devssl::cpu_random();
+        let ai = AuthInfo {
+            secret: cpu.secret().unwrap(),
+            ..Default::default()
+        };
+        let mut ssl = Devssl::server(&ai, &crand, &srand, devssl::DEFAULTALGS).unwrap();
+        // The server's random bytes and first record may come together
+        let mut data = srand.to_vec();
+        data.extend(ssl.encrypt(b"Tversion"));
+        assert_eq!(s.input(&data).unwrap(), b"Tversion");
+        assert_eq!(
+            ssl.decrypt(&s.send(b"Rversion").unwrap()).unwrap(),
+            b"Rversion"
+        );
+    }
+
+    #[test]
+    fn test_close() {
+        let mut r = Registry::default();
+        let a = r.open("a", SESSIONPARAMS);
+        let b = r.open("b", SESSIONPARAMS);
+        assert_eq!(r.handles(), [a, b]);
+        assert!(r.close(a).unwrap().is_empty());
+        assert!(r.close(a).is_err());
+        assert!(r.get(a).is_err());
+        // A new session never takes an old handle
+        let c = r.open("c", SESSIONPARAMS);
+        assert!(c != a && c != b);
+        assert_eq!(r.close_all().len(), 2);
+        assert!(r.is_empty());
+    }
+}
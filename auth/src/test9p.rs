@@ -0,0 +1,120 @@
+//! In-process 9P file server stand-in for tests
+//!
+//! Just the messages an attach needs. Tauth opens an auth fid whose reads
+//! and writes run a `TestServer` conversation; Tattach succeeds only
+//! through an auth fid that has finished it. Every T-message is kept, so
+//! tests can check how the client sized them.
+
+use crate::afid::{
+    get_string, pack, unpack, Qid, IOHDRSZ, NOFID, RATTACH, RAUTH, RCLUNK, RERROR, RREAD, RWRITE,
+    TATTACH, TAUTH, TCLUNK, TREAD, TWRITE,
+};
+use crate::testsrv::TestServer;
+
+pub struct TestFs {
+    msize: u32,
+    /// None when the server wants no authentication
+    cpu: Option<TestServer>,
+    afid: Option<u32>,
+    pub received: Vec<Vec<u8>>,
+}
+
+pub const ROOTQID: Qid = Qid {
+    qtype: 0x80,
+    vers: 0,
+    path: 1,
+};
+
+impl TestFs {
+    pub fn new(msize: u32, cpu: Option<TestServer>) -> Self {
+        TestFs {
+            msize,
+            cpu,
+            afid: None,
+            received: Vec::new(),
+        }
+    }
+
+    /// The cpu server's session secret, once authenticated.
+    pub fn secret(&self) -> Option<Vec<u8>> {
+        self.cpu.as_ref().and_then(TestServer::secret)
+    }
+
+    /// Whether the auth fid is still open.
+    pub fn afid_open(&self) -> bool {
+        self.afid.is_some()
+    }
+
+    /// The counts of every Tread.
+    pub fn reads(&self) -> Vec<u32> {
+        self.received
+            .iter()
+            .filter_map(|m| match unpack(m) {
+                Some((TREAD, _, b)) => Some(u32::from_le_bytes(b[12..16].try_into().unwrap())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Answer one T-message.
+    pub fn rpc(&mut self, t: &[u8]) -> Vec<u8> {
+        self.received.push(t.to_vec());
+        let (mtype, tag, b) = match unpack(t) {
+            Some(m) => m,
+            None => return rerror(0, "bad message"),
+        };
+        if t.len() > self.msize as usize {
+            return rerror(tag, "message too big");
+        }
+        let fid = u32::from_le_bytes(b[..4].try_into().unwrap());
+        let authfid = Some(fid) == self.afid;
+        match mtype {
+            TAUTH => match &self.cpu {
+                None => rerror(tag, "authentication not required"),
+                Some(_) => {
+                    self.afid = Some(fid);
+                    pack(RAUTH, tag, &Qid::default().to_bytes())
+                }
+            },
+            TREAD if authfid => {
+                let count = u32::from_le_bytes(b[12..16].try_into().unwrap());
+                if count > self.msize - IOHDRSZ {
+                    return rerror(tag, "count too big");
+                }
+                let data = self.cpu.as_mut().unwrap().take(count as usize);
+                let mut r = (data.len() as u32).to_le_bytes().to_vec();
+                r.extend(data);
+                pack(RREAD, tag, &r)
+            }
+            TWRITE if authfid => {
+                let data = &b[16..];
+                self.cpu.as_mut().unwrap().put(data);
+                pack(RWRITE, tag, &(data.len() as u32).to_le_bytes())
+            }
+            TATTACH => {
+                let afid = u32::from_le_bytes(b[4..8].try_into().unwrap());
+                let mut s = &b[8..];
+                let uname = get_string(&mut s).unwrap_or_default();
+                let authed = match &self.cpu {
+                    None => afid == NOFID,
+                    Some(cpu) => Some(afid) == self.afid && cpu.secret().is_some(),
+                };
+                if !authed || uname.is_empty() {
+                    return rerror(tag, "authentication failed");
+                }
+                pack(RATTACH, tag, &ROOTQID.to_bytes())
+            }
+            TCLUNK if authfid => {
+                self.afid = None;
+                pack(RCLUNK, tag, &[])
+            }
+            _ => rerror(tag, "unknown fid"),
+        }
+    }
+}
+
+fn rerror(tag: u16, ename: &str) -> Vec<u8> {
+    let mut b = Vec::new();
+    crate::afid::put_string(&mut b, ename);
+    pack(RERROR, tag, &b)
+}
@@ -0,0 +1,60 @@
+//! In-process aan server stand-in for tests
+//!
+//! An echo service behind `aan`, reached over a transport that can be cut.
+//! Hanging up loses whatever was in flight; `connect` starts a new
+//! transport with the server's half of the resume.
+
+use crate::aan::Aan;
+
+pub struct TestAanServer {
+    aan: Aan,
+    connected: bool,
+    outq: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl TestAanServer {
+    pub fn new() -> Self {
+        TestAanServer {
+            aan: Aan::new(),
+            connected: false,
+            outq: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// A new transport.
+    pub fn connect(&mut self) {
+        self.connected = true;
+        self.outq = self.aan.resume();
+    }
+
+    /// Drop the transport and everything in flight on it.
+    pub fn hangup(&mut self) {
+        self.connected = false;
+        self.outq.clear();
+    }
+
+    /// Bytes from the client; echoes the data they carry.
+    pub fn put(&mut self, data: &[u8]) {
+        if !self.connected {
+            return;
+        }
+        let got = self.aan.input(data, 0).unwrap();
+        if !got.is_empty() {
+            self.received.extend_from_slice(&got);
+            self.outq.extend(self.aan.take());
+            self.outq.extend(self.aan.send(&got));
+        }
+    }
+
+    /// Bytes for the client.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outq)
+    }
+
+    /// Everything the service has been sent, once each.
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+}
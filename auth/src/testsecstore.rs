@@ -0,0 +1,215 @@
+//! In-process secstored stand-in for tests
+//!
+//! Just enough of 9front's secstored.c to exercise the client: PAK as the
+//! server, then GET, PUT and BYE against files kept in memory. It runs on a
+//! thread at the far end of a `pipe`.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use num_bigint::BigUint;
+use num_traits::Zero;
+
+use crate::secstore::{
+    mp_from64, mp_to64, pak_g, pak_hi, pak_p, pak_random_exp, parse_fields, shorthash, ByteStream,
+    SConn, SecstoreError, MAXFILESIZE, MAXMSG, VERSION,
+};
+
+/// One end of an in-memory duplex byte stream
+pub struct PipeEnd {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+/// A connected pair of streams; dropping one end is EOF at the other.
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let (tx_a, rx_b) = channel();
+    let (tx_b, rx_a) = channel();
+    let a = PipeEnd {
+        tx: tx_a,
+        rx: rx_a,
+        pending: Vec::new(),
+    };
+    let b = PipeEnd {
+        tx: tx_b,
+        rx: rx_b,
+        pending: Vec::new(),
+    };
+    (a, b)
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv() {
+                Ok(data) => self.pending = data,
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Account {
+    hi: BigUint,
+    hex_hi: String,
+    files: HashMap<String, Vec<u8>>,
+}
+
+pub struct Secstored {
+    pub name: String,
+    accounts: HashMap<String, Account>,
+}
+
+impl Secstored {
+    pub fn new(name: &str) -> Self {
+        Secstored {
+            name: name.to_string(),
+            accounts: HashMap::new(),
+        }
+    }
+
+    /// Create an account; like secuser, only Hi is kept.
+    pub fn add_user(&mut self, user: &str, password: &str) {
+        let (_, hi, hex_hi) = pak_hi(user, password);
+        let acct = Account {
+            hi,
+            hex_hi,
+            files: HashMap::new(),
+        };
+        self.accounts.insert(user.to_string(), acct);
+    }
+
+    pub fn put_file(&mut self, user: &str, name: &str, data: &[u8]) {
+        let acct = self.accounts.get_mut(user).expect("no such user");
+        acct.files.insert(name.to_string(), data.to_vec());
+    }
+
+    pub fn file(&self, user: &str, name: &str) -> Option<Vec<u8>> {
+        self.accounts.get(user)?.files.get(name).cloned()
+    }
+
+    /// Serve one connection on a thread; the stand-in comes back on join.
+    pub fn spawn<S: ByteStream + Send + 'static>(
+        mut self,
+        stream: S,
+    ) -> JoinHandle<(Secstored, Result<(), SecstoreError>)> {
+        thread::spawn(move || {
+            let result = self.serve(stream);
+            (self, result)
+        })
+    }
+
+    fn serve<S: ByteStream>(&mut self, stream: S) -> Result<(), SecstoreError> {
+        let mut conn = SConn::new(stream);
+        let user = self.pak_server(&mut conn)?;
+        conn.write_str("OK")?;
+
+        loop {
+            let cmd = conn.read_str()?;
+            if cmd == "BYE" {
+                return Ok(());
+            }
+            let acct = self.accounts.get_mut(&user).unwrap();
+            if let Some(name) = cmd.strip_prefix("GET ") {
+                match acct.files.get(name) {
+                    Some(data) => {
+                        conn.write_str(&data.len().to_string())?;
+                        for chunk in data.chunks(MAXMSG) {
+                            conn.write_msg(chunk)?;
+                        }
+                    }
+                    None => conn.write_str("-1")?,
+                }
+            } else if let Some(name) = cmd.strip_prefix("PUT ") {
+                let len: i64 = conn.read_str()?.parse().unwrap_or(-1);
+                if !(0..=MAXFILESIZE).contains(&len) {
+                    conn.write_err("implausible filesize")?;
+                    return Err(SecstoreError("implausible filesize".to_string()));
+                }
+                let mut data = Vec::new();
+                while data.len() < len as usize {
+                    data.extend_from_slice(&conn.read_msg()?);
+                }
+                acct.files.insert(name.to_string(), data);
+            } else {
+                conn.write_err("unrecognized verb")?;
+                return Err(SecstoreError(format!("unrecognized verb {:?}", cmd)));
+            }
+        }
+    }
+
+    /// Server side of PAK (PAKserver); returns the authenticated user.
+    fn pak_server<S: ByteStream>(&self, conn: &mut SConn<S>) -> Result<String, SecstoreError> {
+        let mess = conn.read_str()?;
+        let rest = mess
+            .strip_prefix(&format!("{}\tPAK\n", VERSION))
+            .ok_or_else(|| SecstoreError("protocol botch".to_string()))?;
+        let vals = parse_fields(rest, &["C", "m"])?;
+        let (user, hex_m) = (vals[0].to_string(), vals[1]);
+
+        let acct = match self.accounts.get(&user) {
+            Some(a) => a,
+            None => {
+                conn.write_err("account not found")?;
+                return Err(SecstoreError("account not found".to_string()));
+            }
+        };
+        let m = mp_from64(hex_m).unwrap_or_default() % pak_p();
+        if m.is_zero() {
+            conn.write_err("implausible m")?;
+            return Err(SecstoreError("implausible m".to_string()));
+        }
+
+        // random y, mu = g^y, sigma = (m Hi)^y = g^xy
+        let y = pak_random_exp();
+        let mu = pak_g().modpow(&y, pak_p());
+        let sigma = ((m * &acct.hi) % pak_p()).modpow(&y, pak_p());
+        let (hex_mu, hex_sigma) = (mp_to64(&mu), mp_to64(&sigma));
+        let hash = |mess| {
+            shorthash(
+                mess,
+                &user,
+                &self.name,
+                hex_m,
+                &hex_mu,
+                &hex_sigma,
+                &acct.hex_hi,
+            )
+        };
+
+        let k = BASE64.encode(hash("server"));
+        conn.write_str(&format!("mu={}\nk={}\nS={}\n", hex_mu, k, self.name))?;
+
+        let mess = conn.read_str()?;
+        let vals = parse_fields(&mess, &["k'"])?;
+        if vals[0] != BASE64.encode(hash("client")) {
+            conn.write_err("incorrect password")?;
+            return Err(SecstoreError("incorrect password".to_string()));
+        }
+
+        conn.secret(&hash("session"), false);
+        Ok(user)
+    }
+}
@@ -0,0 +1,588 @@
+//! In-process auth server stand-in for tests
+//!
+//! Just enough of 9front's authsrv.c to exercise the client codecs without a
+//! network. Users and their secrets live in a map; a conversation is driven
+//! by handing the stand-in the client's bytes and getting the server's back.
+//!
+//! `TestServer` plays a cpu server answering p9any, and `proxy` drives a
+//! `Factotum` against it the way libauth's auth_proxy does.
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::authpak::{authpak_finish, authpak_new, PakPriv, PAKKEYLEN, PAKYLEN};
+use crate::authsrv::{
+    self, error_reply, ok_reply, okvar_reply, Authkey, Decoded, Ticketreq, AUTH_APOP, AUTH_CHAL,
+    AUTH_CHAP, AUTH_CRAM, AUTH_MSCHAP, AUTH_MSCHAPV2, AUTH_PAK, AUTH_VNC, NETCHLEN, TICKREQLEN,
+};
+use crate::chal::{self, MD5LEN, MSCHALLEN, MSCHALLENV2, MSRESPLEN};
+use crate::des9;
+use crate::form1::{self, Form1Authenticator, Form1Ticket, NONCELEN};
+use crate::keyfs::{self, KeyfsError};
+use crate::netkey;
+use crate::nvram::Nvrsafe;
+use crate::p9sk1::{
+    self, read_fixed_string, Ticket, ANAMELEN, AUTHENTLEN, AUTH_AC, AUTH_AS, AUTH_TC, AUTH_TREQ,
+    AUTH_TS, CHALLEN, TICKETLEN,
+};
+use crate::rpc::{AuthInfo, Factotum, RpcReply};
+use crate::vnc::{self, VNCCHALLEN};
+
+struct User {
+    secret: String,
+    key: Authkey,
+}
+
+/// A challenge conversation in progress
+struct ChalConv {
+    tr: Ticketreq,
+    chal: Vec<u8>,
+}
+
+pub struct TestAuthsrv {
+    pub authdom: String,
+    users: HashMap<String, User>,
+    conv: Option<ChalConv>,
+}
+
+impl TestAuthsrv {
+    pub fn new(authdom: &str) -> Self {
+        TestAuthsrv {
+            authdom: authdom.to_string(),
+            users: HashMap::new(),
+            conv: None,
+        }
+    }
+
+    /// Register a user; the password doubles as the apop/chap secret.
+    pub fn add_user(&mut self, user: &str, password: &str) {
+        let key = Authkey::from_password(password);
+        let u = User {
+            secret: password.to_string(),
+            key,
+        };
+        self.users.insert(user.to_string(), u);
+    }
+
+    /// Start up as an auth server does: domain and machine key from nvram,
+    /// users from the key file sealed under that key.
+    pub fn boot(nv: &Nvrsafe, keys: &[u8]) -> Result<Self, KeyfsError> {
+        let mut srv = TestAuthsrv::new(&nv.authdom);
+        srv.load_keys(keys, &nv.machine_key())?;
+        Ok(srv)
+    }
+
+    /// Take the users from an /adm/keys file, leaving out any keyfs
+    /// would refuse; their secure-net secrets serve the challenge protocols.
+    pub fn load_keys(&mut self, file: &[u8], machine: &Authkey) -> Result<(), KeyfsError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as u32);
+        for u in keyfs::read_keys(file, machine)? {
+            if u.is_valid(now) {
+                let user = User {
+                    secret: u.secret,
+                    key: u.key,
+                };
+                self.users.insert(u.name, user);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn key(&self, user: &str) -> Authkey {
+        self.users[user].key.clone()
+    }
+
+    /// Receive the opening ticket request of a challenge exchange and
+    /// return the challenge message.
+    pub fn chal_start(&mut self, treq: &[u8]) -> Vec<u8> {
+        let tr = match Ticketreq::from_bytes(treq) {
+            Decoded::Done(tr, _) => tr,
+            Decoded::Toosmall(_) => return error_reply("short ticket request"),
+        };
+        let (chal, msg) = match tr.req_type {
+            AUTH_APOP | AUTH_CRAM => {
+                let n = u32::from_le_bytes(random());
+                let chal = format!("<{}.{}@{}>", n, std::process::id(), self.authdom);
+                let msg = okvar_reply(chal.as_bytes());
+                (chal.into_bytes(), msg)
+            }
+            AUTH_CHAP | AUTH_MSCHAP => {
+                let chal = random::<MSCHALLEN>().to_vec();
+                (chal.clone(), chal)
+            }
+            AUTH_MSCHAPV2 => {
+                let chal = random::<MSCHALLENV2>().to_vec();
+                (chal.clone(), chal)
+            }
+            AUTH_CHAL => {
+                let n = u32::from_le_bytes(random()) % 100_000_000;
+                let chal = n.to_string().into_bytes();
+                let mut padded = [0u8; NETCHLEN];
+                padded[..chal.len()].copy_from_slice(&chal);
+                (chal, ok_reply(&padded))
+            }
+            AUTH_VNC => {
+                let chal = random::<VNCCHALLEN>().to_vec();
+                (chal.clone(), okvar_reply(&chal))
+            }
+            _ => return error_reply("unknown request"),
+        };
+        self.conv = Some(ChalConv { tr, chal });
+        msg
+    }
+
+    /// Receive the client's response and return tickets or an error.
+    pub fn chal_finish(&mut self, reply: &[u8]) -> Vec<u8> {
+        let conv = match self.conv.take() {
+            Some(c) => c,
+            None => return error_reply("no challenge outstanding"),
+        };
+        let chal = &conv.chal;
+
+        let (uid, ok) = match conv.tr.req_type {
+            AUTH_APOP | AUTH_CRAM => {
+                let tr = match Ticketreq::from_bytes(reply) {
+                    Decoded::Done(tr, _) => tr,
+                    Decoded::Toosmall(_) => return error_reply("short reply"),
+                };
+                let resp = String::from_utf8_lossy(&reply[TICKREQLEN..]).to_string();
+                let ok = self.users.get(&tr.uid).is_some_and(|u| {
+                    let want = if conv.tr.req_type == AUTH_APOP {
+                        chal::apop_response(chal, &u.secret)
+                    } else {
+                        chal::cram_response(chal, &u.secret)
+                    };
+                    want == resp
+                });
+                (tr.uid, ok)
+            }
+            AUTH_CHAP => {
+                let uid = read_fixed_string(&reply[1..1 + ANAMELEN]);
+                let resp = &reply[1 + ANAMELEN..1 + ANAMELEN + MD5LEN];
+                let ok = self
+                    .users
+                    .get(&uid)
+                    .is_some_and(|u| chal::chap_response(reply[0], chal, &u.secret) == resp);
+                (uid, ok)
+            }
+            AUTH_MSCHAP | AUTH_MSCHAPV2 => {
+                let uid = read_fixed_string(&reply[..ANAMELEN]);
+                let lm = &reply[ANAMELEN..ANAMELEN + MSRESPLEN];
+                let nt = &reply[ANAMELEN + MSRESPLEN..];
+                let ok = self.users.get(&uid).is_some_and(|u| {
+                    let want = if conv.tr.req_type == AUTH_MSCHAP {
+                        let c: [u8; MSCHALLEN] = chal[..].try_into().unwrap();
+                        chal::mschap_challenge_response(&c, &chal::nt_password_hash(&u.secret))
+                    } else {
+                        let c: [u8; MSCHALLENV2] = chal[..].try_into().unwrap();
+                        let peer: [u8; MSCHALLENV2] = lm[..MSCHALLENV2].try_into().unwrap();
+                        chal::mschapv2_response(&c, &peer, &uid, &u.secret)
+                    };
+                    want == nt
+                });
+                (uid, ok)
+            }
+            AUTH_CHAL => {
+                let uid = conv.tr.uid.clone();
+                let resp = read_fixed_string(reply);
+                let ok = self
+                    .users
+                    .get(&uid)
+                    .is_some_and(|u| netkey::netcheck(&u.key.des, &chal_str(chal), &resp));
+                (uid, ok)
+            }
+            AUTH_VNC => {
+                let uid = conv.tr.uid.clone();
+                let ok = match (<&[u8; VNCCHALLEN]>::try_from(reply), self.users.get(&uid)) {
+                    (Ok(resp), Some(u)) => {
+                        let c: [u8; VNCCHALLEN] = chal[..].try_into().unwrap();
+                        vnc::vnc_check(&c, resp, u.secret.as_bytes())
+                    }
+                    _ => false,
+                };
+                (uid, ok)
+            }
+            _ => return error_reply("unknown request"),
+        };
+
+        if !ok {
+            return error_reply("authentication failed");
+        }
+        self.tickauthreply(&conv.tr, &uid)
+    }
+
+    /// Serve one connection's worth of ticket requests (AuthPAK then
+    /// AuthTreq, as dp9ik pipelines them) and return all the replies.
+    pub fn serve(&self, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut pakkeys: Option<([u8; PAKKEYLEN], [u8; PAKKEYLEN])> = None;
+        while !input.is_empty() {
+            let tr = match Ticketreq::from_bytes(input) {
+                Decoded::Done(tr, n) => {
+                    input = &input[n..];
+                    tr
+                }
+                Decoded::Toosmall(_) => return out,
+            };
+            match tr.req_type {
+                AUTH_PAK => {
+                    if input.len() < 2 * PAKYLEN {
+                        return out;
+                    }
+                    let (ya, rest) = input.split_at(2 * PAKYLEN);
+                    input = rest;
+                    let s = self.authpak(&tr.authid, ya[..PAKYLEN].try_into().unwrap());
+                    let c = self.authpak(&tr.uid, ya[PAKYLEN..].try_into().unwrap());
+                    match (s, c) {
+                        (Some((ybs, ks)), Some((ybc, kc))) => {
+                            let mut y = ybs.to_vec();
+                            y.extend_from_slice(&ybc);
+                            out.extend(ok_reply(&y));
+                            pakkeys = Some((ks, kc));
+                        }
+                        _ => {
+                            out.extend(error_reply("authpak failed"));
+                            return out;
+                        }
+                    }
+                }
+                AUTH_TREQ => out.extend(self.ticketreply(&tr, pakkeys.as_ref())),
+                _ => {
+                    out.extend(error_reply("unknown request"));
+                    return out;
+                }
+            }
+        }
+        out
+    }
+
+    /// The auth server's half of AuthPAK for one user: its Y and the key.
+    fn authpak(&self, user: &str, y: &[u8; PAKYLEN]) -> Option<([u8; PAKYLEN], [u8; PAKKEYLEN])> {
+        let mut key = self.users.get(user)?.key.clone();
+        key.authpak_hash(user);
+        let p = authpak_new(&key.pakhash, false);
+        let k = authpak_finish(&p, &key.pakhash, y).ok()?;
+        Some((p.y, k))
+    }
+
+    /// Tc and Ts for a ticket request; form1 under the PAK keys when
+    /// AuthPAK came first (ticketrequest).
+    fn ticketreply(
+        &self,
+        tr: &Ticketreq,
+        pakkeys: Option<&([u8; PAKKEYLEN], [u8; PAKKEYLEN])>,
+    ) -> Vec<u8> {
+        let (host, user) = match (self.users.get(&tr.authid), self.users.get(&tr.uid)) {
+            (Some(h), Some(u)) => (h, u),
+            _ => return error_reply("unknown user"),
+        };
+        let mut data = Vec::new();
+        match pakkeys {
+            Some((ks, kc)) => {
+                let mut t = Form1Ticket {
+                    ticket_type: AUTH_TC,
+                    challenge: tr.chal,
+                    cuid: tr.uid.clone(),
+                    suid: tr.uid.clone(),
+                    key: random(),
+                };
+                data.extend(t.seal(kc));
+                t.ticket_type = AUTH_TS;
+                data.extend(t.seal(ks));
+            }
+            None => {
+                let mut t = Ticket {
+                    ticket_type: AUTH_TC,
+                    challenge: tr.chal,
+                    cuid: tr.uid.clone(),
+                    suid: tr.uid.clone(),
+                    key: authsrv::gen_deskey(),
+                };
+                data.extend(p9sk1::encrypt_ticket(&t, &user.key.des));
+                t.ticket_type = AUTH_TS;
+                data.extend(p9sk1::encrypt_ticket(&t, &host.key.des));
+            }
+        }
+        ok_reply(&data)
+    }
+
+    /// Ticket for the requesting host plus an authenticator (tickauthreply).
+    fn tickauthreply(&self, tr: &Ticketreq, uid: &str) -> Vec<u8> {
+        let host = match self.users.get(&tr.hostid) {
+            Some(h) => &h.key,
+            None => return error_reply("unknown host"),
+        };
+        let t = Ticket {
+            ticket_type: AUTH_TS,
+            challenge: tr.chal,
+            cuid: uid.to_string(),
+            suid: uid.to_string(),
+            key: authsrv::gen_deskey(),
+        };
+        let mut data = p9sk1::encrypt_ticket(&t, &host.des).to_vec();
+        data.extend_from_slice(&p9sk1::make_authenticator(AUTH_AC, &tr.chal, 0, &t.key));
+        ok_reply(&data)
+    }
+}
+
+// ============================================================================
+// The far end of a p9any conversation
+// ============================================================================
+
+enum ServerState {
+    NeedChoice,
+    NeedChal,
+    NeedTicket,
+    Established,
+    Failed,
+}
+
+/// A cpu server's side of p9any with p9sk1 or dp9ik, fed and drained as a
+/// byte stream.
+pub struct TestServer {
+    authid: String,
+    authdom: String,
+    key: Authkey,
+    offer: Vec<String>,
+    dp9ik: bool,
+    state: ServerState,
+    schal: [u8; CHALLEN],
+    cchal: [u8; CHALLEN],
+    pak: Option<PakPriv>,
+    inq: Vec<u8>,
+    outq: Vec<u8>,
+    secret: Option<Vec<u8>>,
+}
+
+impl TestServer {
+    /// `offer` is a space-separated list such as "dp9ik@dom p9sk1@dom".
+    pub fn new(authid: &str, password: &str, authdom: &str, offer: &str) -> Self {
+        let mut key = Authkey::from_password(password);
+        key.authpak_hash(authid);
+        let mut outq = format!("v.2 {}", offer).into_bytes();
+        outq.push(0);
+        TestServer {
+            authid: authid.to_string(),
+            authdom: authdom.to_string(),
+            key,
+            offer: offer.split_whitespace().map(str::to_string).collect(),
+            dp9ik: false,
+            state: ServerState::NeedChoice,
+            schal: [0; CHALLEN],
+            cchal: [0; CHALLEN],
+            pak: None,
+            inq: Vec::new(),
+            outq,
+            secret: None,
+        }
+    }
+
+    /// The session secret, once the client has been authenticated.
+    pub fn secret(&self) -> Option<Vec<u8>> {
+        self.secret.clone()
+    }
+
+    /// Bytes from the client.
+    pub fn put(&mut self, data: &[u8]) {
+        self.inq.extend_from_slice(data);
+        while self.step() {}
+    }
+
+    /// Up to `n` bytes for the client.
+    pub fn take(&mut self, n: usize) -> Vec<u8> {
+        let n = n.min(self.outq.len());
+        self.outq.drain(..n).collect()
+    }
+
+    /// Consume one message if it is complete; false when waiting.
+    fn step(&mut self) -> bool {
+        match self.state {
+            ServerState::NeedChoice => {
+                let i = match self.inq.iter().position(|&b| b == 0) {
+                    Some(i) => i,
+                    None => return false,
+                };
+                let choice = String::from_utf8_lossy(&self.inq[..i]).replacen(' ', "@", 1);
+                self.inq.drain(..=i);
+                if !self.offer.contains(&choice) {
+                    self.state = ServerState::Failed;
+                    return false;
+                }
+                self.dp9ik = choice.starts_with("dp9ik@");
+                self.outq.extend_from_slice(b"OK\0");
+                self.state = ServerState::NeedChal;
+                true
+            }
+            ServerState::NeedChal => {
+                if self.inq.len() < CHALLEN {
+                    return false;
+                }
+                self.cchal.copy_from_slice(&self.inq[..CHALLEN]);
+                self.inq.drain(..CHALLEN);
+                self.schal = random();
+                let tr = Ticketreq {
+                    req_type: AUTH_TREQ,
+                    authid: self.authid.clone(),
+                    authdom: self.authdom.clone(),
+                    chal: self.schal,
+                    hostid: self.authid.clone(),
+                    uid: String::new(),
+                };
+                self.outq.extend_from_slice(&tr.to_bytes());
+                if self.dp9ik {
+                    let pak = authpak_new(&self.key.pakhash, true);
+                    self.outq.extend_from_slice(&pak.y);
+                    self.pak = Some(pak);
+                }
+                self.state = ServerState::NeedTicket;
+                true
+            }
+            ServerState::NeedTicket => {
+                let want = if self.dp9ik {
+                    PAKYLEN + form1::FORM1TICKETLEN + form1::FORM1AUTHENTLEN
+                } else {
+                    TICKETLEN + AUTHENTLEN
+                };
+                if self.inq.len() < want {
+                    return false;
+                }
+                let msg: Vec<u8> = self.inq.drain(..want).collect();
+                let reply = if self.dp9ik {
+                    self.check_form1(&msg)
+                } else {
+                    self.check_des(&msg)
+                };
+                match reply {
+                    Some(r) => {
+                        self.outq.extend(r);
+                        self.state = ServerState::Established;
+                    }
+                    None => self.state = ServerState::Failed,
+                }
+                false
+            }
+            ServerState::Established | ServerState::Failed => false,
+        }
+    }
+
+    fn check_form1(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        let ybs: &[u8; PAKYLEN] = msg[..PAKYLEN].try_into().unwrap();
+        let pakkey = authpak_finish(self.pak.as_ref()?, &self.key.pakhash, ybs).ok()?;
+        let msg = &msg[PAKYLEN..];
+        let t = Form1Ticket::open(msg, &pakkey)?;
+        if t.ticket_type != AUTH_TS || t.challenge != self.schal {
+            return None;
+        }
+        let a = Form1Authenticator::open(&msg[form1::FORM1TICKETLEN..], &t.key)?;
+        if a.auth_type != AUTH_AC || a.challenge != self.schal {
+            return None;
+        }
+        let reply = Form1Authenticator {
+            auth_type: AUTH_AS,
+            challenge: self.cchal,
+            rand: random(),
+        };
+        let mut salt = [0u8; 2 * NONCELEN];
+        salt[..NONCELEN].copy_from_slice(&a.rand);
+        salt[NONCELEN..].copy_from_slice(&reply.rand);
+        let mut secret = vec![0u8; 256];
+        Hkdf::<Sha256>::new(Some(&salt), &t.key)
+            .expand(b"Plan 9 session secret", &mut secret)
+            .ok()?;
+        self.secret = Some(secret);
+        Some(reply.seal(&t.key))
+    }
+
+    fn check_des(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        let t = p9sk1::decrypt_ticket(msg[..TICKETLEN].try_into().unwrap(), &self.key.des);
+        if t.ticket_type != AUTH_TS || t.challenge != self.schal {
+            return None;
+        }
+        let a = p9sk1::decrypt_authenticator(msg[TICKETLEN..].try_into().unwrap(), &t.key);
+        if a.auth_type != AUTH_AC || a.challenge != self.schal {
+            return None;
+        }
+        self.secret = Some(des9::expand_key(&t.key).to_vec());
+        Some(p9sk1::make_authenticator(AUTH_AS, &self.cchal, 0, &t.key).to_vec())
+    }
+}
+
+/// Drive `f` through a client conversation with `server`, sending ticket
+/// requests to `srv`, as auth_proxy does. `getkey` answers needkey with a
+/// ctl line, or None to give up.
+pub fn proxy(
+    f: &mut Factotum,
+    params: &str,
+    server: &mut TestServer,
+    srv: &TestAuthsrv,
+    getkey: &mut dyn FnMut(&str) -> Option<String>,
+) -> Result<AuthInfo, RpcReply> {
+    let mut rpc = |f: &mut Factotum, verb: &str, arg: &[u8]| loop {
+        match f.rpc(verb, arg) {
+            RpcReply::Needkey(want) => match getkey(&want) {
+                Some(line) => f.ctl(&line).map_err(|e| RpcReply::Error(e.0))?,
+                None => return Err(RpcReply::Needkey(want)),
+            },
+            reply => return Ok(reply),
+        }
+    };
+
+    match rpc(f, "start", params.as_bytes())? {
+        RpcReply::Ok(_) => {}
+        r => return Err(r),
+    }
+    loop {
+        let mut input = match rpc(f, "read", b"")? {
+            RpcReply::Ok(data) => {
+                server.put(&data);
+                continue;
+            }
+            RpcReply::Done { haveai: true } => break,
+            RpcReply::Phase(_) => None,
+            RpcReply::Authsrv { data, .. } => Some(srv.serve(&data)),
+            r => return Err(r),
+        };
+
+        // Write until the protocol has a whole message
+        let mut buf = Vec::new();
+        loop {
+            match rpc(f, "write", &buf)? {
+                RpcReply::Ok(_) => break,
+                RpcReply::Done { haveai: true } => break,
+                RpcReply::Toosmall(m) if m > buf.len() && m <= crate::rpc::AUTHRPCMAX => {
+                    let more = match input.as_mut() {
+                        Some(answer) => answer.drain(..(m - buf.len()).min(answer.len())).collect(),
+                        None => server.take(m - buf.len()),
+                    };
+                    if more.is_empty() {
+                        return Err(RpcReply::Error("eof".to_string()));
+                    }
+                    buf.extend(more);
+                }
+                r => return Err(r),
+            }
+        }
+        input.take();
+    }
+    match f.rpc("authinfo", b"") {
+        RpcReply::Ok(data) => {
+            AuthInfo::from_bytes(&data).ok_or(RpcReply::Error("bad authinfo".to_string()))
+        }
+        r => Err(r),
+    }
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut buf = [0u8; N];
+    getrandom::getrandom(&mut buf).expect("Failed to get random bytes");
+    buf
+}
+
+fn chal_str(chal: &[u8]) -> String {
+    String::from_utf8_lossy(chal).to_string()
+}
@@ -0,0 +1,809 @@
+//! tlspsk - TLS 1.2 with a pre-shared key from the session secret
+//!
+//! rcpu runs `tlsclient -a`: after p9any both ends hold the AuthInfo
+//! secret, and the connection continues as TLS 1.2 keyed by it alone, with
+//! PSK identity "p9secret" and no certificates (RFC 4279):
+//!
+//! ```text
+//! C→S ClientHello
+//! S→C ServerHello [ServerKeyExchange(hint)] ServerHelloDone
+//! C→S ClientKeyExchange("p9secret") ChangeCipherSpec Finished
+//! S→C ChangeCipherSpec Finished
+//! ```
+//!
+//! The premaster secret is a run of zeros as long as the PSK followed by
+//! the PSK, each with a 2-byte length. Records are sealed with
+//! ChaCha20-Poly1305 (RFC 7905) or AES-128-GCM (RFC 5288), both with the
+//! SHA-256 PRF.
+//!
+//! `TlsPsk` does no I/O: bytes from the peer go in through `decrypt`, which
+//! returns whatever application data they carried, and bytes for the peer
+//! come out of `take` during the handshake and `encrypt` after it.
+//!
+//! Ported from 9front's tlsclient.c and libsec tlshand.c.
+
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub const PSKID: &str = "p9secret"; // PSK identity tlsclient -a sends
+pub const TLSVERSION: u16 = 0x0303; // TLS 1.2
+pub const MAXFRAGMENT: usize = 1 << 14; // Largest record plaintext
+pub const RANDOMLEN: usize = 32;
+pub const MASTERLEN: usize = 48;
+pub const FINISHEDLEN: usize = 12;
+
+const EMPTYRENEGOTIATIONINFOSCSV: u16 = 0x00FF; // RFC 5746 signalling suite
+const XRENEGOTIATIONINFO: u16 = 0xFF01; // Its extension
+
+// Record content types
+const RCHANGECIPHERSPEC: u8 = 20;
+const RALERT: u8 = 21;
+const RHANDSHAKE: u8 = 22;
+const RAPPLICATIONDATA: u8 = 23;
+
+// Handshake message types
+const HCLIENTHELLO: u8 = 1;
+const HSERVERHELLO: u8 = 2;
+const HSERVERKEYEXCHANGE: u8 = 12;
+const HSERVERHELLODONE: u8 = 14;
+const HCLIENTKEYEXCHANGE: u8 = 16;
+const HFINISHED: u8 = 20;
+
+// Alerts
+const ECLOSENOTIFY: u8 = 0;
+const EUNEXPECTEDMESSAGE: u8 = 10;
+const EBADRECORDMAC: u8 = 20;
+const ERECORDOVERFLOW: u8 = 22;
+const EHANDSHAKEFAILURE: u8 = 40;
+const EDECODEERROR: u8 = 50;
+const EDECRYPTERROR: u8 = 51;
+const EPROTOCOLVERSION: u8 = 70;
+const EUNKNOWNPSKIDENTITY: u8 = 115;
+
+/// Error type for the TLS connection
+#[derive(Debug, Clone)]
+pub struct TlsError(pub String);
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// The PSK cipher suites we speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsSuite {
+    /// TLS_PSK_WITH_CHACHA20_POLY1305_SHA256
+    PskChacha20Poly1305 = 0xCCAB,
+    /// TLS_PSK_WITH_AES_128_GCM_SHA256
+    PskAes128Gcm = 0x00A8,
+}
+
+impl TlsSuite {
+    /// Both, in order of preference.
+    pub const ALL: [TlsSuite; 2] = [TlsSuite::PskChacha20Poly1305, TlsSuite::PskAes128Gcm];
+
+    pub fn from_u16(v: u16) -> Option<TlsSuite> {
+        TlsSuite::ALL.into_iter().find(|&s| s as u16 == v)
+    }
+
+    fn key_len(self) -> usize {
+        match self {
+            TlsSuite::PskChacha20Poly1305 => 32,
+            TlsSuite::PskAes128Gcm => 16,
+        }
+    }
+
+    /// The implicit part of the nonce
+    fn iv_len(self) -> usize {
+        match self {
+            TlsSuite::PskChacha20Poly1305 => 12,
+            TlsSuite::PskAes128Gcm => 4,
+        }
+    }
+}
+
+// ============================================================================
+// PRF and key schedule
+// ============================================================================
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut m = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key");
+    for p in parts {
+        m.update(p);
+    }
+    m.finalize().into_bytes().into()
+}
+
+/// The TLS 1.2 PRF, P_SHA256 (RFC 5246 5).
+pub fn prf(secret: &[u8], label: &str, seed: &[u8], n: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(n + 32);
+    let mut a = hmac_sha256(secret, &[label.as_bytes(), seed]);
+    while out.len() < n {
+        out.extend_from_slice(&hmac_sha256(secret, &[&a, label.as_bytes(), seed]));
+        a = hmac_sha256(secret, &[&a]);
+    }
+    out.truncate(n);
+    out
+}
+
+/// The plain-PSK premaster secret (RFC 4279 2).
+fn psk_premaster(psk: &[u8]) -> Vec<u8> {
+    let n = (psk.len() as u16).to_be_bytes();
+    let mut pms = Vec::with_capacity(4 + 2 * psk.len());
+    pms.extend_from_slice(&n);
+    pms.resize(2 + psk.len(), 0);
+    pms.extend_from_slice(&n);
+    pms.extend_from_slice(psk);
+    pms
+}
+
+// ============================================================================
+// Record protection
+// ============================================================================
+
+enum Aead {
+    Chacha(Box<ChaCha20Poly1305>),
+    Gcm(Box<Aes128Gcm>),
+}
+
+/// One direction's record cipher and sequence number
+struct RecordKey {
+    aead: Aead,
+    iv: Vec<u8>,
+    seq: u64,
+}
+
+impl RecordKey {
+    fn new(suite: TlsSuite, key: &[u8], iv: &[u8]) -> Self {
+        let aead = match suite {
+            TlsSuite::PskChacha20Poly1305 => {
+                Aead::Chacha(Box::new(ChaCha20Poly1305::new_from_slice(key).unwrap()))
+            }
+            TlsSuite::PskAes128Gcm => Aead::Gcm(Box::new(Aes128Gcm::new_from_slice(key).unwrap())),
+        };
+        RecordKey {
+            aead,
+            iv: iv.to_vec(),
+            seq: 0,
+        }
+    }
+
+    fn aad(&self, rtype: u8, len: usize) -> [u8; 13] {
+        let mut aad = [0u8; 13];
+        aad[..8].copy_from_slice(&self.seq.to_be_bytes());
+        aad[8] = rtype;
+        aad[9..11].copy_from_slice(&TLSVERSION.to_be_bytes());
+        aad[11..].copy_from_slice(&(len as u16).to_be_bytes());
+        aad
+    }
+
+    /// The record body for `data`: explicit nonce (GCM only), ciphertext,
+    /// tag.
+    fn seal(&mut self, rtype: u8, data: &[u8]) -> Vec<u8> {
+        let aad = self.aad(rtype, data.len());
+        let seq = self.seq.to_be_bytes();
+        self.seq += 1;
+        let mut body = data.to_vec();
+        let tag = match &self.aead {
+            Aead::Chacha(c) => {
+                let mut nonce = [0u8; 12];
+                nonce.copy_from_slice(&self.iv);
+                for (n, s) in nonce[4..].iter_mut().zip(seq) {
+                    *n ^= s;
+                }
+                c.encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut body)
+            }
+            Aead::Gcm(c) => {
+                let mut nonce = [0u8; 12];
+                nonce[..4].copy_from_slice(&self.iv);
+                nonce[4..].copy_from_slice(&seq);
+                let tag = c.encrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut body);
+                body.splice(0..0, seq);
+                tag
+            }
+        }
+        .expect("aead encrypt");
+        body.extend_from_slice(&tag);
+        body
+    }
+
+    fn open(&mut self, rtype: u8, body: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; 12];
+        let body = match &self.aead {
+            Aead::Chacha(_) => {
+                nonce.copy_from_slice(&self.iv);
+                for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+                    *n ^= s;
+                }
+                body
+            }
+            Aead::Gcm(_) => {
+                let explicit = body.get(..8)?;
+                nonce[..4].copy_from_slice(&self.iv);
+                nonce[4..].copy_from_slice(explicit);
+                &body[8..]
+            }
+        };
+        let len = body.len().checked_sub(16)?;
+        let aad = self.aad(rtype, len);
+        let mut data = body[..len].to_vec();
+        let tag = Tag::from_slice(&body[len..]);
+        let ok = match &self.aead {
+            Aead::Chacha(c) => {
+                c.decrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut data, tag)
+            }
+            Aead::Gcm(c) => {
+                c.decrypt_in_place_detached(Nonce::from_slice(&nonce), &aad, &mut data, tag)
+            }
+        };
+        ok.ok()?;
+        self.seq += 1;
+        Some(data)
+    }
+}
+
+// ============================================================================
+// Handshake
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Client
+    NeedServerHello,
+    NeedServerHelloDone,
+    // Server
+    NeedClientHello,
+    NeedClientKeyExchange,
+    // Both
+    NeedChangeCipherSpec,
+    NeedFinished,
+    Established,
+    Closed,
+}
+
+/// A fatal alert to send and the error to report
+struct Alert(u8, String);
+
+fn alert<T>(desc: u8, msg: &str) -> Result<T, Alert> {
+    Err(Alert(desc, msg.to_string()))
+}
+
+/// Reads the fields of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Alert> {
+        if self.0.len() < n {
+            return alert(EDECODEERROR, "short handshake message");
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u8(&mut self) -> Result<u8, Alert> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Alert> {
+        let v = self.take(2)?;
+        Ok(u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    /// A vector with a `width`-byte length
+    fn vec(&mut self, width: usize) -> Result<&'a [u8], Alert> {
+        let n = match width {
+            1 => self.u8()? as usize,
+            _ => self.u16()? as usize,
+        };
+        self.take(n)
+    }
+}
+
+/// A TLS 1.2 PSK connection, client or server
+pub struct TlsPsk {
+    is_client: bool,
+    psk: Vec<u8>,
+    suites: Vec<TlsSuite>,
+    suite: Option<TlsSuite>,
+    state: State,
+    client_random: [u8; RANDOMLEN],
+    server_random: [u8; RANDOMLEN],
+    master: Vec<u8>,
+    transcript: Sha256,
+    /// Unparsed bytes from the peer
+    inbuf: Vec<u8>,
+    /// Handshake bytes not yet making a whole message
+    hsbuf: Vec<u8>,
+    /// Bytes for the peer
+    out: Vec<u8>,
+    wkey: Option<RecordKey>,
+    rkey: Option<RecordKey>,
+    /// Keys waiting for our ChangeCipherSpec and the peer's
+    pending_wkey: Option<RecordKey>,
+    pending_rkey: Option<RecordKey>,
+}
+
+impl TlsPsk {
+    /// Start a client with `psk`, normally `AuthInfo::secret`; the
+    /// ClientHello is ready to `take`.
+    pub fn client(psk: &[u8], suites: &[TlsSuite]) -> Self {
+        let mut c = Self::new(true, psk, suites);
+        c.state = State::NeedServerHello;
+        c.send_client_hello();
+        c
+    }
+
+    /// Start a server, which waits for the ClientHello.
+    pub fn server(psk: &[u8], suites: &[TlsSuite]) -> Self {
+        let mut s = Self::new(false, psk, suites);
+        s.state = State::NeedClientHello;
+        s
+    }
+
+    fn new(is_client: bool, psk: &[u8], suites: &[TlsSuite]) -> Self {
+        TlsPsk {
+            is_client,
+            psk: psk.to_vec(),
+            suites: suites.to_vec(),
+            suite: None,
+            state: State::Closed,
+            client_random: [0; RANDOMLEN],
+            server_random: [0; RANDOMLEN],
+            master: Vec::new(),
+            transcript: Sha256::new(),
+            inbuf: Vec::new(),
+            hsbuf: Vec::new(),
+            out: Vec::new(),
+            wkey: None,
+            rkey: None,
+            pending_wkey: None,
+            pending_rkey: None,
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// The suite agreed on, once the hellos are through.
+    pub fn suite(&self) -> Option<TlsSuite> {
+        self.suite
+    }
+
+    /// Bytes waiting to go to the peer.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    /// Bytes from the peer, in any pieces. Returns the application data
+    /// they complete; handshake replies are left for `take`.
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, TlsError> {
+        if self.state == State::Closed {
+            return Err(TlsError("tls: connection closed".to_string()));
+        }
+        self.inbuf.extend_from_slice(data);
+        let mut app = Vec::new();
+        match self.records(&mut app) {
+            Ok(()) => Ok(app),
+            Err(Alert(desc, msg)) => {
+                // A fatal alert from the peer has closed us already and
+                // gets no answer; only our own errors are sent
+                if self.state != State::Closed {
+                    self.send_alert(2, desc);
+                }
+                self.state = State::Closed;
+                Err(TlsError(format!("tls: {}", msg)))
+            }
+        }
+    }
+
+    /// Seal application data into records for the peer.
+    pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, TlsError> {
+        if self.state != State::Established {
+            return Err(TlsError("tls: handshake not finished".to_string()));
+        }
+        for chunk in data.chunks(MAXFRAGMENT) {
+            self.send_record(RAPPLICATIONDATA, chunk);
+        }
+        Ok(self.take())
+    }
+
+    /// Say goodbye with close_notify; returns the bytes to send.
+    pub fn close(&mut self) -> Vec<u8> {
+        if self.state != State::Closed {
+            self.send_alert(1, ECLOSENOTIFY);
+            self.state = State::Closed;
+        }
+        self.take()
+    }
+
+    fn send_record(&mut self, rtype: u8, data: &[u8]) {
+        let body = match &mut self.wkey {
+            Some(k) => k.seal(rtype, data),
+            None => data.to_vec(),
+        };
+        self.out.push(rtype);
+        self.out.extend_from_slice(&TLSVERSION.to_be_bytes());
+        self.out
+            .extend_from_slice(&(body.len() as u16).to_be_bytes());
+        self.out.extend_from_slice(&body);
+    }
+
+    fn send_alert(&mut self, level: u8, desc: u8) {
+        self.send_record(RALERT, &[level, desc]);
+    }
+
+    fn send_handshake(&mut self, htype: u8, body: &[u8]) {
+        let mut msg = vec![htype];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(body);
+        self.transcript.update(&msg);
+        self.send_record(RHANDSHAKE, &msg);
+    }
+
+    fn send_client_hello(&mut self) {
+        getrandom::getrandom(&mut self.client_random).expect("Failed to get random bytes");
+        let mut b = TLSVERSION.to_be_bytes().to_vec();
+        b.extend_from_slice(&self.client_random);
+        b.push(0); // no session id
+        b.extend_from_slice(&(2 * self.suites.len() as u16 + 2).to_be_bytes());
+        for s in &self.suites {
+            b.extend_from_slice(&(*s as u16).to_be_bytes());
+        }
+        // We never renegotiate, and say so
+        b.extend_from_slice(&EMPTYRENEGOTIATIONINFOSCSV.to_be_bytes());
+        b.extend_from_slice(&[1, 0]); // null compression
+        self.send_handshake(HCLIENTHELLO, &b);
+    }
+
+    /// Split `inbuf` into records and act on each.
+    fn records(&mut self, app: &mut Vec<u8>) -> Result<(), Alert> {
+        while self.inbuf.len() >= 5 {
+            let rtype = self.inbuf[0];
+            let len = u16::from_be_bytes([self.inbuf[3], self.inbuf[4]]) as usize;
+            if len > MAXFRAGMENT + 2048 {
+                return alert(ERECORDOVERFLOW, "record too long");
+            }
+            if self.inbuf[1] != 3 {
+                return alert(EPROTOCOLVERSION, "not a TLS record");
+            }
+            if self.inbuf.len() < 5 + len {
+                break;
+            }
+            let body: Vec<u8> = self.inbuf.drain(..5 + len).skip(5).collect();
+            let data = match &mut self.rkey {
+                Some(k) => match k.open(rtype, &body) {
+                    Some(d) => d,
+                    None => return alert(EBADRECORDMAC, "bad record mac"),
+                },
+                None => body,
+            };
+            if data.len() > MAXFRAGMENT {
+                return alert(ERECORDOVERFLOW, "record too long");
+            }
+            match rtype {
+                RHANDSHAKE => {
+                    self.hsbuf.extend_from_slice(&data);
+                    self.handshakes()?;
+                }
+                RCHANGECIPHERSPEC => {
+                    if data != [1] || self.state != State::NeedChangeCipherSpec {
+                        return alert(EUNEXPECTEDMESSAGE, "unexpected change cipher spec");
+                    }
+                    self.rkey = self.pending_rkey.take();
+                    self.state = State::NeedFinished;
+                }
+                RALERT => {
+                    if data.len() != 2 {
+                        return alert(EDECODEERROR, "bad alert");
+                    }
+                    if data[1] == ECLOSENOTIFY {
+                        self.state = State::Closed;
+                        return Ok(());
+                    }
+                    if data[0] == 2 {
+                        self.state = State::Closed;
+                        return Err(Alert(data[1], format!("peer sent alert {}", data[1])));
+                    }
+                }
+                RAPPLICATIONDATA if self.state == State::Established => {
+                    app.extend_from_slice(&data);
+                }
+                _ => return alert(EUNEXPECTEDMESSAGE, "unexpected record"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Act on each whole handshake message in `hsbuf`.
+    fn handshakes(&mut self) -> Result<(), Alert> {
+        while self.hsbuf.len() >= 4 {
+            let len = u32::from_be_bytes([0, self.hsbuf[1], self.hsbuf[2], self.hsbuf[3]]) as usize;
+            if len > MAXFRAGMENT {
+                return alert(EDECODEERROR, "handshake message too long");
+            }
+            if self.hsbuf.len() < 4 + len {
+                break;
+            }
+            let msg: Vec<u8> = self.hsbuf.drain(..4 + len).collect();
+            // Finished is checked against the transcript before it
+            let before = self.transcript.clone();
+            self.transcript.update(&msg);
+            self.handshake(msg[0], &msg[4..], before)?;
+        }
+        Ok(())
+    }
+
+    fn handshake(&mut self, htype: u8, body: &[u8], before: Sha256) -> Result<(), Alert> {
+        let mut r = Reader(body);
+        match (htype, self.state) {
+            (HSERVERHELLO, State::NeedServerHello) => {
+                if r.u16()? != TLSVERSION {
+                    return alert(EPROTOCOLVERSION, "server is not TLS 1.2");
+                }
+                self.server_random.copy_from_slice(r.take(RANDOMLEN)?);
+                r.vec(1)?;
+                let suite = TlsSuite::from_u16(r.u16()?).filter(|s| self.suites.contains(s));
+                if suite.is_none() || r.u8()? != 0 {
+                    return alert(EHANDSHAKEFAILURE, "server chose a suite we did not offer");
+                }
+                self.suite = suite;
+                self.state = State::NeedServerHelloDone;
+            }
+            // Only an identity hint, which tlsclient ignores too
+            (HSERVERKEYEXCHANGE, State::NeedServerHelloDone) => {
+                r.vec(2)?;
+            }
+            (HSERVERHELLODONE, State::NeedServerHelloDone) => {
+                let mut ckx = (PSKID.len() as u16).to_be_bytes().to_vec();
+                ckx.extend_from_slice(PSKID.as_bytes());
+                self.send_handshake(HCLIENTKEYEXCHANGE, &ckx);
+                self.derive_keys();
+                self.send_change_cipher_spec();
+                let verify = self.verify_data(true, self.transcript.clone());
+                self.send_handshake(HFINISHED, &verify);
+                self.state = State::NeedChangeCipherSpec;
+            }
+            (HCLIENTHELLO, State::NeedClientHello) => {
+                if r.u16()? < TLSVERSION {
+                    return alert(EPROTOCOLVERSION, "client is older than TLS 1.2");
+                }
+                self.client_random.copy_from_slice(r.take(RANDOMLEN)?);
+                r.vec(1)?;
+                let offered: Vec<u16> = r
+                    .vec(2)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                if !r.vec(1)?.contains(&0) {
+                    return alert(EHANDSHAKEFAILURE, "no null compression");
+                }
+                let mut reneg = offered.contains(&EMPTYRENEGOTIATIONINFOSCSV);
+                if !r.0.is_empty() {
+                    let mut x = Reader(r.vec(2)?);
+                    while !x.0.is_empty() {
+                        reneg |= x.u16()? == XRENEGOTIATIONINFO;
+                        x.vec(2)?;
+                    }
+                }
+                // Our preference, not the client's
+                self.suite = self
+                    .suites
+                    .iter()
+                    .copied()
+                    .find(|s| offered.contains(&(*s as u16)));
+                let suite = match self.suite {
+                    Some(s) => s,
+                    None => return alert(EHANDSHAKEFAILURE, "no PSK suite in common"),
+                };
+                getrandom::getrandom(&mut self.server_random).expect("Failed to get random bytes");
+                let mut b = TLSVERSION.to_be_bytes().to_vec();
+                b.extend_from_slice(&self.server_random);
+                b.push(0);
+                b.extend_from_slice(&(suite as u16).to_be_bytes());
+                b.push(0);
+                if reneg {
+                    // renegotiation_info, empty for a first handshake
+                    b.extend_from_slice(&[0, 5, 0xff, 0x01, 0, 1, 0]);
+                }
+                self.send_handshake(HSERVERHELLO, &b);
+                self.send_handshake(HSERVERHELLODONE, &[]);
+                self.state = State::NeedClientKeyExchange;
+            }
+            (HCLIENTKEYEXCHANGE, State::NeedClientKeyExchange) => {
+                if r.vec(2)? != PSKID.as_bytes() {
+                    return alert(EUNKNOWNPSKIDENTITY, "unknown PSK identity");
+                }
+                self.derive_keys();
+                self.state = State::NeedChangeCipherSpec;
+            }
+            (HFINISHED, State::NeedFinished) => {
+                let want = self.verify_data(!self.is_client, before);
+                if body.len() != FINISHEDLEN || !ct_eq(body, &want) {
+                    return alert(EDECRYPTERROR, "bad finished: wrong secret?");
+                }
+                if !self.is_client {
+                    self.send_change_cipher_spec();
+                    let verify = self.verify_data(false, self.transcript.clone());
+                    self.send_handshake(HFINISHED, &verify);
+                }
+                self.state = State::Established;
+            }
+            _ => return alert(EUNEXPECTEDMESSAGE, "unexpected handshake message"),
+        }
+        Ok(())
+    }
+
+    /// Master secret and record keys, each used from its direction's
+    /// ChangeCipherSpec.
+    fn derive_keys(&mut self) {
+        let suite = self.suite.expect("keys before suite");
+        let randoms = [self.client_random, self.server_random].concat();
+        self.master = prf(
+            &psk_premaster(&self.psk),
+            "master secret",
+            &randoms,
+            MASTERLEN,
+        );
+        let seed = [self.server_random, self.client_random].concat();
+        let (kl, il) = (suite.key_len(), suite.iv_len());
+        let kb = prf(&self.master, "key expansion", &seed, 2 * (kl + il));
+        let client = RecordKey::new(suite, &kb[..kl], &kb[2 * kl..2 * kl + il]);
+        let server = RecordKey::new(suite, &kb[kl..2 * kl], &kb[2 * kl + il..]);
+        let (w, r) = if self.is_client {
+            (client, server)
+        } else {
+            (server, client)
+        };
+        self.pending_wkey = Some(w);
+        self.pending_rkey = Some(r);
+    }
+
+    /// ChangeCipherSpec, in the clear; what follows is sealed.
+    fn send_change_cipher_spec(&mut self) {
+        self.send_record(RCHANGECIPHERSPEC, &[1]);
+        self.wkey = self.pending_wkey.take();
+    }
+
+    fn verify_data(&self, client: bool, transcript: Sha256) -> Vec<u8> {
+        let label = if client {
+            "client finished"
+        } else {
+            "server finished"
+        };
+        prf(&self.master, label, &transcript.finalize(), FINISHEDLEN)
+    }
+}
+
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the handshake, feeding each side's output to the other `chunk`
+    /// bytes at a time.
+    fn handshake(c: &mut TlsPsk, s: &mut TlsPsk, chunk: usize) -> Result<(), TlsError> {
+        for _ in 0..2 {
+            for b in c.take().chunks(chunk) {
+                assert!(s.decrypt(b)?.is_empty());
+            }
+            for b in s.take().chunks(chunk) {
+                assert!(c.decrypt(b)?.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_prf_vector() {
+        // The widely used TLS 1.2 PRF-SHA256 test vector
+        let secret = hex::decode("9bbe436ba940f017b17652849a71db35").unwrap();
+        let seed = hex::decode("a0ba9f936cda311827a6f796ffd5198c").unwrap();
+        let out = prf(&secret, "test label", &seed, 100);
+        assert_eq!(
+            hex::encode(&out[..32]),
+            "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a"
+        );
+        assert_eq!(hex::encode(&out[95..]), "0187347b66");
+    }
+
+    #[test]
+    fn test_handshake_both_suites() {
+        let secret = [7u8; 256];
+        for suite in TlsSuite::ALL {
+            let mut c = TlsPsk::client(&secret, &TlsSuite::ALL);
+            let mut s = TlsPsk::server(&secret, &[suite]);
+            assert!(c.encrypt(b"early").is_err());
+            handshake(&mut c, &mut s, 1 << 20).unwrap();
+            assert!(c.is_established() && s.is_established());
+            assert_eq!((c.suite(), s.suite()), (Some(suite), Some(suite)));
+
+            let rec = c.encrypt(b"Tversion").unwrap();
+            assert!(!rec.windows(8).any(|w| w == b"Tversion"));
+            assert_eq!(s.decrypt(&rec).unwrap(), b"Tversion");
+            let rec = s.encrypt(b"Rversion").unwrap();
+            assert_eq!(c.decrypt(&rec).unwrap(), b"Rversion");
+        }
+    }
+
+    #[test]
+    fn test_fragmented_and_large() {
+        // p9sk1's secret is only 8 bytes
+        let mut c = TlsPsk::client(b"8bytekey", &TlsSuite::ALL);
+        let mut s = TlsPsk::server(b"8bytekey", &TlsSuite::ALL);
+        handshake(&mut c, &mut s, 1).unwrap();
+        assert!(s.is_established());
+
+        let msg: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let recs = c.encrypt(&msg).unwrap();
+        let mut got = Vec::new();
+        for b in recs.chunks(1000) {
+            got.extend(s.decrypt(b).unwrap());
+        }
+        assert_eq!(got, msg);
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let mut c = TlsPsk::client(b"one secret", &TlsSuite::ALL);
+        let mut s = TlsPsk::server(b"another secret", &TlsSuite::ALL);
+        let err = handshake(&mut c, &mut s, 1 << 20).unwrap_err();
+        assert!(err.0.contains("bad record mac"), "{}", err);
+        assert!(s.is_closed());
+        // The alert reaches the client, which sends none back
+        assert!(c.decrypt(&s.take()).is_err());
+        assert!(c.is_closed());
+        assert!(c.take().is_empty());
+    }
+
+    #[test]
+    fn test_tampered_record_and_close() {
+        let mut c = TlsPsk::client(b"secret", &[TlsSuite::PskAes128Gcm]);
+        let mut s = TlsPsk::server(b"secret", &TlsSuite::ALL);
+        handshake(&mut c, &mut s, 1 << 20).unwrap();
+        let mut rec = c.encrypt(b"Twalk").unwrap();
+        let n = rec.len();
+        rec[n - 1] ^= 1;
+        assert!(s.decrypt(&rec).is_err());
+
+        let mut c = TlsPsk::client(b"secret", &TlsSuite::ALL);
+        let mut s = TlsPsk::server(b"secret", &TlsSuite::ALL);
+        handshake(&mut c, &mut s, 1 << 20).unwrap();
+        let bye = c.close();
+        assert!(c.is_closed());
+        assert!(s.decrypt(&bye).unwrap().is_empty());
+        assert!(s.is_closed());
+    }
+
+    #[test]
+    fn test_client_hello_layout() {
+        let mut c = TlsPsk::client(b"secret", &TlsSuite::ALL);
+        let hello = c.take();
+        assert_eq!(&hello[..3], &[RHANDSHAKE, 3, 3]);
+        assert_eq!(hello[5], HCLIENTHELLO);
+        // version, random, empty session id, then the suites and the SCSV
+        let suites = &hello[5 + 4 + 2 + RANDOMLEN + 1..];
+        assert_eq!(&suites[..8], &[0, 6, 0xcc, 0xab, 0x00, 0xa8, 0x00, 0xff]);
+
+        // A server with no suite in common refuses
+        let mut s = TlsPsk::server(b"secret", &[TlsSuite::PskAes128Gcm]);
+        let mut c = TlsPsk::client(b"secret", &[TlsSuite::PskChacha20Poly1305]);
+        assert!(s.decrypt(&c.take()).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! totp - time-based one-time passwords
+//!
+//! RFC 6238: HMAC the count of `period`-second steps since the epoch with
+//! the shared secret, take 31 bits at the offset named by the last nibble
+//! (RFC 4226 dynamic truncation), and print the low `digits` decimal
+//! digits. Keys live in factotum like any other:
+//!
+//! ```text
+//! key proto=totp label=vpn user=glenda digits=6 period=30 algorithm=sha1 !secret=JBSWY3DPEHPK3PXP
+//! ```
+//!
+//! `!secret` is base32, as provisioning QR codes carry it; `digits`,
+//! `period` and `algorithm` (sha1 or sha256) default as shown.
+//!
+//! Ported from 9front's auth/totp.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::keyring::{Key, Keyring, KeyringError};
+
+pub const DEFAULT_DIGITS: u32 = 6;
+pub const DEFAULT_PERIOD: u64 = 30;
+
+/// The HMAC hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+/// Decode RFC 4648 base32, ignoring case, spaces and padding.
+pub fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let v = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// RFC 4226 HOTP code for `counter`.
+pub fn hotp(secret: &[u8], counter: u64, digits: u32, alg: TotpAlgorithm) -> String {
+    let mac = match alg {
+        TotpAlgorithm::Sha1 => {
+            let mut m = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts any key");
+            m.update(&counter.to_be_bytes());
+            m.finalize().into_bytes().to_vec()
+        }
+        TotpAlgorithm::Sha256 => {
+            let mut m =
+                <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key");
+            m.update(&counter.to_be_bytes());
+            m.finalize().into_bytes().to_vec()
+        }
+    };
+    let off = (mac[mac.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(mac[off..off + 4].try_into().unwrap()) & 0x7fff_ffff;
+    let code = code as u64 % 10u64.pow(digits);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+/// RFC 6238 TOTP code at `time` seconds since the epoch.
+pub fn totp(secret: &[u8], time: u64, digits: u32, period: u64, alg: TotpAlgorithm) -> String {
+    hotp(secret, time / period, digits, alg)
+}
+
+/// The parameters of a `proto=totp` key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpKey {
+    pub secret: Vec<u8>,
+    pub digits: u32,
+    pub period: u64,
+    pub algorithm: TotpAlgorithm,
+}
+
+impl TotpKey {
+    pub fn from_key(key: &Key) -> Result<Self, KeyringError> {
+        if key.proto() != "totp" {
+            return Err(KeyringError(format!("not a totp key: {}", key)));
+        }
+        let secret = key
+            .get("!secret")
+            .ok_or_else(|| KeyringError("totp key has no !secret".to_string()))?;
+        let secret = base32_decode(secret)
+            .filter(|s| !s.is_empty())
+            .ok_or_else(|| KeyringError("totp !secret is not base32".to_string()))?;
+        let digits = match key.get("digits") {
+            None => DEFAULT_DIGITS,
+            Some(d) => d
+                .parse()
+                .ok()
+                .filter(|d| (6..=9).contains(d))
+                .ok_or_else(|| KeyringError(format!("bad totp digits {}", d)))?,
+        };
+        let period = match key.get("period") {
+            None => DEFAULT_PERIOD,
+            Some(p) => p
+                .parse()
+                .ok()
+                .filter(|&p| p > 0)
+                .ok_or_else(|| KeyringError(format!("bad totp period {}", p)))?,
+        };
+        let algorithm = match key.get("algorithm") {
+            None | Some("sha1") => TotpAlgorithm::Sha1,
+            Some("sha256") => TotpAlgorithm::Sha256,
+            Some(a) => return Err(KeyringError(format!("unknown totp algorithm {}", a))),
+        };
+        Ok(TotpKey {
+            secret,
+            digits,
+            period,
+            algorithm,
+        })
+    }
+
+    /// The first usable totp key matching `pattern` (extra attributes such
+    /// as `label=vpn`).
+    pub fn find(ring: &Keyring, pattern: &str) -> Result<Self, KeyringError> {
+        Self::from_key(ring.find(&format!("proto=totp {}", pattern))?)
+    }
+
+    /// The code at `time`.
+    pub fn code(&self, time: u64) -> String {
+        totp(&self.secret, time, self.digits, self.period, self.algorithm)
+    }
+
+    /// Seconds until the code at `time` changes.
+    pub fn remaining(&self, time: u64) -> u64 {
+        self.period - time % self.period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(
+            base32_decode("JBSWY3DPEHPK3PXP").unwrap(),
+            b"Hello!\xde\xad\xbe\xef"
+        );
+        assert_eq!(base32_decode("mzxw6===").unwrap(), b"foo");
+        assert_eq!(base32_decode("MZXW 6YQ=").unwrap(), b"foob");
+        assert_eq!(base32_decode("MZ1"), None);
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let sha1 = b"12345678901234567890";
+        let sha256 = b"12345678901234567890123456789012";
+        for (time, want1, want256) in [
+            (59, "94287082", "46119246"),
+            (1111111109, "07081804", "68084774"),
+            (1234567890, "89005924", "91819424"),
+            (20000000000, "65353130", "77737706"),
+        ] {
+            assert_eq!(totp(sha1, time, 8, 30, TotpAlgorithm::Sha1), want1);
+            assert_eq!(totp(sha256, time, 8, 30, TotpAlgorithm::Sha256), want256);
+        }
+    }
+
+    #[test]
+    fn test_rfc4226_vectors() {
+        let want = ["755224", "287082", "359152", "969429", "338314"];
+        for (i, w) in want.iter().enumerate() {
+            assert_eq!(
+                hotp(b"12345678901234567890", i as u64, 6, TotpAlgorithm::Sha1),
+                *w
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_from_keyring() {
+        // GEZDGNBV... is base32 of the RFC secret
+        let ring = Keyring::parse(
+            "key proto=totp label=vpn digits=8 !secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\n\
+             key proto=totp label=mail algorithm=sha512 !secret=GEZDGNBV\n",
+        )
+        .unwrap();
+        let k = TotpKey::find(&ring, "label=vpn").unwrap();
+        assert_eq!(k.secret, b"12345678901234567890");
+        assert_eq!(k.code(59), "94287082");
+        assert_eq!(k.remaining(59), 1);
+        assert!(TotpKey::find(&ring, "label=mail").is_err());
+        assert!(TotpKey::find(&ring, "label=none").is_err());
+    }
+}
@@ -0,0 +1,857 @@
+//! Transcripts of factotum conversations, for debugging failed logins
+//!
+//! With a transcript started, `Factotum` notes every whole message of a
+//! conversation: what it sent and received, from the server or the auth
+//! server, decoded as far as is safe. The caller's side (start, needkey,
+//! keys added, done, error) is noted too.
+//!
+//! Nothing secret is kept. Passwords and other `!` attributes show only
+//! their names; tickets, authenticators and the auth server's AuthOK
+//! replies keep their length but not their bytes; authinfo keeps the
+//! names and not the secret. What is left (offers, challenges, ticket
+//! requests, PAK public values, AuthErr) is safe to send to whoever is
+//! debugging.
+//!
+//! `replay` runs a fresh factotum against a transcript's inputs and
+//! reports the first entry it does differently, or where it had to stop
+//! because the next input was redacted. A login that failed for lack of a
+//! key, for an offer we would not take, or with an auth server error shows
+//! up before then.
+//!
+//! `to_json` and `from_json` carry transcripts out of the browser.
+
+use std::fmt;
+
+use crate::authpak::PAKYLEN;
+use crate::authsrv::{parse_reply, AuthError, Decoded, Ticketreq, AERRLEN, AUTH_PAK, TICKREQLEN};
+use crate::form1::{FORM1AUTHENTLEN, FORM1TICKETLEN};
+use crate::keyring::{format_attrs, parse_attrs, quote, AttrType, Key, Keyring};
+use crate::p9sk1::{AUTHENTLEN, AUTH_ERR, AUTH_OK, AUTH_TREQ, CHALLEN, TICKETLEN};
+use crate::rpc::{AuthInfo, Factotum, RpcReply};
+
+/// Error type for transcripts
+#[derive(Debug, Clone)]
+pub struct TranscriptError(pub String);
+
+impl fmt::Display for TranscriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TranscriptError {}
+
+/// Which way a message went, from our side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Send,
+    Recv,
+}
+
+/// Who was on the other end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    /// The server being authenticated to
+    Server,
+    /// The auth server, through `authsrv` replies
+    Authsrv,
+    /// Whoever drives factotum: start, keys and the outcome
+    Caller,
+}
+
+impl Dir {
+    pub fn name(self) -> &'static str {
+        match self {
+            Dir::Send => "send",
+            Dir::Recv => "recv",
+        }
+    }
+
+    fn from_name(s: &str) -> Option<Dir> {
+        [Dir::Send, Dir::Recv].into_iter().find(|d| d.name() == s)
+    }
+}
+
+impl Peer {
+    pub fn name(self) -> &'static str {
+        match self {
+            Peer::Server => "server",
+            Peer::Authsrv => "authsrv",
+            Peer::Caller => "caller",
+        }
+    }
+
+    fn from_name(s: &str) -> Option<Peer> {
+        [Peer::Server, Peer::Authsrv, Peer::Caller]
+            .into_iter()
+            .find(|p| p.name() == s)
+    }
+}
+
+/// One message or event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub dir: Dir,
+    pub peer: Peer,
+    /// What it was: offer, treq, AuthPAK+AuthTreq, ticket, done...
+    pub msg: String,
+    /// What could be decoded from it without giving anything away. Only
+    /// what a replay should reproduce: no challenges or nonces.
+    pub fields: Vec<(String, String)>,
+    pub len: usize,
+    /// The bytes, unless redacted
+    pub data: Option<Vec<u8>>,
+}
+
+impl Entry {
+    fn event(dir: Dir, msg: &str, fields: Vec<(String, String)>) -> Entry {
+        Entry {
+            dir,
+            peer: Peer::Caller,
+            msg: msg.to_string(),
+            fields,
+            len: 0,
+            data: None,
+        }
+    }
+
+    /// Whether there were bytes the transcript does not keep.
+    pub fn redacted(&self) -> bool {
+        self.len > 0 && self.data.is_none()
+    }
+
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The same message, as far as a replay can tell.
+    fn same(&self, e: &Entry) -> bool {
+        (self.dir, self.peer, &self.msg, &self.fields) == (e.dir, e.peer, &e.msg, &e.fields)
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.dir.name(), self.peer.name(), self.msg)?;
+        for (n, v) in &self.fields {
+            write!(f, " {}={}", n, quote(v))?;
+        }
+        match self.len {
+            0 => Ok(()),
+            n if self.data.is_none() => write!(f, " ({} bytes, redacted)", n),
+            n => write!(f, " ({} bytes)", n),
+        }
+    }
+}
+
+/// A conversation's messages, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    entries: Vec<Entry>,
+    /// The next whole message written is the auth server's answer
+    authsrv: bool,
+    /// The verb that got needkey, which will be retried
+    retry: Option<(String, Vec<u8>)>,
+}
+
+fn field(name: &str, val: &str) -> (String, String) {
+    (name.to_string(), val.to_string())
+}
+
+/// Attributes with private values shown as `!name?`.
+fn redact_attrs(s: &str) -> String {
+    let attrs: Vec<_> = parse_attrs(s)
+        .into_iter()
+        .map(|mut a| {
+            if a.is_private() && a.attr_type != AttrType::Query {
+                a.attr_type = AttrType::Query;
+                a.val.clear();
+            }
+            a
+        })
+        .collect();
+    format_attrs(&attrs)
+}
+
+/// A NUL-terminated message of printable text.
+fn text(data: &[u8]) -> Option<&str> {
+    let (last, s) = data.split_last()?;
+    if *last != 0 || !s.iter().all(|&b| (b' '..0x7f).contains(&b)) {
+        return None;
+    }
+    std::str::from_utf8(s).ok()
+}
+
+fn treq_fields(tr: &Ticketreq) -> Vec<(String, String)> {
+    vec![
+        field("authid", &tr.authid),
+        field("authdom", &tr.authdom),
+        field("hostid", &tr.hostid),
+        field("uid", &tr.uid),
+    ]
+}
+
+/// Name a message and decide what of it is safe to keep: (msg, fields,
+/// public).
+fn decode(peer: Peer, dir: Dir, data: &[u8]) -> (String, Vec<(String, String)>, bool) {
+    let n = data.len();
+    match (peer, dir) {
+        (Peer::Server, Dir::Recv) => {
+            if let Some(s) = text(data) {
+                return match s {
+                    "OK" => ("ok".to_string(), vec![], true),
+                    s if s.starts_with("v.2") || s.contains('@') => {
+                        ("offer".to_string(), vec![field("offers", s)], true)
+                    }
+                    s => ("text".to_string(), vec![field("text", s)], true),
+                };
+            }
+            if (n == TICKREQLEN || n == TICKREQLEN + PAKYLEN) && data[0] == AUTH_TREQ {
+                if let Decoded::Done(tr, _) = Ticketreq::from_bytes(data) {
+                    let mut fields = treq_fields(&tr);
+                    if n > TICKREQLEN {
+                        fields.push(field("pak", "yes"));
+                    }
+                    return ("treq".to_string(), fields, true);
+                }
+            }
+            match n {
+                AUTHENTLEN | FORM1AUTHENTLEN => ("authenticator".to_string(), vec![], false),
+                _ => ("data".to_string(), vec![], false),
+            }
+        }
+        (Peer::Server, Dir::Send) => {
+            if let Some(s) = text(data) {
+                return ("choice".to_string(), vec![field("choice", s)], true);
+            }
+            match n {
+                CHALLEN => ("chal".to_string(), vec![], true),
+                _ if n == TICKETLEN + AUTHENTLEN => {
+                    ("ticket".to_string(), vec![field("form", "des")], false)
+                }
+                _ if n == PAKYLEN + FORM1TICKETLEN + FORM1AUTHENTLEN => {
+                    ("ticket".to_string(), vec![field("form", "form1")], false)
+                }
+                _ => ("data".to_string(), vec![], false),
+            }
+        }
+        (Peer::Authsrv, Dir::Send) => {
+            let mut names = Vec::new();
+            let mut fields = Vec::new();
+            let mut p = data;
+            while let Decoded::Done(tr, used) = Ticketreq::from_bytes(p) {
+                p = &p[used..];
+                names.push(match tr.req_type {
+                    AUTH_TREQ => "AuthTreq".to_string(),
+                    AUTH_PAK => {
+                        p = p.get(2 * PAKYLEN..).unwrap_or(&[]);
+                        "AuthPAK".to_string()
+                    }
+                    t => format!("type{}", t),
+                });
+                if fields.is_empty() {
+                    fields = treq_fields(&tr);
+                }
+            }
+            match names.is_empty() {
+                true => ("data".to_string(), vec![], false),
+                false => (names.join("+"), fields, true),
+            }
+        }
+        (Peer::Authsrv, Dir::Recv) => {
+            let pak = 1 + 2 * PAKYLEN;
+            let err = |buf: &[u8]| match parse_reply(buf, 0) {
+                Err(AuthError(e)) => e.strip_prefix("remote: ").unwrap_or(&e).to_string(),
+                _ => String::new(),
+            };
+            match data.first() {
+                Some(&AUTH_ERR) => (
+                    "AuthErr".to_string(),
+                    vec![field("error", &err(data))],
+                    true,
+                ),
+                Some(&AUTH_OK) if n == pak + 1 + AERRLEN && data[pak] == AUTH_ERR => (
+                    "AuthOK+AuthErr".to_string(),
+                    vec![field("error", &err(&data[pak..]))],
+                    true,
+                ),
+                Some(&AUTH_OK) => ("AuthOK".to_string(), vec![], false),
+                _ => ("data".to_string(), vec![], false),
+            }
+        }
+        (Peer::Caller, _) => ("data".to_string(), vec![], false),
+    }
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    fn message(&mut self, peer: Peer, dir: Dir, data: &[u8]) {
+        let (msg, fields, public) = decode(peer, dir, data);
+        self.entries.push(Entry {
+            dir,
+            peer,
+            msg,
+            fields,
+            len: data.len(),
+            data: public.then(|| data.to_vec()),
+        });
+    }
+
+    /// Note one rpc transaction: whole messages and outcomes only, not
+    /// toosmall or phase, and not a message again when a verb is retried
+    /// after needkey.
+    pub(crate) fn rpc(&mut self, verb: &str, arg: &[u8], reply: &RpcReply, ai: Option<&AuthInfo>) {
+        let retried = self
+            .retry
+            .take()
+            .is_some_and(|(v, a)| v == verb && a == arg);
+        match (verb, reply) {
+            (_, RpcReply::Toosmall(_) | RpcReply::Phase(_)) => return,
+            ("start", _) => self.entries.push(Entry::event(
+                Dir::Recv,
+                "start",
+                vec![field(
+                    "params",
+                    &redact_attrs(&String::from_utf8_lossy(arg)),
+                )],
+            )),
+            ("read", RpcReply::Ok(data)) if !data.is_empty() => {
+                self.message(Peer::Server, Dir::Send, data)
+            }
+            ("read", RpcReply::Authsrv { data, .. }) => {
+                self.message(Peer::Authsrv, Dir::Send, data);
+                self.authsrv = true;
+            }
+            ("write", _) if !retried => {
+                let peer = match std::mem::take(&mut self.authsrv) {
+                    true => Peer::Authsrv,
+                    false => Peer::Server,
+                };
+                self.message(peer, Dir::Recv, arg);
+            }
+            _ => {}
+        }
+        match reply {
+            RpcReply::Needkey(template) => {
+                self.entries.push(Entry::event(
+                    Dir::Send,
+                    "needkey",
+                    vec![field("template", &redact_attrs(template))],
+                ));
+                self.retry = Some((verb.to_string(), arg.to_vec()));
+            }
+            RpcReply::Done { haveai } => {
+                let fields = match (haveai, ai) {
+                    (true, Some(ai)) => vec![field("cuid", &ai.cuid), field("suid", &ai.suid)],
+                    _ => vec![],
+                };
+                self.entries.push(Entry::event(Dir::Send, "done", fields));
+            }
+            RpcReply::Error(e) => {
+                self.entries
+                    .push(Entry::event(Dir::Send, "error", vec![field("error", e)]));
+            }
+            _ => {}
+        }
+    }
+
+    /// Note a key added to the keyring, secrets hidden.
+    pub(crate) fn key(&mut self, key: &Key) {
+        self.entries.push(Entry::event(
+            Dir::Recv,
+            "key",
+            vec![field("key", &key.to_string())],
+        ));
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"entries\":[");
+        for (i, e) in self.entries.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out += &format!(
+                "{{\"dir\":{},\"peer\":{},\"msg\":{},\"len\":{},\"fields\":{{",
+                json_string(e.dir.name()),
+                json_string(e.peer.name()),
+                json_string(&e.msg),
+                e.len
+            );
+            for (j, (n, v)) in e.fields.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                out += &format!("{}:{}", json_string(n), json_string(v));
+            }
+            out.push('}');
+            match &e.data {
+                Some(d) => out += &format!(",\"data\":{}", json_string(&hex::encode(d))),
+                None if e.len > 0 => out += ",\"redacted\":true",
+                None => {}
+            }
+            out.push('}');
+        }
+        out += "]}";
+        out
+    }
+
+    pub fn from_json(s: &str) -> Result<Transcript, TranscriptError> {
+        let bad = |what: &str| TranscriptError(format!("transcript: bad {}", what));
+        let v = Json::parse(s).ok_or_else(|| bad("json"))?;
+        let mut t = Transcript::new();
+        for e in v
+            .get("entries")
+            .and_then(Json::array)
+            .ok_or_else(|| bad("entries"))?
+        {
+            let str_of = |name: &str| e.get(name).and_then(Json::str).ok_or_else(|| bad(name));
+            let fields = match e.get("fields") {
+                Some(Json::Obj(kv)) => kv
+                    .iter()
+                    .map(|(n, v)| v.str().map(|v| field(n, v)).ok_or_else(|| bad("field")))
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(bad("fields")),
+            };
+            let data = match e.get("data") {
+                Some(d) => Some(d.str().and_then(|s| hex::decode(s).ok()).ok_or_else(|| bad("data"))?),
+                None => None,
+            };
+            t.entries.push(Entry {
+                dir: Dir::from_name(str_of("dir")?).ok_or_else(|| bad("dir"))?,
+                peer: Peer::from_name(str_of("peer")?).ok_or_else(|| bad("peer"))?,
+                msg: str_of("msg")?.to_string(),
+                fields,
+                len: match e.get("len") {
+                    Some(Json::Num(n)) if *n >= 0.0 => *n as usize,
+                    _ => return Err(bad("len")),
+                },
+                data,
+            });
+        }
+        Ok(t)
+    }
+}
+
+// ============================================================================
+// Replay
+// ============================================================================
+
+/// How far a replay got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replay {
+    /// Every entry came out the same
+    Matched(usize),
+    /// Entry `index` differs; `None` where one side had nothing
+    Diverged {
+        index: usize,
+        recorded: Option<Entry>,
+        replayed: Option<Entry>,
+    },
+    /// All matched up to `index`, which cannot be replayed
+    Stopped { index: usize, reason: String },
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |e: &Option<Entry>| match e {
+            Some(e) => e.to_string(),
+            None => "nothing".to_string(),
+        };
+        match self {
+            Replay::Matched(n) => write!(f, "all {} entries matched", n),
+            Replay::Diverged {
+                index,
+                recorded,
+                replayed,
+            } => write!(
+                f,
+                "diverged at entry {}: recorded {}; replayed {}",
+                index,
+                show(recorded),
+                show(replayed)
+            ),
+            Replay::Stopped { index, reason } => {
+                write!(f, "matched up to entry {}, which {}", index, reason)
+            }
+        }
+    }
+}
+
+/// Run a factotum holding `ring` through the inputs `t` recorded.
+pub fn replay(t: &Transcript, ring: Keyring) -> Replay {
+    let mut f = Factotum::new(ring);
+    f.start_transcript();
+    let want = t.entries();
+    for (i, e) in want.iter().enumerate() {
+        let have = f.transcript().map_or(0, |t| t.entries.len());
+        if have <= i {
+            match (e.peer, e.dir) {
+                (Peer::Caller, Dir::Recv) if e.msg == "start" => {
+                    f.rpc("start", e.field("params").unwrap_or("").as_bytes());
+                }
+                (Peer::Caller, Dir::Recv) => {
+                    return Replay::Stopped {
+                        index: i,
+                        reason: format!("is a {} from the user", e.msg),
+                    }
+                }
+                (Peer::Server | Peer::Authsrv, Dir::Send) => {
+                    f.rpc("read", b"");
+                }
+                (_, Dir::Recv) => match &e.data {
+                    Some(data) => {
+                        f.rpc("write", data);
+                    }
+                    None => {
+                        return Replay::Stopped {
+                            index: i,
+                            reason: format!("is a redacted {}", e.msg),
+                        }
+                    }
+                },
+                (Peer::Caller, Dir::Send) => {}
+            }
+        }
+        let got = &f.transcript().unwrap().entries;
+        for j in have..got.len().max(i + 1) {
+            if !matches!((want.get(j), got.get(j)), (Some(a), Some(b)) if a.same(b)) {
+                return Replay::Diverged {
+                    index: j,
+                    recorded: want.get(j).cloned(),
+                    replayed: got.get(j).cloned(),
+                };
+            }
+        }
+    }
+    Replay::Matched(want.len())
+}
+
+// ============================================================================
+// JSON
+// ============================================================================
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Just enough JSON to read transcripts back
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(s: &str) -> Option<Json> {
+        let mut p = s.trim_start();
+        let v = Json::value(&mut p)?;
+        p.trim_start().is_empty().then_some(v)
+    }
+
+    fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(kv) => kv.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Arr(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// The value at the start of `p`, advancing past it.
+    fn value(p: &mut &str) -> Option<Json> {
+        *p = p.trim_start();
+        let c = p.chars().next()?;
+        for (word, v) in [
+            ("null", Json::Null),
+            ("true", Json::Bool(true)),
+            ("false", Json::Bool(false)),
+        ] {
+            if let Some(rest) = p.strip_prefix(word) {
+                *p = rest;
+                return Some(v);
+            }
+        }
+        match c {
+            '"' => Json::string(p).map(Json::Str),
+            '[' => {
+                *p = &p[1..];
+                let mut a = Vec::new();
+                loop {
+                    *p = p.trim_start();
+                    if let Some(rest) = p.strip_prefix(']') {
+                        *p = rest;
+                        return Some(Json::Arr(a));
+                    }
+                    if !a.is_empty() {
+                        *p = p.strip_prefix(',')?;
+                    }
+                    a.push(Json::value(p)?);
+                }
+            }
+            '{' => {
+                *p = &p[1..];
+                let mut kv = Vec::new();
+                loop {
+                    *p = p.trim_start();
+                    if let Some(rest) = p.strip_prefix('}') {
+                        *p = rest;
+                        return Some(Json::Obj(kv));
+                    }
+                    if !kv.is_empty() {
+                        *p = p.strip_prefix(',')?.trim_start();
+                    }
+                    let name = Json::string(p)?;
+                    *p = p.trim_start().strip_prefix(':')?;
+                    kv.push((name, Json::value(p)?));
+                }
+            }
+            _ => {
+                let n = p
+                    .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+                    .unwrap_or(p.len());
+                let v = p[..n].parse().ok()?;
+                *p = &p[n..];
+                Some(Json::Num(v))
+            }
+        }
+    }
+
+    fn string(p: &mut &str) -> Option<String> {
+        let mut chars = p.strip_prefix('"')?.char_indices();
+        let mut out = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    *p = &p[1 + i + 1..];
+                    return Some(out);
+                }
+                '\\' => out.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let hex: String =
+                            (0..4).filter_map(|_| chars.next().map(|c| c.1)).collect();
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    c => c,
+                }),
+                c => out.push(c),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::AuthProxy;
+    use crate::testsrv::{TestAuthsrv, TestServer};
+
+    fn setup(keys: &str) -> (Factotum, TestServer, TestAuthsrv) {
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut f = Factotum::new(Keyring::parse(keys).unwrap());
+        f.start_transcript();
+        let cpu = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        (f, cpu, srv)
+    }
+
+    /// Run auth_proxy to the end, answering needkey with `password`.
+    fn converse(f: Factotum, cpu: &mut TestServer, srv: &TestAuthsrv, password: &str) -> Factotum {
+        let mut p = AuthProxy::new(f, "proto=p9any role=client");
+        for _ in 0..20 {
+            if *p.state() == crate::rpc::ProxyState::Key {
+                let req = p.factotum().key_request().unwrap().clone();
+                let key = req
+                    .answer(&[("user", "glenda"), ("!password", password)])
+                    .unwrap();
+                p.supply_key(key);
+            }
+            if let Some(RpcReply::Authsrv { data, .. }) = p.take_authsrv() {
+                p.put_authsrv(&srv.serve(&data));
+            }
+            cpu.put(&p.take());
+            p.put(&cpu.take(4096));
+        }
+        p.into_factotum()
+    }
+
+    fn msgs(t: &Transcript) -> Vec<String> {
+        t.entries()
+            .iter()
+            .map(|e| format!("{} {} {}", e.dir.name(), e.peer.name(), e.msg))
+            .collect()
+    }
+
+    #[test]
+    fn test_record_dp9ik() {
+        let (f, mut cpu, srv) = setup("key proto=dp9ik dom=nawin user=glenda !password=kittens");
+        let f = converse(f, &mut cpu, &srv, "");
+        let t = f.transcript().unwrap();
+        assert_eq!(
+            msgs(t),
+            [
+                "recv caller start",
+                "recv server offer",
+                "send server choice",
+                "recv server ok",
+                "send server chal",
+                "recv server treq",
+                "send authsrv AuthPAK+AuthTreq",
+                "recv authsrv AuthOK",
+                "send server ticket",
+                "recv server authenticator",
+                "send caller done",
+            ]
+        );
+        let e = &t.entries()[5];
+        assert_eq!(e.field("authid"), Some("bootes"));
+        assert_eq!(e.field("pak"), Some("yes"));
+        assert!(t.entries()[8].redacted());
+        assert_eq!(t.entries()[10].field("cuid"), Some("glenda"));
+    }
+
+    #[test]
+    fn test_redaction() {
+        let (f, mut cpu, srv) = setup("");
+        let f = converse(f, &mut cpu, &srv, "kittens");
+        let t = f.transcript().unwrap();
+        // The key went in by needkey, and the message that asked is not
+        // noted twice
+        let m = msgs(t);
+        assert_eq!(m.iter().filter(|m| *m == "recv server treq").count(), 1);
+        assert!(m.contains(&"send caller needkey".to_string()));
+        assert!(m.contains(&"recv caller key".to_string()));
+
+        let json = t.to_json();
+        assert!(!json.contains("kittens"));
+        assert!(json.contains("!password?"));
+        assert!(!json.contains(&hex::encode(&f.authinfo().unwrap().secret)));
+        for e in t.entries() {
+            if matches!(e.msg.as_str(), "ticket" | "authenticator" | "AuthOK") {
+                assert!(e.redacted(), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let (f, mut cpu, srv) = setup("key proto=dp9ik dom=nawin user=glenda !password=kittens");
+        let f = converse(f, &mut cpu, &srv, "");
+        let t = f.transcript().unwrap();
+        let back = Transcript::from_json(&t.to_json()).unwrap();
+        assert_eq!(back.entries(), t.entries());
+
+        let mut odd = Transcript::new();
+        odd.entries.push(Entry::event(
+            Dir::Send,
+            "error",
+            vec![field("error", "a\"b\\c\n\u{1}é")],
+        ));
+        assert_eq!(Transcript::from_json(&odd.to_json()).unwrap(), odd);
+        assert!(Transcript::from_json("{\"entries\":[{}]}").is_err());
+        assert!(Transcript::from_json("[").is_err());
+    }
+
+    #[test]
+    fn test_replay_stops_at_redacted() {
+        let (f, mut cpu, srv) = setup("key proto=dp9ik dom=nawin user=glenda !password=kittens");
+        let t = converse(f, &mut cpu, &srv, "")
+            .transcript()
+            .unwrap()
+            .clone();
+        // Any password does until the tickets, which were not kept
+        let ring = Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=x").unwrap();
+        assert_eq!(
+            replay(&t, ring),
+            Replay::Stopped {
+                index: 7,
+                reason: "is a redacted AuthOK".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_replay_diverges() {
+        let (f, mut cpu, srv) = setup("key proto=dp9ik dom=nawin user=glenda !password=kittens");
+        let t = converse(f, &mut cpu, &srv, "")
+            .transcript()
+            .unwrap()
+            .clone();
+        // Without a key for nawin the replay asks for one instead
+        let ring = Keyring::parse("key proto=dp9ik dom=other user=glenda !password=x").unwrap();
+        match replay(&t, ring) {
+            Replay::Diverged {
+                index: 6,
+                recorded: Some(a),
+                replayed: Some(b),
+            } => {
+                assert_eq!(a.msg, "AuthPAK+AuthTreq");
+                assert_eq!(b.msg, "needkey");
+            }
+            r => panic!("{}", r),
+        }
+    }
+
+    #[test]
+    fn test_authsrv_error() {
+        let (f, mut cpu, srv) = setup("key proto=dp9ik dom=nawin user=rob !password=x");
+        let f = converse(f, &mut cpu, &srv, "");
+        let t = f.transcript().unwrap();
+        let e = t
+            .entries()
+            .iter()
+            .find(|e| e.peer == Peer::Authsrv && e.dir == Dir::Recv);
+        let e = e.unwrap();
+        assert_eq!(e.msg, "AuthErr");
+        assert_eq!(e.field("error"), Some("authpak failed"));
+        assert_eq!(t.entries().last().unwrap().msg, "error");
+        // An auth server error replays in full
+        let ring = Keyring::parse("key proto=dp9ik dom=nawin user=rob !password=y").unwrap();
+        assert_eq!(replay(t, ring), Replay::Matched(t.entries().len()));
+    }
+}
@@ -0,0 +1,83 @@
+//! VNC (RFB) challenge/response
+//!
+//! RFB's VNC authentication encrypts a 16-byte challenge as two DES blocks,
+//! keyed with the password truncated or zero padded to 8 bytes. vncviewer's
+//! DES reads key bits in the opposite order to everybody else's, so each key
+//! byte has its bits reversed first. The auth server can check the answer
+//! too (AuthVNC); that exchange is `ChalProto::Vnc` in `chal`.
+//!
+//! Ported from 9front's factotum p9cr.c (vnc) and authsrv.c.
+
+use crate::des9::Des9Key;
+
+pub const VNCCHALLEN: usize = 16;
+pub const VNCKEYLEN: usize = 8;
+
+/// Mangle a password into vncviewer's DES key.
+pub fn vnc_key(secret: &[u8]) -> [u8; VNCKEYLEN] {
+    let mut key = [0u8; VNCKEYLEN];
+    let n = secret.len().min(VNCKEYLEN);
+    key[..n].copy_from_slice(&secret[..n]);
+    for b in key.iter_mut() {
+        *b = b.reverse_bits();
+    }
+    key
+}
+
+/// Answer an RFB challenge with the given password.
+pub fn vnc_response(chal: &[u8; VNCCHALLEN], secret: &[u8]) -> [u8; VNCCHALLEN] {
+    let dk = Des9Key::from_key8(&vnc_key(secret));
+    let mut resp = *chal;
+    for half in resp.chunks_exact_mut(8) {
+        let block: &mut [u8; 8] = half.try_into().unwrap();
+        dk.encrypt_block(block);
+    }
+    resp
+}
+
+/// Check an RFB response by decrypting it, as the authsrv does.
+pub fn vnc_check(chal: &[u8; VNCCHALLEN], resp: &[u8; VNCCHALLEN], secret: &[u8]) -> bool {
+    let dk = Des9Key::from_key8(&vnc_key(secret));
+    let mut plain = *resp;
+    for half in plain.chunks_exact_mut(8) {
+        let block: &mut [u8; 8] = half.try_into().unwrap();
+        dk.decrypt_block(block);
+    }
+    plain == *chal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::des9::expand_key;
+
+    #[test]
+    fn test_vnc_key_mangling() {
+        let key = vnc_key(b"pass");
+        // 'p' = 0x70 = 01110000 reversed is 00001110
+        assert_eq!(key, [0x0e, 0x86, 0xce, 0xce, 0, 0, 0, 0]);
+
+        // Only 8 bytes are used
+        assert_eq!(vnc_key(b"longpassword"), vnc_key(b"longpass"));
+    }
+
+    #[test]
+    fn test_vnc_response_is_des_ecb() {
+        // Choose a password whose mangled form is the interop DES key from
+        // des9's tests, so the known ciphertext must come out twice.
+        let key8 = expand_key(&[0x67, 0x76, 0xd9, 0x4d, 0x0e, 0x03, 0x40]);
+        let secret: Vec<u8> = key8.iter().map(|b| b.reverse_bits()).collect();
+
+        let chal: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+        let resp = vnc_response(&chal, &secret);
+        assert_eq!(hex::encode(resp), "35597a5f0978217835597a5f09782178");
+    }
+
+    #[test]
+    fn test_vnc_check() {
+        let chal = [0x5au8; VNCCHALLEN];
+        let resp = vnc_response(&chal, b"secret");
+        assert!(vnc_check(&chal, &resp, b"secret"));
+        assert!(!vnc_check(&chal, &resp, b"Secret"));
+    }
+}
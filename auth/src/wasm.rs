@@ -0,0 +1,1262 @@
+//! wasm-bindgen exports for the browser
+//!
+//! Thin wrappers that take and return JS-friendly types. Keys cross the
+//! boundary as opaque handles, so key material stays in WASM memory.
+
+use std::io;
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+
+use crate::aan;
+use crate::afid::Attach;
+use crate::authsrv;
+use crate::conn;
+use crate::devssl;
+use crate::keycache;
+use crate::keyring::{Key, Keyring};
+use crate::netkey;
+use crate::rcpu;
+use crate::relay;
+use crate::rpc;
+use crate::sealed;
+use crate::session;
+use crate::tlspsk::{self, TlsSuite};
+use crate::totp::{self, TotpKey};
+use crate::transcript::{self, Transcript};
+use crate::vnc;
+
+/// A user's derived keys (passtokey), held on the Rust side.
+#[wasm_bindgen(js_name = Authkey)]
+pub struct JsAuthkey(pub(crate) authsrv::Authkey);
+
+#[wasm_bindgen(js_class = Authkey)]
+impl JsAuthkey {
+    /// Derive the DES and AES keys from a password.
+    #[wasm_bindgen(constructor)]
+    pub fn new(password: &str) -> JsAuthkey {
+        JsAuthkey(authsrv::Authkey::from_password(password))
+    }
+}
+
+/// Answer a netkey (SecureNet) challenge; returns the 8 hex digit response.
+#[wasm_bindgen]
+pub fn netkey(challenge: &str, key: &JsAuthkey) -> String {
+    netkey::netkey_response(challenge, &key.0)
+}
+
+/// The decimal form of a netkey response, for keypads without a-f.
+#[wasm_bindgen]
+pub fn netkey_decimal(response: &str) -> String {
+    netkey::netdecimal(response)
+}
+
+/// Answer a 16-byte RFB (VNC) challenge with a password.
+#[wasm_bindgen]
+pub fn vnc_response(challenge: &[u8], password: &str) -> Result<Vec<u8>, JsError> {
+    let chal: &[u8; vnc::VNCCHALLEN] = challenge
+        .try_into()
+        .map_err(|_| JsError::new("vnc challenge must be 16 bytes"))?;
+    Ok(vnc::vnc_response(chal, password.as_bytes()).to_vec())
+}
+
+/// A TOTP code straight from a base32 secret; `algorithm` is sha1 or
+/// sha256.
+#[wasm_bindgen]
+pub fn totp_code(
+    secret: &str,
+    time: f64,
+    digits: u32,
+    period: u32,
+    algorithm: &str,
+) -> Result<String, JsError> {
+    let secret = totp::base32_decode(secret).ok_or_else(|| JsError::new("secret is not base32"))?;
+    let alg = match algorithm {
+        "sha1" => totp::TotpAlgorithm::Sha1,
+        "sha256" => totp::TotpAlgorithm::Sha256,
+        _ => return Err(JsError::new("algorithm must be sha1 or sha256")),
+    };
+    if period == 0 || !(6..=9).contains(&digits) {
+        return Err(JsError::new("bad digits or period"));
+    }
+    Ok(totp::totp(&secret, time as u64, digits, period as u64, alg))
+}
+
+/// Keep keys derived from passwords for `seconds`, so a reconnect within
+/// that time authenticates without deriving them again or asking for the
+/// password; 0 (the default) turns this off and forgets them.
+#[wasm_bindgen]
+pub fn set_key_cache_ttl(seconds: u32) {
+    keycache::with(|c| c.set_ttl(seconds as u64));
+}
+
+/// Forget every cached key, as on logout.
+#[wasm_bindgen]
+pub fn purge_key_cache() {
+    keycache::with(|c| c.purge());
+}
+
+/// The metadata of a sealed keyring, readable without the PIN.
+#[wasm_bindgen]
+pub fn sealed_metadata(blob: &[u8]) -> Result<String, JsError> {
+    sealed::metadata(blob).map_err(|e| JsError::new(&e.0))
+}
+
+/// Run a transcript's inputs through a factotum holding `keys`; says
+/// where it did something different, or how far it got.
+#[wasm_bindgen]
+pub fn replay_transcript(json: &str, keys: &str) -> Result<String, JsError> {
+    let t = Transcript::from_json(json).map_err(|e| JsError::new(&e.0))?;
+    let ring = Keyring::parse(keys).map_err(|e| JsError::new(&e.0))?;
+    Ok(transcript::replay(&t, ring).to_string())
+}
+
+/// An emulated factotum: keys go in through `ctl`, conversations run
+/// through `rpc` with factotum's verbs.
+#[wasm_bindgen(js_name = Factotum)]
+pub struct JsFactotum(rpc::Factotum);
+
+#[wasm_bindgen(js_class = Factotum)]
+impl JsFactotum {
+    /// Start with the keys in a factotum key file (may be empty).
+    #[wasm_bindgen(constructor)]
+    pub fn new(keys: &str) -> Result<JsFactotum, JsError> {
+        let ring = Keyring::parse(keys).map_err(|e| JsError::new(&e.0))?;
+        Ok(JsFactotum(rpc::Factotum::new(ring)))
+    }
+
+    /// Open a keyring sealed by `seal`; fails on a wrong PIN.
+    pub fn unseal(blob: &[u8], pin: &str) -> Result<JsFactotum, JsError> {
+        let ring = sealed::unseal(blob, pin).map_err(|e| JsError::new(&e.0))?;
+        Ok(JsFactotum(rpc::Factotum::new(ring)))
+    }
+
+    /// The keys encrypted under `pin`, for the page to store. `metadata`
+    /// stays readable (see `sealed_metadata`) but is authenticated.
+    pub fn seal(&self, pin: &str, metadata: &str) -> Result<Vec<u8>, JsError> {
+        sealed::seal(self.0.keyring(), pin, metadata).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// A TLS-PSK client keyed by the finished conversation's secret, as
+    /// `tlsclient -a` does after p9any; the secret stays in WASM.
+    #[wasm_bindgen(js_name = tlsClient)]
+    pub fn tls_client(&self) -> Result<JsTlsPsk, JsError> {
+        let ai = self
+            .0
+            .authinfo()
+            .ok_or_else(|| JsError::new("no authinfo available"))?;
+        Ok(JsTlsPsk(tlspsk::TlsPsk::client(&ai.secret, &TlsSuite::ALL)))
+    }
+
+    /// The `cpu -e` record layer keyed by the finished conversation's
+    /// secret, once the client has sent `crand` and read `srand` (four
+    /// bytes each; see `Devssl.random`).
+    #[wasm_bindgen(js_name = sslClient)]
+    pub fn ssl_client(&self, crand: &[u8], srand: &[u8], algs: &str) -> Result<JsDevssl, JsError> {
+        let ai = self
+            .0
+            .authinfo()
+            .ok_or_else(|| JsError::new("no authinfo available"))?;
+        JsDevssl::client(&ai.secret, crand, srand, algs)
+    }
+
+    /// A `key ...` or `delkey ...` line, as written to factotum's ctl file.
+    pub fn ctl(&mut self, line: &str) -> Result<(), JsError> {
+        self.0.ctl(line).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// Refuse p9sk1 and its DES tickets; p9any will only pick dp9ik.
+    #[wasm_bindgen(js_name = refuseP9sk1)]
+    pub fn refuse_p9sk1(&mut self, refuse: bool) {
+        self.0.set_refuse_p9sk1(refuse);
+    }
+
+    /// The one-time code from the `proto=totp` key matching `pattern` (such
+    /// as `label=vpn`) at `time` seconds since the epoch.
+    pub fn totp(&self, pattern: &str, time: f64) -> Result<String, JsError> {
+        let key = TotpKey::find(self.0.keyring(), pattern).map_err(|e| JsError::new(&e.0))?;
+        Ok(key.code(time as u64))
+    }
+
+    /// Note conversations from now on, secrets redacted, for `transcript`.
+    pub fn record(&mut self) {
+        self.0.start_transcript();
+    }
+
+    /// What was noted since `record`, as JSON.
+    pub fn transcript(&self) -> Option<String> {
+        self.0.transcript().map(Transcript::to_json)
+    }
+
+    /// One rpc transaction: start, read, write, authinfo or attr.
+    pub fn rpc(&mut self, verb: &str, arg: &[u8]) -> JsRpcReply {
+        JsRpcReply(self.0.rpc(verb, arg))
+    }
+
+    /// After a needkey reply: what to prompt the user for.
+    #[wasm_bindgen(js_name = keyRequest)]
+    pub fn key_request(&self) -> Option<JsKeyRequest> {
+        self.0.key_request().cloned().map(JsKeyRequest)
+    }
+
+    /// Answer the pending key request, `names[i]` having value `values[i]`,
+    /// and resume the conversation; returns the replayed verb's reply.
+    #[wasm_bindgen(js_name = supplyKey)]
+    pub fn supply_key(
+        &mut self,
+        names: Vec<String>,
+        values: Vec<String>,
+    ) -> Result<JsRpcReply, JsError> {
+        let req = self
+            .0
+            .key_request()
+            .ok_or_else(|| JsError::new("no key request pending"))?;
+        let answers: Vec<(&str, &str)> = names
+            .iter()
+            .map(String::as_str)
+            .zip(values.iter().map(String::as_str))
+            .collect();
+        let key = req.answer(&answers).map_err(|e| JsError::new(&e.0))?;
+        Ok(JsRpcReply(self.0.supply_key(key)))
+    }
+}
+
+/// A TLS 1.2 PSK connection over an existing byte stream. Feed what the
+/// peer sends to `decrypt`, which returns any 9P bytes it carried, and
+/// send whatever `take` has after each call; once `established`, `encrypt`
+/// turns outgoing 9P into records.
+#[wasm_bindgen(js_name = TlsPsk)]
+pub struct JsTlsPsk(tlspsk::TlsPsk);
+
+#[wasm_bindgen(js_class = TlsPsk)]
+impl JsTlsPsk {
+    /// A client keyed by `secret` (an AuthInfo secret); take the
+    /// ClientHello first.
+    pub fn client(secret: &[u8]) -> JsTlsPsk {
+        JsTlsPsk(tlspsk::TlsPsk::client(secret, &TlsSuite::ALL))
+    }
+
+    /// A server keyed by `secret`.
+    pub fn server(secret: &[u8]) -> JsTlsPsk {
+        JsTlsPsk(tlspsk::TlsPsk::server(secret, &TlsSuite::ALL))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn established(&self) -> bool {
+        self.0.is_established()
+    }
+
+    /// The agreed suite's number, 0 before the hellos.
+    #[wasm_bindgen(getter)]
+    pub fn suite(&self) -> u16 {
+        self.0.suite().map_or(0, |s| s as u16)
+    }
+
+    /// Handshake bytes (or a fatal alert) to send to the peer.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.0.take()
+    }
+
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.decrypt(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.encrypt(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// The close_notify to send before hanging up.
+    pub fn close(&mut self) -> Vec<u8> {
+        self.0.close()
+    }
+}
+
+/// devssl records, as `cpu -e` uses them: `encrypt` outgoing 9P and feed
+/// what the server sends to `decrypt`.
+#[wasm_bindgen(js_name = Devssl)]
+pub struct JsDevssl(devssl::Devssl);
+
+fn rand4(b: &[u8]) -> Result<[u8; devssl::RANDLEN], JsError> {
+    b.try_into()
+        .map_err(|_| JsError::new("need 4 random bytes"))
+}
+
+#[wasm_bindgen(js_class = Devssl)]
+impl JsDevssl {
+    /// Four random bytes for the client to send.
+    pub fn random() -> Vec<u8> {
+        devssl::cpu_random().to_vec()
+    }
+
+    /// A client keyed by `secret` (an AuthInfo secret) and the exchanged
+    /// random bytes; `algs` is as for `cpu -e`, "rc4_256 sha1" if empty.
+    pub fn client(
+        secret: &[u8],
+        crand: &[u8],
+        srand: &[u8],
+        algs: &str,
+    ) -> Result<JsDevssl, JsError> {
+        let ai = rpc::AuthInfo {
+            secret: secret.to_vec(),
+            ..Default::default()
+        };
+        let algs = if algs.is_empty() {
+            devssl::DEFAULTALGS
+        } else {
+            algs
+        };
+        devssl::Devssl::client(&ai, &rand4(crand)?, &rand4(srand)?, algs)
+            .map(JsDevssl)
+            .map_err(|e| JsError::new(&e.0))
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.0.encrypt(data)
+    }
+
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.decrypt(data).map_err(|e| JsError::new(&e.0))
+    }
+}
+
+/// The terminal's end of an rcpu connection: p9any, TLS and the script,
+/// then 9P from the server. Send what `take` has after every call; relay
+/// `takeAuthsrv` to the auth server and answer `keyRequest` as for
+/// `Factotum`.
+#[wasm_bindgen(js_name = Rcpu)]
+pub struct JsRcpu(rcpu::Rcpu);
+
+#[wasm_bindgen(js_class = Rcpu)]
+impl JsRcpu {
+    /// Authenticate with `factotum`'s keys (it is used up) and run
+    /// `script`, or drawterm's if empty.
+    #[wasm_bindgen(constructor)]
+    pub fn new(factotum: JsFactotum, script: &str) -> JsRcpu {
+        let script = if script.is_empty() {
+            rcpu::RCPUSCRIPT
+        } else {
+            script
+        };
+        JsRcpu(rcpu::Rcpu::new(factotum.0, script))
+    }
+
+    /// auth, tls, running or closed
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> String {
+        format!("{:?}", self.0.state()).to_lowercase()
+    }
+
+    pub fn take(&mut self) -> Vec<u8> {
+        self.0.take()
+    }
+
+    /// The authentication transcript as JSON, if the factotum was
+    /// recording.
+    pub fn transcript(&self) -> Option<String> {
+        self.0.factotum().transcript().map(Transcript::to_json)
+    }
+
+    /// The next ticket request to send to the auth server for `dom`, if
+    /// any; the answer goes to `authsrvReply`.
+    #[wasm_bindgen(js_name = takeAuthsrv)]
+    pub fn take_authsrv(&mut self) -> Option<JsRpcReply> {
+        self.0.take_authsrv().map(JsRpcReply)
+    }
+
+    #[wasm_bindgen(js_name = authsrvReply)]
+    pub fn authsrv_reply(&mut self, data: &[u8]) -> Result<(), JsError> {
+        self.0.authsrv_reply(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    #[wasm_bindgen(js_name = keyRequest)]
+    pub fn key_request(&self) -> Option<JsKeyRequest> {
+        match self.0.auth_state() {
+            rpc::ProxyState::Key => self.0.factotum().key_request().cloned().map(JsKeyRequest),
+            _ => None,
+        }
+    }
+
+    /// Answer `keyRequest`, `names[i]` having value `values[i]`.
+    #[wasm_bindgen(js_name = supplyKey)]
+    pub fn supply_key(&mut self, names: Vec<String>, values: Vec<String>) -> Result<(), JsError> {
+        let req = self
+            .key_request()
+            .ok_or_else(|| JsError::new("no key request pending"))?;
+        let answers: Vec<(&str, &str)> = names
+            .iter()
+            .map(String::as_str)
+            .zip(values.iter().map(String::as_str))
+            .collect();
+        let key = req.0.answer(&answers).map_err(|e| JsError::new(&e.0))?;
+        self.0.supply_key(key).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// Bytes from the server; returns the 9P requests they carry.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.input(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// 9P replies, as records for the server.
+    pub fn send(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.send(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    pub fn close(&mut self) -> Vec<u8> {
+        self.0.close()
+    }
+}
+
+/// aan under the connection, so it survives the WebSocket: pass outgoing
+/// bytes through `send` and incoming through `input`, send `take` after
+/// each input, and on a new WebSocket send `resume` first.
+#[wasm_bindgen(js_name = Aan)]
+pub struct JsAan(aan::Aan);
+
+#[wasm_bindgen(js_class = Aan)]
+impl JsAan {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> JsAan {
+        JsAan(aan::Aan::new())
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Vec<u8> {
+        self.0.send(data)
+    }
+
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0
+            .input(data, keycache::unix_time())
+            .map_err(|e| JsError::new(&e.0))
+    }
+
+    /// Acks owed to the peer.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.0.take()
+    }
+
+    /// A keepalive, for idle connections.
+    pub fn ack(&self) -> Vec<u8> {
+        self.0.ack()
+    }
+
+    /// What to send first on a new transport.
+    pub fn resume(&mut self) -> Vec<u8> {
+        self.0.resume()
+    }
+
+    /// Messages the peer has yet to ack.
+    #[wasm_bindgen(getter)]
+    pub fn unacked(&self) -> usize {
+        self.0.unacked()
+    }
+
+    /// True once the peer has been silent too long to come back.
+    #[wasm_bindgen(getter)]
+    pub fn expired(&self) -> bool {
+        self.0.expired(keycache::unix_time())
+    }
+}
+
+impl Default for JsAan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The browser's end of the relay: 9P and auth server conversations on
+/// one WebSocket. Wrap outgoing 9P with `send`, unwrap what arrives with
+/// `input`, and hand factotum's authsrv replies to `authsrv`.
+#[wasm_bindgen(js_name = Relay)]
+pub struct JsRelay(relay::RelayClient);
+
+#[wasm_bindgen(js_class = Relay)]
+impl JsRelay {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> JsRelay {
+        JsRelay(relay::RelayClient::new())
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Vec<u8> {
+        self.0.send(data)
+    }
+
+    /// Frames from the relay; returns the 9P they carry.
+    pub fn input(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        self.0.input(data).map_err(|e| JsError::new(&e.0))
+    }
+
+    /// Frames sending an authsrv reply's request to its domain's auth
+    /// server.
+    pub fn authsrv(&mut self, reply: &JsRpcReply) -> Result<Vec<u8>, JsError> {
+        self.0
+            .authsrv_request(&reply.0)
+            .map_err(|e| JsError::new(&e.0))
+    }
+
+    /// The auth server's answer so far, to `write` to factotum.
+    #[wasm_bindgen(js_name = authsrvAnswer)]
+    pub fn authsrv_answer(&mut self) -> Result<Vec<u8>, JsError> {
+        self.0.authsrv_answer().map_err(|e| JsError::new(&e.0))
+    }
+}
+
+impl Default for JsRelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What `authenticate` established: the user, and the secret, kept here
+/// and handed only to the channel built on it.
+#[wasm_bindgen(js_name = AuthInfo)]
+pub struct JsAuthInfo(conn::Authenticated);
+
+#[wasm_bindgen(js_class = AuthInfo)]
+impl JsAuthInfo {
+    #[wasm_bindgen(getter)]
+    pub fn cuid(&self) -> String {
+        self.0.ai.cuid.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn suid(&self) -> String {
+        self.0.ai.suid.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn cap(&self) -> String {
+        self.0.ai.cap.clone()
+    }
+
+    /// What the server sent after the conversation, for the channel.
+    #[wasm_bindgen(getter)]
+    pub fn leftover(&self) -> Vec<u8> {
+        self.0.leftover.clone()
+    }
+
+    /// See `Factotum.tlsClient`.
+    #[wasm_bindgen(js_name = tlsClient)]
+    pub fn tls_client(&self) -> JsTlsPsk {
+        JsTlsPsk(tlspsk::TlsPsk::client(&self.0.ai.secret, &TlsSuite::ALL))
+    }
+
+    /// See `Factotum.sslClient`.
+    #[wasm_bindgen(js_name = sslClient)]
+    pub fn ssl_client(&self, crand: &[u8], srand: &[u8], algs: &str) -> Result<JsDevssl, JsError> {
+        JsDevssl::client(&self.0.ai.secret, crand, srand, algs)
+    }
+}
+
+fn js_io(v: JsValue) -> io::Error {
+    io::Error::other(v.as_string().unwrap_or_else(|| format!("{:?}", v)))
+}
+
+/// Call `obj[name](...args)` and await what it returns.
+async fn call(obj: &JsValue, name: &str, args: &[JsValue]) -> io::Result<JsValue> {
+    let f: js_sys::Function = js_sys::Reflect::get(obj, &JsValue::from_str(name))
+        .map_err(js_io)?
+        .dyn_into()
+        .map_err(|_| io::Error::other(format!("no {} function", name)))?;
+    let args: js_sys::Array = args.iter().collect();
+    let r = f.apply(obj, &args).map_err(js_io)?;
+    JsFuture::from(js_sys::Promise::resolve(&r))
+        .await
+        .map_err(js_io)
+}
+
+/// A JS object with `read(n)` and `write(data)` returning promises
+struct JsStream(JsValue);
+
+impl conn::Transport for JsStream {
+    async fn read(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let v = call(&self.0, "read", &[JsValue::from(n as u32)]).await?;
+        Ok(js_sys::Uint8Array::new(&v).to_vec())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = js_sys::Uint8Array::from(data);
+        call(&self.0, "write", &[data.into()]).await.map(|_| ())
+    }
+}
+
+/// The `authsrv` and `getKey` of an `authenticate` config
+struct JsServices(JsValue);
+
+impl conn::Services for JsServices {
+    type Authsrv = JsStream;
+
+    async fn dial_authsrv(&mut self, dom: &str) -> io::Result<JsStream> {
+        call(&self.0, "authsrv", &[JsValue::from_str(dom)])
+            .await
+            .map(JsStream)
+    }
+
+    async fn key(&mut self, req: &rpc::KeyRequest) -> Option<Key> {
+        let req = JsKeyRequest(req.clone());
+        let line = call(&self.0, "getKey", &[req.into()])
+            .await
+            .ok()?
+            .as_string()?;
+        Keyring::parse(&line).ok()?.keys().first().cloned()
+    }
+}
+
+/// Authenticate over `config.transport`, an object whose `read(n)` and
+/// `write(data)` return promises, and resolve to an `AuthInfo`.
+///
+/// `config.keys` are key lines for the factotum and `config.params` what
+/// to start it with ("proto=p9any role=client" if absent). When tickets
+/// are wanted, `config.authsrv(dom)` must resolve to a transport to that
+/// domain's auth server. A missing key is asked of `config.getKey(req)`,
+/// a `KeyRequest`; it resolves to a key line, or null to give up.
+///
+/// With `config.attach = {uname, aname, msize}` the transport carries 9P
+/// that has been through Tversion: authentication runs through an auth
+/// fid and ends in Tattach of fid 0, resolving to null if the server
+/// needed none.
+#[wasm_bindgen]
+pub fn authenticate(config: JsValue) -> js_sys::Promise {
+    future_to_promise(async move {
+        let get = |name: &str| {
+            js_sys::Reflect::get(&config, &JsValue::from_str(name))
+                .ok()
+                .filter(|v| !v.is_undefined() && !v.is_null())
+        };
+        let keys = get("keys").and_then(|v| v.as_string()).unwrap_or_default();
+        let ring = Keyring::parse(&keys).map_err(|e| JsError::new(&e.0))?;
+        let f = rpc::Factotum::new(ring);
+        let mut conn = JsStream(get("transport").ok_or_else(|| JsError::new("no transport"))?);
+        let mut services = JsServices(config.clone());
+        let done = match get("attach") {
+            Some(a) => {
+                let field = |name: &str| js_sys::Reflect::get(&a, &JsValue::from_str(name)).ok();
+                let mut at = Attach::new(
+                    &field("uname")
+                        .and_then(|v| v.as_string())
+                        .unwrap_or_default(),
+                    &field("aname")
+                        .and_then(|v| v.as_string())
+                        .unwrap_or_default(),
+                );
+                if let Some(msize) = field("msize").and_then(|v| v.as_f64()) {
+                    at.msize = msize as u32;
+                }
+                let at = conn::attach(&mut conn, &mut services, f, at)
+                    .await
+                    .map_err(|e| JsError::new(&e.0))?;
+                match at.ai {
+                    Some(ai) => conn::Authenticated {
+                        ai,
+                        leftover: Vec::new(),
+                    },
+                    None => return Ok(JsValue::NULL),
+                }
+            }
+            None => {
+                let params = get("params").and_then(|v| v.as_string());
+                let params = params.as_deref().unwrap_or(session::SESSIONPARAMS);
+                conn::authenticate(&mut conn, &mut services, f, params)
+                    .await
+                    .map_err(|e| JsError::new(&e.0))?
+            }
+        };
+        Ok(JsAuthInfo(done).into())
+    })
+}
+
+// Sessions, by handle: several authenticated connections in one page.
+// They share the keys given to session_add_keys and supplied for any one.
+
+fn with_session<R>(
+    h: u32,
+    f: impl FnOnce(&mut session::Session) -> Result<R, session::SessionError>,
+) -> Result<R, JsError> {
+    session::with(|r| r.get_mut(h).and_then(f)).map_err(|e| JsError::new(&e.0))
+}
+
+/// Keys (factotum ctl lines) for every session, open or still to come.
+#[wasm_bindgen]
+pub fn session_add_keys(keys: &str) -> Result<(), JsError> {
+    let ring = Keyring::parse(keys).map_err(|e| JsError::new(&e.0))?;
+    session::with(|r| {
+        for key in ring.keys() {
+            r.add_key(key.clone());
+        }
+    });
+    Ok(())
+}
+
+/// Start authenticating a connection to `label`; `params` defaults to
+/// "proto=p9any role=client". Returns its handle.
+#[wasm_bindgen]
+pub fn session_open(label: &str, params: &str) -> u32 {
+    let params = if params.is_empty() {
+        session::SESSIONPARAMS
+    } else {
+        params
+    };
+    session::with(|r| r.open(label, params))
+}
+
+/// peer, authsrv, key, done or failed
+#[wasm_bindgen]
+pub fn session_state(h: u32) -> Result<String, JsError> {
+    with_session(h, |s| {
+        Ok(match s.state() {
+            rpc::ProxyState::Failed(_) => "failed".to_string(),
+            st => format!("{:?}", st).to_lowercase(),
+        })
+    })
+}
+
+/// Why authentication failed, if it did.
+#[wasm_bindgen]
+pub fn session_error(h: u32) -> Result<Option<String>, JsError> {
+    with_session(h, |s| {
+        Ok(match s.state() {
+            rpc::ProxyState::Failed(e) => Some(e.clone()),
+            _ => None,
+        })
+    })
+}
+
+#[wasm_bindgen]
+pub fn session_label(h: u32) -> Result<String, JsError> {
+    with_session(h, |s| Ok(s.label().to_string()))
+}
+
+/// The authenticated user, once done.
+#[wasm_bindgen]
+pub fn session_user(h: u32) -> Result<Option<String>, JsError> {
+    with_session(h, |s| Ok(s.authinfo().map(|ai| ai.cuid.clone())))
+}
+
+#[wasm_bindgen]
+pub fn session_take(h: u32) -> Result<Vec<u8>, JsError> {
+    with_session(h, |s| Ok(s.take()))
+}
+
+/// Bytes from the server; returns what the channel carried.
+#[wasm_bindgen]
+pub fn session_input(h: u32, data: &[u8]) -> Result<Vec<u8>, JsError> {
+    with_session(h, |s| s.input(data))
+}
+
+/// Data for the server, as records.
+#[wasm_bindgen]
+pub fn session_send(h: u32, data: &[u8]) -> Result<Vec<u8>, JsError> {
+    with_session(h, |s| s.send(data))
+}
+
+/// Whether `session_send` can be used.
+#[wasm_bindgen]
+pub fn session_established(h: u32) -> Result<bool, JsError> {
+    with_session(h, |s| Ok(s.is_established()))
+}
+
+/// See `Rcpu.takeAuthsrv`.
+#[wasm_bindgen]
+pub fn session_take_authsrv(h: u32) -> Result<Option<JsRpcReply>, JsError> {
+    with_session(h, |s| Ok(s.auth_mut().take_authsrv().map(JsRpcReply)))
+}
+
+#[wasm_bindgen]
+pub fn session_authsrv_reply(h: u32, data: &[u8]) -> Result<(), JsError> {
+    with_session(h, |s| {
+        s.auth_mut().put_authsrv(data);
+        Ok(())
+    })
+}
+
+#[wasm_bindgen]
+pub fn session_key_request(h: u32) -> Result<Option<JsKeyRequest>, JsError> {
+    with_session(h, |s| {
+        Ok(match s.state() {
+            rpc::ProxyState::Key => s.auth().factotum().key_request().cloned().map(JsKeyRequest),
+            _ => None,
+        })
+    })
+}
+
+/// Answer `session_key_request`, as `Rcpu.supplyKey`; the key goes to
+/// the other sessions too.
+#[wasm_bindgen]
+pub fn session_supply_key(h: u32, names: Vec<String>, values: Vec<String>) -> Result<(), JsError> {
+    let req = session_key_request(h)?.ok_or_else(|| JsError::new("no key request pending"))?;
+    let answers: Vec<(&str, &str)> = names
+        .iter()
+        .map(String::as_str)
+        .zip(values.iter().map(String::as_str))
+        .collect();
+    let key = req.0.answer(&answers).map_err(|e| JsError::new(&e.0))?;
+    session::with(|r| r.supply_key(h, key)).map_err(|e| JsError::new(&e.0))
+}
+
+/// Once done, protect the connection with TLS-PSK, as rcpu does.
+#[wasm_bindgen]
+pub fn session_tls(h: u32) -> Result<(), JsError> {
+    with_session(h, |s| s.start_tls())
+}
+
+/// Once done, protect the connection as `cpu -e algs` does; empty
+/// `algs` means "rc4_256 sha1".
+#[wasm_bindgen]
+pub fn session_ssl(h: u32, algs: &str) -> Result<(), JsError> {
+    let algs = if algs.is_empty() {
+        devssl::DEFAULTALGS
+    } else {
+        algs
+    };
+    with_session(h, |s| s.start_ssl(algs))
+}
+
+/// Forget the session and its secrets; returns the goodbye to send.
+#[wasm_bindgen]
+pub fn session_close(h: u32) -> Result<Vec<u8>, JsError> {
+    session::with(|r| r.close(h)).map_err(|e| JsError::new(&e.0))
+}
+
+/// Forget every session and every key, as on logout.
+#[wasm_bindgen]
+pub fn session_close_all() {
+    session::with(|r| r.close_all());
+}
+
+/// The open sessions' handles.
+#[wasm_bindgen]
+pub fn session_handles() -> Vec<u32> {
+    session::with(|r| r.handles())
+}
+
+/// A suspended needkey: the protocol and domain wanting a key, and the
+/// attribute names to ask for (secret ones start with `!`). A `confirm`
+/// request has none; supplying it approves the key's use.
+#[wasm_bindgen(js_name = KeyRequest)]
+pub struct JsKeyRequest(rpc::KeyRequest);
+
+#[wasm_bindgen(js_class = KeyRequest)]
+impl JsKeyRequest {
+    #[wasm_bindgen(getter)]
+    pub fn proto(&self) -> String {
+        self.0.proto.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn dom(&self) -> Option<String> {
+        self.0.dom.clone()
+    }
+
+    /// Whether this asks the owner to approve a `confirm` key.
+    #[wasm_bindgen(getter)]
+    pub fn confirm(&self) -> bool {
+        self.0.is_confirm()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn prompts(&self) -> Vec<String> {
+        self.0.prompts().into_iter().map(str::to_string).collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn template(&self) -> String {
+        self.0.template()
+    }
+}
+
+/// A reply from `Factotum.rpc`.
+#[wasm_bindgen(js_name = RpcReply)]
+pub struct JsRpcReply(rpc::RpcReply);
+
+#[wasm_bindgen(js_class = RpcReply)]
+impl JsRpcReply {
+    /// ok, done, error, needkey, toosmall, phase or authsrv
+    #[wasm_bindgen(getter)]
+    pub fn verb(&self) -> String {
+        self.0.verb().to_string()
+    }
+
+    /// The reply's argument bytes; for authsrv, the request to send.
+    #[wasm_bindgen(getter)]
+    pub fn arg(&self) -> Vec<u8> {
+        self.0.arg()
+    }
+
+    /// The argument as text, for error, needkey, toosmall and phase.
+    #[wasm_bindgen(getter)]
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.arg()).to_string()
+    }
+
+    /// For authsrv: the authentication domain whose server to dial.
+    #[wasm_bindgen(getter)]
+    pub fn dom(&self) -> Option<String> {
+        match &self.0 {
+            rpc::RpcReply::Authsrv { dom, .. } => Some(dom.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netkey_export() {
+        let key = JsAuthkey::new("secret");
+        let resp = netkey("1234567", &key);
+        assert_eq!(resp, netkey::netcrypt(&key.0.des, "1234567"));
+        assert_eq!(netkey_decimal(&resp), netkey::netdecimal(&resp));
+    }
+
+    #[test]
+    fn test_vnc_export() {
+        let chal = [7u8; vnc::VNCCHALLEN];
+        let resp = vnc_response(&chal, "secret").ok().unwrap();
+        assert_eq!(resp, vnc::vnc_response(&chal, b"secret"));
+    }
+
+    #[test]
+    fn test_factotum_export() {
+        let mut f = JsFactotum::new("key proto=dp9ik dom=nawin user=glenda !password=kittens\n")
+            .ok()
+            .unwrap();
+        assert!(f
+            .ctl("key proto=p9sk1 dom=nawin user=glenda !password=kittens")
+            .is_ok());
+        f.refuse_p9sk1(true);
+        assert_eq!(f.rpc("start", b"proto=p9sk1 role=client").verb(), "error");
+        let r = f.rpc("start", b"proto=dp9ik role=client");
+        assert_eq!(r.verb(), "ok");
+        let r = f.rpc("read", b"");
+        assert_eq!(
+            (r.verb(), r.arg().len(), r.dom()),
+            ("ok".to_string(), 8, None)
+        );
+        let r = f.rpc("write", b"");
+        assert_eq!(
+            (r.verb(), r.text()),
+            ("toosmall".to_string(), "197".to_string())
+        );
+    }
+
+    #[test]
+    fn test_key_request_export() {
+        let mut f = JsFactotum::new("").ok().unwrap();
+        assert!(f.key_request().is_none());
+        assert_eq!(f.rpc("start", b"proto=p9sk1 role=client").verb(), "ok");
+        assert_eq!(f.rpc("read", b"").verb(), "ok");
+        let tr = authsrv::Ticketreq {
+            req_type: crate::p9sk1::AUTH_TREQ,
+            authid: "bootes".to_string(),
+            authdom: "nawin".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(f.rpc("write", &tr.to_bytes()).verb(), "needkey");
+
+        let req = f.key_request().unwrap();
+        assert_eq!(req.dom(), Some("nawin".to_string()));
+        assert_eq!(req.prompts(), vec!["user", "!password"]);
+        let names = vec!["user".to_string(), "!password".to_string()];
+        let values = vec!["glenda".to_string(), "kittens".to_string()];
+        let r = f.supply_key(names, values).ok().unwrap();
+        assert_eq!(r.verb(), "ok");
+        assert_eq!(f.rpc("read", b"").dom(), Some("nawin".to_string()));
+    }
+
+    #[test]
+    fn test_totp_export() {
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(
+            totp_code(secret, 59.0, 8, 30, "sha1").ok().unwrap(),
+            "94287082"
+        );
+        let line = format!("key proto=totp label=vpn digits=8 !secret={}", secret);
+        let f = JsFactotum::new(&line).ok().unwrap();
+        assert_eq!(f.totp("label=vpn", 59.0).ok().unwrap(), "94287082");
+    }
+
+    #[test]
+    fn test_seal_export() {
+        let f = JsFactotum::new("key proto=dp9ik dom=nawin user=glenda !password=kittens\n")
+            .ok()
+            .unwrap();
+        let blob = f.seal("1234", "glenda@nawin").ok().unwrap();
+        assert_eq!(sealed_metadata(&blob).ok().unwrap(), "glenda@nawin");
+        let g = JsFactotum::unseal(&blob, "1234").ok().unwrap();
+        assert_eq!(g.0.keyring().to_text(), f.0.keyring().to_text());
+    }
+
+    #[test]
+    fn test_key_cache_export() {
+        set_key_cache_ttl(60);
+        let key = authsrv::Authkey::from_password("kittens");
+        keycache::with(|c| c.insert("glenda", "nawin", &key, keycache::unix_time()));
+        assert_eq!(keycache::with(|c| c.len()), 1);
+        purge_key_cache();
+        assert_eq!(keycache::with(|c| c.len()), 0);
+        set_key_cache_ttl(0);
+    }
+
+    #[test]
+    fn test_tls_export() {
+        let mut c = JsTlsPsk::client(b"secret");
+        let mut s = JsTlsPsk::server(b"secret");
+        assert_eq!(c.suite(), 0);
+        while !(c.established() && s.established()) {
+            assert!(s.decrypt(&c.take()).ok().unwrap().is_empty());
+            assert!(c.decrypt(&s.take()).ok().unwrap().is_empty());
+        }
+        assert_eq!(s.suite(), 0xCCAB);
+        let rec = c.encrypt(b"Tversion").ok().unwrap();
+        assert_eq!(s.decrypt(&rec).ok().unwrap(), b"Tversion");
+        assert!(s.decrypt(&c.close()).ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tls_client_from_authinfo() {
+        use crate::testsrv::{proxy, TestAuthsrv, TestServer};
+
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut f = JsFactotum::new("key proto=dp9ik dom=nawin user=glenda !password=kittens")
+            .ok()
+            .unwrap();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let p = "proto=p9any role=client";
+        proxy(&mut f.0, p, &mut server, &srv, &mut |_| None).unwrap();
+
+        let mut c = f.tls_client().ok().unwrap();
+        let mut s = JsTlsPsk::server(&server.secret().unwrap());
+        while !c.established() {
+            s.decrypt(&c.take()).ok().unwrap();
+            c.decrypt(&s.take()).ok().unwrap();
+        }
+        let rec = s.encrypt(b"Rattach").ok().unwrap();
+        assert_eq!(c.decrypt(&rec).ok().unwrap(), b"Rattach");
+    }
+
+    #[test]
+    fn test_ssl_client_from_authinfo() {
+        use crate::testsrv::{proxy, TestAuthsrv, TestServer};
+
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut f = JsFactotum::new("key proto=p9sk1 dom=nawin user=glenda !password=kittens")
+            .ok()
+            .unwrap();
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin");
+        let p = "proto=p9any role=client";
+        proxy(&mut f.0, p, &mut server, &srv, &mut |_| None).unwrap();
+
+        let (crand, srand) = (JsDevssl::random(), JsDevssl::random());
+        let mut c = f.ssl_client(&crand, &srand, "").ok().unwrap();
+        let ai = rpc::AuthInfo {
+            secret: server.secret().unwrap(),
+            ..Default::default()
+        };
+        let mut s = devssl::Devssl::server(
+            &ai,
+            &crand.try_into().unwrap(),
+            &srand.try_into().unwrap(),
+            devssl::DEFAULTALGS,
+        )
+        .unwrap();
+        assert_eq!(s.decrypt(&c.encrypt(b"Tattach")).unwrap(), b"Tattach");
+        assert_eq!(c.decrypt(&s.encrypt(b"Rattach")).ok().unwrap(), b"Rattach");
+    }
+
+    #[test]
+    fn test_transcript_export() {
+        let mut f = JsFactotum::new("").ok().unwrap();
+        assert!(f.transcript().is_none());
+        f.record();
+        f.rpc("start", b"proto=p9sk1 role=client");
+        f.rpc("read", b"");
+        let tr = authsrv::Ticketreq {
+            req_type: crate::p9sk1::AUTH_TREQ,
+            authid: "bootes".to_string(),
+            authdom: "nawin".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(f.rpc("write", &tr.to_bytes()).verb(), "needkey");
+        let json = f.transcript().unwrap();
+        assert!(json.contains("\"msg\":\"treq\""));
+
+        assert_eq!(
+            replay_transcript(&json, "").ok().unwrap(),
+            "all 4 entries matched"
+        );
+        let keys = "key proto=p9sk1 dom=nawin user=glenda !password=kittens";
+        let r = replay_transcript(&json, keys).ok().unwrap();
+        assert!(
+            r.starts_with("diverged at entry 3: recorded send caller needkey"),
+            "{}",
+            r
+        );
+    }
+
+    #[test]
+    fn test_rcpu_export() {
+        use crate::testsrv::{TestAuthsrv, TestServer};
+
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut cpu = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let mut c = JsRcpu::new(JsFactotum::new("").ok().unwrap(), "");
+        assert_eq!(c.state(), "auth");
+        while cpu.secret().is_none() {
+            if c.key_request().is_some() {
+                let names = vec!["user".to_string(), "!password".to_string()];
+                let values = vec!["glenda".to_string(), "kittens".to_string()];
+                c.supply_key(names, values).ok().unwrap();
+            }
+            if let Some(r) = c.take_authsrv() {
+                assert_eq!(r.dom().as_deref(), Some("nawin"));
+                c.authsrv_reply(&srv.serve(&r.arg())).ok().unwrap();
+            }
+            cpu.put(&c.take());
+            c.input(&cpu.take(4096)).ok().unwrap();
+        }
+        assert_eq!(c.state(), "tls");
+        let mut s = JsTlsPsk::server(&cpu.secret().unwrap());
+        let mut script = Vec::new();
+        while script.is_empty() {
+            script.extend(s.decrypt(&c.take()).ok().unwrap());
+            c.input(&s.take()).ok().unwrap();
+        }
+        assert_eq!(script, rcpu::script_message(rcpu::RCPUSCRIPT));
+        assert_eq!(c.state(), "running");
+        let rec = c.send(b"Rversion").ok().unwrap();
+        assert_eq!(s.decrypt(&rec).ok().unwrap(), b"Rversion");
+    }
+
+    #[test]
+    fn test_aan_export() {
+        use crate::testaan::TestAanServer;
+
+        let mut srv = TestAanServer::new();
+        let mut a = JsAan::new();
+        srv.connect();
+        srv.put(&a.send(b"Tversion"));
+        srv.hangup();
+        srv.connect();
+        srv.put(&a.resume());
+        assert_eq!(a.input(&srv.take()).ok().unwrap(), b"Tversion");
+        srv.put(&a.take());
+        assert_eq!(a.unacked(), 0);
+        assert!(!a.expired());
+    }
+
+    #[test]
+    fn test_relay_export() {
+        let mut r = relay::RelayServer::new(&[("nawin", "tcp!auth!567")]);
+        let mut c = JsRelay::new();
+        let reply = JsRpcReply(rpc::RpcReply::Authsrv {
+            dom: "nawin".to_string(),
+            data: b"treq".to_vec(),
+        });
+        let ev = r.input(&c.authsrv(&reply).ok().unwrap()).unwrap();
+        assert!(matches!(ev[0], relay::RelayEvent::Dial { chan: 1, .. }));
+        assert_eq!(
+            ev[1],
+            relay::RelayEvent::Authsrv {
+                chan: 1,
+                data: b"treq".to_vec()
+            }
+        );
+        r.dialed(1, Ok(())).unwrap();
+        r.authsrv(1, b"tickets");
+        r.backend(b"Rversion");
+        assert_eq!(c.input(&r.take()).ok().unwrap(), b"Rversion");
+        assert_eq!(c.authsrv_answer().ok().unwrap(), b"tickets");
+    }
+
+    #[test]
+    fn test_session_export() {
+        use crate::testsrv::{TestAuthsrv, TestServer};
+
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut cpus = [
+            TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin"),
+            TestServer::new("bootes", "cpusecret", "nawin", "p9sk1@nawin"),
+        ];
+        let hs = [session_open("cpu1", ""), session_open("cpu2", "")];
+        assert_eq!(session_handles(), hs);
+        assert_eq!(session_label(hs[1]).ok().unwrap(), "cpu2");
+        for _ in 0..6 {
+            for (&h, cpu) in hs.iter().zip(cpus.iter_mut()) {
+                if session_key_request(h).ok().unwrap().is_some() {
+                    let names = vec!["user".to_string(), "!password".to_string()];
+                    let values = vec!["glenda".to_string(), "kittens".to_string()];
+                    session_supply_key(h, names, values).ok().unwrap();
+                }
+                if let Some(r) = session_take_authsrv(h).ok().unwrap() {
+                    session_authsrv_reply(h, &srv.serve(&r.arg())).ok().unwrap();
+                }
+                cpu.put(&session_take(h).ok().unwrap());
+                session_input(h, &cpu.take(4096)).ok().unwrap();
+            }
+        }
+        for h in hs {
+            assert_eq!(session_state(h).ok().unwrap(), "done");
+            assert_eq!(session_error(h).ok().unwrap(), None);
+            assert_eq!(session_user(h).ok().unwrap().as_deref(), Some("glenda"));
+        }
+        session_tls(hs[0]).ok().unwrap();
+        let mut s = JsTlsPsk::server(&cpus[0].secret().unwrap());
+        while !session_established(hs[0]).ok().unwrap() {
+            s.decrypt(&session_take(hs[0]).ok().unwrap()).ok().unwrap();
+            session_input(hs[0], &s.take()).ok().unwrap();
+        }
+        let rec = session_send(hs[0], b"Tversion").ok().unwrap();
+        assert_eq!(s.decrypt(&rec).ok().unwrap(), b"Tversion");
+        assert!(!session_established(hs[1]).ok().unwrap());
+
+        session_close(hs[0]).ok().unwrap();
+        assert_eq!(session_handles(), [hs[1]]);
+        session_close_all();
+        assert!(session_handles().is_empty());
+    }
+
+    #[test]
+    fn test_authinfo_export() {
+        use crate::testsrv::{proxy, TestAuthsrv, TestServer};
+
+        let mut srv = TestAuthsrv::new("nawin");
+        srv.add_user("glenda", "kittens");
+        srv.add_user("bootes", "cpusecret");
+        let mut f = rpc::Factotum::new(
+            Keyring::parse("key proto=dp9ik dom=nawin user=glenda !password=kittens").unwrap(),
+        );
+        let mut server = TestServer::new("bootes", "cpusecret", "nawin", "dp9ik@nawin");
+        let ai = proxy(
+            &mut f,
+            session::SESSIONPARAMS,
+            &mut server,
+            &srv,
+            &mut |_| None,
+        )
+        .unwrap();
+        let ai = JsAuthInfo(conn::Authenticated {
+            ai,
+            leftover: b"Tversion".to_vec(),
+        });
+        assert_eq!(ai.cuid(), "glenda");
+        assert_eq!(ai.leftover(), b"Tversion");
+        let mut c = ai.tls_client();
+        let mut s = JsTlsPsk::server(&server.secret().unwrap());
+        while !c.established() {
+            s.decrypt(&c.take()).ok().unwrap();
+            c.decrypt(&s.take()).ok().unwrap();
+        }
+        let rec = c.encrypt(b"Tattach").ok().unwrap();
+        assert_eq!(s.decrypt(&rec).ok().unwrap(), b"Tattach");
+    }
+}